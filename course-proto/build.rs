@@ -2,8 +2,18 @@ extern crate prost_build;
 
 fn main() {
     let mut config = prost_build::Config::new();
-    config.bytes(&["."]);
-    config.type_attribute(".", "#[derive(PartialOrd)]");
+    config.bytes(["."]);
+    // BTreeMap 实现了 PartialOrd, 而且字段的顺序是确定的
+    config.btree_map(["."]);
+    // prost 生成的 enum 已经实现了 PartialOrd, 这里只给 message 和 oneof 加上
+    config.message_attribute(".", "#[derive(PartialOrd)]");
+    for oneof in [
+        ".abi.CommandRequest.request_data",
+        ".abi.Value.value",
+        ".abi.Hfind.condition",
+    ] {
+        config.type_attribute(oneof, "#[derive(PartialOrd)]");
+    }
     config
         .out_dir("src/pb")
         .compile_protos(&["proto/kv_server/abi.proto"], &["proto/kv_server/"])
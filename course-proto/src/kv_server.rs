@@ -1,4 +1,10 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use crate::pb::abi::{command_request::RequestData, *};
+use prost::bytes::Bytes;
 
 impl KvPair {
     pub fn new(key: impl Into<String>, value: Value) -> Self {
@@ -25,7 +31,230 @@ impl From<&str> for Value {
     }
 }
 
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Self {
+            value: Some(value::Value::Integer(i)),
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Self {
+            value: Some(value::Value::Float(f)),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Self {
+            value: Some(value::Value::Bool(b)),
+        }
+    }
+}
+
+impl From<Bytes> for Value {
+    fn from(buf: Bytes) -> Self {
+        Self {
+            value: Some(value::Value::Binary(buf)),
+        }
+    }
+}
+
+impl From<ValueList> for Value {
+    fn from(list: ValueList) -> Self {
+        Self {
+            value: Some(value::Value::List(list)),
+        }
+    }
+}
+
+impl From<Vec<Value>> for ValueList {
+    fn from(values: Vec<Value>) -> Self {
+        Self { values }
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Self {
+        ValueList::from(values).into()
+    }
+}
+
+impl From<ValueMap> for Value {
+    fn from(map: ValueMap) -> Self {
+        Self {
+            value: Some(value::Value::Map(map)),
+        }
+    }
+}
+
+impl From<BTreeMap<String, Value>> for ValueMap {
+    fn from(fields: BTreeMap<String, Value>) -> Self {
+        Self { fields }
+    }
+}
+
+impl From<BTreeMap<String, Value>> for Value {
+    fn from(fields: BTreeMap<String, Value>) -> Self {
+        ValueMap::from(fields).into()
+    }
+}
+
+impl From<SystemTime> for Value {
+    fn from(t: SystemTime) -> Self {
+        // UNIX 纪元之前的时间用负数表示
+        let micros = match t.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_micros() as i64,
+            Err(e) => -(e.duration().as_micros() as i64),
+        };
+        Self {
+            value: Some(value::Value::Timestamp(micros)),
+        }
+    }
+}
+
+impl Value {
+    /// 把 timestamp 转换成 SystemTime, 其它类型返回 None
+    pub fn as_system_time(&self) -> Option<SystemTime> {
+        match self.value {
+            Some(value::Value::Timestamp(micros)) if micros >= 0 => {
+                Some(UNIX_EPOCH + Duration::from_micros(micros as u64))
+            }
+            Some(value::Value::Timestamp(micros)) => {
+                Some(UNIX_EPOCH - Duration::from_micros(micros.unsigned_abs()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<Value> for CommandResponse {
+    fn from(v: Value) -> Self {
+        Self {
+            status: 200,
+            values: vec![v],
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Vec<Value>> for CommandResponse {
+    fn from(values: Vec<Value>) -> Self {
+        Self {
+            status: 200,
+            values,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Vec<KvPair>> for CommandResponse {
+    fn from(pairs: Vec<KvPair>) -> Self {
+        Self {
+            status: 200,
+            pairs,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<WatchEvent> for CommandResponse {
+    fn from(event: WatchEvent) -> Self {
+        Self {
+            status: 200,
+            events: vec![event],
+            ..Default::default()
+        }
+    }
+}
+
+impl RequestData {
+    /// 命令的名字, 和 kv-cli 中的命令名一致
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Hget(_) => "hget",
+            Self::Hgetall(_) => "hgetall",
+            Self::Hmget(_) => "hmget",
+            Self::Hset(_) => "hset",
+            Self::Hmset(_) => "hmset",
+            Self::Hdel(_) => "hdel",
+            Self::Hmdel(_) => "hmdel",
+            Self::Hexist(_) => "hexist",
+            Self::Hmexist(_) => "hmexist",
+            Self::Hexport(_) => "hexport",
+            Self::Himport(_) => "himport",
+            Self::Backup(_) => "backup",
+            Self::Restore(_) => "restore",
+            Self::Hexpire(_) => "hexpire",
+            Self::CreateIndex(_) => "createindex",
+            Self::Hfind(_) => "hfind",
+            Self::Watch(_) => "watch",
+            Self::Lpush(_) => "lpush",
+            Self::Lrange(_) => "lrange",
+            Self::MapGetField(_) => "mapgetfield",
+            Self::Snapshot(_) => "snapshot",
+            Self::ReleaseSnapshot(_) => "releasesnapshot",
+            Self::Eval(_) => "eval",
+            Self::EvalSha(_) => "evalsha",
+            Self::ScriptLoad(_) => "scriptload",
+            Self::Metrics(_) => "metrics",
+            Self::SlowLog(_) => "slowlog",
+            Self::Tables(_) => "tables",
+        }
+    }
+}
+
 impl CommandRequest {
+    /// 创建 HGET 命令
+    pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self::new_hget_at(table, key, 0)
+    }
+
+    /// 创建从 snapshot 中读取的 HGET 命令
+    pub fn new_hget_at(table: impl Into<String>, key: impl Into<String>, snapshot: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hget(Hget {
+                table: table.into(),
+                key: key.into(),
+                snapshot,
+            })),
+        }
+    }
+
+    /// 创建 HGETALL 命令
+    pub fn new_hgetall(table: impl Into<String>) -> Self {
+        Self::new_hgetall_at(table, 0)
+    }
+
+    /// 创建从 snapshot 中读取的 HGETALL 命令
+    pub fn new_hgetall_at(table: impl Into<String>, snapshot: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetall(Hgetall {
+                table: table.into(),
+                snapshot,
+            })),
+        }
+    }
+
+    /// 创建 HMGET 命令
+    pub fn new_hmget(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self::new_hmget_at(table, keys, 0)
+    }
+
+    /// 创建从 snapshot 中读取的 HMGET 命令
+    pub fn new_hmget_at(table: impl Into<String>, keys: Vec<String>, snapshot: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmget(Hmget {
+                table: table.into(),
+                keys,
+                snapshot,
+            })),
+        }
+    }
+
     /// 创建 HSET 命令
     pub fn new_hset(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
         Self {
@@ -35,4 +264,265 @@ impl CommandRequest {
             })),
         }
     }
+
+    /// 创建 HMSET 命令
+    pub fn new_hmset(table: impl Into<String>, pairs: Vec<KvPair>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmset(Hmset {
+                table: table.into(),
+                pairs,
+            })),
+        }
+    }
+
+    /// 创建 HDEL 命令
+    pub fn new_hdel(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hdel(Hdel {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    /// 创建 HMDEL 命令
+    pub fn new_hmdel(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmdel(Hmdel {
+                table: table.into(),
+                keys,
+            })),
+        }
+    }
+
+    /// 创建 HEXIST 命令
+    pub fn new_hexist(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexist(Hexist {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    /// 创建 HMEXIST 命令
+    pub fn new_hmexist(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmexist(Hmexist {
+                table: table.into(),
+                keys,
+            })),
+        }
+    }
+
+    /// 创建 HEXPORT 命令
+    pub fn new_hexport(table: impl Into<String>, after: Option<String>, limit: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexport(Hexport {
+                table: table.into(),
+                after,
+                limit,
+            })),
+        }
+    }
+
+    /// 创建 HIMPORT 命令
+    pub fn new_himport(table: impl Into<String>, pairs: Vec<KvPair>) -> Self {
+        Self {
+            request_data: Some(RequestData::Himport(Himport {
+                table: table.into(),
+                pairs,
+            })),
+        }
+    }
+
+    /// 创建 BACKUP 命令
+    pub fn new_backup(path: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Backup(Backup { path: path.into() })),
+        }
+    }
+
+    /// 创建 RESTORE 命令
+    pub fn new_restore(path: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Restore(Restore { path: path.into() })),
+        }
+    }
+
+    /// 创建 CREATE INDEX 命令
+    pub fn new_create_index(table: impl Into<String>, kind: IndexKind) -> Self {
+        Self {
+            request_data: Some(RequestData::CreateIndex(CreateIndex {
+                table: table.into(),
+                kind: kind.into(),
+            })),
+        }
+    }
+
+    /// 创建按 value 精确查找的 HFIND 命令
+    pub fn new_hfind_equal(table: impl Into<String>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hfind(Hfind {
+                table: table.into(),
+                condition: Some(hfind::Condition::Equal(value)),
+            })),
+        }
+    }
+
+    /// 创建按 value 范围查找的 HFIND 命令
+    pub fn new_hfind_range(
+        table: impl Into<String>,
+        min: Option<Value>,
+        max: Option<Value>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hfind(Hfind {
+                table: table.into(),
+                condition: Some(hfind::Condition::Range(ValueRange { min, max })),
+            })),
+        }
+    }
+
+    /// 创建 WATCH 命令
+    pub fn new_watch(table: impl Into<String>, prefix: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Watch(Watch {
+                table: table.into(),
+                prefix: prefix.into(),
+            })),
+        }
+    }
+
+    /// 创建 LPUSH 命令
+    pub fn new_lpush(table: impl Into<String>, key: impl Into<String>, values: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Lpush(Lpush {
+                table: table.into(),
+                key: key.into(),
+                values,
+                return_old: false,
+            })),
+        }
+    }
+
+    /// 创建 LRANGE 命令
+    pub fn new_lrange(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        start: i64,
+        stop: i64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Lrange(Lrange {
+                table: table.into(),
+                key: key.into(),
+                start,
+                stop,
+            })),
+        }
+    }
+
+    /// 创建 MAP GET FIELD 命令
+    pub fn new_map_get_field(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        field: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::MapGetField(MapGetField {
+                table: table.into(),
+                key: key.into(),
+                field: field.into(),
+            })),
+        }
+    }
+
+    /// 创建 SNAPSHOT 命令
+    pub fn new_snapshot() -> Self {
+        Self {
+            request_data: Some(RequestData::Snapshot(Snapshot {})),
+        }
+    }
+
+    /// 创建 RELEASESNAPSHOT 命令
+    pub fn new_release_snapshot(id: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::ReleaseSnapshot(ReleaseSnapshot { id })),
+        }
+    }
+
+    /// 创建 EVAL 命令
+    pub fn new_eval(script: impl Into<String>, args: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Eval(Eval {
+                script: script.into(),
+                args,
+            })),
+        }
+    }
+
+    /// 创建 EVALSHA 命令
+    pub fn new_evalsha(sha: impl Into<String>, args: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::EvalSha(EvalSha {
+                sha: sha.into(),
+                args,
+            })),
+        }
+    }
+
+    /// 创建 SCRIPTLOAD 命令
+    pub fn new_script_load(script: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::ScriptLoad(ScriptLoad {
+                script: script.into(),
+            })),
+        }
+    }
+
+    /// 创建 METRICS 命令
+    pub fn new_metrics() -> Self {
+        Self {
+            request_data: Some(RequestData::Metrics(Metrics {})),
+        }
+    }
+
+    /// 创建 SLOWLOG 命令
+    pub fn new_slow_log(count: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::SlowLog(SlowLog {
+                count,
+                reset: false,
+            })),
+        }
+    }
+
+    /// 创建清空慢命令日志的 SLOWLOG 命令
+    pub fn new_slow_log_reset() -> Self {
+        Self {
+            request_data: Some(RequestData::SlowLog(SlowLog {
+                count: 0,
+                reset: true,
+            })),
+        }
+    }
+
+    /// 创建 TABLES 命令
+    pub fn new_tables() -> Self {
+        Self {
+            request_data: Some(RequestData::Tables(Tables {})),
+        }
+    }
+
+    /// 创建 HEXPIRE 命令
+    pub fn new_hexpire(table: impl Into<String>, key: impl Into<String>, ttl_ms: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexpire(Hexpire {
+                table: table.into(),
+                key: key.into(),
+                ttl_ms,
+            })),
+        }
+    }
 }
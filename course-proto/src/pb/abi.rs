@@ -3,7 +3,10 @@
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
-    #[prost(oneof = "command_request::RequestData", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9")]
+    #[prost(
+        oneof = "command_request::RequestData",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28"
+    )]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
@@ -29,6 +32,44 @@ pub mod command_request {
         Hexist(super::Hexist),
         #[prost(message, tag = "9")]
         Hmexist(super::Hmexist),
+        #[prost(message, tag = "10")]
+        Hexport(super::Hexport),
+        #[prost(message, tag = "11")]
+        Himport(super::Himport),
+        #[prost(message, tag = "12")]
+        Backup(super::Backup),
+        #[prost(message, tag = "13")]
+        Restore(super::Restore),
+        #[prost(message, tag = "14")]
+        Hexpire(super::Hexpire),
+        #[prost(message, tag = "15")]
+        CreateIndex(super::CreateIndex),
+        #[prost(message, tag = "16")]
+        Hfind(super::Hfind),
+        #[prost(message, tag = "17")]
+        Watch(super::Watch),
+        #[prost(message, tag = "18")]
+        Lpush(super::Lpush),
+        #[prost(message, tag = "19")]
+        Lrange(super::Lrange),
+        #[prost(message, tag = "20")]
+        MapGetField(super::MapGetField),
+        #[prost(message, tag = "21")]
+        Snapshot(super::Snapshot),
+        #[prost(message, tag = "22")]
+        ReleaseSnapshot(super::ReleaseSnapshot),
+        #[prost(message, tag = "23")]
+        Eval(super::Eval),
+        #[prost(message, tag = "24")]
+        EvalSha(super::EvalSha),
+        #[prost(message, tag = "25")]
+        ScriptLoad(super::ScriptLoad),
+        #[prost(message, tag = "26")]
+        Metrics(super::Metrics),
+        #[prost(message, tag = "27")]
+        SlowLog(super::SlowLog),
+        #[prost(message, tag = "28")]
+        Tables(super::Tables),
     }
 }
 /// 服务器的响应
@@ -41,12 +82,15 @@ pub struct CommandResponse {
     /// 如果不是 2xx, message 里包含详细的信息
     #[prost(string, tag = "2")]
     pub message: ::prost::alloc::string::String,
-    /// 成功返回的 values
+    /// 成功返回的 values; 状态码为 429 时是需要等待多少毫秒才能重试
     #[prost(message, repeated, tag = "3")]
     pub values: ::prost::alloc::vec::Vec<Value>,
     /// 成功返回的 kv pairs
     #[prost(message, repeated, tag = "4")]
     pub pairs: ::prost::alloc::vec::Vec<KvPair>,
+    /// Watch 命令推送的变化
+    #[prost(message, repeated, tag = "5")]
+    pub events: ::prost::alloc::vec::Vec<WatchEvent>,
 }
 /// 从 table 中获取一个 key, 返回 value
 #[derive(PartialOrd)]
@@ -56,6 +100,9 @@ pub struct Hget {
     pub table: ::prost::alloc::string::String,
     #[prost(string, tag = "2")]
     pub key: ::prost::alloc::string::String,
+    /// 不为 0 时从这个 snapshot 中读取
+    #[prost(uint64, tag = "3")]
+    pub snapshot: u64,
 }
 /// 从 table 中获取所有的 KvPair
 #[derive(PartialOrd)]
@@ -63,6 +110,9 @@ pub struct Hget {
 pub struct Hgetall {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
+    /// 不为 0 时从这个 snapshot 中读取
+    #[prost(uint64, tag = "2")]
+    pub snapshot: u64,
 }
 /// 从 table 中获取一组 key, 返回它们的 value
 #[derive(PartialOrd)]
@@ -72,12 +122,15 @@ pub struct Hmget {
     pub table: ::prost::alloc::string::String,
     #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// 不为 0 时从这个 snapshot 中读取
+    #[prost(uint64, tag = "3")]
+    pub snapshot: u64,
 }
 /// 返回的值
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5")]
+    #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5, 6, 7, 8")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
@@ -95,8 +148,32 @@ pub mod value {
         Float(f64),
         #[prost(bool, tag = "5")]
         Bool(bool),
+        #[prost(message, tag = "6")]
+        List(super::ValueList),
+        #[prost(message, tag = "7")]
+        Map(super::ValueMap),
+        /// 自 UNIX 纪元以来的微秒数
+        #[prost(int64, tag = "8")]
+        Timestamp(i64),
     }
 }
+/// 一组有序的 Value
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueList {
+    #[prost(message, repeated, tag = "1")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// 字段名到 Value 的映射, 字段可以继续嵌套
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueMap {
+    #[prost(btree_map = "string, message", tag = "1")]
+    pub fields: ::prost::alloc::collections::BTreeMap<
+        ::prost::alloc::string::String,
+        Value,
+    >,
+}
 /// 返回的 KvPair
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -162,3 +239,271 @@ pub struct Hmexist {
     #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 按 key 的顺序分页导出 table 中的 KvPair
+/// 从 after 之后的 key 开始, 最多返回 limit 个
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexport {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, optional, tag = "2")]
+    pub after: ::core::option::Option<::prost::alloc::string::String>,
+    #[prost(uint32, tag = "3")]
+    pub limit: u32,
+}
+/// 批量导入一组 KvPair, 返回导入的个数
+/// 如果 table 不存在则创建这个 table
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Himport {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub pairs: ::prost::alloc::vec::Vec<KvPair>,
+}
+/// 把所有 table 的一致性快照写到服务器备份目录下的 path 文件中, 返回写入的 KvPair 个数.
+/// path 必须是备份目录中的相对路径, 不能包含 `..`
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Backup {
+    #[prost(string, tag = "1")]
+    pub path: ::prost::alloc::string::String,
+}
+/// 从服务器备份目录下的 path 文件中恢复快照, 只能在没有数据的服务器上执行
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Restore {
+    #[prost(string, tag = "1")]
+    pub path: ::prost::alloc::string::String,
+}
+/// 让 table 中的 key 在 ttl_ms 毫秒之后过期, 返回 key 是否存在
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpire {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "3")]
+    pub ttl_ms: u64,
+}
+/// 在 table 的 value 上创建二级索引, 返回建立索引的 KvPair 个数
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CreateIndex {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(enumeration = "IndexKind", tag = "2")]
+    pub kind: i32,
+}
+/// value 的范围, min 和 max 都包含在内, 不提供时表示不限制
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueRange {
+    #[prost(message, optional, tag = "1")]
+    pub min: ::core::option::Option<Value>,
+    #[prost(message, optional, tag = "2")]
+    pub max: ::core::option::Option<Value>,
+}
+/// 查找 value 满足条件的 KvPair, 按 key 的顺序返回
+/// table 上有对应的索引时不需要扫描整个 table
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hfind {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(oneof = "hfind::Condition", tags = "2, 3")]
+    pub condition: ::core::option::Option<hfind::Condition>,
+}
+/// Nested message and enum types in `Hfind`.
+pub mod hfind {
+    #[derive(PartialOrd)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Condition {
+        #[prost(message, tag = "2")]
+        Equal(super::Value),
+        #[prost(message, tag = "3")]
+        Range(super::ValueRange),
+    }
+}
+/// 监听 table 中以 prefix 开头的 key 的变化, prefix 为空时监听整个 table
+/// 服务器先返回一个空的 200 响应, 之后每次变化推送一个带 events 的响应
+/// 客户端关闭连接或者发送任意请求时结束监听
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Watch {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub prefix: ::prost::alloc::string::String,
+}
+/// key 的一次变化, 对应的 value 不存在时不设置 old_value 或 new_value
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WatchEvent {
+    #[prost(enumeration = "EventKind", tag = "1")]
+    pub kind: i32,
+    #[prost(string, tag = "2")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "4")]
+    pub old_value: ::core::option::Option<Value>,
+    #[prost(message, optional, tag = "5")]
+    pub new_value: ::core::option::Option<Value>,
+}
+/// 把 values 依次插入到 key 对应的 list 的头部, 返回插入之后 list 的长度
+/// key 不存在时创建一个新的 list. return_old 为 true 时在长度之后再返回插入之前的 list,
+/// key 原来不存在时不返回
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lpush {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "3")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+    #[prost(bool, tag = "4")]
+    pub return_old: bool,
+}
+/// 返回 key 对应的 list 中 \[start, stop\] 之间的元素
+/// 负数表示从末尾开始数, -1 是最后一个元素
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lrange {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag = "3")]
+    pub start: i64,
+    #[prost(int64, tag = "4")]
+    pub stop: i64,
+}
+/// 返回 key 对应的 map 中的一个字段
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MapGetField {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub field: ::prost::alloc::string::String,
+}
+/// 固定当前版本的数据, 返回 snapshot 的 id, 之后的读取可以通过 id 看到一致的数据
+/// snapshot 只能被创建它的连接读取和释放, 连接断开时会被自动释放
+#[derive(PartialOrd)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Snapshot {}
+/// 释放一个 snapshot, 返回它是否存在
+#[derive(PartialOrd)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct ReleaseSnapshot {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+}
+/// 原子地执行一段 Lua 脚本, 脚本通过 ARGV 读取 args, 通过 kv.get/kv.set/kv.del 读写数据,
+/// 返回脚本的返回值. 脚本会按照 SHA1 缓存下来
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Eval {
+    #[prost(string, tag = "1")]
+    pub script: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub args: ::prost::alloc::vec::Vec<Value>,
+}
+/// 执行之前通过 Eval 或 ScriptLoad 缓存的脚本
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct EvalSha {
+    #[prost(string, tag = "1")]
+    pub sha: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub args: ::prost::alloc::vec::Vec<Value>,
+}
+/// 编译并缓存脚本, 返回脚本的 SHA1
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ScriptLoad {
+    #[prost(string, tag = "1")]
+    pub script: ::prost::alloc::string::String,
+}
+/// 返回服务器的运行指标, 每个 KvPair 是一个指标的名字和当前的值
+#[derive(PartialOrd)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Metrics {}
+/// 返回最新的 count 条 (0 表示全部) 执行时间超过阈值的命令, 每条记录是一个 map.
+/// reset 为 true 时清空日志, 返回清空的记录数
+#[derive(PartialOrd)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct SlowLog {
+    #[prost(uint32, tag = "1")]
+    pub count: u32,
+    #[prost(bool, tag = "2")]
+    pub reset: bool,
+}
+/// 返回所有 table 的名字
+#[derive(PartialOrd)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Tables {}
+/// 二级索引的类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum IndexKind {
+    /// 按 value 精确匹配, 支持 string 和 integer
+    Exact = 0,
+    /// 按 value 范围查找, 支持 integer 和 float
+    Range = 1,
+}
+impl IndexKind {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Exact => "EXACT",
+            Self::Range => "RANGE",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "EXACT" => Some(Self::Exact),
+            "RANGE" => Some(Self::Range),
+            _ => None,
+        }
+    }
+}
+/// 变化的类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum EventKind {
+    Set = 0,
+    Delete = 1,
+    Expire = 2,
+}
+impl EventKind {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Set => "SET",
+            Self::Delete => "DELETE",
+            Self::Expire => "EXPIRE",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "SET" => Some(Self::Set),
+            "DELETE" => Some(Self::Delete),
+            "EXPIRE" => Some(Self::Expire),
+            _ => None,
+        }
+    }
+}
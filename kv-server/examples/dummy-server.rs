@@ -25,9 +25,11 @@ async fn main() -> Result<()> {
                 let msg = CommandRequest::decode(&bytes[..])?;
                 info!("Got a new command: {:?}", msg);
                 
-                let mut resp = CommandResponse::default();
-                resp.status = 404;
-                resp.message = "Not Found".to_string();
+                let resp = CommandResponse {
+                    status: 404,
+                    message: "Not Found".to_string(),
+                    ..Default::default()
+                };
                 
                 let mut buf = BytesMut::new();
                 resp.encode(&mut buf)?;
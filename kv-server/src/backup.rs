@@ -0,0 +1,281 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Component, Path, PathBuf},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use course_proto::pb::abi::KvPair;
+use prost::Message;
+
+use crate::{command::Storage, error::KvError};
+
+/// 快照文件开头的魔数
+const MAGIC: &[u8; 6] = b"KVSNAP";
+/// 当前写入的快照版本. 修改快照格式时增加版本号, 并在 decode 中保留旧版本的解析逻辑
+const CURRENT_VERSION: u16 = 1;
+/// 魔数 + 版本号 + 记录个数
+const HEADER_LEN: usize = MAGIC.len() + 2 + 8;
+/// 文件末尾的 crc32 校验和
+const CHECKSUM_LEN: usize = 4;
+
+/// 快照中的一条记录
+#[derive(Clone, PartialEq, Message)]
+struct SnapshotEntry {
+    #[prost(string, tag = "1")]
+    table: String,
+    #[prost(message, optional, tag = "2")]
+    pair: Option<KvPair>,
+}
+
+/// 把客户端给出的 path 解析为备份目录 dir 下的文件.
+///
+/// 只接受相对路径, 并且不能包含 `..`, 客户端不能读写备份目录之外的文件
+pub fn resolve(dir: &Path, path: &str) -> Result<PathBuf, KvError> {
+    let relative = Path::new(path);
+    let inside = relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !inside || relative.file_name().is_none() {
+        return Err(KvError::InvalidCommand(format!(
+            "Backup path `{}` must be a relative path inside the backup directory",
+            path
+        )));
+    }
+    Ok(dir.join(relative))
+}
+
+/// 所有 table 在某一时刻的一致性快照
+#[derive(Debug, Default, PartialEq)]
+pub struct Snapshot {
+    tables: Vec<(String, Vec<KvPair>)>,
+}
+
+impl Snapshot {
+    /// 从存储中复制出所有 table 的数据.
+    ///
+    /// 调用者需要保证复制期间没有写入, 数据只在内存中复制, 写文件在这之后进行.
+    pub fn capture(store: &impl Storage) -> Result<Self, KvError> {
+        let mut tables = Vec::new();
+        for name in store.tables()? {
+            let pairs = store.get_all(&name)?;
+            if !pairs.is_empty() {
+                tables.push((name, pairs));
+            }
+        }
+        Ok(Self { tables })
+    }
+
+    /// 从存储的 snapshot 中复制出所有 table 的数据, 复制期间写入可以继续进行
+    pub fn capture_at(store: &impl Storage, snapshot: u64) -> Result<Self, KvError> {
+        let mut tables = Vec::new();
+        for name in store.tables()? {
+            // snapshot 之后才创建的 table 在 snapshot 中是空的
+            let pairs = store.get_all_at(snapshot, &name)?;
+            if !pairs.is_empty() {
+                tables.push((name, pairs));
+            }
+        }
+        Ok(Self { tables })
+    }
+
+    /// 快照中 KvPair 的个数
+    pub fn len(&self) -> usize {
+        self.tables.iter().map(|(_, pairs)| pairs.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 把快照写入 path, 先写临时文件再重命名, 避免留下写了一半的快照
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), KvError> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&self.encode())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// 从 path 读取快照, 会校验魔数, 版本和校验和
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        Self::decode(&fs::read(path)?)
+    }
+
+    /// 把快照中的数据写入存储
+    pub fn restore(self, store: &impl Storage) -> Result<usize, KvError> {
+        let len = self.len();
+        for (table, pairs) in self.tables {
+            for pair in pairs {
+                store.set(&table, pair.key, pair.value.unwrap_or_default())?;
+            }
+        }
+        Ok(len)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        buf.put_slice(MAGIC);
+        buf.put_u16_le(CURRENT_VERSION);
+        buf.put_u64_le(self.len() as u64);
+
+        for (table, pairs) in &self.tables {
+            for pair in pairs {
+                let entry = SnapshotEntry {
+                    table: table.clone(),
+                    pair: Some(pair.clone()),
+                };
+                // 写入 BytesMut 不会失败
+                entry.encode_length_delimited(&mut buf).unwrap();
+            }
+        }
+
+        let checksum = crc32fast::hash(&buf);
+        buf.put_u32_le(checksum);
+        buf.to_vec()
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, KvError> {
+        let invalid = |msg: &str| KvError::InvalidSnapshot(msg.into());
+        if data.len() < HEADER_LEN + CHECKSUM_LEN || &data[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a snapshot file"));
+        }
+
+        let (content, mut checksum) = data.split_at(data.len() - CHECKSUM_LEN);
+        if crc32fast::hash(content) != checksum.get_u32_le() {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let mut buf = &content[MAGIC.len()..];
+        let version = buf.get_u16_le();
+        let count = buf.get_u64_le();
+        match version {
+            1 => Self::decode_v1(buf, count),
+            v => Err(invalid(&format!("unsupported version {}", v))),
+        }
+    }
+
+    fn decode_v1(mut buf: &[u8], count: u64) -> Result<Self, KvError> {
+        let mut snapshot = Snapshot::default();
+        for _ in 0..count {
+            let entry = SnapshotEntry::decode_length_delimited(&mut buf)?;
+            let pair = entry.pair.unwrap_or_default();
+            match snapshot.tables.last_mut() {
+                Some((table, pairs)) if *table == entry.table => pairs.push(pair),
+                _ => snapshot.tables.push((entry.table, vec![pair])),
+            }
+        }
+
+        if buf.has_remaining() {
+            return Err(KvError::InvalidSnapshot("trailing data".into()));
+        }
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::storage::memory::MemTable;
+
+    fn sample_store() -> MemTable {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), 42.into()).unwrap();
+        store.set("t2", "k1".into(), true.into()).unwrap();
+        // 空的 table 不会出现在快照里
+        store.get("t3", "k1").unwrap();
+        store
+    }
+
+    fn sorted(store: &impl Storage, table: &str) -> Vec<KvPair> {
+        store.get_range(table, None, usize::MAX).unwrap()
+    }
+
+    #[test]
+    fn snapshot_should_round_trip() {
+        let store = sample_store();
+        let snapshot = Snapshot::capture(&store).unwrap();
+        assert_eq!(snapshot.len(), 3);
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("backup.snap");
+        snapshot.save(&path).unwrap();
+
+        let loaded = Snapshot::load(&path).unwrap();
+        assert_eq!(loaded.len(), 3);
+
+        let restored = MemTable::new();
+        assert_eq!(loaded.restore(&restored).unwrap(), 3);
+        for table in ["t1", "t2"] {
+            assert_eq!(sorted(&restored, table), sorted(&store, table));
+        }
+    }
+
+    #[test]
+    fn capture_at_should_ignore_later_writes() {
+        let store = sample_store();
+        let id = store.snapshot().unwrap();
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        store.set("t4", "k1".into(), "v1".into()).unwrap();
+
+        let snapshot = Snapshot::capture_at(&store, id).unwrap();
+        assert_eq!(snapshot.len(), 3);
+        let restored = MemTable::new();
+        snapshot.restore(&restored).unwrap();
+        assert_eq!(sorted(&restored, "t1"), sorted(&sample_store(), "t1"));
+        assert!(sorted(&restored, "t4").is_empty());
+    }
+
+    #[test]
+    fn resolve_should_stay_inside_backup_dir() {
+        let dir = Path::new("/var/lib/kv/backups");
+        assert_eq!(
+            resolve(dir, "daily/kv.snap").unwrap(),
+            dir.join("daily/kv.snap")
+        );
+        for path in ["", ".", "/etc/passwd", "../kv.snap", "daily/../../kv.snap"] {
+            assert!(resolve(dir, path).is_err(), "{} should be rejected", path);
+        }
+    }
+
+    #[test]
+    fn corrupted_snapshot_should_be_rejected() {
+        let mut data = Snapshot::capture(&sample_store()).unwrap().encode();
+        let mid = data.len() / 2;
+        data[mid] ^= 0xff;
+
+        let err = Snapshot::decode(&data).unwrap_err();
+        assert_eq!(err, KvError::InvalidSnapshot("checksum mismatch".into()));
+    }
+
+    #[test]
+    fn unknown_version_should_be_rejected() {
+        let mut data = Snapshot::default().encode();
+        data[MAGIC.len()] = 99;
+        let len = data.len() - CHECKSUM_LEN;
+        let checksum = crc32fast::hash(&data[..len]);
+        data[len..].copy_from_slice(&checksum.to_le_bytes());
+
+        let err = Snapshot::decode(&data).unwrap_err();
+        assert_eq!(
+            err,
+            KvError::InvalidSnapshot("unsupported version 99".into())
+        );
+    }
+
+    #[test]
+    fn non_snapshot_file_should_be_rejected() {
+        let err = Snapshot::decode(b"hello world, this is not a snapshot").unwrap_err();
+        assert_eq!(err, KvError::InvalidSnapshot("not a snapshot file".into()));
+    }
+}
@@ -0,0 +1,154 @@
+use std::{env, path::PathBuf};
+
+use anyhow::Result;
+use clap::Parser;
+use course_proto::pb::abi::{CommandRequest, CommandResponse, command_request::RequestData};
+use futures::StreamExt;
+use kv_server::{
+    cli::{complete, parse_args, parse_command, render_table, to_json},
+    client::KvClient,
+};
+use rustyline::{
+    Context, Editor, Helper, completion::Completer, error::ReadlineError, highlight::Highlighter,
+    hint::Hinter, history::DefaultHistory, validate::Validator,
+};
+use tokio::signal;
+
+#[derive(Parser, Debug)]
+#[command(
+    author = "ohmycloud",
+    version = "0.1.0",
+    about = "kv-cli",
+    long_about = "kv-server 的命令行客户端"
+)]
+struct Cli {
+    /// kv-server 的地址
+    #[arg(short, long, default_value = "127.0.0.1:9527")]
+    addr: String,
+    /// 以 json 格式输出结果
+    #[arg(long)]
+    json: bool,
+    /// 要执行的命令, 比如 `hset t1 language raku`; 不提供时进入交互模式
+    command: Vec<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut client = KvClient::connect(&cli.addr).await?;
+
+    if !cli.command.is_empty() {
+        let cmd = parse_args(&cli.command)?;
+        if is_watch(&cmd) {
+            return watch(client, cmd, cli.json).await;
+        }
+        let res = client.execute(cmd).await?;
+        print_response(&res, cli.json);
+        return Ok(());
+    }
+
+    repl(&mut client, &cli.addr, cli.json).await
+}
+
+async fn repl(client: &mut KvClient, addr: &str, json: bool) -> Result<()> {
+    let mut rl = Editor::<KvHelper, DefaultHistory>::new()?;
+    rl.set_helper(Some(KvHelper));
+
+    let history = history_path();
+    if let Some(path) = &history {
+        let _ = rl.load_history(path);
+    }
+
+    loop {
+        match rl.readline("kv> ") {
+            Ok(line) => {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                rl.add_history_entry(line)?;
+                if matches!(line, "exit" | "quit") {
+                    break;
+                }
+
+                match parse_command(line) {
+                    // Watch 会占用整个连接, 用一个新的连接来监听
+                    Ok(cmd) if is_watch(&cmd) => {
+                        watch(KvClient::connect(addr).await?, cmd, json).await?
+                    }
+                    Ok(cmd) => {
+                        let res = client.execute(cmd).await?;
+                        print_response(&res, json);
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if let Some(path) = &history {
+        rl.save_history(path)?;
+    }
+    Ok(())
+}
+
+fn is_watch(cmd: &CommandRequest) -> bool {
+    matches!(cmd.request_data, Some(RequestData::Watch(_)))
+}
+
+/// 打印服务器推送的事件, 直到连接关闭或者按下 Ctrl-C
+async fn watch(client: KvClient, cmd: CommandRequest, json: bool) -> Result<()> {
+    let stream = client.watch(cmd).await?;
+    tokio::pin!(stream);
+    loop {
+        tokio::select! {
+            _ = signal::ctrl_c() => break,
+            res = stream.next() => match res {
+                Some(res) => print_response(&res?, json),
+                None => break,
+            },
+        }
+    }
+    Ok(())
+}
+
+fn print_response(res: &CommandResponse, json: bool) {
+    if json {
+        println!("{}", to_json(res));
+    } else {
+        println!("{}", render_table(res));
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".kv_cli_history"))
+}
+
+/// 为 rustyline 提供命令名和值字面量的补全
+struct KvHelper;
+
+impl Completer for KvHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        Ok((start, complete(&line[..start], &line[start..pos])))
+    }
+}
+
+impl Hinter for KvHelper {
+    type Hint = String;
+}
+
+impl Highlighter for KvHelper {}
+
+impl Validator for KvHelper {}
+
+impl Helper for KvHelper {}
@@ -0,0 +1,187 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow};
+use clap::{Args, Parser, Subcommand};
+use course_proto::pb::abi::{CommandRequest, CommandResponse, KvPair};
+use kv_server::{
+    client::KvClient,
+    transfer::{Format, PairReader, PairWriter},
+};
+
+#[derive(Parser, Debug)]
+#[command(
+    author = "ohmycloud",
+    version = "0.1.0",
+    about = "kv-dump",
+    long_about = "导入导出 kv-server 的 table"
+)]
+struct Cli {
+    /// kv-server 的地址
+    #[arg(short, long, default_value = "127.0.0.1:9527")]
+    addr: String,
+    #[command(subcommand)]
+    command: SubCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum SubCommand {
+    Export(Export),
+    Import(Import),
+}
+
+/// 把 table 导出到文件
+#[derive(Args, Debug)]
+struct Export {
+    /// 要导出的 table
+    #[arg(short, long)]
+    table: String,
+    /// 输出的文件, 不提供时输出到 stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// 文件格式: jsonl, csv 或 protobuf, 不提供时根据扩展名推断
+    #[arg(short, long)]
+    format: Option<Format>,
+    /// 每个请求获取的 KvPair 个数
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u32).range(1..))]
+    batch: u32,
+}
+
+/// 从文件导入 table
+#[derive(Args, Debug)]
+struct Import {
+    /// 导入到哪个 table
+    #[arg(short, long)]
+    table: String,
+    /// 输入的文件
+    #[arg(short, long)]
+    input: PathBuf,
+    /// 文件格式: jsonl, csv 或 protobuf, 不提供时根据扩展名推断
+    #[arg(short, long)]
+    format: Option<Format>,
+    /// 每个请求写入的 KvPair 个数, 也是写 checkpoint 的间隔
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u32).range(1..))]
+    batch: u32,
+    /// 从上一次中断的地方继续导入
+    #[arg(long)]
+    resume: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut client = KvClient::connect(&cli.addr).await?;
+
+    match cli.command {
+        SubCommand::Export(args) => export(&mut client, args).await,
+        SubCommand::Import(args) => import(&mut client, args).await,
+    }
+}
+
+async fn export(client: &mut KvClient, args: Export) -> Result<()> {
+    let format = args
+        .format
+        .or_else(|| args.output.as_ref().and_then(Format::from_path))
+        .unwrap_or(Format::Jsonl);
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+
+    let mut writer = PairWriter::new(format, output)?;
+    let mut after = None;
+    let mut count = 0;
+    loop {
+        let cmd = CommandRequest::new_hexport(&args.table, after, args.batch);
+        let res = check(client.execute(cmd).await?)?;
+        let Some(last) = res.pairs.last() else { break };
+        after = Some(last.key.clone());
+
+        for pair in &res.pairs {
+            writer.write(pair)?;
+        }
+        count += res.pairs.len();
+    }
+    writer.finish()?;
+
+    eprintln!("Exported {} pairs from table {}", count, args.table);
+    Ok(())
+}
+
+async fn import(client: &mut KvClient, args: Import) -> Result<()> {
+    let format = args
+        .format
+        .or_else(|| Format::from_path(&args.input))
+        .ok_or_else(|| anyhow!("Cannot infer format of {:?}", args.input))?;
+    let checkpoint = checkpoint_path(&args.input);
+
+    // checkpoint 文件中记录了导入到哪个 table 和已经成功导入的记录数
+    let skip = match (args.resume, fs::read_to_string(&checkpoint)) {
+        (true, Ok(content)) => resume_from(&content, &args.table)?,
+        _ => 0,
+    };
+    if skip > 0 {
+        eprintln!("Resuming import after {} records", skip);
+    }
+
+    let reader = PairReader::new(format, BufReader::new(File::open(&args.input)?));
+    let mut imported = skip;
+    let mut batch = Vec::with_capacity(args.batch as usize);
+    for pair in reader.skip(skip) {
+        batch.push(pair?);
+        if batch.len() == args.batch as usize {
+            imported += flush_batch(client, &args.table, &mut batch).await?;
+            fs::write(&checkpoint, format!("{}\n{}", args.table, imported))?;
+        }
+    }
+    imported += flush_batch(client, &args.table, &mut batch).await?;
+
+    // 全部导入成功后就不再需要 checkpoint 了
+    if checkpoint.exists() {
+        fs::remove_file(&checkpoint)?;
+    }
+    eprintln!("Imported {} pairs into table {}", imported, args.table);
+    Ok(())
+}
+
+async fn flush_batch(client: &mut KvClient, table: &str, batch: &mut Vec<KvPair>) -> Result<usize> {
+    if batch.is_empty() {
+        return Ok(0);
+    }
+    let len = batch.len();
+    let cmd = CommandRequest::new_himport(table, std::mem::take(batch));
+    check(client.execute(cmd).await?)?;
+    Ok(len)
+}
+
+fn checkpoint_path(input: &Path) -> PathBuf {
+    let mut name = input.as_os_str().to_owned();
+    name.push(".checkpoint");
+    PathBuf::from(name)
+}
+
+/// 解析 checkpoint 中已经导入的记录数, 它必须是导入到同一个 table 时留下的
+fn resume_from(content: &str, table: &str) -> Result<usize> {
+    let (name, count) = content
+        .trim_end()
+        .rsplit_once('\n')
+        .ok_or_else(|| anyhow!("Invalid checkpoint: {:?}", content))?;
+    if name != table {
+        return Err(anyhow!(
+            "Checkpoint belongs to an import into table {}, not {}",
+            name,
+            table
+        ));
+    }
+    Ok(count.parse()?)
+}
+
+fn check(res: CommandResponse) -> Result<CommandResponse> {
+    match res.status {
+        200..300 => Ok(res),
+        status => Err(anyhow!("Server returned {}: {}", status, res.message)),
+    }
+}
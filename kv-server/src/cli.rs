@@ -0,0 +1,544 @@
+use bytes::Bytes;
+use colored::*;
+use course_proto::pb::abi::{CommandRequest, CommandResponse, IndexKind, KvPair, Value, value};
+use serde_json::json;
+
+use crate::{
+    error::KvError,
+    transfer::{value_from_json, value_to_json},
+};
+
+/// kv-cli 支持的命令
+pub const COMMANDS: &[&str] = &[
+    "hget",
+    "hgetall",
+    "hmget",
+    "hset",
+    "hmset",
+    "hdel",
+    "hmdel",
+    "hexist",
+    "hmexist",
+    "hexpire",
+    "hfind",
+    "lpush",
+    "lrange",
+    "mapgetfield",
+    "createindex",
+    "watch",
+    "snapshot",
+    "releasesnapshot",
+    "eval",
+    "evalsha",
+    "scriptload",
+    "metrics",
+    "slowlog",
+    "tables",
+    "backup",
+    "restore",
+];
+
+const NIL: Value = Value { value: None };
+
+/// 带类型的值的前缀, 没有前缀的值都当作字符串处理
+pub const VALUE_LITERALS: &[&str] = &["s:", "i:", "f:", "b:true", "b:false", "x:", "t:", "j:"];
+
+/// 把一行输入解析成 CommandRequest, 比如 `hset t1 language raku`
+pub fn parse_command(line: &str) -> Result<CommandRequest, KvError> {
+    let args = split_args(line)?;
+    parse_args(&args)
+}
+
+/// 把已经切分好的参数解析成 CommandRequest
+pub fn parse_args(args: &[String]) -> Result<CommandRequest, KvError> {
+    let invalid = || KvError::InvalidCommand(args.join(" "));
+    let (cmd, args) = args.split_first().ok_or_else(invalid)?;
+
+    let cmd = match (cmd.to_lowercase().as_str(), args) {
+        ("hget", [table, key]) => CommandRequest::new_hget(table, key),
+        ("hget", [table, key, at]) => {
+            CommandRequest::new_hget_at(table, key, parse_snapshot(at).ok_or_else(invalid)?)
+        }
+        ("hgetall", [table]) => CommandRequest::new_hgetall(table),
+        ("hgetall", [table, at]) => {
+            CommandRequest::new_hgetall_at(table, parse_snapshot(at).ok_or_else(invalid)?)
+        }
+        ("hmget", [table, keys @ ..]) if !keys.is_empty() => {
+            CommandRequest::new_hmget(table, keys.to_vec())
+        }
+        ("hset", [table, key, value]) => CommandRequest::new_hset(table, key, parse_value(value)?),
+        ("hmset", [table, rest @ ..]) if !rest.is_empty() && rest.len().is_multiple_of(2) => {
+            let pairs = rest
+                .chunks(2)
+                .map(|kv| Ok(KvPair::new(&kv[0], parse_value(&kv[1])?)))
+                .collect::<Result<_, KvError>>()?;
+            CommandRequest::new_hmset(table, pairs)
+        }
+        ("hdel", [table, key]) => CommandRequest::new_hdel(table, key),
+        ("hmdel", [table, keys @ ..]) if !keys.is_empty() => {
+            CommandRequest::new_hmdel(table, keys.to_vec())
+        }
+        ("hexist", [table, key]) => CommandRequest::new_hexist(table, key),
+        ("hmexist", [table, keys @ ..]) if !keys.is_empty() => {
+            CommandRequest::new_hmexist(table, keys.to_vec())
+        }
+        ("hexpire", [table, key, ttl_ms]) => {
+            let ttl_ms = ttl_ms.parse().map_err(|_| invalid())?;
+            CommandRequest::new_hexpire(table, key, ttl_ms)
+        }
+        ("lpush", [table, key, values @ ..]) if !values.is_empty() => {
+            CommandRequest::new_lpush(table, key, parse_values(values)?)
+        }
+        ("lrange", [table, key, start, stop]) => {
+            let start = start.parse().map_err(|_| invalid())?;
+            let stop = stop.parse().map_err(|_| invalid())?;
+            CommandRequest::new_lrange(table, key, start, stop)
+        }
+        ("mapgetfield", [table, key, field]) => {
+            CommandRequest::new_map_get_field(table, key, field)
+        }
+        ("hfind", [table, value]) => CommandRequest::new_hfind_equal(table, parse_value(value)?),
+        ("hfind", [table, min, max]) => {
+            CommandRequest::new_hfind_range(table, parse_bound(min)?, parse_bound(max)?)
+        }
+        ("createindex", [table, kind]) => {
+            let kind = IndexKind::from_str_name(&kind.to_uppercase()).ok_or_else(invalid)?;
+            CommandRequest::new_create_index(table, kind)
+        }
+        ("watch", [table]) => CommandRequest::new_watch(table, ""),
+        ("watch", [table, prefix]) => CommandRequest::new_watch(table, prefix),
+        ("snapshot", []) => CommandRequest::new_snapshot(),
+        ("releasesnapshot", [id]) => {
+            CommandRequest::new_release_snapshot(id.parse().map_err(|_| invalid())?)
+        }
+        ("eval", [script, values @ ..]) => CommandRequest::new_eval(script, parse_values(values)?),
+        ("evalsha", [sha, values @ ..]) => CommandRequest::new_evalsha(sha, parse_values(values)?),
+        ("scriptload", [script]) => CommandRequest::new_script_load(script),
+        ("metrics", []) => CommandRequest::new_metrics(),
+        ("slowlog", []) => CommandRequest::new_slow_log(0),
+        ("slowlog", [arg]) if arg.eq_ignore_ascii_case("reset") => {
+            CommandRequest::new_slow_log_reset()
+        }
+        ("slowlog", [count]) => CommandRequest::new_slow_log(count.parse().map_err(|_| invalid())?),
+        ("tables", []) => CommandRequest::new_tables(),
+        ("backup", [path]) => CommandRequest::new_backup(path),
+        ("restore", [path]) => CommandRequest::new_restore(path),
+        _ => return Err(invalid()),
+    };
+    Ok(cmd)
+}
+
+fn parse_values(values: &[String]) -> Result<Vec<Value>, KvError> {
+    values.iter().map(|v| parse_value(v)).collect()
+}
+
+/// 解析 `@<id>` 形式的 snapshot id
+fn parse_snapshot(s: &str) -> Option<u64> {
+    s.strip_prefix('@')?.parse().ok()
+}
+
+/// 解析 hfind 的范围边界, `-` 表示不限制, 没有前缀的值当作数字处理
+fn parse_bound(s: &str) -> Result<Option<Value>, KvError> {
+    if s == "-" {
+        return Ok(None);
+    }
+    if is_typed_literal(s) {
+        return parse_value(s).map(Some);
+    }
+    if let Ok(i) = s.parse::<i64>() {
+        return Ok(Some(i.into()));
+    }
+    match s.parse::<f64>() {
+        Ok(f) => Ok(Some(f.into())),
+        Err(_) => Err(KvError::ConvertError(s.into(), "Number")),
+    }
+}
+
+/// 按空白切分参数, 支持用单引号或双引号包含空白
+pub fn split_args(line: &str) -> Result<Vec<String>, KvError> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote = None;
+
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                current.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(current.take()),
+            (None, c) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quote.is_some() {
+        return Err(KvError::InvalidCommand(format!(
+            "Unclosed quote in `{}`",
+            line
+        )));
+    }
+    args.extend(current);
+    Ok(args)
+}
+
+/// 解析带类型的值: `i:42`, `f:3.14`, `b:true`, `x:deadbeef`, `t:1700000000000000`, `s:text`
+/// 或者普通字符串. list 和 map 使用 `j:` 加上 json, 比如 `j:{"list":[{"integer":1}]}`
+pub fn parse_value(s: &str) -> Result<Value, KvError> {
+    let invalid = || KvError::InvalidCommand(format!("Invalid value literal `{}`", s));
+    let value = match s.split_once(':') {
+        Some(("s", v)) => v.into(),
+        Some(("i", v)) => v.parse::<i64>().map_err(|_| invalid())?.into(),
+        Some(("f", v)) => v.parse::<f64>().map_err(|_| invalid())?.into(),
+        Some(("b", v)) => v.parse::<bool>().map_err(|_| invalid())?.into(),
+        Some(("x", v)) => Bytes::from(decode_hex(v).ok_or_else(invalid)?).into(),
+        Some(("t", v)) => Value {
+            value: Some(value::Value::Timestamp(v.parse().map_err(|_| invalid())?)),
+        },
+        Some(("j", v)) => {
+            let json: serde_json::Value = serde_json::from_str(v).map_err(|_| invalid())?;
+            value_from_json(&json)?
+        }
+        _ => s.into(),
+    };
+    Ok(value)
+}
+
+/// 把值格式化成 parse_value 能够解析回来的字面量
+pub fn format_value(v: &Value) -> String {
+    match &v.value {
+        Some(value::Value::String(s)) if is_typed_literal(s) => format!("s:{}", s),
+        Some(value::Value::String(s)) => s.clone(),
+        Some(value::Value::Integer(i)) => format!("i:{}", i),
+        Some(value::Value::Float(f)) => format!("f:{}", f),
+        Some(value::Value::Bool(b)) => format!("b:{}", b),
+        Some(value::Value::Binary(buf)) => format!("x:{}", encode_hex(buf)),
+        Some(value::Value::Timestamp(t)) => format!("t:{}", t),
+        Some(value::Value::List(_) | value::Value::Map(_)) => format!("j:{}", value_to_json(v)),
+        None => String::new(),
+    }
+}
+
+fn is_typed_literal(s: &str) -> bool {
+    matches!(
+        s.split_once(':'),
+        Some(("s" | "i" | "f" | "b" | "x" | "t" | "j", _))
+    )
+}
+
+pub fn encode_hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+/// 根据当前正在输入的单词给出补全候选, `prev` 是光标所在单词之前的内容
+pub fn complete(prev: &str, word: &str) -> Vec<String> {
+    let args: Vec<&str> = prev.split_whitespace().collect();
+    let candidates: &[&str] = match args.first().map(|s| s.to_lowercase()) {
+        None => COMMANDS,
+        // hset table key value
+        Some(cmd) if cmd == "hset" && args.len() == 3 => VALUE_LITERALS,
+        // lpush table key value value ...
+        Some(cmd) if cmd == "lpush" && args.len() >= 3 => VALUE_LITERALS,
+        // hmset table key value key value ...
+        Some(cmd) if cmd == "hmset" && args.len() >= 3 && !args.len().is_multiple_of(2) => {
+            VALUE_LITERALS
+        }
+        Some(_) => &[],
+    };
+
+    candidates
+        .iter()
+        .filter(|c| c.starts_with(word))
+        .map(|c| c.to_string())
+        .collect()
+}
+
+/// 把 CommandResponse 转换成 json
+pub fn to_json(res: &CommandResponse) -> serde_json::Value {
+    json!({
+        "status": res.status,
+        "message": res.message,
+        "values": res.values.iter().map(value_to_json).collect::<Vec<_>>(),
+        "pairs": res.pairs.iter().map(|p| json!({
+            "key": p.key,
+            "value": p.value.as_ref().map_or(serde_json::Value::Null, value_to_json),
+        })).collect::<Vec<_>>(),
+        "events": res.events.iter().map(|e| json!({
+            "kind": e.kind().as_str_name(),
+            "table": e.table,
+            "key": e.key,
+            "old_value": e.old_value.as_ref().map_or(serde_json::Value::Null, value_to_json),
+            "new_value": e.new_value.as_ref().map_or(serde_json::Value::Null, value_to_json),
+        })).collect::<Vec<_>>(),
+    })
+}
+
+/// 把 CommandResponse 渲染成带颜色的表格
+pub fn render_table(res: &CommandResponse) -> String {
+    let mut out = Vec::new();
+    let status = format!("{}", res.status);
+    if (200..300).contains(&res.status) {
+        // Watch 推送的事件每个一行, 不需要状态码
+        if res.events.is_empty() {
+            out.push(status.green().bold().to_string());
+        }
+    } else {
+        out.push(format!("{} {}", status.red().bold(), res.message.red()));
+    }
+
+    if !res.values.is_empty() {
+        let rows: Vec<_> = res
+            .values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (format!("{})", i + 1), v))
+            .collect();
+        out.extend(render_rows(&rows));
+    }
+
+    if !res.pairs.is_empty() {
+        let rows: Vec<_> = res
+            .pairs
+            .iter()
+            .map(|p| (p.key.clone(), p.value.as_ref().unwrap_or(&NIL)))
+            .collect();
+        out.extend(render_rows(&rows));
+    }
+
+    for e in &res.events {
+        let old = e.old_value.as_ref().unwrap_or(&NIL);
+        let new = e.new_value.as_ref().unwrap_or(&NIL);
+        out.push(format!(
+            "{} {}/{} | {} -> {}",
+            e.kind().as_str_name().blue().bold(),
+            e.table,
+            e.key.bold(),
+            colorize(old),
+            colorize(new)
+        ));
+    }
+
+    out.join("\n")
+}
+
+fn render_rows(rows: &[(String, &Value)]) -> Vec<String> {
+    let width = rows.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+    rows.iter()
+        .map(|(k, v)| format!("{:<width$} | {}", k.bold(), colorize(v), width = width))
+        .collect()
+}
+
+fn colorize(v: &Value) -> ColoredString {
+    let s = format_value(v);
+    match &v.value {
+        Some(value::Value::String(_)) => s.green(),
+        Some(value::Value::Binary(_)) => s.magenta(),
+        Some(value::Value::Integer(_)) | Some(value::Value::Float(_)) => s.cyan(),
+        Some(value::Value::Bool(_)) => s.yellow(),
+        Some(value::Value::Timestamp(_)) => s.cyan(),
+        Some(value::Value::List(_) | value::Value::Map(_)) => s.blue(),
+        None => "(nil)".dimmed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::SystemTime};
+
+    use course_proto::pb::abi::{EventKind, WatchEvent};
+
+    use super::*;
+
+    #[test]
+    fn parse_command_should_work() {
+        assert_eq!(
+            parse_command("hset t1 language raku").unwrap(),
+            CommandRequest::new_hset("t1", "language", "raku".into())
+        );
+        assert_eq!(
+            parse_command("backup daily/kv.snap").unwrap(),
+            CommandRequest::new_backup("daily/kv.snap")
+        );
+        assert_eq!(
+            parse_command("hfind t1 18 -").unwrap(),
+            CommandRequest::new_hfind_range("t1", Some(18.into()), None)
+        );
+        assert_eq!(
+            parse_command("lpush t1 k1 a i:1").unwrap(),
+            CommandRequest::new_lpush("t1", "k1", vec!["a".into(), 1.into()])
+        );
+        assert_eq!(
+            parse_command("lrange t1 k1 0 -1").unwrap(),
+            CommandRequest::new_lrange("t1", "k1", 0, -1)
+        );
+        assert_eq!(
+            parse_command("hgetall t1 @3").unwrap(),
+            CommandRequest::new_hgetall_at("t1", 3)
+        );
+        assert_eq!(
+            parse_command("releasesnapshot 3").unwrap(),
+            CommandRequest::new_release_snapshot(3)
+        );
+        assert_eq!(
+            parse_command(r#"eval "return kv.get('t1', ARGV[1])" k1 i:1"#).unwrap(),
+            CommandRequest::new_eval("return kv.get('t1', ARGV[1])", vec!["k1".into(), 1.into()])
+        );
+        assert_eq!(
+            parse_command("evalsha abc").unwrap(),
+            CommandRequest::new_evalsha("abc", vec![])
+        );
+        assert_eq!(
+            parse_command("scriptload 'return 1'").unwrap(),
+            CommandRequest::new_script_load("return 1")
+        );
+        assert_eq!(
+            parse_command("metrics").unwrap(),
+            CommandRequest::new_metrics()
+        );
+        assert_eq!(
+            parse_command("slowlog 10").unwrap(),
+            CommandRequest::new_slow_log(10)
+        );
+        assert_eq!(
+            parse_command("slowlog reset").unwrap(),
+            CommandRequest::new_slow_log_reset()
+        );
+        assert_eq!(
+            parse_command("tables").unwrap(),
+            CommandRequest::new_tables()
+        );
+        assert_eq!(
+            parse_command("createindex t1 range").unwrap(),
+            CommandRequest::new_create_index("t1", IndexKind::Range)
+        );
+        assert_eq!(
+            parse_command("HMGET t1 a b c").unwrap(),
+            CommandRequest::new_hmget("t1", vec!["a".into(), "b".into(), "c".into()])
+        );
+        assert_eq!(
+            parse_command("hmset t1 a i:42 b 'hello world'").unwrap(),
+            CommandRequest::new_hmset(
+                "t1",
+                vec![
+                    KvPair::new("a", 42.into()),
+                    KvPair::new("b", "hello world".into())
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn parse_invalid_command_should_fail() {
+        assert!(parse_command("").is_err());
+        assert!(parse_command("hget t1").is_err());
+        assert!(parse_command("hmset t1 a").is_err());
+        assert!(parse_command("hset t1 a \"raku").is_err());
+        assert!(parse_command("hfoo t1 a").is_err());
+        assert!(parse_command("hexpire t1 a soon").is_err());
+        assert!(parse_command("hfind t1 ten 20").is_err());
+        assert!(parse_command("createindex t1 fulltext").is_err());
+        assert!(parse_command("watch").is_err());
+        assert!(parse_command("lpush t1 k1").is_err());
+        assert!(parse_command("lrange t1 k1 0 end").is_err());
+        assert!(parse_command("hget t1 k1 3").is_err());
+        assert!(parse_command("eval").is_err());
+        assert!(parse_command("slowlog all").is_err());
+    }
+
+    #[test]
+    fn typed_value_literal_should_round_trip() {
+        let values: Vec<Value> = vec![
+            "raku".into(),
+            "i:not a number".into(),
+            42.into(),
+            1.5.into(),
+            true.into(),
+            Bytes::from_static(b"\xde\xad\xbe\xef").into(),
+            SystemTime::UNIX_EPOCH.into(),
+            vec![1.into(), "a b".into()].into(),
+            BTreeMap::from([("k".to_string(), vec![Value::from(1.5)].into())]).into(),
+        ];
+
+        for v in values {
+            assert_eq!(parse_value(&format_value(&v)).unwrap(), v);
+        }
+        assert_eq!(
+            format_value(&parse_value("x:deadbeef").unwrap()),
+            "x:deadbeef"
+        );
+        assert!(parse_value("i:4.2").is_err());
+        assert!(parse_value("x:abc").is_err());
+        assert!(parse_value("j:[1, 2]").is_err());
+        assert_eq!(
+            parse_value(r#"j:{"list":[{"integer":1}]}"#).unwrap(),
+            vec![1.into()].into()
+        );
+    }
+
+    #[test]
+    fn complete_should_work() {
+        assert_eq!(
+            complete("", "hm"),
+            vec!["hmget", "hmset", "hmdel", "hmexist"]
+        );
+        assert_eq!(complete("hset t1 k1 ", "b"), vec!["b:true", "b:false"]);
+        assert_eq!(
+            complete("hmset t1 k1 i:1 k2 ", "").len(),
+            VALUE_LITERALS.len()
+        );
+        assert!(complete("hmset t1 k1 i:1 ", "").is_empty());
+        assert!(complete("hget t1 ", "").is_empty());
+    }
+
+    #[test]
+    fn watch_event_should_be_rendered() {
+        let event = WatchEvent {
+            kind: EventKind::Delete.into(),
+            table: "t1".into(),
+            key: "k1".into(),
+            old_value: Some(42.into()),
+            new_value: None,
+        };
+        colored::control::set_override(false);
+        assert_eq!(
+            render_table(&event.clone().into()),
+            "DELETE t1/k1 | i:42 -> (nil)"
+        );
+        assert_eq!(
+            to_json(&event.into())["events"][0],
+            json!({
+                "kind": "DELETE",
+                "table": "t1",
+                "key": "k1",
+                "old_value": {"integer": 42},
+                "new_value": null,
+            })
+        );
+    }
+
+    #[test]
+    fn to_json_should_keep_value_types() {
+        let res = CommandResponse::from(vec![KvPair::new("k1", 42.into())]);
+        assert_eq!(
+            to_json(&res),
+            json!({
+                "status": 200,
+                "message": "",
+                "values": [],
+                "pairs": [{ "key": "k1", "value": { "integer": 42 } }],
+                "events": [],
+            })
+        );
+    }
+}
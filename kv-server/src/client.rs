@@ -0,0 +1,53 @@
+use bytes::Bytes;
+use course_proto::pb::abi::{CommandRequest, CommandResponse};
+use futures::prelude::*;
+use prost::Message;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, ToSocketAddrs},
+};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::error::KvError;
+
+/// kv-server 的客户端, 每次发送一个 CommandRequest 并等待对应的 CommandResponse
+pub struct KvClient<S = TcpStream> {
+    inner: Framed<S, LengthDelimitedCodec>,
+}
+
+impl KvClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self, KvError> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Self::new(stream))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> KvClient<S> {
+    /// 在已经建立的连接上创建客户端
+    pub fn new(stream: S) -> Self {
+        Self {
+            inner: Framed::new(stream, LengthDelimitedCodec::new()),
+        }
+    }
+
+    pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        self.inner.send(Bytes::from(cmd.encode_to_vec())).await?;
+
+        match self.inner.next().await {
+            Some(Ok(buf)) => Ok(CommandResponse::decode(buf)?),
+            Some(Err(e)) => Err(e.into()),
+            None => Err(KvError::Internal("Connection closed by server".into())),
+        }
+    }
+
+    /// 发送 Watch 命令, 返回服务器推送的响应流.
+    ///
+    /// 第一个响应表示订阅是否成功, 之后的每个响应都带有一个事件. Watch 会占用整个连接.
+    pub async fn watch(
+        mut self,
+        cmd: CommandRequest,
+    ) -> Result<impl Stream<Item = Result<CommandResponse, KvError>>, KvError> {
+        self.inner.send(Bytes::from(cmd.encode_to_vec())).await?;
+        Ok(self.inner.map(|frame| Ok(CommandResponse::decode(frame?)?)))
+    }
+}
@@ -1,15 +1,207 @@
+use std::{future::Future, time::Duration};
+
 use anyhow::Result;
-use course_proto::pb::abi::{CommandResponse, KvPair, Value};
+use course_proto::pb::abi::{CommandResponse, IndexKind, KvPair, Value, hfind::Condition, value};
+use futures::{TryStreamExt, stream::BoxStream};
+
+use crate::{
+    error::KvError,
+    storage::{index::matches, watch::Subscription},
+};
 
-use crate::error::KvError;
+/// Storage::update 中根据旧的 value 计算新的 value 的函数, 可能会被调用多次
+pub type UpdateFn<'a> = dyn FnMut(Option<&Value>) -> Result<Value, KvError> + 'a;
+/// Storage::update 返回的旧的和新的 value
+pub type Updated = (Option<Value>, Value);
+
+/// 把 values 逐个插入到 list old 的表头得到的新 list, old 不存在时从空的 list 开始
+pub(crate) fn push_front(old: Option<&Value>, values: &[Value]) -> Result<Value, KvError> {
+    let mut list = match old {
+        None => Vec::new(),
+        Some(Value {
+            value: Some(value::Value::List(list)),
+        }) => list.values.clone(),
+        Some(v) => return Err(KvError::ConvertError(v.clone(), "List")),
+    };
+    list.splice(0..0, values.iter().rev().cloned());
+    Ok(list.into())
+}
 
-pub trait Storage {
+pub trait Storage: Send + Sync + 'static {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError>;
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
     fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError>;
+    /// 返回所有 table 的名字
+    fn tables(&self) -> Result<Vec<String>, KvError>;
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = KvPair>>>;
+    /// 按 key 的顺序返回 after 之后的最多 limit 个 KvPair.
+    ///
+    /// 默认实现每次都读出并排序整个 table; 用它分页读取 (Hexport 和不阻塞的存储上的 scan)
+    /// 的存储需要覆盖它, 直接从 after 的位置开始读
+    fn get_range(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<KvPair>, KvError> {
+        let mut pairs: Vec<_> = self
+            .get_all(table)?
+            .into_iter()
+            .filter(|pair| after.is_none_or(|after| pair.key.as_str() > after))
+            .collect();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+    /// 用 f 根据 key 当前的 value 计算出新的 value 并写回, 返回旧的和新的 value.
+    ///
+    /// 默认实现先读后写, 不是原子的; 能够原子修改的存储需要覆盖它
+    fn update(&self, table: &str, key: &str, f: &mut UpdateFn<'_>) -> Result<Updated, KvError> {
+        let old = self.get(table, key)?;
+        let new = f(old.as_ref())?;
+        self.set(table, key.into(), new.clone())?;
+        Ok((old, new))
+    }
+    /// 和 Redis 的 LPUSH 一样, 把 values 逐个插入到 list 的表头, 最后一个 value 在最前面.
+    ///
+    /// 默认实现基于 update; 能把命令原样转发给远程服务器的存储需要覆盖它
+    fn lpush(&self, table: &str, key: &str, values: Vec<Value>) -> Result<Updated, KvError> {
+        self.update(table, key, &mut |old| push_front(old, &values))
+    }
+    /// 让 key 在 ttl 之后过期, key 不存在时返回 false
+    fn expire(&self, _table: &str, _key: &str, _ttl: Duration) -> Result<bool, KvError> {
+        Err(KvError::Unsupported("expire"))
+    }
+    /// 在 table 的 value 上创建二级索引, 返回建立索引的 KvPair 个数
+    fn create_index(&self, _table: &str, _kind: IndexKind) -> Result<usize, KvError> {
+        Err(KvError::Unsupported("create_index"))
+    }
+    /// 按 key 的顺序返回 value 满足条件的 KvPair, 默认实现会扫描整个 table
+    fn find(&self, table: &str, cond: &Condition) -> Result<Vec<KvPair>, KvError> {
+        let mut pairs = Vec::new();
+        for pair in self.get_all(table)? {
+            if matches(cond, pair.value.as_ref().unwrap_or(&Value::default()))? {
+                pairs.push(pair);
+            }
+        }
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(pairs)
+    }
+    /// 固定当前版本的数据, 返回 snapshot 的 id
+    fn snapshot(&self) -> Result<u64, KvError> {
+        Err(KvError::Unsupported("snapshot"))
+    }
+    /// 释放 snapshot 以便回收它需要的旧版本, snapshot 不存在时返回 false
+    fn release_snapshot(&self, _id: u64) -> Result<bool, KvError> {
+        Err(KvError::Unsupported("snapshot"))
+    }
+    /// 从 snapshot 中读取一个 key
+    fn get_at(&self, _snapshot: u64, _table: &str, _key: &str) -> Result<Option<Value>, KvError> {
+        Err(KvError::Unsupported("snapshot"))
+    }
+    /// 从 snapshot 中读取 table 的所有 KvPair
+    fn get_all_at(&self, _snapshot: u64, _table: &str) -> Result<Vec<KvPair>, KvError> {
+        Err(KvError::Unsupported("snapshot"))
+    }
+    /// 订阅 table 中以 prefix 开头的 key 的变化
+    fn watch(&self, _table: &str, _prefix: &str) -> Result<Subscription, KvError> {
+        Err(KvError::Unsupported("watch"))
+    }
+    /// 把尚未落盘的数据刷到持久化介质上, 纯内存的实现无需处理
+    fn flush(&self) -> Result<(), KvError> {
+        Ok(())
+    }
+    /// 操作是否会因为磁盘或者网络 I/O 阻塞. 阻塞的存储在异步环境中需要放到专门的线程上执行
+    fn is_blocking(&self) -> bool {
+        false
+    }
+}
+
+/// 异步的存储接口, 适用于读写需要等待磁盘或网络 I/O 的后端.
+///
+/// 同步的 Storage 可以通过 storage::adapter::Adapter 当作 AsyncStorage 使用
+pub trait AsyncStorage: Send + Sync + 'static {
+    fn get(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send;
+    fn set(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send;
+    fn contains(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<bool, KvError>> + Send;
+    fn del(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send;
+    /// 一次读取多个 key, 不存在的 key 返回 None
+    fn get_many(
+        &self,
+        table: &str,
+        keys: Vec<String>,
+    ) -> impl Future<Output = Result<Vec<Option<Value>>, KvError>> + Send {
+        async move {
+            let mut values = Vec::with_capacity(keys.len());
+            for key in &keys {
+                values.push(self.get(table, key).await?);
+            }
+            Ok(values)
+        }
+    }
+    /// 一次检查多个 key 是否存在
+    fn contains_many(
+        &self,
+        table: &str,
+        keys: Vec<String>,
+    ) -> impl Future<Output = Result<Vec<bool>, KvError>> + Send {
+        async move {
+            let mut values = Vec::with_capacity(keys.len());
+            for key in &keys {
+                values.push(self.contains(table, key).await?);
+            }
+            Ok(values)
+        }
+    }
+    /// 返回所有 table 的名字
+    fn tables(&self) -> impl Future<Output = Result<Vec<String>, KvError>> + Send;
+    /// 按 key 的顺序返回 after 之后的最多 limit 个 KvPair
+    fn get_range(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<KvPair>, KvError>> + Send {
+        async move {
+            let mut pairs: Vec<KvPair> = self
+                .scan(table)
+                .try_filter(|pair| {
+                    let keep = after.is_none_or(|after| pair.key.as_str() > after);
+                    futures::future::ready(keep)
+                })
+                .try_collect()
+                .await?;
+            pairs.sort_by(|a, b| a.key.cmp(&b.key));
+            pairs.truncate(limit);
+            Ok(pairs)
+        }
+    }
+    /// 逐个返回 table 中的 KvPair, 取代同步接口中的 get_iter
+    fn scan(&self, table: &str) -> BoxStream<'static, Result<KvPair, KvError>>;
+    /// 收集 table 中所有的 KvPair
+    fn get_all(&self, table: &str) -> impl Future<Output = Result<Vec<KvPair>, KvError>> + Send {
+        self.scan(table).try_collect()
+    }
+    fn flush(&self) -> impl Future<Output = Result<(), KvError>> + Send;
 }
 
 pub trait CommandService {
@@ -17,25 +209,35 @@ pub trait CommandService {
     fn execute(self, store: &impl Storage) -> CommandResponse;
 }
 
+pub trait AsyncCommandService {
+    /// 在异步的存储上处理 Command, 返回 Response
+    fn execute_async(
+        self,
+        store: &impl AsyncStorage,
+    ) -> impl Future<Output = CommandResponse> + Send;
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::storage::memory::MemTable;
+    use crate::storage::{memory::MemTable, sleddb::SledDb};
+    use tempfile::tempdir;
 
     fn test_basi_interface(store: impl Storage) {
         let v = store.set("t1", "language".into(), "Perl 6".into());
+        assert_eq!(v.unwrap(), None);
         let v1 = store.set("t1", "language".into(), "Raku".into());
         assert_eq!(v1.unwrap(), Some("Perl 6".into()));
 
         let v = store.get("t1", "language");
         assert_eq!(v.unwrap(), Some("Raku".into()));
 
-        assert_eq!(None, store.get("t1", "Raku".into()).unwrap());
+        assert_eq!(None, store.get("t1", "Raku").unwrap());
         assert!(store.get("t2", "language").unwrap().is_none());
 
-        assert_eq!(store.contains("t1", "language").unwrap(), true);
-        assert_eq!(store.contains("t1", "lan").unwrap(), false);
-        assert_eq!(store.contains("t2", "language").unwrap(), false);
+        assert!(store.contains("t1", "language").unwrap());
+        assert!(!store.contains("t1", "lan").unwrap());
+        assert!(!store.contains("t2", "language").unwrap());
 
         let v = store.del("t1", "language").unwrap();
         assert_eq!(v, Some("Raku".into()));
@@ -75,6 +277,34 @@ mod tests {
         );
     }
 
+    fn test_get_range(store: impl Storage) {
+        for k in ["k3", "k1", "k4", "k2"] {
+            store.set("t3", k.into(), k.into()).unwrap();
+        }
+        store.set("t4", "k0".into(), "v0".into()).unwrap();
+
+        let keys = |pairs: Vec<KvPair>| pairs.into_iter().map(|p| p.key).collect::<Vec<_>>();
+        assert_eq!(keys(store.get_range("t3", None, 2).unwrap()), ["k1", "k2"]);
+        assert_eq!(
+            keys(store.get_range("t3", Some("k2"), 10).unwrap()),
+            ["k3", "k4"]
+        );
+        assert!(store.get_range("t3", Some("k4"), 10).unwrap().is_empty());
+        assert!(store.get_range("t3", None, 0).unwrap().is_empty());
+        assert!(store.get_range("t5", None, 10).unwrap().is_empty());
+    }
+
+    fn test_tables(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k2".into(), "v2".into()).unwrap();
+        store.set("t10", "k1".into(), "v1".into()).unwrap();
+
+        let mut tables = store.tables().unwrap();
+        tables.sort();
+        assert_eq!(tables, ["t1", "t10", "t2"]);
+    }
+
     #[test]
     fn memtable_basic_interface_should_work() {
         let store = MemTable::new();
@@ -84,6 +314,59 @@ mod tests {
     #[test]
     fn memtable_get_all_should_work() {
         let store = MemTable::new();
-        //test_get_all(store);
+        test_get_all(store);
+    }
+
+    #[test]
+    fn memtable_iter_should_work() {
+        let store = MemTable::new();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn memtable_get_range_should_work() {
+        let store = MemTable::new();
+        test_get_range(store);
+    }
+
+    #[test]
+    fn memtable_tables_should_work() {
+        let store = MemTable::new();
+        test_tables(store);
+    }
+
+    #[test]
+    fn sleddb_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        test_basi_interface(store);
+    }
+
+    #[test]
+    fn sleddb_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        test_get_all(store);
+    }
+
+    #[test]
+    fn sleddb_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn sleddb_get_range_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        test_get_range(store);
+    }
+
+    #[test]
+    fn sleddb_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).unwrap();
+        test_tables(store);
     }
 }
@@ -0,0 +1,226 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::KvError,
+    ratelimit::RateLimitConfig,
+    script::ScriptLimits,
+    slowlog::SlowLogConfig,
+    storage::{
+        eviction::{EvictionPolicy, MemoryLimit},
+        remote::RemoteConfig,
+    },
+};
+
+/// kv-server 的配置, 从 toml 文件中加载
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ServerConfig {
+    #[serde(default)]
+    pub general: GeneralConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub memory: MemoryConfig,
+    /// Eval 执行脚本时的限制
+    #[serde(default)]
+    pub script: ScriptLimits,
+    /// 按客户端和 table 限流
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// 慢命令日志
+    #[serde(default)]
+    pub slow_log: SlowLogConfig,
+    #[serde(default)]
+    pub tracing: TracingConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct GeneralConfig {
+    /// 监听的地址
+    pub addr: String,
+    /// 收到关闭信号后, 等待正在处理的请求完成的最长时间
+    #[serde(default = "default_drain_timeout_ms")]
+    pub drain_timeout_ms: u64,
+    /// Backup 和 Restore 读写的快照文件都在这个目录下
+    #[serde(default = "default_backup_dir")]
+    pub backup_dir: PathBuf,
+}
+
+/// tracing 的导出设置
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct TracingConfig {
+    /// OpenTelemetry collector 的 OTLP gRPC 地址, 比如 `http://localhost:4317`.
+    /// 只在编译时启用了 otel feature 时生效
+    pub otlp_endpoint: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", content = "args")]
+pub enum StorageConfig {
+    #[default]
+    MemTable,
+    SledDb(String),
+    /// 把所有操作转发给另一个 kv-server
+    Remote(RemoteConfig),
+}
+
+/// MemTable 的内存预算, 只对 MemTable 生效
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct MemoryConfig {
+    /// 最多使用的内存字节数, 不设置时不做限制
+    pub max_bytes: Option<usize>,
+    /// 达到上限时的淘汰策略
+    #[serde(default)]
+    pub eviction: EvictionPolicy,
+}
+
+impl MemoryConfig {
+    pub fn limit(&self) -> Option<MemoryLimit> {
+        self.max_bytes.map(|max_bytes| MemoryLimit {
+            max_bytes,
+            policy: self.eviction,
+        })
+    }
+}
+
+fn default_drain_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_backup_dir() -> PathBuf {
+    "backups".into()
+}
+
+impl Default for GeneralConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:9527".into(),
+            drain_timeout_ms: default_drain_timeout_ms(),
+            backup_dir: default_backup_dir(),
+        }
+    }
+}
+
+impl GeneralConfig {
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_millis(self.drain_timeout_ms)
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let content = fs::read_to_string(path)?;
+        let config: Self =
+            toml::from_str(&content).map_err(|e| KvError::ConfigError(e.to_string()))?;
+        config.rate_limit.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::remote::CacheConfig;
+
+    #[test]
+    fn server_config_should_be_parsed() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "0.0.0.0:9527"
+
+            [storage]
+            type = "SledDb"
+            args = "/tmp/kvserver"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.general.addr, "0.0.0.0:9527");
+        assert_eq!(config.general.drain_timeout(), Duration::from_secs(5));
+        assert_eq!(config.general.backup_dir, Path::new("backups"));
+        assert_eq!(
+            config.storage,
+            StorageConfig::SledDb("/tmp/kvserver".into())
+        );
+    }
+
+    #[test]
+    fn empty_config_should_use_defaults() {
+        let config: ServerConfig = toml::from_str("").unwrap();
+        assert_eq!(config, ServerConfig::default());
+        assert_eq!(config.memory.limit(), None);
+    }
+
+    #[test]
+    fn memory_config_should_be_parsed() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [memory]
+            max_bytes = 1048576
+            eviction = "ttl_first"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.memory.limit(),
+            Some(MemoryLimit {
+                max_bytes: 1048576,
+                policy: EvictionPolicy::TtlFirst,
+            })
+        );
+    }
+
+    #[test]
+    fn script_config_should_be_parsed() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [script]
+            max_instructions = 1000
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.script,
+            ScriptLimits {
+                max_instructions: 1000,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn remote_storage_config_should_be_parsed() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [storage]
+            type = "Remote"
+
+            [storage.args]
+            addr = "10.0.0.1:9527"
+
+            [storage.args.cache]
+            watch_tables = ["users"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.storage,
+            StorageConfig::Remote(RemoteConfig {
+                cache: Some(CacheConfig {
+                    watch_tables: vec!["users".into()],
+                    ..Default::default()
+                }),
+                ..RemoteConfig::new("10.0.0.1:9527")
+            })
+        );
+    }
+}
@@ -1,4 +1,6 @@
-use course_proto::pb::abi::Value;
+use std::time::Duration;
+
+use course_proto::pb::abi::{CommandResponse, Value};
 use thiserror::Error;
 
 #[derive(Error, Debug, PartialEq)]
@@ -15,6 +17,77 @@ pub enum KvError {
     EncodeError(#[from] prost::EncodeError),
     #[error("Failed to decode protobuf message")]
     DecodeError(#[from] prost::DecodeError),
+    #[error("Failed to access sled db")]
+    SledError(#[from] sled::Error),
+    #[error("I/O error: {0}")]
+    IoError(String),
+    #[error("Failed to parse {0} record: {1}")]
+    ParseError(&'static str, String),
+    #[error("Invalid snapshot: {0}")]
+    InvalidSnapshot(String),
+    #[error("Failed to load config: {0}")]
+    ConfigError(String),
+    #[error("Out of memory: the memory limit of {0} bytes is reached")]
+    OutOfMemory(usize),
+    #[error("Operation {0} is not supported by the storage")]
+    Unsupported(&'static str),
+    #[error("Snapshot {0} does not exist or has been released")]
+    SnapshotNotFound(u64),
+    #[error("Script error: {0}")]
+    ScriptError(String),
+    #[error("No script matches SHA1 {0}, load it with ScriptLoad or Eval first")]
+    ScriptNotFound(String),
+    #[error("Rate limit exceeded for {0}, retry after {1} ms")]
+    RateLimited(String, u64),
+    #[error("Watcher lagged behind, {0} events were dropped")]
+    WatchLagged(u64),
+    #[error("Snapshot {0} was lost because the connection to remote kv-server was re-established")]
+    SnapshotLost(u64),
+    #[error("Remote kv-server did not respond within {0:?}")]
+    Timeout(Duration),
+    #[error("Remote kv-server returned {0}: {1}")]
+    Remote(u32, String),
     #[error("Internal error: {0}")]
     Internal(String),
 }
+
+impl From<std::io::Error> for KvError {
+    fn from(e: std::io::Error) -> Self {
+        Self::IoError(e.to_string())
+    }
+}
+
+impl From<KvError> for CommandResponse {
+    fn from(e: KvError) -> Self {
+        let status = match e {
+            KvError::NotFound(_, _)
+            | KvError::SnapshotNotFound(_)
+            | KvError::SnapshotLost(_)
+            | KvError::ScriptNotFound(_) => 404,
+            KvError::InvalidCommand(_)
+            | KvError::ConvertError(_, _)
+            | KvError::ParseError(_, _)
+            | KvError::ScriptError(_) => 400,
+            KvError::RateLimited(_, _) => 429,
+            KvError::Unsupported(_) => 501,
+            KvError::OutOfMemory(_) => 507,
+            KvError::Timeout(_) => 504,
+            // 转发远程服务器的状态码
+            KvError::Remote(status, _) => status,
+            _ => 500,
+        };
+
+        // 被限流时在 values 中返回需要等待的毫秒数, 客户端可以据此重试
+        let values = match e {
+            KvError::RateLimited(_, retry_after_ms) => vec![Value::from(retry_after_ms as i64)],
+            _ => vec![],
+        };
+
+        Self {
+            status,
+            message: e.to_string(),
+            values,
+            ..Default::default()
+        }
+    }
+}
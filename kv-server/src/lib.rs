@@ -1,3 +1,13 @@
+pub mod backup;
+pub mod cli;
+pub mod client;
 pub mod command;
+pub mod config;
 pub mod error;
+pub mod ratelimit;
+pub mod script;
+pub mod server;
+pub mod service;
+pub mod slowlog;
 pub mod storage;
+pub mod transfer;
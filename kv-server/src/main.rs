@@ -1,3 +1,116 @@
-fn main() {
-    println!("Hello, world!");
+use std::env;
+
+use anyhow::Result;
+use kv_server::{
+    command::Storage,
+    config::{ServerConfig, StorageConfig, TracingConfig},
+    server::Server,
+    service::Service,
+    storage::{index::Indexed, memory::MemTable, remote::Remote, sleddb::SledDb, watch::Watched},
+};
+use tokio::{
+    net::TcpListener,
+    signal::{
+        self,
+        unix::{SignalKind, signal},
+    },
+};
+use tracing::info;
+use tracing_subscriber::{filter::LevelFilter, prelude::*};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // 配置文件的路径从 KV_SERVER_CONFIG 环境变量中读取, 没有设置时使用默认配置
+    let config = match env::var("KV_SERVER_CONFIG") {
+        Ok(path) => ServerConfig::load(path)?,
+        Err(_) => ServerConfig::default(),
+    };
+    init_tracing(&config.tracing)?;
+
+    let res = match config.storage.clone() {
+        StorageConfig::MemTable => {
+            let store = match config.memory.limit() {
+                Some(limit) => MemTable::with_limit(limit),
+                None => MemTable::new(),
+            };
+            start_server(&config, store).await
+        }
+        StorageConfig::SledDb(path) => start_server(&config, SledDb::new(path)?).await,
+        StorageConfig::Remote(remote) => {
+            info!("Proxying to remote kv-server {}", remote.addr);
+            start_server(&config, Remote::connect(remote)?).await
+        }
+    };
+
+    // 退出前把还没导出的 span 发送出去
+    #[cfg(feature = "otel")]
+    opentelemetry::global::shutdown_tracer_provider();
+    res
+}
+
+/// 初始化日志. 启用了 otel feature 并且配置了 OTLP 地址时, 同时把 span 导出到 collector
+fn init_tracing(config: &TracingConfig) -> Result<()> {
+    let registry = tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer());
+
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = &config.otlp_endpoint {
+        registry.with(otel_layer(endpoint)?).init();
+        info!("Exporting spans to {}", endpoint);
+        return Ok(());
+    }
+
+    registry.init();
+    #[cfg(not(feature = "otel"))]
+    if config.otlp_endpoint.is_some() {
+        tracing::warn!("kv-server is built without the otel feature, spans will not be exported");
+    }
+    Ok(())
+}
+
+#[cfg(feature = "otel")]
+fn otel_layer<S>(endpoint: &str) -> Result<impl tracing_subscriber::Layer<S>>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    use opentelemetry::{KeyValue, trace::TracerProvider as _};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{Resource, runtime, trace::TracerProvider};
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", "kv-server")]))
+        .build();
+    let tracer = provider.tracer("kv-server");
+    opentelemetry::global::set_tracer_provider(provider);
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+async fn start_server<Store: Storage>(config: &ServerConfig, store: Store) -> Result<()> {
+    let addr = &config.general.addr;
+    let listener = TcpListener::bind(addr).await?;
+    info!("Start listening on {}", addr);
+
+    let store = Watched::new(Indexed::new(store));
+    let server = Server::new(
+        Service::with_config(store, config),
+        config.general.drain_timeout(),
+    );
+    server.run(listener, shutdown_signal()).await?;
+    Ok(())
+}
+
+/// 等待 SIGTERM 或者 Ctrl-C
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+
+    tokio::select! {
+        _ = signal::ctrl_c() => info!("Received Ctrl-C"),
+        _ = terminate.recv() => info!("Received SIGTERM"),
+    }
 }
@@ -0,0 +1,350 @@
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use course_proto::pb::abi::KvPair;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+use crate::error::KvError;
+
+/// 客户端和 table 各自最多保留的桶数, 超出时淘汰最久没有用到的桶.
+/// 很久没有用到的桶通常已经装满, 和新建的桶没有区别
+const MAX_BUCKETS: NonZeroUsize = NonZeroUsize::new(10_000).unwrap();
+
+/// 令牌桶的速率
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Rate {
+    /// 每秒补充的令牌数, 也就是长期平均下每秒允许的请求数
+    pub per_second: f64,
+    /// 桶的容量, 也就是允许的突发请求数
+    pub burst: u32,
+}
+
+impl Rate {
+    fn validate(&self, name: &str) -> Result<(), KvError> {
+        if !(self.per_second.is_finite() && self.per_second > 0.0) || self.burst == 0 {
+            return Err(KvError::ConfigError(format!(
+                "Rate limit of {} needs a positive per_second and burst, got {:?}",
+                name, self
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// 限流的配置, 不设置时不限流
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// 每个客户端的限制, 客户端按照 IP 地址区分
+    pub client: Option<Rate>,
+    /// 每个 table 的默认限制
+    pub table: Option<Rate>,
+    /// 单独设置的 table 的限制, 优先于 table
+    pub tables: BTreeMap<String, Rate>,
+}
+
+impl RateLimitConfig {
+    /// 检查所有的速率, 每秒的令牌数和桶的容量都必须大于 0
+    pub fn validate(&self) -> Result<(), KvError> {
+        if let Some(rate) = &self.client {
+            rate.validate("client")?;
+        }
+        if let Some(rate) = &self.table {
+            rate.validate("table")?;
+        }
+        for (name, rate) in &self.tables {
+            rate.validate(&format!("table {}", name))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant,
+    allowed: u64,
+    throttled: u64,
+}
+
+impl Bucket {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.burst as f64,
+            updated: now,
+            allowed: 0,
+            throttled: 0,
+        }
+    }
+
+    /// now 时桶里的令牌数
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * self.rate.per_second).min(self.rate.burst as f64)
+    }
+
+    /// 补充令牌, 令牌不足一个时返回需要等待的时间
+    fn refill(&mut self, now: Instant) -> Option<Duration> {
+        self.tokens = self.tokens_at(now);
+        self.updated = self.updated.max(now);
+        match self.tokens >= 1.0 {
+            true => None,
+            false => {
+                let secs = (1.0 - self.tokens) / self.rate.per_second;
+                Some(Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX))
+            }
+        }
+    }
+}
+
+/// 按客户端和 table 的令牌桶限流, 每个请求消耗客户端和它访问的 table 各一个令牌
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    clients: Mutex<LruCache<String, Bucket>>,
+    tables: Mutex<LruCache<String, Bucket>>,
+    allowed: AtomicU64,
+    throttled: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            clients: Mutex::new(LruCache::new(MAX_BUCKETS)),
+            tables: Mutex::new(LruCache::new(MAX_BUCKETS)),
+            allowed: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// 检查 client 访问 table 的一个请求是否允许执行, 允许时消耗令牌.
+    /// 任何一个桶的令牌不足时都不消耗令牌, 返回 KvError::RateLimited
+    pub fn check(
+        &self,
+        client: Option<&str>,
+        table: Option<&str>,
+        now: Instant,
+    ) -> Result<(), KvError> {
+        let client = client.zip(self.config.client);
+        let table = table.and_then(|t| {
+            let rate = self.config.tables.get(t).or(self.config.table.as_ref());
+            rate.map(|rate| (t, *rate))
+        });
+        if client.is_none() && table.is_none() {
+            return Ok(());
+        }
+
+        let mut clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut tables = self.tables.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut buckets = Vec::with_capacity(2);
+        if let Some((id, rate)) = client {
+            buckets.push((
+                format!("client {}", id),
+                bucket(&mut clients, id, rate, now),
+            ));
+        }
+        if let Some((name, rate)) = table {
+            buckets.push((
+                format!("table {}", name),
+                bucket(&mut tables, name, rate, now),
+            ));
+        }
+
+        let mut limited: Option<(String, Duration)> = None;
+        for (name, bucket) in buckets.iter_mut() {
+            if let Some(wait) = bucket.refill(now) {
+                bucket.throttled += 1;
+                if limited.as_ref().is_none_or(|(_, max)| wait > *max) {
+                    limited = Some((name.clone(), wait));
+                }
+            }
+        }
+        if let Some((name, wait)) = limited {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            let retry_after_ms = wait.as_nanos().div_ceil(1_000_000);
+            let retry_after_ms = retry_after_ms.try_into().unwrap_or(u64::MAX);
+            return Err(KvError::RateLimited(name, retry_after_ms));
+        }
+
+        for (_, bucket) in buckets {
+            bucket.tokens -= 1.0;
+            bucket.allowed += 1;
+        }
+        self.allowed.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// 限流的状态: 总的放行和拒绝次数, 客户端的个数, 以及每个 table 的桶当前的令牌数, 放行和拒绝次数.
+    ///
+    /// 任何客户端都可以读取 metrics, 所以不按客户端列出, 避免暴露其它客户端的地址
+    pub fn metrics(&self, now: Instant) -> Vec<KvPair> {
+        let mut pairs = vec![
+            KvPair::new(
+                "rate_limit_allowed_total",
+                (self.allowed.load(Ordering::Relaxed) as i64).into(),
+            ),
+            KvPair::new(
+                "rate_limit_throttled_total",
+                (self.throttled.load(Ordering::Relaxed) as i64).into(),
+            ),
+        ];
+        let clients = self
+            .clients
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len();
+        pairs.push(KvPair::new("rate_limit_clients", (clients as i64).into()));
+
+        let tables = self.tables.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut buckets: Vec<_> = tables.iter().collect();
+        buckets.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, bucket) in buckets {
+            let labels = format!("{{table=\"{}\"}}", name);
+            pairs.extend([
+                KvPair::new(
+                    format!("rate_limit_tokens{}", labels),
+                    bucket.tokens_at(now).into(),
+                ),
+                KvPair::new(
+                    format!("rate_limit_allowed{}", labels),
+                    (bucket.allowed as i64).into(),
+                ),
+                KvPair::new(
+                    format!("rate_limit_throttled{}", labels),
+                    (bucket.throttled as i64).into(),
+                ),
+            ]);
+        }
+        pairs
+    }
+}
+
+fn bucket<'a>(
+    buckets: &'a mut LruCache<String, Bucket>,
+    name: &str,
+    rate: Rate,
+    now: Instant,
+) -> &'a mut Bucket {
+    buckets.get_or_insert_mut(name.into(), || Bucket::new(rate, now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(per_second: f64, burst: u32) -> Rate {
+        Rate { per_second, burst }
+    }
+
+    #[test]
+    fn client_bucket_should_allow_burst_then_refill() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            client: Some(rate(10.0, 2)),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert!(limiter.check(Some("c1"), None, now).is_ok());
+        assert!(limiter.check(Some("c1"), None, now).is_ok());
+        assert_eq!(
+            limiter.check(Some("c1"), None, now),
+            Err(KvError::RateLimited("client c1".into(), 100))
+        );
+        // 其他客户端不受影响, 没有客户端信息的请求不按客户端限流
+        assert!(limiter.check(Some("c2"), None, now).is_ok());
+        assert!(limiter.check(None, Some("t1"), now).is_ok());
+
+        let later = now + Duration::from_millis(150);
+        assert!(limiter.check(Some("c1"), None, later).is_ok());
+        assert!(limiter.check(Some("c1"), None, later).is_err());
+    }
+
+    #[test]
+    fn table_limit_should_apply_to_all_clients() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            client: Some(rate(100.0, 100)),
+            table: Some(rate(1.0, 1)),
+            tables: BTreeMap::from([("hot".to_string(), rate(1.0, 2))]),
+        });
+        let now = Instant::now();
+        assert!(limiter.check(Some("c1"), Some("t1"), now).is_ok());
+        let err = limiter.check(Some("c2"), Some("t1"), now).unwrap_err();
+        assert_eq!(err, KvError::RateLimited("table t1".into(), 1000));
+
+        assert!(limiter.check(Some("c1"), Some("hot"), now).is_ok());
+        assert!(limiter.check(Some("c2"), Some("hot"), now).is_ok());
+        assert!(limiter.check(Some("c2"), Some("hot"), now).is_err());
+
+        // 被拒绝的请求不消耗客户端的令牌
+        let metrics = limiter.metrics(now);
+        let get = |name: &str| {
+            metrics
+                .iter()
+                .find(|p| p.key == name)
+                .and_then(|p| p.value.clone())
+        };
+        assert_eq!(get("rate_limit_allowed_total"), Some(3.into()));
+        assert_eq!(get("rate_limit_throttled_total"), Some(2.into()));
+        assert_eq!(get("rate_limit_tokens{table=\"t1\"}"), Some(0.0.into()));
+        assert_eq!(get("rate_limit_throttled{table=\"hot\"}"), Some(1.into()));
+        // 不列出每个客户端, 避免暴露其它客户端的地址
+        assert_eq!(get("rate_limit_clients"), Some(2.into()));
+        assert!(metrics.iter().all(|p| !p.key.contains("c2")));
+    }
+
+    #[test]
+    fn rate_limit_config_should_be_parsed() {
+        let config: RateLimitConfig = toml::from_str(
+            r#"
+            client = { per_second = 100, burst = 200 }
+
+            [tables.hot]
+            per_second = 0.5
+            burst = 1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.client, Some(rate(100.0, 200)));
+        assert_eq!(config.table, None);
+        assert_eq!(config.tables["hot"], rate(0.5, 1));
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_rates_should_be_rejected() {
+        for bad in [rate(0.0, 1), rate(-1.0, 1), rate(f64::NAN, 1), rate(1.0, 0)] {
+            let config = RateLimitConfig {
+                tables: BTreeMap::from([("hot".to_string(), bad)]),
+                ..Default::default()
+            };
+            assert!(
+                matches!(config.validate(), Err(KvError::ConfigError(msg)) if msg.contains("table hot"))
+            );
+        }
+    }
+
+    #[test]
+    fn least_recently_used_bucket_should_be_evicted() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            client: Some(rate(1.0, 1)),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        for i in 0..MAX_BUCKETS.get() + 1 {
+            assert!(limiter.check(Some(&i.to_string()), None, now).is_ok());
+        }
+        let clients = limiter.clients.lock().unwrap();
+        assert_eq!(clients.len(), MAX_BUCKETS.get());
+        assert!(!clients.contains("0"));
+    }
+}
@@ -0,0 +1,488 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use course_proto::pb::abi::{Value, value};
+use lru::LruCache;
+use mlua::{
+    HookTriggers, Lua, LuaOptions, MetaMethod, MultiValue, StdLib, UserData, UserDataFields,
+    UserDataMethods, UserDataRef, Value as LuaValue,
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{command::Storage, error::KvError};
+
+/// 每执行这么多条指令检查一次是否超出了指令数的限制
+const HOOK_INTERVAL: u32 = 1000;
+/// Lua 的 table 和 Value 互相转换时最多嵌套的层数, 避免循环引用的 table 导致无限递归
+const MAX_DEPTH: usize = 32;
+
+/// 执行脚本的限制
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScriptLimits {
+    /// 一次执行最多运行的 Lua 指令数
+    pub max_instructions: u64,
+    /// 一次执行最多使用的内存字节数
+    pub max_memory: usize,
+    /// 最多缓存的脚本个数, 超出时淘汰最久没有用到的脚本
+    pub cache_size: usize,
+}
+
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        Self {
+            max_instructions: 10_000_000,
+            max_memory: 16 * 1024 * 1024,
+            cache_size: 1024,
+        }
+    }
+}
+
+/// 执行 Lua 脚本, 并按照源码的 SHA1 缓存脚本.
+///
+/// 每次执行都使用一个新的 Lua 虚拟机, 只加载 table/string/math/utf8 这几个标准库.
+/// 脚本通过全局的 `ARGV` 读取参数, 通过 `kv.get/kv.set/kv.del(table, key, ...)` 读写数据,
+/// 脚本的返回值就是命令的返回值. 脚本的写入先缓存起来, 执行成功后才写入存储;
+/// 脚本出错或者写入存储失败时都不会留下部分写入
+pub struct Scripts {
+    limits: ScriptLimits,
+    cache: Mutex<LruCache<String, Arc<str>>>,
+}
+
+impl Scripts {
+    pub fn new(limits: ScriptLimits) -> Self {
+        let size = NonZeroUsize::new(limits.cache_size).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            limits,
+            cache: Mutex::new(LruCache::new(size)),
+        }
+    }
+
+    /// 编译并缓存脚本, 返回它的 SHA1
+    pub fn load(&self, script: &str) -> Result<String, KvError> {
+        let lua = self.sandbox()?;
+        lua.load(script).into_function().map_err(script_error)?;
+        Ok(self.cache(script))
+    }
+
+    /// 执行脚本, 能够编译的脚本会被缓存
+    pub fn eval(
+        &self,
+        store: &impl Storage,
+        script: &str,
+        args: Vec<Value>,
+    ) -> Result<Vec<Value>, KvError> {
+        let lua = self.sandbox()?;
+        let func = lua.load(script).into_function().map_err(script_error)?;
+        self.cache(script);
+        execute(&lua, func, store, args)
+    }
+
+    /// 执行之前缓存的脚本
+    pub fn eval_sha(
+        &self,
+        store: &impl Storage,
+        sha: &str,
+        args: Vec<Value>,
+    ) -> Result<Vec<Value>, KvError> {
+        let script = self
+            .cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&sha.to_lowercase())
+            .cloned()
+            .ok_or_else(|| KvError::ScriptNotFound(sha.into()))?;
+        let lua = self.sandbox()?;
+        let func = lua.load(&*script).into_function().map_err(script_error)?;
+        execute(&lua, func, store, args)
+    }
+
+    fn cache(&self, script: &str) -> String {
+        let sha = sha1_smol::Sha1::from(script).digest().to_string();
+        self.cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .put(sha.clone(), script.into());
+        sha
+    }
+
+    /// 创建一个有内存和指令数限制, 不能访问文件和进程的 Lua 虚拟机
+    fn sandbox(&self) -> Result<Lua, KvError> {
+        let libs = StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8;
+        let lua = Lua::new_with(libs, LuaOptions::new()).map_err(script_error)?;
+        // 基础库中这几个函数会访问文件或者标准输出
+        for name in ["dofile", "loadfile", "print"] {
+            lua.globals()
+                .set(name, LuaValue::Nil)
+                .map_err(script_error)?;
+        }
+        lua.set_memory_limit(self.limits.max_memory)
+            .map_err(script_error)?;
+
+        let max = self.limits.max_instructions;
+        let executed = Cell::new(0u64);
+        let triggers = HookTriggers::new().every_nth_instruction(HOOK_INTERVAL);
+        lua.set_hook(triggers, move |_, _| {
+            executed.set(executed.get() + HOOK_INTERVAL as u64);
+            match executed.get() > max {
+                true => Err(mlua::Error::runtime(format!(
+                    "script exceeded the limit of {} instructions",
+                    max
+                ))),
+                false => Ok(()),
+            }
+        });
+        Ok(lua)
+    }
+}
+
+impl Default for Scripts {
+    fn default() -> Self {
+        Self::new(ScriptLimits::default())
+    }
+}
+
+/// 执行脚本, 成功后再把脚本的写入按 key 的顺序写入存储
+fn execute(
+    lua: &Lua,
+    func: mlua::Function,
+    store: &impl Storage,
+    args: Vec<Value>,
+) -> Result<Vec<Value>, KvError> {
+    // (table, key) -> 写入的 value, None 表示删除
+    let writes: RefCell<BTreeMap<(String, String), Option<Value>>> = RefCell::default();
+    let read = |table: String, key: String| -> mlua::Result<Option<Value>> {
+        if let Some(v) = writes.borrow().get(&(table.clone(), key.clone())) {
+            return Ok(v.clone());
+        }
+        store.get(&table, &key).map_err(mlua::Error::external)
+    };
+
+    let values = lua
+        .scope(|scope| {
+            let kv = lua.create_table()?;
+            let get = scope.create_function(|lua, (table, key): (String, String)| {
+                to_lua(lua, read(table, key)?, 0)
+            })?;
+            let set =
+                scope.create_function(|lua, (table, key, value): (String, String, LuaValue)| {
+                    let value = from_lua(value, 0)?;
+                    let old = read(table.clone(), key.clone())?;
+                    writes.borrow_mut().insert((table, key), Some(value));
+                    to_lua(lua, old, 0)
+                })?;
+            let del = scope.create_function(|lua, (table, key): (String, String)| {
+                let old = read(table.clone(), key.clone())?;
+                writes.borrow_mut().insert((table, key), None);
+                to_lua(lua, old, 0)
+            })?;
+            kv.set("get", get)?;
+            kv.set("set", set)?;
+            kv.set("del", del)?;
+            kv.set(
+                "timestamp",
+                lua.create_function(|_, micros: i64| Ok(Timestamp(micros)))?,
+            )?;
+            lua.globals().set("kv", kv)?;
+
+            let argv = args
+                .into_iter()
+                .map(|v| to_lua(lua, Some(v), 0))
+                .collect::<mlua::Result<Vec<_>>>()?;
+            lua.globals().set("ARGV", lua.create_sequence_from(argv)?)?;
+
+            let ret: MultiValue = func.call(())?;
+            ret.into_iter().map(|v| from_lua(v, 0)).collect()
+        })
+        .map_err(script_error)?;
+
+    // 中途写入失败 (比如内存不足) 时, 恢复已经写入的 key
+    let mut applied = Vec::new();
+    for ((table, key), value) in writes.into_inner() {
+        let old = match value {
+            Some(v) => store.set(&table, key.clone(), v),
+            None => store.del(&table, &key),
+        };
+        match old {
+            Ok(old) => applied.push((table, key, old)),
+            Err(e) => {
+                rollback(store, applied);
+                return Err(e);
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// 按相反的顺序把 key 恢复成写入前的 value. 调用者持有 write_gate, 不会覆盖其它命令的写入;
+/// 恢复的 key 不再有过期时间
+fn rollback(store: &impl Storage, applied: Vec<(String, String, Option<Value>)>) {
+    for (table, key, old) in applied.into_iter().rev() {
+        let res = match old {
+            Some(v) => store.set(&table, key.clone(), v).map(|_| ()),
+            None => store.del(&table, &key).map(|_| ()),
+        };
+        if let Err(e) = res {
+            warn!(
+                "Failed to roll back {}/{} after script error: {}",
+                table, key, e
+            );
+        }
+    }
+}
+
+/// Lua 中的时间戳, 保证 Value::Timestamp 经过脚本之后还是时间戳.
+///
+/// 通过 `t.micros` 读取微秒数, 时间戳之间可以比较; 脚本用 `kv.timestamp(micros)` 创建时间戳
+#[derive(Clone, Copy, Debug)]
+struct Timestamp(i64);
+
+impl UserData for Timestamp {
+    fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("micros", |_, this| Ok(this.0));
+    }
+
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::Eq, |_, this, other: UserDataRef<Self>| {
+            Ok(this.0 == other.0)
+        });
+        methods.add_meta_method(MetaMethod::Lt, |_, this, other: UserDataRef<Self>| {
+            Ok(this.0 < other.0)
+        });
+        methods.add_meta_method(MetaMethod::Le, |_, this, other: UserDataRef<Self>| {
+            Ok(this.0 <= other.0)
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, this, ()| Ok(this.0.to_string()));
+    }
+}
+
+/// 把 Value 转换成 Lua 的值, binary 转换成 Lua 的 string, 时间戳转换成 Timestamp
+fn to_lua(lua: &Lua, v: Option<Value>, depth: usize) -> mlua::Result<LuaValue<'_>> {
+    if depth > MAX_DEPTH {
+        return Err(mlua::Error::runtime("value is nested too deeply"));
+    }
+    let v = match v.and_then(|v| v.value) {
+        None => LuaValue::Nil,
+        Some(value::Value::String(s)) => LuaValue::String(lua.create_string(s)?),
+        Some(value::Value::Binary(b)) => LuaValue::String(lua.create_string(b)?),
+        Some(value::Value::Integer(i)) => LuaValue::Integer(i),
+        Some(value::Value::Timestamp(t)) => LuaValue::UserData(lua.create_userdata(Timestamp(t))?),
+        Some(value::Value::Float(f)) => LuaValue::Number(f),
+        Some(value::Value::Bool(b)) => LuaValue::Boolean(b),
+        Some(value::Value::List(list)) => {
+            let values = list
+                .values
+                .into_iter()
+                .map(|v| to_lua(lua, Some(v), depth + 1))
+                .collect::<mlua::Result<Vec<_>>>()?;
+            LuaValue::Table(lua.create_sequence_from(values)?)
+        }
+        Some(value::Value::Map(map)) => {
+            let table = lua.create_table()?;
+            for (k, v) in map.fields {
+                table.raw_set(k, to_lua(lua, Some(v), depth + 1)?)?;
+            }
+            LuaValue::Table(table)
+        }
+    };
+    Ok(v)
+}
+
+/// 把 Lua 的值转换成 Value. 不是合法 UTF-8 的 string 转换成 binary,
+/// 只有连续整数下标的 table 转换成 list, 只有 string 下标的 table 转换成 map
+fn from_lua(v: LuaValue, depth: usize) -> mlua::Result<Value> {
+    if depth > MAX_DEPTH {
+        return Err(mlua::Error::runtime("value is nested too deeply"));
+    }
+    let v = match v {
+        LuaValue::Nil => Value::default(),
+        LuaValue::Boolean(b) => b.into(),
+        LuaValue::Integer(i) => i.into(),
+        LuaValue::Number(f) => f.into(),
+        LuaValue::String(s) => match s.to_str() {
+            Ok(s) => s.into(),
+            Err(_) => bytes::Bytes::copy_from_slice(s.as_bytes()).into(),
+        },
+        LuaValue::UserData(ud) if ud.is::<Timestamp>() => Value {
+            value: Some(value::Value::Timestamp(ud.borrow::<Timestamp>()?.0)),
+        },
+        LuaValue::Table(table) => {
+            let len = table.raw_len();
+            let pairs = table
+                .clone()
+                .pairs::<LuaValue, LuaValue>()
+                .collect::<mlua::Result<Vec<_>>>()?;
+            if pairs.len() == len {
+                let values = (1..=len)
+                    .map(|i| from_lua(table.raw_get(i)?, depth + 1))
+                    .collect::<mlua::Result<Vec<_>>>()?;
+                values.into()
+            } else {
+                let mut fields = BTreeMap::new();
+                for (k, v) in pairs {
+                    let LuaValue::String(k) = k else {
+                        return Err(mlua::Error::runtime(
+                            "only tables with string keys can be converted to map",
+                        ));
+                    };
+                    fields.insert(k.to_str()?.to_string(), from_lua(v, depth + 1)?);
+                }
+                fields.into()
+            }
+        }
+        v => {
+            return Err(mlua::Error::runtime(format!(
+                "cannot convert {} to value",
+                v.type_name()
+            )));
+        }
+    };
+    Ok(v)
+}
+
+fn script_error(e: mlua::Error) -> KvError {
+    KvError::ScriptError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{
+        eviction::{EvictionPolicy, MemoryLimit, entry_size},
+        memory::MemTable,
+    };
+
+    #[test]
+    fn eval_should_read_and_write_store() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), 10.into()).unwrap();
+        let scripts = Scripts::default();
+
+        let script = r#"
+            local n = kv.get("t1", "k1") + ARGV[1]
+            kv.set("t1", "k1", n)
+            kv.del("t1", "k2")
+            return n, kv.get("t1", "k1"), {1, "a"}, {name = "raku"}
+        "#;
+        let values = scripts.eval(&store, script, vec![5.into()]).unwrap();
+        assert_eq!(
+            values,
+            [
+                15.into(),
+                15.into(),
+                vec![1.into(), "a".into()].into(),
+                BTreeMap::from([("name".to_string(), "raku".into())]).into(),
+            ]
+        );
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(15.into()));
+    }
+
+    #[test]
+    fn failed_script_should_not_write() {
+        let store = MemTable::new();
+        let scripts = Scripts::default();
+
+        let script = r#"kv.set("t1", "k1", "v1"); error("boom")"#;
+        let err = scripts.eval(&store, script, vec![]).unwrap_err();
+        assert!(matches!(err, KvError::ScriptError(msg) if msg.contains("boom")));
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+    }
+
+    #[test]
+    fn failed_write_should_roll_back_script() {
+        let limit = entry_size("k1", &"v".into()) * 2;
+        let store = MemTable::with_limit(MemoryLimit {
+            max_bytes: limit,
+            policy: EvictionPolicy::NoEviction,
+        });
+        store.set("t1", "k1".into(), "v".into()).unwrap();
+        let scripts = Scripts::default();
+
+        // k1 先写入成功, 写入 k2 时内存不足
+        let script = r#"kv.set("t1", "k1", "w"); kv.set("t1", "k2", string.rep("x", 1024))"#;
+        let err = scripts.eval(&store, script, vec![]).unwrap_err();
+        assert_eq!(err, KvError::OutOfMemory(limit));
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+    }
+
+    #[test]
+    fn timestamp_should_round_trip() {
+        let store = MemTable::new();
+        let ts = Value {
+            value: Some(value::Value::Timestamp(1_700_000_000_000_000)),
+        };
+        store.set("t1", "k1".into(), ts.clone()).unwrap();
+        let scripts = Scripts::default();
+
+        let script = r#"
+            local t = kv.get("t1", "k1")
+            kv.set("t1", "k2", kv.timestamp(t.micros + 1))
+            return t, t.micros, t < kv.get("t1", "k2"), t == kv.timestamp(t.micros)
+        "#;
+        let values = scripts.eval(&store, script, vec![]).unwrap();
+        assert_eq!(
+            values,
+            [ts, 1_700_000_000_000_000.into(), true.into(), true.into()]
+        );
+        assert_eq!(
+            store.get("t1", "k2").unwrap(),
+            Some(Value {
+                value: Some(value::Value::Timestamp(1_700_000_000_000_001)),
+            })
+        );
+    }
+
+    #[test]
+    fn eval_sha_should_use_cached_script() {
+        let store = MemTable::new();
+        let scripts = Scripts::default();
+        let sha = scripts.load("return ARGV[1]").unwrap();
+        assert_eq!(sha.len(), 40);
+        // 同样的脚本得到同样的 SHA1
+        assert_eq!(scripts.load("return ARGV[1]").unwrap(), sha);
+
+        let values = scripts.eval_sha(&store, &sha, vec!["hi".into()]).unwrap();
+        assert_eq!(values, ["hi".into()]);
+
+        let err = scripts.eval_sha(&store, "nope", vec![]).unwrap_err();
+        assert_eq!(err, KvError::ScriptNotFound("nope".into()));
+        assert!(matches!(
+            scripts.load("return +").unwrap_err(),
+            KvError::ScriptError(_)
+        ));
+    }
+
+    #[test]
+    fn script_should_respect_limits() {
+        let store = MemTable::new();
+        let scripts = Scripts::new(ScriptLimits {
+            max_instructions: 100_000,
+            max_memory: 1024 * 1024,
+            cache_size: 1,
+        });
+
+        let err = scripts
+            .eval(&store, "while true do end", vec![])
+            .unwrap_err();
+        assert!(matches!(err, KvError::ScriptError(msg) if msg.contains("instructions")));
+
+        let script = r#"return string.rep("x", 2 * 1024 * 1024)"#;
+        let err = scripts.eval(&store, script, vec![]).unwrap_err();
+        assert!(matches!(err, KvError::ScriptError(msg) if msg.contains("memory")));
+    }
+
+    #[test]
+    fn script_should_not_access_host() {
+        let store = MemTable::new();
+        let scripts = Scripts::default();
+        let script = "return io, os, dofile, require";
+        let values = scripts.eval(&store, script, vec![]).unwrap();
+        assert_eq!(values, vec![Value::default(); 4]);
+    }
+}
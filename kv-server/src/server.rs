@@ -0,0 +1,263 @@
+use std::{future::Future, io, mem, net::SocketAddr, time::Duration};
+
+use bytes::Bytes;
+use course_proto::pb::abi::{
+    CommandRequest, CommandResponse, Value, command_request::RequestData, value,
+};
+use futures::prelude::*;
+use prost::Message;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    runtime::Handle,
+    task::JoinSet,
+    time,
+};
+use tokio_util::{
+    codec::{Framed, LengthDelimitedCodec},
+    sync::CancellationToken,
+};
+use tracing::{info, warn};
+
+use crate::{command::Storage, error::KvError, service::Service, storage::watch::Subscription};
+
+/// accept 出错后重试前等待的时间
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// 服务器接受连接的来源, 除了 tokio 的 TcpListener, 也可以是模拟测试中的网络
+pub trait Listener: Send + Sync + 'static {
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    fn accept(&self) -> impl Future<Output = io::Result<(Self::Stream, SocketAddr)>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self).await
+    }
+}
+
+/// 处理客户端连接的 TCP 服务器, 支持优雅退出
+pub struct Server<Store> {
+    service: Service<Store>,
+    drain_timeout: Duration,
+}
+
+impl<Store: Storage> Server<Store> {
+    pub fn new(service: Service<Store>, drain_timeout: Duration) -> Self {
+        Self {
+            service,
+            drain_timeout,
+        }
+    }
+
+    /// 在 listener 上接受连接, 直到 shutdown 完成.
+    ///
+    /// 退出时先停止接受新连接, 再等待已经收到的请求处理完毕(最多等待 drain_timeout),
+    /// 最后把存储刷盘.
+    pub async fn run(
+        self,
+        listener: impl Listener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), KvError> {
+        let token = CancellationToken::new();
+        let mut conns = JoinSet::new();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                Some(_) = conns.join_next(), if !conns.is_empty() => {}
+                res = listener.accept() => {
+                    // EMFILE, ECONNABORTED 等错误只影响这一次 accept, 稍等之后继续接受连接
+                    let (stream, addr) = match res {
+                        Ok(v) => v,
+                        Err(e) => {
+                            warn!("Failed to accept connection: {}", e);
+                            time::sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    info!("Client {:?} connected", addr);
+                    let service = self.service.clone();
+                    let token = token.clone();
+                    conns.spawn(async move {
+                        if let Err(e) = handle_connection(stream, addr, service, token).await {
+                            warn!("Failed to process client {:?}: {}", addr, e);
+                        }
+                    });
+                }
+            }
+        }
+
+        // 不再接受新连接, 通知所有连接在处理完手头的请求后退出
+        drop(listener);
+        token.cancel();
+        info!("Shutting down, draining {} connections", conns.len());
+
+        let drained = time::timeout(self.drain_timeout, async {
+            while conns.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "Drain timeout exceeded, aborting {} connections",
+                conns.len()
+            );
+            conns.shutdown().await;
+        }
+
+        self.service.flush()?;
+        info!("Server stopped");
+        Ok(())
+    }
+}
+
+async fn handle_connection<Store: Storage, S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    addr: SocketAddr,
+    service: Service<Store>,
+    token: CancellationToken,
+) -> Result<(), KvError> {
+    let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+    let mut snapshots = ConnSnapshots {
+        service: service.clone(),
+        client: addr.ip().to_string(),
+        ids: Vec::new(),
+    };
+
+    let res = async {
+        loop {
+            // 只在等待下一个请求时响应退出信号, 已经读到的请求一定会执行完并返回结果
+            let frame = tokio::select! {
+                _ = token.cancelled() => break,
+                frame = framed.next() => frame,
+            };
+            let Some(frame) = frame else { break };
+
+            let cmd = CommandRequest::decode(frame?)?;
+            if let Some(RequestData::Watch(param)) = &cmd.request_data {
+                // Watch 会占用整个连接, 结束之后关闭连接
+                match service.watch(param) {
+                    Ok(sub) => stream_events(&mut framed, sub, &token).await?,
+                    Err(e) => send(&mut framed, e.into()).await?,
+                }
+                break;
+            }
+
+            let res = snapshots.execute(cmd).await;
+            send(&mut framed, res).await?;
+        }
+        Ok::<_, KvError>(())
+    }
+    .await;
+
+    // 出错断开时也要释放连接创建的 snapshot
+    release_snapshots(&snapshots.service, mem::take(&mut snapshots.ids)).await;
+    info!("Client {:?} disconnected", addr);
+    res
+}
+
+/// 连接创建的 snapshot, 连接断开时自动释放, 避免客户端忘记释放导致旧版本无法回收.
+///
+/// snapshot 的 id 只在创建它的连接上有效, 其它连接不能读取或者释放它
+struct ConnSnapshots<Store: Storage> {
+    service: Service<Store>,
+    /// 客户端的 IP 地址, 用来按客户端限流
+    client: String,
+    ids: Vec<u64>,
+}
+
+impl<Store: Storage> ConnSnapshots<Store> {
+    /// 执行命令, 同时记录这个连接创建和释放的 snapshot
+    async fn execute(&mut self, cmd: CommandRequest) -> CommandResponse {
+        if let Some(id) = cmd.request_data.as_ref().and_then(snapshot_of)
+            && !self.ids.contains(&id)
+        {
+            return KvError::SnapshotNotFound(id).into();
+        }
+
+        let is_snapshot = matches!(cmd.request_data, Some(RequestData::Snapshot(_)));
+        if let Some(RequestData::ReleaseSnapshot(param)) = &cmd.request_data {
+            self.ids.retain(|id| *id != param.id);
+        }
+
+        let res = self.service.execute_async(Some(&self.client), cmd).await;
+        if is_snapshot
+            && res.status == 200
+            && let Some(value::Value::Integer(id)) =
+                res.values.first().and_then(|v| v.value.as_ref())
+        {
+            self.ids.push(*id as u64);
+        }
+        res
+    }
+}
+
+/// 命令读取或者释放的 snapshot
+fn snapshot_of(data: &RequestData) -> Option<u64> {
+    let id = match data {
+        RequestData::Hget(param) => param.snapshot,
+        RequestData::Hgetall(param) => param.snapshot,
+        RequestData::Hmget(param) => param.snapshot,
+        RequestData::ReleaseSnapshot(param) => param.id,
+        _ => 0,
+    };
+    (id != 0).then_some(id)
+}
+
+/// 连接正常结束时已经释放了所有 snapshot, 只有连接被中止时才会剩下.
+/// 这时不能在 drop 中同步地执行命令, 交给新的任务去释放
+impl<Store: Storage> Drop for ConnSnapshots<Store> {
+    fn drop(&mut self) {
+        if self.ids.is_empty() {
+            return;
+        }
+        let (service, ids) = (self.service.clone(), mem::take(&mut self.ids));
+        if let Ok(handle) = Handle::try_current() {
+            handle.spawn(async move { release_snapshots(&service, ids).await });
+        }
+    }
+}
+
+async fn release_snapshots<Store: Storage>(service: &Service<Store>, ids: Vec<u64>) {
+    for id in ids {
+        let cmd = CommandRequest::new_release_snapshot(id);
+        service.execute_async(None, cmd).await;
+    }
+}
+
+/// 持续推送订阅到的事件, 直到客户端关闭连接, 发送新的请求或者服务器退出
+async fn stream_events<S: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<S, LengthDelimitedCodec>,
+    mut sub: Subscription,
+    token: &CancellationToken,
+) -> Result<(), KvError> {
+    // 先返回一个空的响应, 表示订阅成功
+    send(framed, Vec::<Value>::new().into()).await?;
+
+    loop {
+        let res = tokio::select! {
+            _ = token.cancelled() => break,
+            _ = framed.next() => break,
+            event = sub.recv() => match event {
+                Ok(Some(event)) => event.into(),
+                Ok(None) => break,
+                // 跟不上时通知客户端丢失了事件, 然后继续推送
+                Err(e) => e.into(),
+            },
+        };
+        send(framed, res).await?;
+    }
+    Ok(())
+}
+
+async fn send<S: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<S, LengthDelimitedCodec>,
+    res: CommandResponse,
+) -> Result<(), KvError> {
+    framed.send(Bytes::from(res.encode_to_vec())).await?;
+    Ok(())
+}
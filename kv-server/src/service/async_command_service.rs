@@ -0,0 +1,191 @@
+use course_proto::pb::abi::*;
+
+use crate::{
+    command::{AsyncCommandService, AsyncStorage},
+    error::KvError,
+    service::command_service::{
+        export_limit, lrange_response, map_field_response, tables_response,
+    },
+};
+
+impl AsyncCommandService for Hget {
+    async fn execute_async(self, store: &impl AsyncStorage) -> CommandResponse {
+        if self.snapshot != 0 {
+            return KvError::Unsupported("snapshot").into();
+        }
+        match store.get(&self.table, &self.key).await {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl AsyncCommandService for Hgetall {
+    async fn execute_async(self, store: &impl AsyncStorage) -> CommandResponse {
+        if self.snapshot != 0 {
+            return KvError::Unsupported("snapshot").into();
+        }
+        store
+            .get_all(&self.table)
+            .await
+            .map_or_else(Into::into, Into::into)
+    }
+}
+
+impl AsyncCommandService for Hmget {
+    async fn execute_async(self, store: &impl AsyncStorage) -> CommandResponse {
+        if self.snapshot != 0 {
+            return KvError::Unsupported("snapshot").into();
+        }
+        match store.get_many(&self.table, self.keys).await {
+            Ok(values) => values
+                .into_iter()
+                .map(Option::unwrap_or_default)
+                .collect::<Vec<_>>()
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl AsyncCommandService for Hset {
+    async fn execute_async(self, store: &impl AsyncStorage) -> CommandResponse {
+        match self.pair {
+            Some(v) => store
+                .set(&self.table, v.key, v.value.unwrap_or_default())
+                .await
+                .map_or_else(Into::into, |v| v.unwrap_or_default().into()),
+            None => KvError::InvalidCommand(format!("{:?}", self)).into(),
+        }
+    }
+}
+
+impl AsyncCommandService for Hmset {
+    async fn execute_async(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.pairs.len());
+        for pair in self.pairs {
+            let value = pair.value.unwrap_or_default();
+            match store.set(&self.table, pair.key, value).await {
+                Ok(v) => values.push(v.unwrap_or_default()),
+                Err(e) => return e.into(),
+            }
+        }
+        values.into()
+    }
+}
+
+impl AsyncCommandService for Hdel {
+    async fn execute_async(self, store: &impl AsyncStorage) -> CommandResponse {
+        store
+            .del(&self.table, &self.key)
+            .await
+            .map_or_else(Into::into, |v| v.unwrap_or_default().into())
+    }
+}
+
+impl AsyncCommandService for Hmdel {
+    async fn execute_async(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in &self.keys {
+            match store.del(&self.table, key).await {
+                Ok(v) => values.push(v.unwrap_or_default()),
+                Err(e) => return e.into(),
+            }
+        }
+        values.into()
+    }
+}
+
+impl AsyncCommandService for Hexist {
+    async fn execute_async(self, store: &impl AsyncStorage) -> CommandResponse {
+        store
+            .contains(&self.table, &self.key)
+            .await
+            .map_or_else(Into::into, |v| Value::from(v).into())
+    }
+}
+
+impl AsyncCommandService for Hmexist {
+    async fn execute_async(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.contains_many(&self.table, self.keys).await {
+            Ok(values) => values
+                .into_iter()
+                .map(Value::from)
+                .collect::<Vec<_>>()
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl AsyncCommandService for Hexport {
+    async fn execute_async(self, store: &impl AsyncStorage) -> CommandResponse {
+        let limit = export_limit(self.limit);
+        store
+            .get_range(&self.table, self.after.as_deref(), limit)
+            .await
+            .map_or_else(Into::into, Into::into)
+    }
+}
+
+impl AsyncCommandService for Lrange {
+    async fn execute_async(self, store: &impl AsyncStorage) -> CommandResponse {
+        let value = store.get(&self.table, &self.key).await;
+        lrange_response(self, value)
+    }
+}
+
+impl AsyncCommandService for MapGetField {
+    async fn execute_async(self, store: &impl AsyncStorage) -> CommandResponse {
+        let value = store.get(&self.table, &self.key).await;
+        map_field_response(self, value)
+    }
+}
+
+impl AsyncCommandService for Tables {
+    async fn execute_async(self, store: &impl AsyncStorage) -> CommandResponse {
+        tables_response(store.tables().await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        service::{assert_res_error, assert_res_ok, dispatch_async},
+        storage::{adapter::Adapter, memory::MemTable},
+    };
+
+    #[tokio::test]
+    async fn async_commands_should_work() {
+        let store = Adapter::new(MemTable::new());
+        let pairs = vec![KvPair::new("k1", 1.into()), KvPair::new("k2", 2.into())];
+        let res = dispatch_async(CommandRequest::new_hmset("t1", pairs.clone()), &store).await;
+        assert_res_ok(res, &[Value::default(), Value::default()], &[]);
+
+        let res = dispatch_async(CommandRequest::new_hget("t1", "k1"), &store).await;
+        assert_res_ok(res, &[1.into()], &[]);
+        let res = dispatch_async(CommandRequest::new_hgetall("t1"), &store).await;
+        assert_res_ok(res, &[], &pairs);
+        let cmd = CommandRequest::new_hmexist("t1", vec!["k1".into(), "k3".into()]);
+        let res = dispatch_async(cmd, &store).await;
+        assert_res_ok(res, &[true.into(), false.into()], &[]);
+
+        let res = dispatch_async(CommandRequest::new_hdel("t1", "k1"), &store).await;
+        assert_res_ok(res, &[1.into()], &[]);
+        let res = dispatch_async(CommandRequest::new_hget("t1", "k1"), &store).await;
+        assert_res_error(res, 404, "Not Found");
+
+        let res = dispatch_async(CommandRequest::new_hget_at("t1", "k2", 1), &store).await;
+        assert_res_error(res, 501, "snapshot");
+        let res = dispatch_async(CommandRequest::new_lrange("t1", "k2", 0, -1), &store).await;
+        assert_res_error(res, 400, "List");
+        let res = dispatch_async(CommandRequest::new_hexport("t1", None, 0), &store).await;
+        assert_res_ok(res, &[], &[KvPair::new("k2", 2.into())]);
+        let res = dispatch_async(CommandRequest::new_tables(), &store).await;
+        assert_res_ok(res, &["t1".into()], &[]);
+        let res = dispatch_async(CommandRequest::new_watch("t1", ""), &store).await;
+        assert_res_error(res, 501, "watch");
+    }
+}
@@ -0,0 +1,598 @@
+use std::time::Duration;
+
+use course_proto::pb::abi::*;
+
+use crate::{
+    command::{CommandService, Storage},
+    error::KvError,
+    storage::index::validate,
+};
+
+impl CommandService for Hget {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = match self.snapshot {
+            0 => store.get(&self.table, &self.key),
+            id => store.get_at(id, &self.table, &self.key),
+        };
+        match value {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hgetall {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let pairs = match self.snapshot {
+            0 => store.get_all(&self.table),
+            id => store.get_all_at(id, &self.table),
+        };
+        match pairs {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmget {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        self.keys
+            .iter()
+            .map(|key| {
+                let value = match self.snapshot {
+                    0 => store.get(&self.table, key),
+                    id => store.get_at(id, &self.table, key),
+                };
+                value.map(Option::unwrap_or_default)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_or_else(Into::into, Into::into)
+    }
+}
+
+impl CommandService for Hset {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match self.pair {
+            Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
+                Ok(Some(v)) => v.into(),
+                Ok(None) => Value::default().into(),
+                Err(e) => e.into(),
+            },
+            None => KvError::InvalidCommand(format!("{:?}", self)).into(),
+        }
+    }
+}
+
+impl CommandService for Hmset {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let table = self.table;
+        self.pairs
+            .into_iter()
+            .map(|pair| {
+                let value = pair.value.unwrap_or_default();
+                store
+                    .set(&table, pair.key, value)
+                    .map(Option::unwrap_or_default)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_or_else(Into::into, Into::into)
+    }
+}
+
+impl CommandService for Hdel {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.del(&self.table, &self.key) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => Value::default().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmdel {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        self.keys
+            .iter()
+            .map(|key| store.del(&self.table, key).map(Option::unwrap_or_default))
+            .collect::<Result<Vec<_>, _>>()
+            .map_or_else(Into::into, Into::into)
+    }
+}
+
+impl CommandService for Hexist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.contains(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmexist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        self.keys
+            .iter()
+            .map(|key| store.contains(&self.table, key).map(Value::from))
+            .collect::<Result<Vec<_>, _>>()
+            .map_or_else(Into::into, Into::into)
+    }
+}
+
+/// 每次导出时默认返回的 KvPair 个数
+const DEFAULT_EXPORT_LIMIT: usize = 1000;
+
+impl CommandService for Hexport {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get_range(&self.table, self.after.as_deref(), export_limit(self.limit)) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// 这次导出最多返回的 KvPair 个数
+pub(super) fn export_limit(limit: u32) -> usize {
+    match limit {
+        0 => DEFAULT_EXPORT_LIMIT,
+        n => n as usize,
+    }
+}
+
+impl CommandService for Himport {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let count = self.pairs.len() as i64;
+        for pair in self.pairs {
+            let value = pair.value.unwrap_or_default();
+            if let Err(e) = store.set(&self.table, pair.key, value) {
+                return e.into();
+            }
+        }
+        Value::from(count).into()
+    }
+}
+
+impl CommandService for Hexpire {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let ttl = Duration::from_millis(self.ttl_ms);
+        match store.expire(&self.table, &self.key, ttl) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for CreateIndex {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let kind = match IndexKind::try_from(self.kind) {
+            Ok(kind) => kind,
+            Err(_) => {
+                return KvError::InvalidCommand(format!("Unknown index kind {}", self.kind)).into();
+            }
+        };
+        match store.create_index(&self.table, kind) {
+            Ok(count) => Value::from(count as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hfind {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let Some(cond) = self.condition else {
+            return KvError::InvalidCommand("Hfind has no condition".into()).into();
+        };
+        match validate(&cond).and_then(|_| store.find(&self.table, &cond)) {
+            Ok(pairs) => pairs.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Lpush {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.lpush(&self.table, &self.key, self.values) {
+            Ok((old, new)) => {
+                let len = Value::from(list_len(&new) as i64);
+                match self.return_old {
+                    true => [len].into_iter().chain(old).collect::<Vec<_>>().into(),
+                    false => len.into(),
+                }
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Lrange {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = store.get(&self.table, &self.key);
+        lrange_response(self, value)
+    }
+}
+
+/// 从读到的 value 中取出 start 到 stop 之间的元素
+pub(super) fn lrange_response(
+    param: Lrange,
+    value: Result<Option<Value>, KvError>,
+) -> CommandResponse {
+    let v = match value {
+        Ok(Some(v)) => v,
+        Ok(None) => return KvError::NotFound(param.table, param.key).into(),
+        Err(e) => return e.into(),
+    };
+    let Some(value::Value::List(list)) = v.value else {
+        return KvError::ConvertError(v, "List").into();
+    };
+
+    // start 和 stop 都是闭区间, 负数表示从末尾开始计算
+    let len = list.values.len() as i64;
+    let index = |i: i64| if i < 0 { len + i } else { i };
+    let start = index(param.start).max(0);
+    let stop = index(param.stop).min(len - 1);
+    if start > stop {
+        return Vec::<Value>::new().into();
+    }
+    list.values[start as usize..=stop as usize].to_vec().into()
+}
+
+impl CommandService for MapGetField {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = store.get(&self.table, &self.key);
+        map_field_response(self, value)
+    }
+}
+
+/// 从读到的 value 中取出 field
+pub(super) fn map_field_response(
+    param: MapGetField,
+    value: Result<Option<Value>, KvError>,
+) -> CommandResponse {
+    let v = match value {
+        Ok(Some(v)) => v,
+        Ok(None) => return KvError::NotFound(param.table, param.key).into(),
+        Err(e) => return e.into(),
+    };
+    let Some(value::Value::Map(mut map)) = v.value else {
+        return KvError::ConvertError(v, "Map").into();
+    };
+    match map.fields.remove(&param.field) {
+        Some(v) => v.into(),
+        None => KvError::NotFound(param.table, format!("{}.{}", param.key, param.field)).into(),
+    }
+}
+
+impl CommandService for Snapshot {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.snapshot() {
+            Ok(id) => Value::from(id as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Tables {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        tables_response(store.tables())
+    }
+}
+
+pub(super) fn tables_response(tables: Result<Vec<String>, KvError>) -> CommandResponse {
+    match tables {
+        Ok(tables) => tables
+            .into_iter()
+            .map(Value::from)
+            .collect::<Vec<_>>()
+            .into(),
+        Err(e) => e.into(),
+    }
+}
+
+impl CommandService for ReleaseSnapshot {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.release_snapshot(self.id) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+fn list_len(v: &Value) -> usize {
+    match &v.value {
+        Some(value::Value::List(list)) => list.values.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        service::{assert_res_error, assert_res_ok, dispatch},
+        storage::{
+            eviction::{EvictionPolicy, MemoryLimit},
+            index::Indexed,
+            memory::MemTable,
+        },
+    };
+
+    #[test]
+    fn hset_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hset("t1", "hello", "world".into());
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &["world".into()], &[]);
+    }
+
+    #[test]
+    fn hget_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hset("score", "u1", 10.into());
+        dispatch(cmd, &store);
+        let cmd = CommandRequest::new_hget("score", "u1");
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[10.into()], &[]);
+    }
+
+    #[test]
+    fn hget_with_non_exist_key_should_return_404() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hget("score", "u1");
+        let res = dispatch(cmd, &store);
+        assert_res_error(res, 404, "Not Found");
+    }
+
+    #[test]
+    fn hmget_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("user", vec![("u1", "Tyr"), ("u2", "Lindsey")], &store);
+
+        let cmd = CommandRequest::new_hmget("user", vec!["u1".into(), "u3".into(), "u2".into()]);
+        let res = dispatch(cmd, &store);
+        let values = &["Tyr".into(), Value::default(), "Lindsey".into()];
+        assert_res_ok(res, values, &[]);
+    }
+
+    #[test]
+    fn hgetall_should_work() {
+        let store = MemTable::new();
+        set_key_pairs(
+            "score",
+            vec![("u1", 10), ("u2", 8), ("u3", 11), ("u1", 6)],
+            &store,
+        );
+
+        let cmd = CommandRequest::new_hgetall("score");
+        let res = dispatch(cmd, &store);
+        let pairs = &[
+            KvPair::new("u1", 6.into()),
+            KvPair::new("u2", 8.into()),
+            KvPair::new("u3", 11.into()),
+        ];
+        assert_res_ok(res, &[], pairs);
+    }
+
+    #[test]
+    fn hmset_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "world")], &store);
+        let pairs = vec![
+            KvPair::new("u1", 10.5.into()),
+            KvPair::new("u2", 8.1.into()),
+        ];
+        let cmd = CommandRequest::new_hmset("t1", pairs);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &["world".into(), Value::default()], &[]);
+    }
+
+    #[test]
+    fn hdel_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        let cmd = CommandRequest::new_hdel("t1", "u2");
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let cmd = CommandRequest::new_hdel("t1", "u1");
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn hmdel_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+
+        let cmd = CommandRequest::new_hmdel("t1", vec!["u1".into(), "u3".into()]);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &["v1".into(), Value::default()], &[]);
+    }
+
+    #[test]
+    fn hexist_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        let cmd = CommandRequest::new_hexist("t1", "u2");
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[false.into()], &[]);
+
+        let cmd = CommandRequest::new_hexist("t1", "u1");
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[true.into()], &[]);
+    }
+
+    #[test]
+    fn hmexist_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+
+        let cmd = CommandRequest::new_hmexist("t1", vec!["u1".into(), "u3".into()]);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[true.into(), false.into()], &[]);
+    }
+
+    #[test]
+    fn hexport_should_page_through_table() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k3", 3), ("k1", 1), ("k2", 2)], &store);
+
+        let cmd = CommandRequest::new_hexport("t1", None, 2);
+        let res = dispatch(cmd, &store);
+        let pairs = &[KvPair::new("k1", 1.into()), KvPair::new("k2", 2.into())];
+        assert_res_ok(res, &[], pairs);
+
+        let cmd = CommandRequest::new_hexport("t1", Some("k2".into()), 2);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[], &[KvPair::new("k3", 3.into())]);
+    }
+
+    #[test]
+    fn himport_should_work() {
+        let store = MemTable::new();
+        let pairs = vec![KvPair::new("k1", 1.into()), KvPair::new("k2", true.into())];
+        let cmd = CommandRequest::new_himport("t1", pairs.clone());
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[2.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hgetall("t1"), &store);
+        assert_res_ok(res, &[], &pairs);
+    }
+
+    #[test]
+    fn hexpire_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", 1)], &store);
+
+        let res = dispatch(CommandRequest::new_hexpire("t1", "k1", 0), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_error(res, 404, "Not Found");
+
+        let res = dispatch(CommandRequest::new_hexpire("t1", "k1", 1000), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+    }
+
+    #[test]
+    fn hset_should_fail_when_out_of_memory() {
+        let store = MemTable::with_limit(MemoryLimit {
+            max_bytes: 100,
+            policy: EvictionPolicy::NoEviction,
+        });
+        let cmd = CommandRequest::new_hset("t1", "k1", "a".repeat(100).into());
+        let res = dispatch(cmd, &store);
+        assert_res_error(res, 507, "Out of memory");
+    }
+
+    #[test]
+    fn hfind_should_use_index() {
+        let store = Indexed::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", 18), ("u2", 30), ("u3", 45)], &store);
+
+        let cmd = CommandRequest::new_create_index("t1", IndexKind::Range);
+        assert_res_ok(dispatch(cmd, &store), &[3.into()], &[]);
+
+        let cmd = CommandRequest::new_hfind_range("t1", Some(20.into()), Some(50.into()));
+        let pairs = &[KvPair::new("u2", 30.into()), KvPair::new("u3", 45.into())];
+        assert_res_ok(dispatch(cmd, &store), &[], pairs);
+
+        let cmd = CommandRequest::new_hfind_equal("t1", 18.into());
+        assert_res_ok(dispatch(cmd, &store), &[], &[KvPair::new("u1", 18.into())]);
+    }
+
+    #[test]
+    fn create_index_on_plain_storage_should_fail() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_create_index("t1", IndexKind::Exact);
+        assert_res_error(dispatch(cmd, &store), 501, "not supported");
+    }
+
+    #[test]
+    fn hfind_with_invalid_range_should_fail() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hfind_range("t1", Some("a".into()), None);
+        assert_res_error(dispatch(cmd, &store), 400, "Cannot convert value");
+    }
+
+    #[test]
+    fn lpush_and_lrange_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_lpush("t1", "l1", vec![1.into(), 2.into()]);
+        assert_res_ok(dispatch(cmd, &store), &[2.into()], &[]);
+        let cmd = CommandRequest::new_lpush("t1", "l1", vec![3.into()]);
+        assert_res_ok(dispatch(cmd, &store), &[3.into()], &[]);
+
+        let cmd = CommandRequest::new_lrange("t1", "l1", 0, -1);
+        assert_res_ok(dispatch(cmd, &store), &[3.into(), 2.into(), 1.into()], &[]);
+        let cmd = CommandRequest::new_lrange("t1", "l1", -2, 10);
+        assert_res_ok(dispatch(cmd, &store), &[2.into(), 1.into()], &[]);
+        let cmd = CommandRequest::new_lrange("t1", "l1", 2, 1);
+        assert_res_ok(dispatch(cmd, &store), &[], &[]);
+    }
+
+    #[test]
+    fn list_commands_on_wrong_type_should_fail() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1")], &store);
+        let cmd = CommandRequest::new_lpush("t1", "k1", vec![1.into()]);
+        assert_res_error(dispatch(cmd, &store), 400, "Cannot convert value");
+        let cmd = CommandRequest::new_lrange("t1", "k1", 0, -1);
+        assert_res_error(dispatch(cmd, &store), 400, "Cannot convert value");
+        // 类型不对时不会修改原来的 value
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        assert_res_ok(dispatch(cmd, &store), &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn map_get_field_should_work() {
+        let store = MemTable::new();
+        let map = BTreeMap::from([("name".to_string(), "alice".into())]);
+        set_key_pairs("t1", vec![("u1", map)], &store);
+
+        let cmd = CommandRequest::new_map_get_field("t1", "u1", "name");
+        assert_res_ok(dispatch(cmd, &store), &["alice".into()], &[]);
+        let cmd = CommandRequest::new_map_get_field("t1", "u1", "age");
+        assert_res_error(dispatch(cmd, &store), 404, "u1.age");
+        let cmd = CommandRequest::new_map_get_field("t1", "u2", "name");
+        assert_res_error(dispatch(cmd, &store), 404, "Not Found");
+    }
+
+    #[test]
+    fn snapshot_reads_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1")], &store);
+        let res = dispatch(CommandRequest::new_snapshot(), &store);
+        assert_res_ok(res, &[1.into()], &[]);
+        set_key_pairs("t1", vec![("k1", "v2"), ("k2", "v2")], &store);
+
+        let cmd = CommandRequest::new_hget_at("t1", "k1", 1);
+        assert_res_ok(dispatch(cmd, &store), &["v1".into()], &[]);
+        let cmd = CommandRequest::new_hmget_at("t1", vec!["k1".into(), "k2".into()], 1);
+        assert_res_ok(dispatch(cmd, &store), &["v1".into(), Value::default()], &[]);
+        let cmd = CommandRequest::new_hgetall_at("t1", 1);
+        assert_res_ok(
+            dispatch(cmd, &store),
+            &[],
+            &[KvPair::new("k1", "v1".into())],
+        );
+
+        let cmd = CommandRequest::new_release_snapshot(1);
+        assert_res_ok(dispatch(cmd.clone(), &store), &[true.into()], &[]);
+        assert_res_ok(dispatch(cmd, &store), &[false.into()], &[]);
+        let cmd = CommandRequest::new_hget_at("t1", "k1", 1);
+        assert_res_error(dispatch(cmd, &store), 404, "Snapshot 1");
+    }
+
+    fn set_key_pairs<T: Into<Value>>(table: &str, pairs: Vec<(&str, T)>, store: &impl Storage) {
+        pairs
+            .into_iter()
+            .map(|(k, v)| CommandRequest::new_hset(table, k, v.into()))
+            .for_each(|cmd| {
+                dispatch(cmd, store);
+            });
+    }
+}
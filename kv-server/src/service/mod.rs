@@ -0,0 +1,727 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, PoisonError, RwLock},
+    time::{Instant, SystemTime},
+};
+
+use course_proto::pb::abi::{
+    Backup, CommandRequest, CommandResponse, Restore, Value, Watch, command_request::RequestData,
+};
+use tokio::task;
+use tracing::{Instrument, Span, debug, field, info_span};
+
+use crate::{
+    backup::{self, Snapshot},
+    command::{AsyncCommandService, AsyncStorage, CommandService, Storage},
+    config::ServerConfig,
+    error::KvError,
+    ratelimit::RateLimiter,
+    script::Scripts,
+    slowlog::{SlowEntry, SlowLog},
+    storage::{adapter::Adapter, memory::MemTable, watch::Subscription},
+};
+
+mod async_command_service;
+mod command_service;
+
+/// Service 数据结构, 内部用 Arc 包裹, 可以在多个连接之间廉价地 clone
+pub struct Service<Store = MemTable> {
+    inner: Arc<ServiceInner<Store>>,
+}
+
+impl<Store> Clone for Service<Store> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+/// Service 内部的数据结构
+pub struct ServiceInner<Store> {
+    /// 通过 Adapter 同时提供同步和异步的接口
+    store: Adapter<Store>,
+    /// 写命令持有读锁, 可以并发执行; 制作快照时持有写锁, 保证快照的一致性
+    write_gate: RwLock<()>,
+    scripts: Scripts,
+    limiter: RateLimiter,
+    slow_log: SlowLog,
+    /// Backup 和 Restore 只能读写这个目录下的文件
+    backup_dir: PathBuf,
+}
+
+impl<Store: Storage> Service<Store> {
+    pub fn new(store: Store) -> Self {
+        Self::with_config(store, &ServerConfig::default())
+    }
+
+    /// 按照配置中的脚本限制, 限流和慢命令日志的设置创建 Service
+    pub fn with_config(store: Store, config: &ServerConfig) -> Self {
+        Self {
+            inner: Arc::new(ServiceInner {
+                store: Adapter::new(store),
+                write_gate: RwLock::new(()),
+                scripts: Scripts::new(config.script),
+                limiter: RateLimiter::new(config.rate_limit.clone()),
+                slow_log: SlowLog::new(config.slow_log),
+                backup_dir: config.general.backup_dir.clone(),
+            }),
+        }
+    }
+
+    /// 执行一个 CommandRequest, 返回 CommandResponse
+    pub fn execute(&self, cmd: CommandRequest) -> CommandResponse {
+        self.execute_from(None, cmd)
+    }
+
+    /// 执行客户端 client 发来的 CommandRequest, 先按照客户端和命令访问的 table 限流.
+    ///
+    /// 每个命令都在一个 span 中执行, 记录命令的类型, table, key 的个数, 状态码和耗时,
+    /// 耗时超过阈值的命令会被记录到慢命令日志中
+    pub fn execute_from(&self, client: Option<&str>, cmd: CommandRequest) -> CommandResponse {
+        let call = Call::new(client, &cmd);
+        let res = call.span.in_scope(|| match self.throttle(&call) {
+            Ok(()) => self.run(cmd),
+            Err(res) => res,
+        });
+        self.finish(call, res)
+    }
+
+    /// 异步地执行客户端 client 发来的 CommandRequest, 和 execute_from 一样限流并记录.
+    ///
+    /// 不需要加锁的读取通过 AsyncStorage 执行; 其它命令在阻塞的存储上会放到阻塞线程池中执行,
+    /// 不会占用 tokio 的工作线程
+    pub async fn execute_async(
+        &self,
+        client: Option<&str>,
+        cmd: CommandRequest,
+    ) -> CommandResponse {
+        let call = Call::new(client, &cmd);
+        let res = match call.span.in_scope(|| self.throttle(&call)) {
+            Ok(()) => self.run_async(cmd).instrument(call.span.clone()).await,
+            Err(res) => res,
+        };
+        self.finish(call, res)
+    }
+
+    fn throttle(&self, call: &Call) -> Result<(), CommandResponse> {
+        let client = call.client.as_deref();
+        let table = call.table.as_deref();
+        self.inner
+            .limiter
+            .check(client, table, call.start)
+            .map_err(|e| {
+                debug!("Request throttled: {}", e);
+                e.into()
+            })
+    }
+
+    fn finish(&self, call: Call, res: CommandResponse) -> CommandResponse {
+        let duration = call.start.elapsed();
+        call.span.record("status", res.status);
+        call.span.record("latency_us", duration.as_micros() as u64);
+        if self.inner.slow_log.is_slow(duration) {
+            call.span
+                .in_scope(|| debug!("Slow command took {:?}", duration));
+            self.inner.slow_log.push(SlowEntry {
+                id: 0,
+                time: call.time,
+                duration,
+                command: call.command,
+                table: call.table.unwrap_or_default(),
+                keys: call.keys,
+                client: call.client.unwrap_or_default(),
+            });
+        }
+        res
+    }
+
+    async fn run_async(&self, cmd: CommandRequest) -> CommandResponse {
+        match &cmd.request_data {
+            Some(data) if is_plain_read(data) => dispatch_async(cmd, &self.inner.store).await,
+            // 阻塞的存储, 以及会长时间持有 write_gate 或读写文件的命令都不能占用 runtime 的线程
+            Some(data) if self.inner.store.is_blocking() || is_long_running(data) => {
+                let service = self.clone();
+                let span = Span::current();
+                task::spawn_blocking(move || span.in_scope(|| service.run(cmd)))
+                    .await
+                    .unwrap_or_else(|e| KvError::Internal(e.to_string()).into())
+            }
+            _ => self.run(cmd),
+        }
+    }
+
+    fn run(&self, cmd: CommandRequest) -> CommandResponse {
+        debug!("Got request: {:?}", cmd);
+        let gate = &self.inner.write_gate;
+        let res = match cmd.request_data {
+            Some(RequestData::Backup(param)) => self.backup(param),
+            // 恢复快照时不允许其他写入
+            Some(RequestData::Restore(param)) => {
+                let _guard = gate.write().unwrap_or_else(PoisonError::into_inner);
+                self.restore(param)
+            }
+            // 脚本执行期间不允许其他写入, 脚本中的读写是原子的
+            Some(RequestData::Eval(param)) => {
+                let _guard = gate.write().unwrap_or_else(PoisonError::into_inner);
+                let store = self.inner.store.get_ref();
+                let res = self.inner.scripts.eval(store, &param.script, param.args);
+                res.map_or_else(Into::into, Into::into)
+            }
+            Some(RequestData::EvalSha(param)) => {
+                let _guard = gate.write().unwrap_or_else(PoisonError::into_inner);
+                let store = self.inner.store.get_ref();
+                let res = self.inner.scripts.eval_sha(store, &param.sha, param.args);
+                res.map_or_else(Into::into, Into::into)
+            }
+            Some(RequestData::ScriptLoad(param)) => self
+                .inner
+                .scripts
+                .load(&param.script)
+                .map_or_else(Into::into, |sha| Value::from(sha).into()),
+            Some(RequestData::Metrics(_)) => self.inner.limiter.metrics(Instant::now()).into(),
+            Some(RequestData::SlowLog(param)) if param.reset => {
+                Value::from(self.inner.slow_log.reset() as i64).into()
+            }
+            Some(RequestData::SlowLog(param)) => {
+                let entries = self.inner.slow_log.get(param.count as usize);
+                entries
+                    .into_iter()
+                    .map(Value::from)
+                    .collect::<Vec<_>>()
+                    .into()
+            }
+            Some(ref data) if is_mutation(data) => {
+                let _guard = gate.read().unwrap_or_else(PoisonError::into_inner);
+                dispatch(cmd, self.inner.store.get_ref())
+            }
+            _ => dispatch(cmd, self.inner.store.get_ref()),
+        };
+        debug!("Executed response: {:?}", res);
+        res
+    }
+
+    /// 把快照写入备份目录, 写文件时写入可以继续进行
+    fn backup(&self, param: Backup) -> CommandResponse {
+        let result = backup::resolve(&self.inner.backup_dir, &param.path).and_then(|path| {
+            let snapshot = self.capture()?;
+            snapshot.save(path)?;
+            Ok(snapshot.len())
+        });
+        match result {
+            Ok(len) => Value::from(len as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// 从备份目录中的快照恢复数据, 只能恢复到空的 server 中. 调用者持有 write_gate
+    fn restore(&self, param: Restore) -> CommandResponse {
+        let store = self.inner.store.get_ref();
+        let result = backup::resolve(&self.inner.backup_dir, &param.path).and_then(|path| {
+            if !is_empty(store)? {
+                return Err(KvError::InvalidCommand(
+                    "Restore is only allowed on an empty server".into(),
+                ));
+            }
+            Snapshot::load(path)?.restore(store)
+        });
+        match result {
+            Ok(len) => Value::from(len as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// 存储支持 snapshot 时只在创建 snapshot 时阻塞写入, 之后从 snapshot 中复制数据;
+    /// 否则在复制数据的整个过程中阻塞写入
+    fn capture(&self) -> Result<Snapshot, KvError> {
+        let store = self.inner.store.get_ref();
+        let guard = self
+            .inner
+            .write_gate
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        match store.snapshot() {
+            Ok(id) => {
+                drop(guard);
+                let snapshot = Snapshot::capture_at(store, id);
+                store.release_snapshot(id)?;
+                snapshot
+            }
+            Err(KvError::Unsupported(_)) => Snapshot::capture(store),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 订阅 Watch 命令指定的变化
+    pub fn watch(&self, param: &Watch) -> Result<Subscription, KvError> {
+        self.inner
+            .store
+            .get_ref()
+            .watch(&param.table, &param.prefix)
+    }
+
+    /// 把底层存储中尚未持久化的数据刷盘
+    pub fn flush(&self) -> Result<(), KvError> {
+        self.inner.store.get_ref().flush()
+    }
+}
+
+/// 从 Request 中得到 Response, 目前处理 HGET/HGETALL/HSET 等命令
+pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
+    match cmd.request_data {
+        Some(RequestData::Hget(param)) => param.execute(store),
+        Some(RequestData::Hgetall(param)) => param.execute(store),
+        Some(RequestData::Hmget(param)) => param.execute(store),
+        Some(RequestData::Hset(param)) => param.execute(store),
+        Some(RequestData::Hmset(param)) => param.execute(store),
+        Some(RequestData::Hdel(param)) => param.execute(store),
+        Some(RequestData::Hmdel(param)) => param.execute(store),
+        Some(RequestData::Hexist(param)) => param.execute(store),
+        Some(RequestData::Hmexist(param)) => param.execute(store),
+        Some(RequestData::Hexport(param)) => param.execute(store),
+        Some(RequestData::Himport(param)) => param.execute(store),
+        Some(RequestData::Hexpire(param)) => param.execute(store),
+        Some(RequestData::CreateIndex(param)) => param.execute(store),
+        Some(RequestData::Hfind(param)) => param.execute(store),
+        Some(RequestData::Lpush(param)) => param.execute(store),
+        Some(RequestData::Lrange(param)) => param.execute(store),
+        Some(RequestData::MapGetField(param)) => param.execute(store),
+        Some(RequestData::Snapshot(param)) => param.execute(store),
+        Some(RequestData::ReleaseSnapshot(param)) => param.execute(store),
+        Some(RequestData::Tables(param)) => param.execute(store),
+        Some(RequestData::Watch(_)) => {
+            KvError::InvalidCommand("Watch is only available on a server connection".into()).into()
+        }
+        Some(RequestData::Eval(_) | RequestData::EvalSha(_) | RequestData::ScriptLoad(_)) => {
+            KvError::InvalidCommand("Scripts can only be executed by Service".into()).into()
+        }
+        // Backup 和 Restore 要限制在备份目录中并持有 write_gate, 只能通过 Service 执行
+        Some(
+            data @ (RequestData::Backup(_)
+            | RequestData::Restore(_)
+            | RequestData::Metrics(_)
+            | RequestData::SlowLog(_)),
+        ) => {
+            let name = data.name();
+            KvError::InvalidCommand(format!("{} is only available through Service", name)).into()
+        }
+        None => KvError::InvalidCommand("Request has no data".into()).into(),
+    }
+}
+
+/// 用异步接口处理 HGET/HGETALL/HSET 等基本命令, 其它命令返回 KvError::Unsupported
+pub async fn dispatch_async(cmd: CommandRequest, store: &impl AsyncStorage) -> CommandResponse {
+    match cmd.request_data {
+        Some(RequestData::Hget(param)) => param.execute_async(store).await,
+        Some(RequestData::Hgetall(param)) => param.execute_async(store).await,
+        Some(RequestData::Hmget(param)) => param.execute_async(store).await,
+        Some(RequestData::Hset(param)) => param.execute_async(store).await,
+        Some(RequestData::Hmset(param)) => param.execute_async(store).await,
+        Some(RequestData::Hdel(param)) => param.execute_async(store).await,
+        Some(RequestData::Hmdel(param)) => param.execute_async(store).await,
+        Some(RequestData::Hexist(param)) => param.execute_async(store).await,
+        Some(RequestData::Hmexist(param)) => param.execute_async(store).await,
+        Some(RequestData::Hexport(param)) => param.execute_async(store).await,
+        Some(RequestData::Lrange(param)) => param.execute_async(store).await,
+        Some(RequestData::MapGetField(param)) => param.execute_async(store).await,
+        Some(RequestData::Tables(param)) => param.execute_async(store).await,
+        Some(data) => KvError::Unsupported(data.name()).into(),
+        None => KvError::InvalidCommand("Request has no data".into()).into(),
+    }
+}
+
+/// 正在执行的一个命令, 用来记录 span 和慢命令日志
+struct Call {
+    span: Span,
+    command: &'static str,
+    table: Option<String>,
+    keys: usize,
+    client: Option<String>,
+    time: SystemTime,
+    start: Instant,
+}
+
+impl Call {
+    fn new(client: Option<&str>, cmd: &CommandRequest) -> Self {
+        let data = cmd.request_data.as_ref();
+        let command = data.map_or("none", RequestData::name);
+        let table = data.and_then(table_of).map(str::to_string);
+        let keys = data.map_or(0, key_count);
+        let span = info_span!(
+            "command",
+            command,
+            table = table.as_deref().unwrap_or_default(),
+            keys,
+            client = client.unwrap_or_default(),
+            status = field::Empty,
+            latency_us = field::Empty,
+        );
+        Self {
+            span,
+            command,
+            table,
+            keys,
+            client: client.map(str::to_string),
+            time: SystemTime::now(),
+            start: Instant::now(),
+        }
+    }
+}
+
+/// 不需要持有 write_gate 的读取命令, 可以直接通过异步接口执行
+fn is_plain_read(data: &RequestData) -> bool {
+    match data {
+        RequestData::Hget(param) => param.snapshot == 0,
+        RequestData::Hgetall(param) => param.snapshot == 0,
+        RequestData::Hmget(param) => param.snapshot == 0,
+        RequestData::Hexist(_)
+        | RequestData::Hmexist(_)
+        | RequestData::Hexport(_)
+        | RequestData::Lrange(_)
+        | RequestData::MapGetField(_)
+        | RequestData::Tables(_) => true,
+        _ => false,
+    }
+}
+
+/// 命令访问的 table, 没有访问某个 table 的命令返回 None
+fn table_of(data: &RequestData) -> Option<&str> {
+    let table = match data {
+        RequestData::Hget(param) => &param.table,
+        RequestData::Hgetall(param) => &param.table,
+        RequestData::Hmget(param) => &param.table,
+        RequestData::Hset(param) => &param.table,
+        RequestData::Hmset(param) => &param.table,
+        RequestData::Hdel(param) => &param.table,
+        RequestData::Hmdel(param) => &param.table,
+        RequestData::Hexist(param) => &param.table,
+        RequestData::Hmexist(param) => &param.table,
+        RequestData::Hexport(param) => &param.table,
+        RequestData::Himport(param) => &param.table,
+        RequestData::Hexpire(param) => &param.table,
+        RequestData::CreateIndex(param) => &param.table,
+        RequestData::Hfind(param) => &param.table,
+        RequestData::Watch(param) => &param.table,
+        RequestData::Lpush(param) => &param.table,
+        RequestData::Lrange(param) => &param.table,
+        RequestData::MapGetField(param) => &param.table,
+        _ => return None,
+    };
+    Some(table)
+}
+
+/// 命令访问的 key 的个数
+fn key_count(data: &RequestData) -> usize {
+    match data {
+        RequestData::Hmget(param) => param.keys.len(),
+        RequestData::Hmdel(param) => param.keys.len(),
+        RequestData::Hmexist(param) => param.keys.len(),
+        RequestData::Hmset(param) => param.pairs.len(),
+        RequestData::Himport(param) => param.pairs.len(),
+        RequestData::Hget(_)
+        | RequestData::Hset(_)
+        | RequestData::Hdel(_)
+        | RequestData::Hexist(_)
+        | RequestData::Hexpire(_)
+        | RequestData::Lpush(_)
+        | RequestData::Lrange(_)
+        | RequestData::MapGetField(_) => 1,
+        _ => 0,
+    }
+}
+
+/// 是否是会修改数据的命令
+/// 执行脚本和恢复快照时持有 write_gate, 备份和恢复要读写文件, 都可能运行很长时间
+fn is_long_running(data: &RequestData) -> bool {
+    matches!(
+        data,
+        RequestData::Eval(_)
+            | RequestData::EvalSha(_)
+            | RequestData::Backup(_)
+            | RequestData::Restore(_)
+    )
+}
+
+fn is_empty(store: &impl Storage) -> Result<bool, KvError> {
+    for table in store.tables()? {
+        if !store.get_range(&table, None, 1)?.is_empty() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn is_mutation(data: &RequestData) -> bool {
+    matches!(
+        data,
+        RequestData::Hset(_)
+            | RequestData::Hmset(_)
+            | RequestData::Hdel(_)
+            | RequestData::Hmdel(_)
+            | RequestData::Himport(_)
+            | RequestData::Hexpire(_)
+            | RequestData::Lpush(_)
+    )
+}
+
+#[cfg(test)]
+use course_proto::pb::abi::KvPair;
+
+// 测试成功返回的结果
+#[cfg(test)]
+pub fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[KvPair]) {
+    res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(res.status, 200);
+    assert_eq!(res.message, "");
+    assert_eq!(res.values, values);
+    assert_eq!(res.pairs, pairs);
+}
+
+// 测试失败返回的结果
+#[cfg(test)]
+pub fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
+    assert_eq!(res.status, code);
+    assert!(res.message.contains(msg));
+    assert_eq!(res.values, &[]);
+    assert_eq!(res.pairs, &[]);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, thread};
+
+    use course_proto::pb::abi::value;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::storage::sleddb::SledDb;
+
+    #[test]
+    fn service_should_works() {
+        let service = Service::new(MemTable::default());
+
+        // service 可以运行在多线程环境下, 它的 clone 应该是轻量级的
+        let cloned = service.clone();
+
+        // 创建一个线程, 在 table t1 中写入 k1, v1
+        let handle = thread::spawn(move || {
+            let res = cloned.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+            assert_res_ok(res, &[Value::default()], &[]);
+        });
+        handle.join().unwrap();
+
+        // 在当前线程下读取 table t1 的 k1, 应该返回 v1
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn empty_request_should_be_rejected() {
+        let service = Service::new(MemTable::default());
+        let res = service.execute(CommandRequest::default());
+        assert_res_error(res, 400, "Request has no data");
+    }
+
+    fn with_backup_dir(dir: &Path) -> Service {
+        let mut config = ServerConfig::default();
+        config.general.backup_dir = dir.into();
+        Service::with_config(MemTable::default(), &config)
+    }
+
+    #[test]
+    fn backup_and_restore_should_work() {
+        let dir = tempdir().unwrap();
+        let path = "daily/backup.snap";
+
+        let service = with_backup_dir(dir.path());
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        service.execute(CommandRequest::new_hset("t2", "k2", 2.into()));
+        let res = service.execute(CommandRequest::new_backup(path));
+        assert_res_ok(res, &[2.into()], &[]);
+
+        // 写入备份之后的数据不会出现在快照里
+        service.execute(CommandRequest::new_hset("t1", "k3", "v3".into()));
+
+        assert!(dir.path().join(path).exists());
+        let restored = with_backup_dir(dir.path());
+        let res = restored.execute(CommandRequest::new_restore(path));
+        assert_res_ok(res, &[2.into()], &[]);
+
+        let res = restored.execute(CommandRequest::new_hgetall("t1"));
+        assert_res_ok(res, &[], &[KvPair::new("k1", "v1".into())]);
+        let res = restored.execute(CommandRequest::new_hget("t2", "k2"));
+        assert_res_ok(res, &[2.into()], &[]);
+    }
+
+    #[test]
+    fn restore_into_non_empty_server_should_fail() {
+        let dir = tempdir().unwrap();
+        let path = "backup.snap";
+
+        let service = with_backup_dir(dir.path());
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        service.execute(CommandRequest::new_backup(path));
+
+        let res = service.execute(CommandRequest::new_restore(path));
+        assert_res_error(res, 400, "empty server");
+    }
+
+    #[test]
+    fn backup_outside_backup_dir_should_be_rejected() {
+        let dir = tempdir().unwrap();
+        let service = with_backup_dir(&dir.path().join("backups"));
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+
+        let outside = dir.path().join("backup.snap");
+        let outside = outside.to_str().unwrap();
+        for path in [outside, "../backup.snap"] {
+            let res = service.execute(CommandRequest::new_backup(path));
+            assert_res_error(res, 400, "inside the backup directory");
+            let res = service.execute(CommandRequest::new_restore(path));
+            assert_res_error(res, 400, "inside the backup directory");
+        }
+        assert!(!dir.path().join("backup.snap").exists());
+
+        // 绕过 Service 直接 dispatch 也不能读写任意文件
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_backup(outside), &store);
+        assert_res_error(res, 400, "only available through Service");
+        let res = dispatch(CommandRequest::new_restore(outside), &store);
+        assert_res_error(res, 400, "only available through Service");
+        assert!(!dir.path().join("backup.snap").exists());
+    }
+
+    #[test]
+    fn eval_and_evalsha_should_work() {
+        let service = Service::new(MemTable::default());
+        let script = r#"return kv.set("t1", ARGV[1], ARGV[2])"#;
+        let res = service.execute(CommandRequest::new_eval(
+            script,
+            vec!["k1".into(), 1.into()],
+        ));
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = service.execute(CommandRequest::new_script_load(script));
+        let sha = res.values[0].clone();
+        let Some(value::Value::String(sha)) = sha.value else {
+            panic!("ScriptLoad should return the SHA1");
+        };
+        let res = service.execute(CommandRequest::new_evalsha(
+            &sha,
+            vec!["k1".into(), 2.into()],
+        ));
+        assert_res_ok(res, &[1.into()], &[]);
+
+        let res = service.execute(CommandRequest::new_evalsha("unknown", vec![]));
+        assert_res_error(res, 404, "No script matches");
+        let res = service.execute(CommandRequest::new_eval("return +", vec![]));
+        assert_res_error(res, 400, "Script error");
+        let res = dispatch(CommandRequest::new_eval(script, vec![]), &MemTable::new());
+        assert_res_error(res, 400, "only be executed by Service");
+    }
+
+    #[test]
+    fn throttled_request_should_get_429() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [rate_limit]
+            client = { per_second = 1, burst = 1 }
+            "#,
+        )
+        .unwrap();
+        let service = Service::with_config(MemTable::default(), &config);
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = service.execute_from(Some("c1"), cmd.clone());
+        assert_res_error(res, 404, "Not Found");
+        let res = service.execute_from(Some("c1"), cmd.clone());
+        assert_eq!(res.status, 429);
+        assert!(res.message.contains("client c1"));
+        assert_eq!(res.values, [1000.into()]);
+        // 不是来自客户端的请求不受客户端的限制
+        let res = service.execute(cmd);
+        assert_eq!(res.status, 404);
+
+        let res = service.execute(CommandRequest::new_metrics());
+        assert_eq!(res.status, 200);
+        let throttled = res
+            .pairs
+            .iter()
+            .find(|p| p.key == "rate_limit_throttled_total");
+        assert_eq!(
+            throttled,
+            Some(&KvPair::new("rate_limit_throttled_total", 1.into()))
+        );
+        assert!(res.pairs.iter().all(|p| !p.key.contains("c1")));
+    }
+
+    #[test]
+    fn slow_commands_should_be_logged() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [slow_log]
+            threshold_us = 0
+            max_len = 2
+            "#,
+        )
+        .unwrap();
+        let service = Service::with_config(MemTable::default(), &config);
+        service.execute_from(Some("c1"), CommandRequest::new_hget("t1", "k1"));
+        let cmd = CommandRequest::new_hmset(
+            "t2",
+            vec![KvPair::new("k1", 1.into()), KvPair::new("k2", 2.into())],
+        );
+        service.execute(cmd);
+
+        let res = service.execute(CommandRequest::new_slow_log(0));
+        assert_eq!(res.status, 200);
+        let entries: Vec<_> = res
+            .values
+            .iter()
+            .map(|v| match &v.value {
+                Some(value::Value::Map(map)) => (
+                    map.fields["command"].clone(),
+                    map.fields["table"].clone(),
+                    map.fields["keys"].clone(),
+                    map.fields["client"].clone(),
+                ),
+                _ => panic!("slow log entry should be a map"),
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("hmset".into(), "t2".into(), 2.into(), "".into()),
+                ("hget".into(), "t1".into(), 1.into(), "c1".into()),
+            ]
+        );
+
+        // SLOWLOG 命令自己也会被记录
+        let res = service.execute(CommandRequest::new_slow_log_reset());
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = service.execute(CommandRequest::new_slow_log(0));
+        assert_eq!(res.values.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn execute_async_should_work_on_blocking_store() {
+        let dir = tempdir().unwrap();
+        let service = Service::new(SledDb::new(dir.path()).unwrap());
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = service.execute_async(Some("c1"), cmd).await;
+        assert_res_ok(res, &[Value::default()], &[]);
+        let cmd = CommandRequest::new_lpush("t1", "k2", vec![1.into()]);
+        let res = service.execute_async(Some("c1"), cmd).await;
+        assert_res_ok(res, &[1.into()], &[]);
+
+        let res = service
+            .execute_async(None, CommandRequest::new_hget("t1", "k1"))
+            .await;
+        assert_res_ok(res, &["v1".into()], &[]);
+        let res = service
+            .execute_async(None, CommandRequest::new_hgetall("t1"))
+            .await;
+        assert_eq!(res.pairs.len(), 2);
+    }
+}
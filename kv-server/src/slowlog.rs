@@ -0,0 +1,171 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use course_proto::pb::abi::Value;
+use serde::{Deserialize, Serialize};
+
+/// 慢命令日志的配置
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SlowLogConfig {
+    /// 执行时间超过这么多微秒的命令会被记录下来
+    pub threshold_us: u64,
+    /// 最多保留的记录数, 超出时丢弃最早的记录
+    pub max_len: usize,
+}
+
+impl Default for SlowLogConfig {
+    fn default() -> Self {
+        Self {
+            threshold_us: 10_000,
+            max_len: 128,
+        }
+    }
+}
+
+/// 一条慢命令记录
+#[derive(Clone, Debug, PartialEq)]
+pub struct SlowEntry {
+    /// 递增的记录 id, 清空日志后也不会重复
+    pub id: u64,
+    /// 命令开始执行的时间
+    pub time: SystemTime,
+    pub duration: Duration,
+    pub command: &'static str,
+    pub table: String,
+    pub keys: usize,
+    /// 发出命令的客户端, 不是来自客户端连接的命令为空
+    pub client: String,
+}
+
+impl From<SlowEntry> for Value {
+    fn from(e: SlowEntry) -> Self {
+        let duration_us: i64 = e.duration.as_micros().try_into().unwrap_or(i64::MAX);
+        let fields = BTreeMap::from([
+            ("id".to_string(), (e.id as i64).into()),
+            ("time".to_string(), e.time.into()),
+            ("duration_us".to_string(), duration_us.into()),
+            ("command".to_string(), e.command.into()),
+            ("table".to_string(), e.table.into()),
+            ("keys".to_string(), (e.keys as i64).into()),
+            ("client".to_string(), e.client.into()),
+        ]);
+        fields.into()
+    }
+}
+
+/// 内存中的慢命令日志, 新的记录在前
+#[derive(Debug, Default)]
+pub struct SlowLog {
+    config: SlowLogConfig,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: u64,
+    entries: VecDeque<SlowEntry>,
+}
+
+impl SlowLog {
+    pub fn new(config: SlowLogConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// 执行时间是否超过了阈值
+    pub fn is_slow(&self, duration: Duration) -> bool {
+        duration.as_micros() >= self.config.threshold_us as u128
+    }
+
+    /// 记录一条慢命令, entry 的 id 会被重新分配
+    pub fn push(&self, mut entry: SlowEntry) {
+        if self.config.max_len == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        entry.id = inner.next_id;
+        inner.next_id += 1;
+        inner.entries.push_front(entry);
+        inner.entries.truncate(self.config.max_len);
+    }
+
+    /// 最新的 count 条记录, count 为 0 时返回所有记录
+    pub fn get(&self, count: usize) -> Vec<SlowEntry> {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = match count {
+            0 => inner.entries.len(),
+            n => n,
+        };
+        inner.entries.iter().take(count).cloned().collect()
+    }
+
+    /// 清空日志, 返回清空之前的记录数
+    pub fn reset(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let len = inner.entries.len();
+        inner.entries.clear();
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use course_proto::pb::abi::value;
+
+    use super::*;
+
+    fn entry(command: &'static str, duration_ms: u64) -> SlowEntry {
+        SlowEntry {
+            id: 0,
+            time: SystemTime::now(),
+            duration: Duration::from_millis(duration_ms),
+            command,
+            table: "t1".into(),
+            keys: 1,
+            client: String::new(),
+        }
+    }
+
+    #[test]
+    fn slow_log_should_keep_latest_entries() {
+        let log = SlowLog::new(SlowLogConfig {
+            threshold_us: 1000,
+            max_len: 2,
+        });
+        assert!(!log.is_slow(Duration::from_micros(999)));
+        assert!(log.is_slow(Duration::from_millis(1)));
+
+        for (i, command) in ["hget", "hset", "hdel"].into_iter().enumerate() {
+            log.push(entry(command, i as u64));
+        }
+        let entries = log.get(0);
+        let ids: Vec<_> = entries.iter().map(|e| (e.id, e.command)).collect();
+        assert_eq!(ids, [(2, "hdel"), (1, "hset")]);
+        assert_eq!(log.get(1).len(), 1);
+
+        assert_eq!(log.reset(), 2);
+        assert!(log.get(0).is_empty());
+        log.push(entry("hget", 5));
+        assert_eq!(log.get(0)[0].id, 3);
+    }
+
+    #[test]
+    fn slow_entry_should_convert_to_map() {
+        let value: Value = entry("hget", 2).into();
+        let Some(value::Value::Map(map)) = value.value else {
+            panic!("slow entry should be a map");
+        };
+        assert_eq!(map.fields["command"], "hget".into());
+        assert_eq!(map.fields["duration_us"], 2000.into());
+        assert!(matches!(
+            map.fields["time"].value,
+            Some(value::Value::Timestamp(t)) if t > 0
+        ));
+    }
+}
@@ -0,0 +1,276 @@
+use std::sync::Arc;
+
+use course_proto::pb::abi::{KvPair, Value};
+use futures::{
+    StreamExt, TryStreamExt,
+    stream::{self, BoxStream},
+};
+use tokio::{sync::mpsc, task};
+
+use crate::{
+    command::{AsyncStorage, Storage},
+    error::KvError,
+};
+
+/// 阻塞的存储扫描 table 时, 最多缓存这么多个还没被取走的 KvPair
+const SCAN_BUFFER: usize = 128;
+/// 不阻塞的存储扫描 table 时, 每次读取这么多个 KvPair
+const SCAN_CHUNK: usize = 128;
+
+/// 把同步的 Storage 当作 AsyncStorage 使用.
+///
+/// 不会阻塞的存储 (比如 MemTable) 直接在当前任务中执行; is_blocking 的存储 (比如 SledDb)
+/// 放到 tokio 的阻塞线程池中执行, 不会占用异步的工作线程
+pub struct Adapter<S> {
+    store: Arc<S>,
+    blocking: bool,
+}
+
+impl<S> Clone for Adapter<S> {
+    fn clone(&self) -> Self {
+        Self {
+            store: Arc::clone(&self.store),
+            blocking: self.blocking,
+        }
+    }
+}
+
+impl<S: Storage> Adapter<S> {
+    pub fn new(store: S) -> Self {
+        let blocking = store.is_blocking();
+        Self {
+            store: Arc::new(store),
+            blocking,
+        }
+    }
+
+    /// 被包装的同步存储
+    pub fn get_ref(&self) -> &S {
+        &self.store
+    }
+
+    pub fn is_blocking(&self) -> bool {
+        self.blocking
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S) -> Result<T, KvError> + Send + 'static,
+    {
+        if !self.blocking {
+            return f(&self.store);
+        }
+        let store = Arc::clone(&self.store);
+        task::spawn_blocking(move || f(&store))
+            .await
+            .map_err(|e| KvError::Internal(e.to_string()))?
+    }
+}
+
+impl<S: Storage> AsyncStorage for Adapter<S> {
+    async fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.to_string(), key.to_string());
+        self.run(move |store| store.get(&table, &key)).await
+    }
+
+    async fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let table = table.to_string();
+        self.run(move |store| store.set(&table, key, value)).await
+    }
+
+    async fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let (table, key) = (table.to_string(), key.to_string());
+        self.run(move |store| store.contains(&table, &key)).await
+    }
+
+    async fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.to_string(), key.to_string());
+        self.run(move |store| store.del(&table, &key)).await
+    }
+
+    async fn get_many(
+        &self,
+        table: &str,
+        keys: Vec<String>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let table = table.to_string();
+        self.run(move |store| keys.iter().map(|key| store.get(&table, key)).collect())
+            .await
+    }
+
+    async fn contains_many(&self, table: &str, keys: Vec<String>) -> Result<Vec<bool>, KvError> {
+        let table = table.to_string();
+        self.run(move |store| keys.iter().map(|key| store.contains(&table, key)).collect())
+            .await
+    }
+
+    async fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.run(|store| store.tables()).await
+    }
+
+    async fn get_range(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<KvPair>, KvError> {
+        let (table, after) = (table.to_string(), after.map(str::to_string));
+        self.run(move |store| store.get_range(&table, after.as_deref(), limit))
+            .await
+    }
+
+    async fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        let table = table.to_string();
+        self.run(move |store| store.get_all(&table)).await
+    }
+
+    fn scan(&self, table: &str) -> BoxStream<'static, Result<KvPair, KvError>> {
+        let store = Arc::clone(&self.store);
+        let table = table.to_string();
+        if !self.blocking {
+            // 按 key 的顺序分批读取, 不会一次拷贝出整个 table. MemTable 从有序的 key 索引中
+            // 定位每一批的起点, 整个 scan 只遍历 table 一次. 状态是下一批的起点, None 表示读完了
+            let chunks = stream::try_unfold(Some(None), move |after: Option<Option<String>>| {
+                let (store, table) = (Arc::clone(&store), table.clone());
+                async move {
+                    let Some(after) = after else {
+                        return Ok::<_, KvError>(None);
+                    };
+                    let chunk = store.get_range(&table, after.as_deref(), SCAN_CHUNK)?;
+                    let next = match chunk.len() < SCAN_CHUNK {
+                        true => None,
+                        false => chunk.last().map(|pair| Some(pair.key.clone())),
+                    };
+                    Ok(Some((
+                        stream::iter(chunk.into_iter().map(Ok::<_, KvError>)),
+                        next,
+                    )))
+                }
+            });
+            return chunks.try_flatten().boxed();
+        }
+
+        // 在阻塞线程中迭代, 通过 channel 逐个发送给 stream. 消费者跟不上时迭代会暂停
+        let start = async move {
+            let (tx, rx) = mpsc::channel(SCAN_BUFFER);
+            task::spawn_blocking(move || {
+                let iter = match store.get_iter(&table) {
+                    Ok(iter) => iter,
+                    Err(e) => {
+                        let _ = tx.blocking_send(Err(from_anyhow(e)));
+                        return;
+                    }
+                };
+                for pair in iter {
+                    // stream 已经被丢弃, 不再需要继续迭代
+                    if tx.blocking_send(Ok(pair)).is_err() {
+                        break;
+                    }
+                }
+            });
+            rx
+        };
+        stream::once(start)
+            .flat_map(|rx| stream::unfold(rx, |mut rx| async { rx.recv().await.map(|v| (v, rx)) }))
+            .boxed()
+    }
+
+    async fn flush(&self) -> Result<(), KvError> {
+        self.run(|store| store.flush()).await
+    }
+}
+
+fn from_anyhow(e: anyhow::Error) -> KvError {
+    e.downcast::<KvError>()
+        .unwrap_or_else(|e| KvError::Internal(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::storage::{memory::MemTable, sleddb::SledDb};
+
+    async fn test_async_interface(store: impl AsyncStorage) {
+        assert_eq!(store.set("t1", "k1".into(), "v1".into()).await, Ok(None));
+        assert_eq!(
+            store.set("t1", "k1".into(), "v2".into()).await,
+            Ok(Some("v1".into()))
+        );
+        store.set("t1", "k2".into(), 2.into()).await.unwrap();
+        assert_eq!(store.get("t1", "k1").await, Ok(Some("v2".into())));
+        assert_eq!(store.contains("t1", "k3").await, Ok(false));
+        let keys = vec!["k1".to_string(), "k3".to_string()];
+        assert_eq!(
+            store.get_many("t1", keys.clone()).await,
+            Ok(vec![Some("v2".into()), None])
+        );
+        assert_eq!(store.contains_many("t1", keys).await, Ok(vec![true, false]));
+        assert_eq!(store.tables().await, Ok(vec!["t1".to_string()]));
+
+        let mut pairs: Vec<_> = store.scan("t1").try_collect().await.unwrap();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(
+            pairs,
+            [KvPair::new("k1", "v2".into()), KvPair::new("k2", 2.into())]
+        );
+
+        assert_eq!(store.del("t1", "k2").await, Ok(Some(2.into())));
+        assert_eq!(store.get_all("t1").await.unwrap().len(), 1);
+        assert!(store.flush().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn memtable_adapter_should_work() {
+        let store = Adapter::new(MemTable::new());
+        assert!(!store.is_blocking());
+        test_async_interface(store).await;
+    }
+
+    #[tokio::test]
+    async fn sleddb_adapter_should_run_on_blocking_threads() {
+        let dir = tempdir().unwrap();
+        let store = Adapter::new(SledDb::new(dir.path()).unwrap());
+        assert!(store.is_blocking());
+        test_async_interface(store).await;
+    }
+
+    #[tokio::test]
+    async fn memtable_scan_should_read_in_chunks() {
+        let store = Adapter::new(MemTable::new());
+        for i in 0..SCAN_CHUNK * 2 + 1 {
+            store
+                .set("t1", format!("k{:03}", i), 1.into())
+                .await
+                .unwrap();
+        }
+        // 删除的 key 正好在两批之间, 不影响下一批的起点
+        for i in [SCAN_CHUNK - 1, SCAN_CHUNK] {
+            store.del("t1", &format!("k{:03}", i)).await.unwrap();
+        }
+        let pairs: Vec<_> = store.scan("t1").try_collect().await.unwrap();
+        let keys: Vec<_> = pairs.iter().map(|pair| pair.key.clone()).collect();
+        let expected: Vec<_> = (0..SCAN_CHUNK * 2 + 1)
+            .filter(|i| ![SCAN_CHUNK - 1, SCAN_CHUNK].contains(i))
+            .map(|i| format!("k{:03}", i))
+            .collect();
+        assert_eq!(keys, expected);
+
+        let page = store.get_range("t1", Some("k001"), 2).await.unwrap();
+        assert_eq!(page[0].key, "k002");
+        assert_eq!(page.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn dropped_scan_should_stop_iterating() {
+        let dir = tempdir().unwrap();
+        let store = Adapter::new(SledDb::new(dir.path()).unwrap());
+        for i in 0..SCAN_BUFFER * 4 {
+            store.set("t1", format!("k{}", i), 1.into()).await.unwrap();
+        }
+        let first: Vec<_> = store.scan("t1").take(3).try_collect().await.unwrap();
+        assert_eq!(first.len(), 3);
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    mem::size_of,
+    time::Instant,
+};
+
+use course_proto::pb::abi::{Value, value};
+use lru::LruCache;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// 每个 KvPair 除了 key 和 value 之外的额外开销, 包括 DashMap 的节点和元数据
+const ENTRY_OVERHEAD: usize = 64;
+
+/// 内存达到上限时的淘汰策略
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// 不淘汰, 直接返回 KvError::OutOfMemory
+    #[default]
+    NoEviction,
+    /// 淘汰最久没有被访问的 key
+    Lru,
+    /// 淘汰访问次数最少的 key
+    Lfu,
+    /// 随机淘汰
+    Random,
+    /// 只淘汰设置了过期时间的 key, 最先过期的最先淘汰
+    TtlFirst,
+}
+
+/// MemTable 的内存预算
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryLimit {
+    pub max_bytes: usize,
+    pub policy: EvictionPolicy,
+}
+
+/// 粗略估计一个 KvPair 占用的内存
+pub fn entry_size(key: &str, value: &Value) -> usize {
+    ENTRY_OVERHEAD + key.len() + value_size(value)
+}
+
+/// 粗略估计一个 Value 占用的内存, 包括 string 和 binary 在堆上的数据, 以及 list 和 map 里的元素
+pub fn value_size(v: &Value) -> usize {
+    let payload = match &v.value {
+        Some(value::Value::String(s)) => s.len(),
+        Some(value::Value::Binary(buf)) => buf.len(),
+        Some(value::Value::List(list)) => list.values.iter().map(value_size).sum(),
+        Some(value::Value::Map(map)) => map
+            .fields
+            .iter()
+            .map(|(k, v)| k.len() + value_size(v))
+            .sum(),
+        _ => 0,
+    };
+    size_of::<Value>() + payload
+}
+
+/// (table, key)
+pub(crate) type EntryKey = (String, String);
+
+/// 按照淘汰策略记录 key 的访问情况, 在需要时选出被淘汰的 key
+pub(crate) enum Tracker {
+    NoEviction,
+    Lru(LruCache<EntryKey, ()>),
+    Lfu(LfuTracker),
+    Random(RandomTracker),
+    TtlFirst(TtlTracker),
+}
+
+impl Tracker {
+    pub fn new(policy: EvictionPolicy) -> Self {
+        match policy {
+            EvictionPolicy::NoEviction => Self::NoEviction,
+            EvictionPolicy::Lru => Self::Lru(LruCache::unbounded()),
+            EvictionPolicy::Lfu => Self::Lfu(LfuTracker::default()),
+            EvictionPolicy::Random => Self::Random(RandomTracker::default()),
+            EvictionPolicy::TtlFirst => Self::TtlFirst(TtlTracker::default()),
+        }
+    }
+
+    /// 写入了一个 key, 写入会清除之前的过期时间
+    pub fn insert(&mut self, key: EntryKey) {
+        match self {
+            Self::NoEviction => {}
+            Self::Lru(cache) => {
+                cache.put(key, ());
+            }
+            Self::Lfu(lfu) => lfu.touch(key),
+            Self::Random(random) => random.insert(key),
+            Self::TtlFirst(ttl) => ttl.set_expire(key, None),
+        }
+    }
+
+    /// 读取了一个 key
+    pub fn touch(&mut self, key: &EntryKey) {
+        match self {
+            Self::Lru(cache) => {
+                cache.promote(key);
+            }
+            Self::Lfu(lfu) if lfu.entries.contains_key(key) => lfu.touch(key.clone()),
+            _ => {}
+        }
+    }
+
+    /// 给 key 设置了过期时间
+    pub fn set_expire(&mut self, key: EntryKey, expire_at: Instant) {
+        if let Self::TtlFirst(ttl) = self {
+            ttl.set_expire(key, Some(expire_at));
+        }
+    }
+
+    /// 删除了一个 key
+    pub fn remove(&mut self, key: &EntryKey) {
+        match self {
+            Self::NoEviction => {}
+            Self::Lru(cache) => {
+                cache.pop(key);
+            }
+            Self::Lfu(lfu) => lfu.remove(key),
+            Self::Random(random) => random.remove(key),
+            Self::TtlFirst(ttl) => ttl.set_expire(key.clone(), None),
+        }
+    }
+
+    /// 选出并移除下一个要淘汰的 key
+    pub fn pop_victim(&mut self) -> Option<EntryKey> {
+        match self {
+            Self::NoEviction => None,
+            Self::Lru(cache) => cache.pop_lru().map(|(k, _)| k),
+            Self::Lfu(lfu) => lfu.pop(),
+            Self::Random(random) => random.pop(),
+            Self::TtlFirst(ttl) => ttl.pop(),
+        }
+    }
+}
+
+impl fmt::Debug for Tracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, len) = match self {
+            Self::NoEviction => ("NoEviction", 0),
+            Self::Lru(cache) => ("Lru", cache.len()),
+            Self::Lfu(lfu) => ("Lfu", lfu.entries.len()),
+            Self::Random(random) => ("Random", random.keys.len()),
+            Self::TtlFirst(ttl) => ("TtlFirst", ttl.entries.len()),
+        };
+        f.debug_struct("Tracker")
+            .field("policy", &name)
+            .field("tracked", &len)
+            .finish()
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct LfuTracker {
+    seq: u64,
+    /// key -> (访问次数, 最后访问的序号)
+    entries: HashMap<EntryKey, (u64, u64)>,
+    /// 按访问次数排序, 次数相同时淘汰更早访问的
+    order: BTreeSet<(u64, u64, EntryKey)>,
+}
+
+impl LfuTracker {
+    fn touch(&mut self, key: EntryKey) {
+        self.seq += 1;
+        let count = match self.entries.get(&key) {
+            Some(&(count, seq)) => {
+                self.order.remove(&(count, seq, key.clone()));
+                count + 1
+            }
+            None => 1,
+        };
+        self.entries.insert(key.clone(), (count, self.seq));
+        self.order.insert((count, self.seq, key));
+    }
+
+    fn remove(&mut self, key: &EntryKey) {
+        if let Some((count, seq)) = self.entries.remove(key) {
+            self.order.remove(&(count, seq, key.clone()));
+        }
+    }
+
+    fn pop(&mut self) -> Option<EntryKey> {
+        let (_, _, key) = self.order.pop_first()?;
+        self.entries.remove(&key);
+        Some(key)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct RandomTracker {
+    keys: Vec<EntryKey>,
+    index: HashMap<EntryKey, usize>,
+}
+
+impl RandomTracker {
+    fn insert(&mut self, key: EntryKey) {
+        if !self.index.contains_key(&key) {
+            self.index.insert(key.clone(), self.keys.len());
+            self.keys.push(key);
+        }
+    }
+
+    fn remove(&mut self, key: &EntryKey) {
+        if let Some(i) = self.index.remove(key) {
+            self.swap_remove(i);
+        }
+    }
+
+    fn pop(&mut self) -> Option<EntryKey> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let i = rand::thread_rng().gen_range(0..self.keys.len());
+        let key = self.swap_remove(i);
+        self.index.remove(&key);
+        Some(key)
+    }
+
+    fn swap_remove(&mut self, i: usize) -> EntryKey {
+        let key = self.keys.swap_remove(i);
+        if let Some(moved) = self.keys.get(i) {
+            self.index.insert(moved.clone(), i);
+        }
+        key
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct TtlTracker {
+    entries: HashMap<EntryKey, Instant>,
+    order: BTreeSet<(Instant, EntryKey)>,
+}
+
+impl TtlTracker {
+    fn set_expire(&mut self, key: EntryKey, expire_at: Option<Instant>) {
+        if let Some(old) = self.entries.remove(&key) {
+            self.order.remove(&(old, key.clone()));
+        }
+        if let Some(expire_at) = expire_at {
+            self.entries.insert(key.clone(), expire_at);
+            self.order.insert((expire_at, key));
+        }
+    }
+
+    fn pop(&mut self) -> Option<EntryKey> {
+        let (_, key) = self.order.pop_first()?;
+        self.entries.remove(&key);
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn key(k: &str) -> EntryKey {
+        ("t1".into(), k.into())
+    }
+
+    fn drain(tracker: &mut Tracker) -> Vec<String> {
+        std::iter::from_fn(|| tracker.pop_victim().map(|(_, k)| k)).collect()
+    }
+
+    #[test]
+    fn lru_should_evict_least_recently_used() {
+        let mut tracker = Tracker::new(EvictionPolicy::Lru);
+        for k in ["k1", "k2", "k3"] {
+            tracker.insert(key(k));
+        }
+        tracker.touch(&key("k1"));
+        tracker.remove(&key("k3"));
+        assert_eq!(drain(&mut tracker), ["k2", "k1"]);
+    }
+
+    #[test]
+    fn lfu_should_evict_least_frequently_used() {
+        let mut tracker = Tracker::new(EvictionPolicy::Lfu);
+        for k in ["k1", "k2", "k3"] {
+            tracker.insert(key(k));
+        }
+        tracker.touch(&key("k1"));
+        tracker.touch(&key("k1"));
+        tracker.touch(&key("k3"));
+        // 读取一个不存在的 key 不会被记录
+        tracker.touch(&key("k4"));
+        assert_eq!(drain(&mut tracker), ["k2", "k3", "k1"]);
+    }
+
+    #[test]
+    fn random_should_evict_every_key_once() {
+        let mut tracker = Tracker::new(EvictionPolicy::Random);
+        for k in ["k1", "k2", "k3", "k4"] {
+            tracker.insert(key(k));
+        }
+        tracker.insert(key("k1"));
+        tracker.remove(&key("k2"));
+
+        let mut evicted = drain(&mut tracker);
+        evicted.sort();
+        assert_eq!(evicted, ["k1", "k3", "k4"]);
+    }
+
+    #[test]
+    fn ttl_first_should_only_evict_keys_with_ttl() {
+        let mut tracker = Tracker::new(EvictionPolicy::TtlFirst);
+        let now = Instant::now();
+        for k in ["k1", "k2", "k3", "k4"] {
+            tracker.insert(key(k));
+        }
+        tracker.set_expire(key("k1"), now + Duration::from_secs(10));
+        tracker.set_expire(key("k2"), now + Duration::from_secs(5));
+        tracker.set_expire(key("k3"), now + Duration::from_secs(1));
+        // 重新写入会清除过期时间
+        tracker.insert(key("k3"));
+        assert_eq!(drain(&mut tracker), ["k2", "k1"]);
+    }
+
+    #[test]
+    fn no_eviction_should_never_evict() {
+        let mut tracker = Tracker::new(EvictionPolicy::NoEviction);
+        tracker.insert(key("k1"));
+        assert!(tracker.pop_victim().is_none());
+    }
+
+    #[test]
+    fn value_size_should_include_payload() {
+        let small = value_size(&"a".into());
+        let large = value_size(&bytes::Bytes::from(vec![0u8; 1024]).into());
+        assert_eq!(large - small, 1023);
+        assert_eq!(value_size(&42.into()), size_of::<Value>());
+
+        let list: Value = vec!["a".into(), 42.into()].into();
+        assert_eq!(value_size(&list), size_of::<Value>() * 3 + 1);
+    }
+}
@@ -0,0 +1,522 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    ops::Bound,
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use course_proto::pb::abi::{IndexKind, KvPair, Value, ValueRange, hfind::Condition, value};
+
+use super::watch::Subscription;
+use crate::{
+    command::{Storage, UpdateFn, Updated},
+    error::KvError,
+};
+
+/// 判断 value 是否满足 Hfind 的条件
+pub fn matches(cond: &Condition, v: &Value) -> Result<bool, KvError> {
+    match cond {
+        Condition::Equal(expected) => Ok(v == expected),
+        Condition::Range(range) => {
+            let (min, max) = range_bounds(range)?;
+            Ok(as_number(v)
+                .is_some_and(|n| min.is_none_or(|min| n >= min) && max.is_none_or(|max| n <= max)))
+        }
+    }
+}
+
+/// 检查 Hfind 的条件是否合法
+pub fn validate(cond: &Condition) -> Result<(), KvError> {
+    match cond {
+        Condition::Equal(_) => Ok(()),
+        Condition::Range(range) => range_bounds(range).map(|_| ()),
+    }
+}
+
+/// 取出范围的上下界, 上下界只能是 integer 或者 float
+fn range_bounds(range: &ValueRange) -> Result<(Option<Number>, Option<Number>), KvError> {
+    let bound = |v: &Option<Value>| match v {
+        Some(v) => as_number(v)
+            .map(Some)
+            .ok_or_else(|| KvError::ConvertError(v.clone(), "Number")),
+        None => Ok(None),
+    };
+    Ok((bound(&range.min)?, bound(&range.max)?))
+}
+
+fn as_number(v: &Value) -> Option<Number> {
+    match v.value {
+        Some(value::Value::Integer(i)) => Some(Number::Integer(i)),
+        Some(value::Value::Float(f)) => Some(Number::Float(f)),
+        _ => None,
+    }
+}
+
+/// 范围索引里的数值. integer 单独保存, 超过 2^53 的 integer 也不会因为转换成 f64 而相等;
+/// integer 和 float 按照数值精确比较
+#[derive(Clone, Copy, Debug)]
+enum Number {
+    Integer(i64),
+    Float(f64),
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Number {}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Number {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (*self, *other) {
+            (Number::Integer(a), Number::Integer(b)) => a.cmp(&b),
+            (Number::Integer(a), Number::Float(b)) => cmp_integer_float(a, b),
+            (Number::Float(a), Number::Integer(b)) => cmp_integer_float(b, a).reverse(),
+            // -0.0 和 0.0 相等, NaN 按符号排在所有数值的两端
+            (Number::Float(a), Number::Float(b)) => {
+                a.partial_cmp(&b).unwrap_or_else(|| a.total_cmp(&b))
+            }
+        }
+    }
+}
+
+/// 精确比较 integer 和 float, 不会把 integer 转换成 f64 丢失精度
+fn cmp_integer_float(i: i64, f: f64) -> Ordering {
+    if f.is_nan() {
+        return match f.is_sign_negative() {
+            true => Ordering::Greater,
+            false => Ordering::Less,
+        };
+    }
+    // i64 的范围是 [-2^63, 2^63), 两个边界都能用 f64 精确表示
+    const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+    if f >= LIMIT {
+        return Ordering::Less;
+    }
+    if f < -LIMIT {
+        return Ordering::Greater;
+    }
+    let trunc = f.trunc();
+    match i.cmp(&(trunc as i64)) {
+        Ordering::Equal => match f - trunc {
+            frac if frac > 0.0 => Ordering::Less,
+            frac if frac < 0.0 => Ordering::Greater,
+            _ => Ordering::Equal,
+        },
+        ord => ord,
+    }
+}
+
+/// 精确索引里的值, 只支持 string 和 integer
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum ExactKey {
+    String(String),
+    Integer(i64),
+}
+
+impl ExactKey {
+    fn from_value(v: &Value) -> Option<Self> {
+        match &v.value {
+            Some(value::Value::String(s)) => Some(Self::String(s.clone())),
+            Some(value::Value::Integer(i)) => Some(Self::Integer(*i)),
+            _ => None,
+        }
+    }
+}
+
+/// 一个 table 上的所有索引
+#[derive(Debug, Default)]
+struct TableIndex {
+    exact: Option<HashMap<ExactKey, BTreeSet<String>>>,
+    range: Option<BTreeSet<(Number, String)>>,
+    /// 每个 key 当前被索引的值, 用来在覆盖和删除时找到旧的索引项
+    entries: HashMap<String, (Option<ExactKey>, Option<Number>)>,
+}
+
+impl TableIndex {
+    fn has(&self, kind: IndexKind) -> bool {
+        match kind {
+            IndexKind::Exact => self.exact.is_some(),
+            IndexKind::Range => self.range.is_some(),
+        }
+    }
+
+    fn create(&mut self, kind: IndexKind, pairs: Vec<KvPair>) {
+        match kind {
+            IndexKind::Exact => self.exact = Some(HashMap::new()),
+            IndexKind::Range => self.range = Some(BTreeSet::new()),
+        }
+        self.entries.clear();
+        // 重新建立所有的索引, 包括已经存在的那个
+        if let Some(exact) = &mut self.exact {
+            exact.clear();
+        }
+        if let Some(range) = &mut self.range {
+            range.clear();
+        }
+        for pair in pairs {
+            self.insert(&pair.key, &pair.value.unwrap_or_default());
+        }
+    }
+
+    fn insert(&mut self, key: &str, v: &Value) {
+        self.remove(key);
+        let exact = self.exact.as_mut().and_then(|index| {
+            let k = ExactKey::from_value(v)?;
+            index.entry(k.clone()).or_default().insert(key.into());
+            Some(k)
+        });
+        let number = self.range.as_mut().and_then(|index| {
+            let n = as_number(v)?;
+            index.insert((n, key.into()));
+            Some(n)
+        });
+        if exact.is_some() || number.is_some() {
+            self.entries.insert(key.into(), (exact, number));
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        let Some((exact, number)) = self.entries.remove(key) else {
+            return;
+        };
+        if let (Some(index), Some(k)) = (&mut self.exact, exact)
+            && let Some(keys) = index.get_mut(&k)
+        {
+            keys.remove(key);
+            if keys.is_empty() {
+                index.remove(&k);
+            }
+        }
+        if let (Some(index), Some(n)) = (&mut self.range, number) {
+            index.remove(&(n, key.to_string()));
+        }
+    }
+
+    /// 用索引找出可能满足条件的 key, 没有合适的索引时返回 None
+    fn candidates(&self, cond: &Condition) -> Result<Option<Vec<String>>, KvError> {
+        let keys = match cond {
+            Condition::Equal(v) => {
+                let (Some(index), Some(k)) = (&self.exact, ExactKey::from_value(v)) else {
+                    return Ok(None);
+                };
+                index
+                    .get(&k)
+                    .map(|keys| keys.iter().cloned().collect())
+                    .unwrap_or_default()
+            }
+            Condition::Range(range) => {
+                let Some(index) = &self.range else {
+                    return Ok(None);
+                };
+                let (min, max) = range_bounds(range)?;
+                if min.zip(max).is_some_and(|(min, max)| min > max) {
+                    return Ok(Some(vec![]));
+                }
+                // key 是 (数值, key), 用空字符串和 char::MAX 作为同一个数值下 key 的上下界
+                let start = min.map_or(Bound::Unbounded, |n| Bound::Included((n, String::new())));
+                let end = max.map_or(Bound::Unbounded, |n| {
+                    Bound::Included((n, char::MAX.to_string()))
+                });
+                index
+                    .range((start, end))
+                    .map(|(_, key)| key.clone())
+                    .collect()
+            }
+        };
+        Ok(Some(keys))
+    }
+}
+
+/// 给任意的 Storage 加上 value 的二级索引
+///
+/// 有索引的 table 的写入会串行执行, 保证索引和数据一致; 没有索引的 table 不受影响.
+/// 索引只保存在内存中, 重启之后需要重新创建.
+#[derive(Debug, Default)]
+pub struct Indexed<S> {
+    inner: S,
+    indexes: RwLock<HashMap<String, Arc<Mutex<TableIndex>>>>,
+}
+
+impl<S: Storage> Indexed<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            indexes: RwLock::default(),
+        }
+    }
+
+    fn table_index(&self, table: &str) -> Option<Arc<Mutex<TableIndex>>> {
+        let indexes = self.indexes.read().unwrap_or_else(PoisonError::into_inner);
+        indexes.get(table).cloned()
+    }
+
+    /// 修改 value 并更新 table 的索引, 用于 update 和 lpush
+    fn modify(
+        &self,
+        table: &str,
+        key: &str,
+        f: impl FnOnce(&S) -> Result<Updated, KvError>,
+    ) -> Result<Updated, KvError> {
+        let Some(index) = self.table_index(table) else {
+            return f(&self.inner);
+        };
+        let mut index = index.lock().unwrap_or_else(PoisonError::into_inner);
+        let (old, new) = f(&self.inner)?;
+        index.insert(key, &new);
+        Ok((old, new))
+    }
+}
+
+impl<S: Storage> Storage for Indexed<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        // 没有索引时在持有读锁的情况下写入, 这样 create_index 不会漏掉正在进行的写入
+        let indexes = self.indexes.read().unwrap_or_else(PoisonError::into_inner);
+        let Some(index) = indexes.get(table).cloned() else {
+            return self.inner.set(table, key, value);
+        };
+        drop(indexes);
+        let mut index = index.lock().unwrap_or_else(PoisonError::into_inner);
+        let old = self.inner.set(table, key.clone(), value.clone())?;
+        index.insert(&key, &value);
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        // 没有索引时在持有读锁的情况下写入, 这样 create_index 不会漏掉正在进行的写入
+        let indexes = self.indexes.read().unwrap_or_else(PoisonError::into_inner);
+        let Some(index) = indexes.get(table).cloned() else {
+            return self.inner.del(table, key);
+        };
+        drop(indexes);
+        let mut index = index.lock().unwrap_or_else(PoisonError::into_inner);
+        let old = self.inner.del(table, key)?;
+        index.remove(key);
+        Ok(old)
+    }
+
+    fn update(&self, table: &str, key: &str, f: &mut UpdateFn<'_>) -> Result<Updated, KvError> {
+        self.modify(table, key, |inner| inner.update(table, key, f))
+    }
+
+    fn lpush(&self, table: &str, key: &str, values: Vec<Value>) -> Result<Updated, KvError> {
+        self.modify(table, key, |inner| inner.lpush(table, key, values))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.inner.tables()
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = KvPair>>> {
+        self.inner.get_iter(table)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_range(table, after, limit)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        // 过期的 key 会在 find 的时候从索引中清除
+        self.inner.expire(table, key, ttl)
+    }
+
+    fn watch(&self, table: &str, prefix: &str) -> Result<Subscription, KvError> {
+        self.inner.watch(table, prefix)
+    }
+
+    fn snapshot(&self) -> Result<u64, KvError> {
+        self.inner.snapshot()
+    }
+
+    fn release_snapshot(&self, id: u64) -> Result<bool, KvError> {
+        self.inner.release_snapshot(id)
+    }
+
+    fn get_at(&self, snapshot: u64, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get_at(snapshot, table, key)
+    }
+
+    fn get_all_at(&self, snapshot: u64, table: &str) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_all_at(snapshot, table)
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.inner.flush()
+    }
+
+    fn is_blocking(&self) -> bool {
+        self.inner.is_blocking()
+    }
+
+    fn create_index(&self, table: &str, kind: IndexKind) -> Result<usize, KvError> {
+        let mut indexes = self.indexes.write().unwrap_or_else(PoisonError::into_inner);
+        let index = indexes.entry(table.into()).or_default().clone();
+        // 先锁住 table 的索引再释放 indexes, 之后的写入会等待索引建立完成
+        let mut index = index.lock().unwrap_or_else(PoisonError::into_inner);
+        drop(indexes);
+        if !index.has(kind) {
+            index.create(kind, self.inner.get_all(table)?);
+        }
+        Ok(index.entries.len())
+    }
+
+    fn find(&self, table: &str, cond: &Condition) -> Result<Vec<KvPair>, KvError> {
+        let Some(index) = self.table_index(table) else {
+            return self.inner.find(table, cond);
+        };
+        let mut index = index.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(keys) = index.candidates(cond)? else {
+            drop(index);
+            return self.inner.find(table, cond);
+        };
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            match self.inner.get(table, &key)? {
+                Some(v) if matches(cond, &v)? => pairs.push(KvPair::new(key, v)),
+                Some(_) => {}
+                // 已经过期或者被淘汰的 key
+                None => index.remove(&key),
+            }
+        }
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(pairs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemTable;
+
+    fn equal(v: impl Into<Value>) -> Condition {
+        Condition::Equal(v.into())
+    }
+
+    fn range(min: Option<Value>, max: Option<Value>) -> Condition {
+        Condition::Range(ValueRange { min, max })
+    }
+
+    fn keys(pairs: Vec<KvPair>) -> Vec<String> {
+        pairs.into_iter().map(|p| p.key).collect()
+    }
+
+    fn sample_store() -> Indexed<MemTable> {
+        let store = Indexed::new(MemTable::new());
+        store.set("t1", "u1".into(), 30.into()).unwrap();
+        store.set("t1", "u2".into(), 25.5.into()).unwrap();
+        store.set("t1", "u3".into(), "admin".into()).unwrap();
+        store.set("t1", "u4".into(), 30.into()).unwrap();
+        store
+    }
+
+    #[test]
+    fn exact_index_should_work() {
+        let store = sample_store();
+        assert_eq!(store.create_index("t1", IndexKind::Exact).unwrap(), 3);
+
+        assert_eq!(keys(store.find("t1", &equal(30)).unwrap()), ["u1", "u4"]);
+        assert_eq!(keys(store.find("t1", &equal("admin")).unwrap()), ["u3"]);
+        // float 不在精确索引里, 会退化成扫描
+        assert_eq!(keys(store.find("t1", &equal(25.5)).unwrap()), ["u2"]);
+    }
+
+    #[test]
+    fn range_index_should_work() {
+        let store = sample_store();
+        assert_eq!(store.create_index("t1", IndexKind::Range).unwrap(), 3);
+
+        let cond = range(Some(25.into()), Some(30.into()));
+        assert_eq!(keys(store.find("t1", &cond).unwrap()), ["u1", "u2", "u4"]);
+        let cond = range(Some(26.into()), None);
+        assert_eq!(keys(store.find("t1", &cond).unwrap()), ["u1", "u4"]);
+        let cond = range(None, Some(30.0.into()));
+        assert_eq!(keys(store.find("t1", &cond).unwrap()), ["u1", "u2", "u4"]);
+        let cond = range(Some(30.into()), Some(25.into()));
+        assert!(store.find("t1", &cond).unwrap().is_empty());
+    }
+
+    #[test]
+    fn range_index_should_keep_large_integers_apart() {
+        let store = Indexed::new(MemTable::new());
+        let big = 1i64 << 53;
+        store.set("t1", "k1".into(), big.into()).unwrap();
+        store.set("t1", "k2".into(), (big + 1).into()).unwrap();
+        store.set("t1", "k3".into(), (big as f64).into()).unwrap();
+        store.set("t1", "k4".into(), i64::MAX.into()).unwrap();
+        store.set("t1", "k5".into(), 0.5.into()).unwrap();
+        store.create_index("t1", IndexKind::Range).unwrap();
+
+        let exact = |v: i64| range(Some(v.into()), Some(v.into()));
+        assert_eq!(keys(store.find("t1", &exact(big + 1)).unwrap()), ["k2"]);
+        assert_eq!(keys(store.find("t1", &exact(big)).unwrap()), ["k1", "k3"]);
+        assert_eq!(keys(store.find("t1", &exact(i64::MAX)).unwrap()), ["k4"]);
+        let below_one = range(Some(0.into()), Some(1.into()));
+        assert_eq!(keys(store.find("t1", &below_one).unwrap()), ["k5"]);
+        let above = range(Some((big as f64 + 2.0).into()), None);
+        assert_eq!(keys(store.find("t1", &above).unwrap()), ["k4"]);
+    }
+
+    #[test]
+    fn index_should_follow_set_and_del() {
+        let store = sample_store();
+        store.create_index("t1", IndexKind::Exact).unwrap();
+        store.create_index("t1", IndexKind::Range).unwrap();
+
+        store.set("t1", "u1".into(), 31.into()).unwrap();
+        store.del("t1", "u4").unwrap();
+        store.set("t1", "u5".into(), 30.into()).unwrap();
+
+        assert_eq!(keys(store.find("t1", &equal(30)).unwrap()), ["u5"]);
+        let cond = range(Some(30.5.into()), None);
+        assert_eq!(keys(store.find("t1", &cond).unwrap()), ["u1"]);
+    }
+
+    #[test]
+    fn expired_key_should_be_dropped_from_index() {
+        let store = sample_store();
+        store.create_index("t1", IndexKind::Exact).unwrap();
+        store.expire("t1", "u1", Duration::ZERO).unwrap();
+
+        assert_eq!(keys(store.find("t1", &equal(30)).unwrap()), ["u4"]);
+        let index = store.table_index("t1").unwrap();
+        assert!(!index.lock().unwrap().entries.contains_key("u1"));
+    }
+
+    #[test]
+    fn find_without_index_should_scan() {
+        let store = sample_store();
+        assert_eq!(keys(store.find("t1", &equal(30)).unwrap()), ["u1", "u4"]);
+
+        let cond = range(Some("a".into()), None);
+        let err = store.find("t1", &cond).unwrap_err();
+        assert_eq!(err, KvError::ConvertError("a".into(), "Number"));
+    }
+}
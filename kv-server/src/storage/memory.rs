@@ -1,12 +1,63 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    ops::Bound,
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
 use anyhow::Result;
 use course_proto::pb::abi::{KvPair, Value};
-use dashmap::{mapref::one::Ref, DashMap};
+use dashmap::{
+    DashMap,
+    mapref::{entry::Entry as MapEntry, one::Ref},
+};
+
+use super::{
+    eviction::{EntryKey, MemoryLimit, Tracker, entry_size},
+    mvcc::Versions,
+};
+use crate::{
+    command::{Storage, UpdateFn, Updated},
+    error::KvError,
+};
 
-use crate::{command::Storage, error::KvError};
+/// MemTable 中保存的一条记录
+#[derive(Clone, Debug)]
+struct Entry {
+    value: Value,
+    expire_at: Option<Instant>,
+}
+
+impl Entry {
+    fn new(value: Value) -> Self {
+        Self {
+            value,
+            expire_at: None,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expire_at.is_some_and(|t| t <= now)
+    }
+}
 
-#[derive(Clone, Debug, Default)]
+#[derive(Debug, Default)]
 pub struct MemTable {
-    pub tables: DashMap<String, DashMap<String, Value>>,
+    tables: DashMap<String, DashMap<String, Entry>>,
+    /// 每个 table 中按顺序排列的 key, 用于分页读取. 在持有 key 的锁时维护, 可能暂时多出
+    /// 刚删除的 key, 但不会缺少已经写入的 key
+    keys: DashMap<String, Mutex<BTreeSet<String>>>,
+    /// 所有记录粗略估计的内存占用
+    used: AtomicUsize,
+    /// 内存预算, 没有设置时不做限制
+    limit: Option<MemoryLimit>,
+    /// 设置了内存预算时, 记录 key 的访问情况用于淘汰
+    tracker: Option<Mutex<Tracker>>,
+    /// snapshot 和它们需要的旧版本, 旧版本不计入内存占用
+    versions: Versions<Entry>,
 }
 
 impl MemTable {
@@ -14,8 +65,32 @@ impl MemTable {
         Self::default()
     }
 
+    /// 创建一个有内存预算的 MemTable, 超出预算时按照 limit.policy 淘汰
+    pub fn with_limit(limit: MemoryLimit) -> Self {
+        Self {
+            limit: Some(limit),
+            tracker: Some(Mutex::new(Tracker::new(limit.policy))),
+            ..Default::default()
+        }
+    }
+
+    /// 当前粗略估计的内存占用
+    pub fn used_memory(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// 为了 snapshot 保存的旧版本的个数
+    pub fn retained_versions(&self) -> usize {
+        self.versions.retained()
+    }
+
+    /// 记录一次写入, old 是被覆盖的记录. 调用时需要持有 key 在 table 中的锁
+    fn record_write(&self, table: &str, key: &str, old: Option<&Entry>) {
+        self.versions.record(table, key, old);
+    }
+
     /// 如果名为 name 的 hash table 不存在, 则创建, 否则返回
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    fn get_or_create_table(&self, name: &str) -> Ref<'_, String, DashMap<String, Entry>> {
         match self.tables.get(name) {
             Some(table) => table,
             None => {
@@ -24,43 +99,662 @@ impl MemTable {
             }
         }
     }
+
+    /// 修改 table 的有序 key 索引. 调用时可以持有 tables 里的引用, 读索引时不会反过来加锁
+    fn index(&self, table: &str, f: impl FnOnce(&mut BTreeSet<String>)) {
+        let keys = match self.keys.get(table) {
+            Some(keys) => keys,
+            None => self.keys.entry(table.into()).or_default().downgrade(),
+        };
+        f(&mut keys.lock().unwrap_or_else(PoisonError::into_inner));
+    }
+
+    /// 在 tracker 上执行操作. 调用时不能持有 tables 里的任何引用, 否则可能和淘汰互相死锁
+    fn track(&self, f: impl FnOnce(&mut Tracker)) {
+        if let Some(tracker) = &self.tracker {
+            f(&mut tracker.lock().unwrap_or_else(PoisonError::into_inner));
+        }
+    }
+
+    /// 读取一条没有过期的记录, 已经过期的记录会被删除
+    fn get_entry(&self, table: &str, key: &str) -> Option<Entry> {
+        let now = Instant::now();
+        let entry = self.get_or_create_table(table).get(key)?.clone();
+        if entry.is_expired(now) {
+            self.remove_entry(table, key, |e| e.is_expired(now));
+            return None;
+        }
+        Some(entry)
+    }
+
+    /// 删除满足 cond 的记录, 并更新内存占用和 tracker
+    fn remove_entry(
+        &self,
+        table: &str,
+        key: &str,
+        cond: impl FnOnce(&Entry) -> bool,
+    ) -> Option<Entry> {
+        let (key, entry) = {
+            let entries = self.get_or_create_table(table);
+            let MapEntry::Occupied(e) = entries.entry(key.into()) else {
+                return None;
+            };
+            if !cond(e.get()) {
+                return None;
+            }
+            self.record_write(table, e.key(), Some(e.get()));
+            self.index(table, |keys| {
+                keys.remove(key);
+            });
+            e.remove_entry()
+        };
+        self.used
+            .fetch_sub(entry_size(&key, &entry.value), Ordering::Relaxed);
+        self.track(|t| t.remove(&(table.into(), key)));
+        Some(entry)
+    }
+
+    /// 在内存占用中为新写入预留 size 字节, 需要时按照淘汰策略删除其它 key
+    fn reserve(&self, size: usize) -> Result<(), KvError> {
+        let Some(limit) = self.limit else {
+            self.used.fetch_add(size, Ordering::Relaxed);
+            return Ok(());
+        };
+        if size > limit.max_bytes {
+            return Err(KvError::OutOfMemory(limit.max_bytes));
+        }
+
+        while !self.try_reserve(size) {
+            let mut victim: Option<EntryKey> = None;
+            self.track(|t| victim = t.pop_victim());
+            let Some((table, key)) = victim else {
+                return Err(KvError::OutOfMemory(limit.max_bytes));
+            };
+            self.remove_entry(&table, &key, |_| true);
+        }
+        Ok(())
+    }
+
+    /// 不超出预算时原子地预留 size 字节, 不会淘汰其它 key
+    fn try_reserve(&self, size: usize) -> bool {
+        let max_bytes = self.limit.map_or(usize::MAX, |l| l.max_bytes);
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(size).filter(|&n| n <= max_bytes)
+            })
+            .is_ok()
+    }
+
+    /// 持有 key 的锁, 用 size 字节的记录替换 old_size 字节的记录时, 结算之前预留的 reserved 字节.
+    ///
+    /// 记录在预留之后被其它写入修改过时, 实际需要的空间可能比预留的多; 这时持有锁不能淘汰,
+    /// 超出预算时撤销预留并返回 false, 调用者释放锁后重试
+    fn settle(&self, reserved: usize, size: usize, old_size: usize) -> bool {
+        let needed = size.saturating_sub(old_size);
+        if needed > reserved {
+            if !self.try_reserve(needed - reserved) {
+                self.used.fetch_sub(reserved, Ordering::Relaxed);
+                return false;
+            }
+        } else {
+            let freed = old_size.saturating_sub(size);
+            self.used
+                .fetch_sub(reserved - needed + freed, Ordering::Relaxed);
+        }
+        true
+    }
 }
 
 impl Storage for MemTable {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        let value = table.get(key).map(|v| v.value().clone());
-        Ok(value)
+        let entry = self.get_entry(table, key);
+        if entry.is_some() {
+            self.track(|t| t.touch(&(table.into(), key.into())));
+        }
+        Ok(entry.map(|e| e.value))
     }
 
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        let value = table.insert(key, value);
-        Ok(value)
+        let size = entry_size(&key, &value);
+        let old = loop {
+            // 覆盖已有的 key 时, 只需要为增加的部分腾出空间
+            let old_size = match self.limit {
+                Some(_) => self
+                    .get_entry(table, &key)
+                    .map_or(0, |e| entry_size(&key, &e.value)),
+                None => 0,
+            };
+            let reserved = size.saturating_sub(old_size);
+            self.reserve(reserved)?;
+
+            match self.get_or_create_table(table).entry(key.clone()) {
+                MapEntry::Occupied(mut e) => {
+                    if !self.settle(reserved, size, entry_size(&key, &e.get().value)) {
+                        continue;
+                    }
+                    self.record_write(table, &key, Some(e.get()));
+                    break Some(e.insert(Entry::new(value)));
+                }
+                MapEntry::Vacant(e) => {
+                    if !self.settle(reserved, size, 0) {
+                        continue;
+                    }
+                    self.record_write(table, &key, None);
+                    let _entry = e.insert(Entry::new(value));
+                    self.index(table, |keys| {
+                        keys.insert(key.clone());
+                    });
+                    break None;
+                }
+            }
+        };
+        self.track(|t| t.insert((table.into(), key)));
+
+        let now = Instant::now();
+        Ok(old.filter(|e| !e.is_expired(now)).map(|e| e.value))
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let table = self.get_or_create_table(table);
-        let value = table.contains_key(key);
-        Ok(value)
+        Ok(self.get_entry(table, key).is_some())
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        let value = table.remove(key).map(|(_k, v)| v);
-        Ok(value)
+        let now = Instant::now();
+        let entry = self.remove_entry(table, key, |_| true);
+        Ok(entry.filter(|e| !e.is_expired(now)).map(|e| e.value))
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
-        let table = self.get_or_create_table(table);
-        let value = table
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let pairs = self
+            .get_or_create_table(table)
             .iter()
-            .map(|v| KvPair::new(v.key(), v.value().clone()))
+            .filter_map(|e| match e.is_expired(now) {
+                true => {
+                    expired.push(e.key().clone());
+                    None
+                }
+                false => Some(KvPair::new(e.key(), e.value.clone())),
+            })
             .collect();
-        Ok(value)
+
+        for key in expired {
+            self.remove_entry(table, &key, |e| e.is_expired(now));
+        }
+        Ok(pairs)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        Ok(self.tables.iter().map(|t| t.key().clone()).collect())
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<KvPair>, KvError> {
+        // 从有序索引中 after 之后的位置开始读, 跳过已经删除或过期的 key 之后继续向后找.
+        // 先拷贝出 key 再释放索引的锁, 读记录时不能持有它
+        let now = Instant::now();
+        let mut pairs = Vec::new();
+        let mut after = after.map(String::from);
+        while pairs.len() < limit {
+            let keys: Vec<String> = match self.keys.get(table) {
+                Some(keys) => {
+                    let keys = keys.lock().unwrap_or_else(PoisonError::into_inner);
+                    let start = match &after {
+                        Some(after) => Bound::Excluded(after.as_str()),
+                        None => Bound::Unbounded,
+                    };
+                    keys.range::<str, _>((start, Bound::Unbounded))
+                        .take(limit - pairs.len())
+                        .cloned()
+                        .collect()
+                }
+                None => break,
+            };
+            let Some(last) = keys.last() else {
+                break;
+            };
+            after = Some(last.clone());
+
+            let Some(entries) = self.tables.get(table) else {
+                break;
+            };
+            for key in keys {
+                if let Some(e) = entries.get(&key).filter(|e| !e.is_expired(now)) {
+                    pairs.push(KvPair::new(key, e.value.clone()));
+                }
+            }
+        }
+        Ok(pairs)
     }
 
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = KvPair>>> {
-        todo!()
+        // DashMap 的迭代器会持有 table 的读锁, 这里先拷贝出来再返回
+        let iter = self.get_all(table)?.into_iter();
+        Ok(Box::new(iter))
+    }
+
+    fn update(&self, table: &str, key: &str, f: &mut UpdateFn<'_>) -> Result<Updated, KvError> {
+        loop {
+            let old = self.get_entry(table, key).map(|e| e.value);
+            let new = f(old.as_ref())?;
+            let size = entry_size(key, &new);
+            let old_size = old.as_ref().map_or(0, |v| entry_size(key, v));
+            let reserved = size.saturating_sub(old_size);
+            self.reserve(reserved)?;
+
+            // 只有 value 在计算期间没有被其它写入修改时才写回, 否则撤销预留重新计算
+            let now = Instant::now();
+            let replaced = match self.get_or_create_table(table).entry(key.into()) {
+                MapEntry::Occupied(mut e) => {
+                    let current = e.get();
+                    let live = !current.is_expired(now);
+                    if live.then_some(&current.value) != old.as_ref() {
+                        self.used.fetch_sub(reserved, Ordering::Relaxed);
+                        continue;
+                    }
+                    if !self.settle(reserved, size, entry_size(key, &current.value)) {
+                        continue;
+                    }
+                    // 修改 value 会保留过期时间, 已经过期的 key 相当于重新写入
+                    let expire_at = current.expire_at.filter(|_| live);
+                    self.record_write(table, key, Some(current));
+                    e.insert(Entry {
+                        value: new.clone(),
+                        expire_at,
+                    });
+                    Some(live)
+                }
+                MapEntry::Vacant(_) if old.is_some() => {
+                    self.used.fetch_sub(reserved, Ordering::Relaxed);
+                    continue;
+                }
+                MapEntry::Vacant(e) => {
+                    if !self.settle(reserved, size, 0) {
+                        continue;
+                    }
+                    self.record_write(table, key, None);
+                    let _entry = e.insert(Entry::new(new.clone()));
+                    self.index(table, |keys| {
+                        keys.insert(key.into());
+                    });
+                    None
+                }
+            };
+
+            let entry_key = (table.to_string(), key.to_string());
+            match replaced {
+                Some(true) => self.track(|t| t.touch(&entry_key)),
+                Some(false) | None => self.track(|t| t.insert(entry_key)),
+            }
+            return Ok((old, new));
+        }
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let now = Instant::now();
+        let expire_at = now + ttl;
+        let found = match self.get_or_create_table(table).get_mut(key) {
+            Some(mut entry) if !entry.is_expired(now) => {
+                entry.expire_at = Some(expire_at);
+                true
+            }
+            _ => false,
+        };
+
+        if found {
+            self.track(|t| t.set_expire((table.into(), key.into()), expire_at));
+        }
+        Ok(found)
+    }
+
+    fn snapshot(&self) -> Result<u64, KvError> {
+        Ok(self.versions.snapshot(Instant::now()))
+    }
+
+    fn release_snapshot(&self, id: u64) -> Result<bool, KvError> {
+        Ok(self.versions.release(id))
+    }
+
+    fn get_at(&self, snapshot: u64, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        // 先读当前的记录再查旧版本, 这样读完之后发生的写入一定已经被记录下来
+        let current = self
+            .tables
+            .get(table)
+            .and_then(|entries| entries.get(key).map(|e| e.clone()));
+        let pinned = self
+            .versions
+            .get(snapshot)
+            .ok_or(KvError::SnapshotNotFound(snapshot))?;
+        let entry = match self.versions.value_at(table, key, pinned.version) {
+            Some(old) => old,
+            None => current,
+        };
+        Ok(entry.filter(|e| !e.is_expired(pinned.at)).map(|e| e.value))
+    }
+
+    fn get_all_at(&self, snapshot: u64, table: &str) -> Result<Vec<KvPair>, KvError> {
+        let mut entries: HashMap<String, Entry> = match self.tables.get(table) {
+            Some(entries) => entries
+                .iter()
+                .map(|e| (e.key().clone(), e.value().clone()))
+                .collect(),
+            None => HashMap::new(),
+        };
+
+        let pinned = self
+            .versions
+            .get(snapshot)
+            .ok_or(KvError::SnapshotNotFound(snapshot))?;
+        for (key, old) in self.versions.table_at(table, pinned.version) {
+            match old {
+                Some(old) => entries.insert(key, old),
+                None => entries.remove(&key),
+            };
+        }
+
+        let mut pairs: Vec<_> = entries
+            .into_iter()
+            .filter(|(_, e)| !e.is_expired(pinned.at))
+            .map(|(key, e)| KvPair::new(key, e.value))
+            .collect();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(pairs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use bytes::Bytes;
+    use course_proto::pb::abi::value;
+
+    use super::*;
+    use crate::storage::eviction::EvictionPolicy;
+
+    /// 一个 key 为 "kN", value 为 1 字节 string 的记录的大小
+    fn small_entry() -> usize {
+        entry_size("k1", &"v".into())
+    }
+
+    fn limited(entries: usize, policy: EvictionPolicy) -> MemTable {
+        MemTable::with_limit(MemoryLimit {
+            max_bytes: small_entry() * entries,
+            policy,
+        })
+    }
+
+    fn keys(store: &MemTable) -> Vec<String> {
+        let mut keys: Vec<_> = store
+            .get_all("t1")
+            .unwrap()
+            .into_iter()
+            .map(|p| p.key)
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn used_memory_should_track_set_and_del() {
+        let store = MemTable::new();
+        let binary = Bytes::from(vec![0u8; 4096]);
+        store.set("t1", "k1".into(), binary.into()).unwrap();
+        assert!(store.used_memory() > 4096);
+
+        store.set("t1", "k1".into(), "v".into()).unwrap();
+        assert_eq!(store.used_memory(), small_entry());
+
+        store.del("t1", "k1").unwrap();
+        assert_eq!(store.used_memory(), 0);
+    }
+
+    #[test]
+    fn no_eviction_should_return_out_of_memory() {
+        let store = limited(2, EvictionPolicy::NoEviction);
+        store.set("t1", "k1".into(), "v".into()).unwrap();
+        store.set("t1", "k2".into(), "v".into()).unwrap();
+
+        let err = store.set("t1", "k3".into(), "v".into()).unwrap_err();
+        assert_eq!(err, KvError::OutOfMemory(small_entry() * 2));
+        // 覆盖已有的 key 不需要更多的内存
+        store.set("t1", "k2".into(), "w".into()).unwrap();
+        assert_eq!(keys(&store), ["k1", "k2"]);
+    }
+
+    #[test]
+    fn concurrent_writers_should_stay_within_budget() {
+        let store = Arc::new(limited(10, EvictionPolicy::NoEviction));
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for j in 0..100 {
+                        let key = format!("k{}", (i * 100 + j) % 10);
+                        let value = if j % 2 == 0 { "v" } else { "" };
+                        _ = store.set("t1", key, value.into());
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let used: usize = store
+            .get_all("t1")
+            .unwrap()
+            .iter()
+            .map(|p| entry_size(&p.key, p.value.as_ref().unwrap()))
+            .sum();
+        assert_eq!(store.used_memory(), used);
+        assert!(used <= small_entry() * 10);
+    }
+
+    #[test]
+    fn lru_should_evict_least_recently_used_key() {
+        let store = limited(2, EvictionPolicy::Lru);
+        store.set("t1", "k1".into(), "v".into()).unwrap();
+        store.set("t1", "k2".into(), "v".into()).unwrap();
+        store.get("t1", "k1").unwrap();
+
+        store.set("t1", "k3".into(), "v".into()).unwrap();
+        assert_eq!(keys(&store), ["k1", "k3"]);
+        assert_eq!(store.used_memory(), small_entry() * 2);
+    }
+
+    #[test]
+    fn lfu_should_evict_least_frequently_used_key() {
+        let store = limited(2, EvictionPolicy::Lfu);
+        store.set("t1", "k1".into(), "v".into()).unwrap();
+        store.set("t1", "k2".into(), "v".into()).unwrap();
+        store.get("t1", "k2").unwrap();
+        store.get("t1", "k2").unwrap();
+        store.get("t1", "k1").unwrap();
+
+        store.set("t1", "k3".into(), "v".into()).unwrap();
+        assert_eq!(keys(&store), ["k2", "k3"]);
+    }
+
+    #[test]
+    fn random_should_stay_within_budget() {
+        let store = limited(3, EvictionPolicy::Random);
+        for i in 0..9 {
+            store.set("t1", format!("k{}", i), "v".into()).unwrap();
+        }
+        assert_eq!(keys(&store).len(), 3);
+        assert!(store.get("t1", "k8").unwrap().is_some());
+    }
+
+    #[test]
+    fn ttl_first_should_only_evict_keys_with_ttl() {
+        let store = limited(2, EvictionPolicy::TtlFirst);
+        store.set("t1", "k1".into(), "v".into()).unwrap();
+        store.set("t1", "k2".into(), "v".into()).unwrap();
+        store.expire("t1", "k2", Duration::from_secs(60)).unwrap();
+
+        store.set("t1", "k3".into(), "v".into()).unwrap();
+        assert_eq!(keys(&store), ["k1", "k3"]);
+
+        // 没有可以淘汰的 key 了
+        let err = store.set("t1", "k4".into(), "v".into()).unwrap_err();
+        assert!(matches!(err, KvError::OutOfMemory(_)));
+    }
+
+    #[test]
+    fn value_larger_than_budget_should_be_rejected() {
+        let store = limited(2, EvictionPolicy::Lru);
+        store.set("t1", "k1".into(), "v".into()).unwrap();
+
+        let binary = Bytes::from(vec![0u8; small_entry() * 2]);
+        let err = store.set("t1", "k2".into(), binary.into()).unwrap_err();
+        assert!(matches!(err, KvError::OutOfMemory(_)));
+        // 不会为了一个放不下的 value 淘汰其它 key
+        assert_eq!(keys(&store), ["k1"]);
+    }
+
+    #[test]
+    fn snapshot_should_not_see_later_writes() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        let id = store.snapshot().unwrap();
+
+        store.set("t1", "k1".into(), "v1.1".into()).unwrap();
+        store.set("t1", "k1".into(), "v1.2".into()).unwrap();
+        store.del("t1", "k2").unwrap();
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        store
+            .update("t1", "k3", &mut |_| Ok("v3.1".into()))
+            .unwrap();
+
+        assert_eq!(store.get_at(id, "t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get_at(id, "t1", "k3").unwrap(), None);
+        assert_eq!(
+            store.get_all_at(id, "t1").unwrap(),
+            [
+                KvPair::new("k1", "v1".into()),
+                KvPair::new("k2", "v2".into())
+            ]
+        );
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1.2".into()));
+        assert!(store.retained_versions() > 0);
+
+        assert!(store.release_snapshot(id).unwrap());
+        assert_eq!(store.retained_versions(), 0);
+        let err = store.get_at(id, "t1", "k1").unwrap_err();
+        assert_eq!(err, KvError::SnapshotNotFound(id));
+    }
+
+    #[test]
+    fn snapshot_should_keep_evicted_and_expired_keys() {
+        let store = limited(1, EvictionPolicy::Lru);
+        store.set("t1", "k1".into(), "v".into()).unwrap();
+        store.expire("t1", "k1", Duration::from_millis(10)).unwrap();
+        let id = store.snapshot().unwrap();
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get_at(id, "t1", "k1").unwrap(), Some("v".into()));
+
+        // 新的 snapshot 里 k1 已经过期
+        let id = store.snapshot().unwrap();
+        store.set("t1", "k2".into(), "v".into()).unwrap();
+        assert_eq!(store.get_at(id, "t1", "k1").unwrap(), None);
+        assert_eq!(store.get_all_at(id, "t1").unwrap(), []);
+    }
+
+    #[test]
+    fn update_should_be_atomic() {
+        let store = Arc::new(MemTable::new());
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        store
+                            .update("t1", "counter", &mut |v| {
+                                let n = match v.and_then(|v| v.value.clone()) {
+                                    Some(value::Value::Integer(n)) => n,
+                                    _ => 0,
+                                };
+                                Ok((n + 1).into())
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(store.get("t1", "counter").unwrap(), Some(400.into()));
+        assert_eq!(store.used_memory(), entry_size("counter", &400.into()));
+    }
+
+    #[test]
+    fn update_should_keep_ttl() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), 1.into()).unwrap();
+        store.expire("t1", "k1", Duration::from_millis(10)).unwrap();
+        let (old, new) = store.update("t1", "k1", &mut |_| Ok(2.into())).unwrap();
+        assert_eq!((old, new), (Some(1.into()), 2.into()));
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+    }
+
+    #[test]
+    fn expired_key_should_disappear() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v".into()).unwrap();
+        store.set("t1", "k2".into(), "v".into()).unwrap();
+        assert!(store.expire("t1", "k1", Duration::from_millis(10)).unwrap());
+        assert!(!store.expire("t1", "k3", Duration::from_millis(10)).unwrap());
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert!(!store.contains("t1", "k1").unwrap());
+        assert_eq!(keys(&store), ["k2"]);
+        assert_eq!(store.used_memory(), small_entry());
+    }
+
+    #[test]
+    fn get_range_should_page_in_key_order() {
+        let store = MemTable::new();
+        for i in [3, 7, 0, 9, 5, 1, 8, 2, 6, 4] {
+            store.set("t1", format!("k{}", i), i.into()).unwrap();
+        }
+        store.del("t1", "k3").unwrap();
+        store.expire("t1", "k5", Duration::ZERO).unwrap();
+        store.update("t1", "k10", &mut |_| Ok(10.into())).unwrap();
+
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let page = store.get_range("t1", after.as_deref(), 3).unwrap();
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some(last.key.clone());
+            pages.push(page.into_iter().map(|p| p.key).collect::<Vec<_>>());
+        }
+        assert_eq!(
+            pages,
+            [
+                vec!["k0", "k1", "k10"],
+                vec!["k2", "k4", "k6"],
+                vec!["k7", "k8", "k9"]
+            ]
+        );
+        // 删除的 key 不会留在索引中
+        let index = store.keys.get("t1").unwrap();
+        assert!(!index.lock().unwrap().contains("k3"));
     }
 }
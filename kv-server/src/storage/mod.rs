@@ -1 +1,8 @@
+pub mod adapter;
+pub mod eviction;
+pub mod index;
 pub mod memory;
+mod mvcc;
+pub mod remote;
+pub mod sleddb;
+pub mod watch;
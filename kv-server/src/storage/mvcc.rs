@@ -0,0 +1,239 @@
+use std::{
+    collections::{BTreeMap, HashMap, btree_map},
+    sync::{
+        Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Instant,
+};
+
+use dashmap::DashMap;
+
+/// 一个 snapshot 固定的版本
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Pinned {
+    /// 创建 snapshot 时最后一次写入的版本号, 之后的写入对它不可见
+    pub version: u64,
+    /// 创建 snapshot 的时间, 用来判断 key 在 snapshot 中是否已经过期
+    pub at: Instant,
+}
+
+/// 覆盖 value 的写入的版本号, 以及被覆盖的 value (写入前 key 不存在时为 None)
+type Record<T> = (u64, Option<T>);
+
+/// 多版本记录. 每次写入都会分配一个新的版本号, snapshot 固定在创建时的版本号上.
+///
+/// 写入时只在有 snapshot 可能需要的情况下把被覆盖的 value 记录下来; 按 snapshot 读取时,
+/// key 在 snapshot 之后第一次被覆盖前的 value 就是 snapshot 中的 value, 没有被覆盖过时就是当前的 value.
+/// snapshot 释放后, 不再被任何 snapshot 需要的记录会被回收.
+///
+/// 没有 snapshot 时写入不加任何锁; 旧 value 按 table 分开加锁, 读取一个 table 的旧 value 不会阻塞其它 table 的写入
+#[derive(Debug)]
+pub(crate) struct Versions<T> {
+    /// 存活的 snapshot 的个数, 只在持有 registry 的锁时修改
+    live: AtomicUsize,
+    registry: Mutex<Registry>,
+    /// table -> key -> 按版本号排序的记录
+    history: DashMap<String, HashMap<String, Vec<Record<T>>>>,
+}
+
+/// 版本号和所有存活的 snapshot
+#[derive(Debug, Default)]
+struct Registry {
+    /// 最后一次记录的写入的版本号
+    version: u64,
+    /// 最后一个 snapshot 的 id, id 从 1 开始, 不会重复使用
+    last_id: u64,
+    snapshots: HashMap<u64, Pinned>,
+    /// 每个版本上有多少个 snapshot
+    pinned: BTreeMap<u64, usize>,
+}
+
+impl<T> Default for Versions<T> {
+    fn default() -> Self {
+        Self {
+            live: AtomicUsize::new(0),
+            registry: Mutex::default(),
+            history: DashMap::new(),
+        }
+    }
+}
+
+impl<T: Clone> Versions<T> {
+    fn registry(&self) -> MutexGuard<'_, Registry> {
+        self.registry.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// 在当前版本上创建一个 snapshot, 返回它的 id
+    pub fn snapshot(&self, at: Instant) -> u64 {
+        let mut registry = self.registry();
+        registry.last_id += 1;
+        let id = registry.last_id;
+        let pinned = Pinned {
+            version: registry.version,
+            at,
+        };
+        registry.snapshots.insert(id, pinned);
+        *registry.pinned.entry(pinned.version).or_default() += 1;
+        self.live.fetch_add(1, Ordering::SeqCst);
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<Pinned> {
+        self.registry().snapshots.get(&id).copied()
+    }
+
+    /// 释放一个 snapshot 并回收不再需要的记录, snapshot 不存在时返回 false
+    pub fn release(&self, id: u64) -> bool {
+        let (pinned, version) = {
+            let mut registry = self.registry();
+            let Some(snapshot) = registry.snapshots.remove(&id) else {
+                return false;
+            };
+            if let btree_map::Entry::Occupied(mut e) = registry.pinned.entry(snapshot.version) {
+                *e.get_mut() -= 1;
+                if *e.get() == 0 {
+                    e.remove();
+                }
+            }
+            self.live.fetch_sub(1, Ordering::SeqCst);
+            (registry.pinned.clone(), registry.version)
+        };
+        self.gc(&pinned, version);
+        true
+    }
+
+    /// 记录一次对 key 的写入, old 是被覆盖的 value.
+    ///
+    /// 调用时需要持有 key 所在的锁, 保证读到当前 value 的读取者随后一定能看到这条记录.
+    /// 没有 snapshot 时直接返回: 之后创建的 snapshot 读取 key 时要等这次写入完成, 读到的就是写入后的 value
+    pub fn record(&self, table: &str, key: &str, old: Option<&T>) {
+        if self.live.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let (version, latest) = {
+            let mut registry = self.registry();
+            let Some((&latest, _)) = registry.pinned.last_key_value() else {
+                return;
+            };
+            registry.version += 1;
+            (registry.version, latest)
+        };
+
+        let mut keys = match self.history.get_mut(table) {
+            Some(keys) => keys,
+            None => self.history.entry(table.into()).or_default(),
+        };
+        // 上一次覆盖之后没有创建过 snapshot 时, 不会有 snapshot 读到 old
+        let prev = keys
+            .get(key)
+            .and_then(|records| records.last())
+            .map_or(0, |(version, _)| *version);
+        if latest < prev {
+            return;
+        }
+        keys.entry(key.into())
+            .or_default()
+            .push((version, old.cloned()));
+    }
+
+    /// key 在 version 时的 value. 返回 None 表示 key 在那之后没有被覆盖过, 当前的 value 就是那时的 value
+    pub fn value_at(&self, table: &str, key: &str, version: u64) -> Option<Option<T>> {
+        let keys = self.history.get(table)?;
+        first_after(keys.get(key)?, version).map(|old| old.cloned())
+    }
+
+    /// table 中在 version 之后被覆盖过的 key, 以及它们在 version 时的 value
+    pub fn table_at(&self, table: &str, version: u64) -> Vec<(String, Option<T>)> {
+        let Some(keys) = self.history.get(table) else {
+            return Vec::new();
+        };
+        keys.iter()
+            .filter_map(|(key, records)| {
+                first_after(records, version).map(|old| (key.clone(), old.cloned()))
+            })
+            .collect()
+    }
+
+    /// 当前保存的旧 value 的个数
+    pub fn retained(&self) -> usize {
+        self.history
+            .iter()
+            .map(|keys| keys.values().map(Vec::len).sum::<usize>())
+            .sum()
+    }
+
+    /// 一条记录只会被版本号在 [上一条记录的版本号, 它的版本号) 之间的 snapshot 读到, 没有这样的 snapshot 时删除它.
+    ///
+    /// pinned 是 version 时的 snapshot, 之后创建的 snapshot 不会读到版本号不超过 version 的记录,
+    /// 更新的记录留到下一次回收
+    fn gc(&self, pinned: &BTreeMap<u64, usize>, version: u64) {
+        self.history.retain(|_, keys| {
+            keys.retain(|_, records| {
+                let mut prev = 0;
+                records.retain(|(v, _)| {
+                    let needed = *v > version || pinned.range(prev..*v).next().is_some();
+                    prev = *v;
+                    needed
+                });
+                !records.is_empty()
+            });
+            !keys.is_empty()
+        });
+    }
+}
+
+fn first_after<T>(records: &[Record<T>], version: u64) -> Option<Option<&T>> {
+    let i = records.partition_point(|(v, _)| *v <= version);
+    records.get(i).map(|(_, old)| old.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_should_see_value_before_later_writes() {
+        let versions = Versions::default();
+        versions.record("t1", "k1", None);
+        let s1 = versions.snapshot(Instant::now());
+        versions.record("t1", "k1", Some(&1));
+        versions.record("t1", "k1", Some(&2));
+        let s2 = versions.snapshot(Instant::now());
+        versions.record("t1", "k1", Some(&3));
+        versions.record("t1", "k2", None);
+
+        let at = |id| versions.get(id).unwrap().version;
+        assert_eq!(versions.value_at("t1", "k1", at(s1)), Some(Some(1)));
+        assert_eq!(versions.value_at("t1", "k1", at(s2)), Some(Some(3)));
+        assert_eq!(versions.value_at("t1", "k2", at(s2)), Some(None));
+        // 第二次覆盖之前没有新的 snapshot, 不需要记录
+        assert_eq!(versions.retained(), 3);
+
+        let mut changed = versions.table_at("t1", at(s2));
+        changed.sort();
+        assert_eq!(changed, [("k1".into(), Some(3)), ("k2".into(), None)]);
+    }
+
+    #[test]
+    fn release_should_collect_unused_records() {
+        let versions = Versions::default();
+        let s1 = versions.snapshot(Instant::now());
+        versions.record("t1", "k1", None);
+        let s2 = versions.snapshot(Instant::now());
+        versions.record("t1", "k1", Some(&1));
+        assert_eq!(versions.retained(), 2);
+
+        assert!(versions.release(s1));
+        assert!(!versions.release(s1));
+        assert_eq!(versions.retained(), 1);
+        assert_eq!(versions.value_at("t1", "k1", 1), Some(Some(1)));
+
+        assert!(versions.release(s2));
+        assert_eq!(versions.retained(), 0);
+        assert!(versions.get(s2).is_none());
+        // 没有 snapshot 时不会记录
+        versions.record("t1", "k1", Some(&2));
+        assert_eq!(versions.retained(), 0);
+    }
+}
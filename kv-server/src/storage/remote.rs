@@ -0,0 +1,635 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicUsize, Ordering},
+        mpsc as std_mpsc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use course_proto::pb::abi::{
+    CommandRequest, CommandResponse, Hfind, IndexKind, KvPair, Lpush, Value,
+    command_request::RequestData, hfind::Condition, value,
+};
+use futures::{StreamExt, executor, future, stream::BoxStream};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    runtime,
+    sync::{mpsc, oneshot},
+    time,
+};
+use tracing::{info, warn};
+
+use super::{
+    eviction::{EvictionPolicy, MemoryLimit},
+    memory::MemTable,
+};
+use crate::{
+    client::KvClient,
+    command::{Storage, Updated, push_front},
+    error::KvError,
+};
+
+/// get_iter 通过 Hexport 分页读取时, 每页的 KvPair 个数
+const PAGE_SIZE: usize = 1000;
+/// Watch 的连接断开之后, 隔这么久重新订阅
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// 远程 kv-server 的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RemoteConfig {
+    /// 远程 kv-server 的地址
+    pub addr: String,
+    /// 建立连接和每个请求最多等待的毫秒数, 超时的连接会被断开
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// 到远程服务器的连接数, 请求轮流使用这些连接
+    #[serde(default = "default_pool_size")]
+    pub pool_size: usize,
+    /// 本地的读缓存, 不设置时每次读取都访问远程服务器
+    pub cache: Option<CacheConfig>,
+}
+
+impl RemoteConfig {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            timeout_ms: default_timeout_ms(),
+            pool_size: default_pool_size(),
+            cache: None,
+        }
+    }
+}
+
+fn default_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_pool_size() -> usize {
+    4
+}
+
+/// Remote 在本地的读缓存
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// 缓存的 value 在这么多毫秒之后过期. 其它客户端直接写远程服务器时,
+    /// 没有被 Watch 的 table 最多读到这么久之前的数据
+    pub ttl_ms: u64,
+    /// 缓存最多使用的内存字节数, 超出时淘汰最久没有被访问的 key
+    pub max_bytes: Option<usize>,
+    /// 通过 Watch 订阅这些 table 在远程服务器上的变化, 有变化时立即让对应的缓存失效
+    pub watch_tables: Vec<String>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_ms: 60_000,
+            max_bytes: None,
+            watch_tables: Vec::new(),
+        }
+    }
+}
+
+/// 发给某个连接的请求
+struct Call {
+    cmd: CommandRequest,
+    /// 读取 snapshot 时是 (snapshot id, 创建它的连接的代数), 连接重建过就不能再执行
+    pinned: Option<(u64, u64)>,
+    /// 成功时同时返回执行请求的连接的代数
+    reply: oneshot::Sender<Result<(CommandResponse, u64), KvError>>,
+}
+
+/// 把所有操作都转发给远程 kv-server 的存储, 用于分层存储以及在集群之间迁移数据.
+///
+/// 请求轮流分配给后台线程上的 pool_size 个连接, 每个连接依次发送自己的请求,
+/// 出错或超时后断开, 在下一个请求时重新连接. 远程服务器的 snapshot 只属于创建它的连接,
+/// 所以读取和释放 snapshot 的请求总是发给那个连接; 连接重建之后 snapshot 已经被服务器释放,
+/// 读取时返回 KvError::SnapshotLost.
+///
+/// lpush 作为 Lpush 命令转发, 在远程服务器上原子地执行并返回旧的 value; 其它的 update 使用默认的先读后写实现,
+/// 不是原子的. watch 不会转发, 需要由 Watched 在本地提供.
+///
+/// 开启缓存时 get 和 contains 先读本地的 MemTable, 没有命中时再读远程服务器并写入缓存.
+/// 通过这个 Remote 的写入会让对应的 key 失效, 其它客户端的写入通过 Watch 或者 ttl 失效
+pub struct Remote {
+    conns: Vec<mpsc::UnboundedSender<Call>>,
+    next: AtomicUsize,
+    /// snapshot id -> (创建它的连接, 连接的代数)
+    snapshots: Mutex<HashMap<u64, (usize, u64)>>,
+    cache: Option<Arc<Cache>>,
+}
+
+impl Remote {
+    /// 连接远程 kv-server, 开启缓存时同时订阅 watch_tables 的变化
+    pub fn connect(config: RemoteConfig) -> Result<Self, KvError> {
+        if config.pool_size == 0 || config.timeout_ms == 0 {
+            return Err(KvError::ConfigError(
+                "Remote storage needs a positive pool_size and timeout_ms".into(),
+            ));
+        }
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..config.pool_size)
+            .map(|_| mpsc::unbounded_channel())
+            .unzip();
+        let (ready_tx, ready_rx) = std_mpsc::channel();
+        let watch_tables = config
+            .cache
+            .as_ref()
+            .map(|c| c.watch_tables.clone())
+            .unwrap_or_default();
+        let cache = config.cache.map(|c| Arc::new(Cache::new(&c)));
+
+        let worker = Worker {
+            addr: config.addr,
+            timeout: Duration::from_millis(config.timeout_ms),
+            cache: cache.clone(),
+            watch_tables,
+        };
+        thread::Builder::new()
+            .name("kv-remote".into())
+            .spawn(move || {
+                let rt = match runtime::Builder::new_current_thread().enable_all().build() {
+                    Ok(rt) => rt,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e.into()));
+                        return;
+                    }
+                };
+                rt.block_on(worker.run(receivers, ready_tx));
+            })?;
+
+        ready_rx
+            .recv()
+            .map_err(|_| KvError::Internal("Remote storage thread exited".into()))??;
+        Ok(Self {
+            conns: senders,
+            next: AtomicUsize::new(0),
+            snapshots: Mutex::default(),
+            cache,
+        })
+    }
+
+    /// 通过下一个连接发送命令并等待响应
+    fn call(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let conn = self.next.fetch_add(1, Ordering::Relaxed) % self.conns.len();
+        self.call_on(conn, cmd, None).map(|(res, _)| res)
+    }
+
+    /// 发送 snapshot 相关的命令, 只能由创建 snapshot 的连接执行
+    fn call_at(&self, id: u64, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let pinned = self.snapshots().get(&id).copied();
+        let Some((conn, generation)) = pinned else {
+            return Err(KvError::SnapshotNotFound(id));
+        };
+        let res = self.call_on(conn, cmd, Some((id, generation)));
+        if let Err(KvError::SnapshotLost(_)) = res {
+            self.snapshots().remove(&id);
+        }
+        res.map(|(res, _)| res)
+    }
+
+    /// 发送命令并等待响应, 非 200 的响应转换成 KvError::Remote
+    fn call_on(
+        &self,
+        conn: usize,
+        cmd: CommandRequest,
+        pinned: Option<(u64, u64)>,
+    ) -> Result<(CommandResponse, u64), KvError> {
+        let closed = || KvError::Internal("Remote storage thread exited".into());
+        let (reply, rx) = oneshot::channel();
+        let call = Call { cmd, pinned, reply };
+        self.conns[conn].send(call).map_err(|_| closed())?;
+        // 不依赖 tokio 的 runtime, 在异步任务中调用也不会 panic. 连接的任务保证在超时之后回复
+        let (res, generation) = executor::block_on(rx).map_err(|_| closed())??;
+        match res.status {
+            200 => Ok((res, generation)),
+            status => Err(KvError::Remote(status, res.message)),
+        }
+    }
+
+    fn snapshots(&self) -> MutexGuard<'_, HashMap<u64, (usize, u64)>> {
+        self.snapshots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn invalidate(&self, table: &str, key: &str) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(table, key);
+        }
+    }
+}
+
+impl Storage for Remote {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let epoch = match &self.cache {
+            Some(cache) => match cache.get(table, key) {
+                Some(v) => return Ok(Some(v)),
+                None => Some(cache.epoch()),
+            },
+            None => None,
+        };
+        let value = match self.call(CommandRequest::new_hget(table, key)) {
+            Ok(res) => first(res),
+            Err(e) if is_not_found(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if let (Some(cache), Some(epoch)) = (&self.cache, epoch) {
+            cache.fill(epoch, table, key, value.clone());
+        }
+        Ok(Some(value))
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let res = self.call(CommandRequest::new_hset(table, key.as_str(), value));
+        self.invalidate(table, &key);
+        Ok(optional(first(res?)))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        if self
+            .cache
+            .as_ref()
+            .is_some_and(|c| c.get(table, key).is_some())
+        {
+            return Ok(true);
+        }
+        to_bool(first(self.call(CommandRequest::new_hexist(table, key))?))
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let res = self.call(CommandRequest::new_hdel(table, key));
+        self.invalidate(table, key);
+        Ok(optional(first(res?)))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        Ok(self.call(CommandRequest::new_hgetall(table))?.pairs)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.call(CommandRequest::new_tables())?
+            .values
+            .into_iter()
+            .map(|v| match v.value {
+                Some(value::Value::String(s)) => Ok(s),
+                _ => Err(KvError::ConvertError(v, "String")),
+            })
+            .collect()
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = KvPair>>> {
+        // 迭代器无法返回错误, 先分页读出所有数据, 读取失败时由调用者处理
+        let mut pairs: Vec<KvPair> = Vec::new();
+        loop {
+            let after = pairs.last().map(|pair| pair.key.as_str());
+            let page = self.get_range(table, after, PAGE_SIZE)?;
+            let done = page.len() < PAGE_SIZE;
+            pairs.extend(page);
+            if done {
+                return Ok(Box::new(pairs.into_iter()));
+            }
+        }
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<KvPair>, KvError> {
+        // Hexport 的 limit 为 0 时使用服务器的默认值
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        let limit = limit.try_into().unwrap_or(u32::MAX);
+        let cmd = CommandRequest::new_hexport(table, after.map(str::to_string), limit);
+        Ok(self.call(cmd)?.pairs)
+    }
+
+    fn lpush(&self, table: &str, key: &str, values: Vec<Value>) -> Result<Updated, KvError> {
+        let lpush = Lpush {
+            table: table.into(),
+            key: key.into(),
+            values: values.clone(),
+            return_old: true,
+        };
+        let res = self.call(CommandRequest {
+            request_data: Some(RequestData::Lpush(lpush)),
+        });
+        self.invalidate(table, key);
+        // 服务器在长度之后返回插入之前的 list, 和插入的 values 一起得到新的 value,
+        // 不需要再读一次, 也不会读到其它客户端之后的写入
+        let old = res?.values.into_iter().nth(1);
+        let new = push_front(old.as_ref(), &values)?;
+        Ok((old, new))
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let ttl_ms = ttl.as_millis().try_into().unwrap_or(u64::MAX);
+        let res = self.call(CommandRequest::new_hexpire(table, key, ttl_ms));
+        self.invalidate(table, key);
+        to_bool(first(res?))
+    }
+
+    fn create_index(&self, table: &str, kind: IndexKind) -> Result<usize, KvError> {
+        let count = to_i64(first(
+            self.call(CommandRequest::new_create_index(table, kind))?,
+        ))?;
+        Ok(count as usize)
+    }
+
+    fn find(&self, table: &str, cond: &Condition) -> Result<Vec<KvPair>, KvError> {
+        let cmd = CommandRequest {
+            request_data: Some(RequestData::Hfind(Hfind {
+                table: table.into(),
+                condition: Some(cond.clone()),
+            })),
+        };
+        Ok(self.call(cmd)?.pairs)
+    }
+
+    fn snapshot(&self) -> Result<u64, KvError> {
+        let conn = self.next.fetch_add(1, Ordering::Relaxed) % self.conns.len();
+        let (res, generation) = self.call_on(conn, CommandRequest::new_snapshot(), None)?;
+        let id = to_i64(first(res))? as u64;
+        self.snapshots().insert(id, (conn, generation));
+        Ok(id)
+    }
+
+    fn release_snapshot(&self, id: u64) -> Result<bool, KvError> {
+        let res = self.call_at(id, CommandRequest::new_release_snapshot(id));
+        if res.is_ok() {
+            self.snapshots().remove(&id);
+        }
+        to_bool(first(res?))
+    }
+
+    fn get_at(&self, snapshot: u64, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        match self.call_at(snapshot, CommandRequest::new_hget_at(table, key, snapshot)) {
+            Ok(res) => Ok(Some(first(res))),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get_all_at(&self, snapshot: u64, table: &str) -> Result<Vec<KvPair>, KvError> {
+        let cmd = CommandRequest::new_hgetall_at(table, snapshot);
+        Ok(self.call_at(snapshot, cmd)?.pairs)
+    }
+
+    fn is_blocking(&self) -> bool {
+        true
+    }
+}
+
+/// 本地的读缓存
+struct Cache {
+    store: MemTable,
+    ttl: Duration,
+    /// 每次失效时加一. 读取远程数据期间发生过失效时, 读到的数据可能已经过时, 不写入缓存
+    epoch: Mutex<u64>,
+}
+
+impl Cache {
+    fn new(config: &CacheConfig) -> Self {
+        let store = match config.max_bytes {
+            Some(max_bytes) => MemTable::with_limit(MemoryLimit {
+                max_bytes,
+                policy: EvictionPolicy::Lru,
+            }),
+            None => MemTable::new(),
+        };
+        Self {
+            store,
+            ttl: Duration::from_millis(config.ttl_ms),
+            epoch: Mutex::new(0),
+        }
+    }
+
+    fn get(&self, table: &str, key: &str) -> Option<Value> {
+        self.store.get(table, key).ok().flatten()
+    }
+
+    fn epoch(&self) -> u64 {
+        *self.epoch.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn fill(&self, epoch: u64, table: &str, key: &str, value: Value) {
+        let current = self.epoch.lock().unwrap_or_else(PoisonError::into_inner);
+        if *current != epoch {
+            return;
+        }
+        // 写缓存失败不影响读取的结果
+        let res = self.store.set(table, key.into(), value);
+        let _ = res.and_then(|_| self.store.expire(table, key, self.ttl));
+    }
+
+    fn invalidate(&self, table: &str, key: &str) {
+        let mut epoch = self.epoch.lock().unwrap_or_else(PoisonError::into_inner);
+        *epoch += 1;
+        let _ = self.store.del(table, key);
+    }
+
+    fn invalidate_table(&self, table: &str) {
+        let mut epoch = self.epoch.lock().unwrap_or_else(PoisonError::into_inner);
+        *epoch += 1;
+        for pair in self.store.get_all(table).unwrap_or_default() {
+            let _ = self.store.del(table, &pair.key);
+        }
+    }
+}
+
+/// 运行在后台线程上, 持有到远程服务器的连接
+struct Worker {
+    addr: String,
+    timeout: Duration,
+    cache: Option<Arc<Cache>>,
+    watch_tables: Vec<String>,
+}
+
+impl Worker {
+    async fn run(
+        self,
+        receivers: Vec<mpsc::UnboundedReceiver<Call>>,
+        ready: std_mpsc::Sender<Result<(), KvError>>,
+    ) {
+        let mut conns = Vec::with_capacity(receivers.len());
+        for rx in receivers {
+            let mut conn = Conn {
+                addr: self.addr.clone(),
+                timeout: self.timeout,
+                client: None,
+                generation: 0,
+            };
+            if let Err(e) = conn.client().await {
+                let _ = ready.send(Err(e));
+                return;
+            }
+            conns.push((conn, rx));
+        }
+        if let Some(cache) = &self.cache {
+            for table in &self.watch_tables {
+                match subscribe(&self.addr, table).await {
+                    Ok(stream) => {
+                        let (addr, table) = (self.addr.clone(), table.clone());
+                        tokio::spawn(watch_table(addr, table, Arc::clone(cache), stream));
+                    }
+                    Err(e) => {
+                        let _ = ready.send(Err(e));
+                        return;
+                    }
+                }
+            }
+        }
+        info!(
+            "Connected to remote kv-server {} with {} connections",
+            self.addr,
+            conns.len()
+        );
+        let _ = ready.send(Ok(()));
+
+        // Remote 被丢弃后所有连接的 rx 都返回 None, runtime 随线程退出, Watch 的任务也一起结束
+        future::join_all(conns.into_iter().map(|(conn, rx)| conn.run(rx))).await;
+    }
+}
+
+/// 连接池中的一个连接, 依次执行发给它的请求
+struct Conn {
+    addr: String,
+    timeout: Duration,
+    client: Option<KvClient>,
+    /// 每次断开连接时加一, 之前的连接上创建的 snapshot 都已经被服务器释放
+    generation: u64,
+}
+
+impl Conn {
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Call>) {
+        while let Some(call) = rx.recv().await {
+            let res = match call.pinned {
+                Some((id, generation)) if generation != self.generation => {
+                    Err(KvError::SnapshotLost(id))
+                }
+                _ => self.execute(call.cmd).await,
+            };
+            let _ = call.reply.send(res.map(|res| (res, self.generation)));
+        }
+    }
+
+    async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let timeout = self.timeout;
+        let client = self.client().await?;
+        let res = match time::timeout(timeout, client.execute(cmd)).await {
+            Ok(res) => res,
+            Err(_) => Err(KvError::Timeout(timeout)),
+        };
+        // 出错或超时后连接上可能还有没读完的响应, 丢弃它, 下一个请求时重新连接
+        if let Err(e) = &res {
+            warn!("Remote kv-server {} failed: {}", self.addr, e);
+            self.client = None;
+            self.generation += 1;
+        }
+        res
+    }
+
+    async fn client(&mut self) -> Result<&mut KvClient, KvError> {
+        let client = match self.client.take() {
+            Some(client) => client,
+            None => match time::timeout(self.timeout, KvClient::connect(&self.addr)).await {
+                Ok(client) => client?,
+                Err(_) => return Err(KvError::Timeout(self.timeout)),
+            },
+        };
+        Ok(self.client.insert(client))
+    }
+}
+
+/// 订阅远程 table 的所有变化, 返回订阅成功之后的事件流
+async fn subscribe(
+    addr: &str,
+    table: &str,
+) -> Result<BoxStream<'static, Result<CommandResponse, KvError>>, KvError> {
+    let client = KvClient::connect(addr).await?;
+    let mut stream = client
+        .watch(CommandRequest::new_watch(table, ""))
+        .await?
+        .boxed();
+    match stream.next().await {
+        Some(Ok(res)) if res.status == 200 => Ok(stream),
+        Some(Ok(res)) => Err(KvError::Remote(res.status, res.message)),
+        Some(Err(e)) => Err(e),
+        None => Err(KvError::Internal("Connection closed by server".into())),
+    }
+}
+
+/// 根据远程 table 的变化让缓存失效, 断开后不断重试订阅
+async fn watch_table(
+    addr: String,
+    table: String,
+    cache: Arc<Cache>,
+    mut stream: BoxStream<'static, Result<CommandResponse, KvError>>,
+) {
+    loop {
+        while let Some(res) = stream.next().await {
+            match res {
+                Ok(res) if res.status == 200 => {
+                    for event in res.events {
+                        cache.invalidate(&event.table, &event.key);
+                    }
+                }
+                // 丢失了一部分事件, 不知道哪些 key 发生了变化
+                Ok(res) => {
+                    warn!("Watch on remote table {} failed: {}", table, res.message);
+                    cache.invalidate_table(&table);
+                }
+                Err(e) => {
+                    warn!("Watch on remote table {} disconnected: {}", table, e);
+                    break;
+                }
+            }
+        }
+
+        // 断开期间的变化都收不到, 重新订阅之后清空整个 table 的缓存
+        cache.invalidate_table(&table);
+        stream = loop {
+            time::sleep(RETRY_DELAY).await;
+            match subscribe(&addr, &table).await {
+                Ok(stream) => break stream,
+                Err(e) => warn!("Failed to watch remote table {}: {}", table, e),
+            }
+        };
+        cache.invalidate_table(&table);
+    }
+}
+
+/// Hget 等命令的 key 不存在, 而不是 snapshot 不存在之类的错误
+fn is_not_found(e: &KvError) -> bool {
+    matches!(e, KvError::Remote(404, msg) if msg.starts_with("Not Found"))
+}
+
+fn first(res: CommandResponse) -> Value {
+    res.values.into_iter().next().unwrap_or_default()
+}
+
+/// Hset 和 Hdel 在 key 不存在时返回空的 Value
+fn optional(v: Value) -> Option<Value> {
+    v.value.is_some().then_some(v)
+}
+
+fn to_bool(v: Value) -> Result<bool, KvError> {
+    match v.value {
+        Some(value::Value::Bool(b)) => Ok(b),
+        _ => Err(KvError::ConvertError(v, "Bool")),
+    }
+}
+
+fn to_i64(v: Value) -> Result<i64, KvError> {
+    match v.value {
+        Some(value::Value::Integer(i)) => Ok(i),
+        _ => Err(KvError::ConvertError(v, "Integer")),
+    }
+}
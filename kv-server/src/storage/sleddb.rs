@@ -0,0 +1,166 @@
+use std::{
+    ops::Bound::{Excluded, Included},
+    path::Path,
+    str,
+};
+
+use anyhow::Result;
+use course_proto::pb::abi::{KvPair, Value};
+use prost::Message;
+use sled::{Db, IVec};
+
+use crate::{
+    command::{Storage, UpdateFn, Updated},
+    error::KvError,
+};
+
+/// 基于 sled 的持久化存储, table 和 key 以 `table:key` 的形式拼接成 sled 里的 key
+#[derive(Debug)]
+pub struct SledDb(Db);
+
+impl SledDb {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        Ok(Self(sled::open(path)?))
+    }
+
+    fn get_full_key(table: &str, key: &str) -> String {
+        format!("{}:{}", table, key)
+    }
+
+    fn get_table_prefix(table: &str) -> String {
+        format!("{}:", table)
+    }
+
+    /// table 中所有 key 的上界, ';' 是 ':' 的下一个字符
+    fn get_table_end(table: &str) -> String {
+        format!("{};", table)
+    }
+}
+
+/// 把 Option<Result<T, E>> 翻转成 Result<Option<T>, E>
+fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
+    x.map_or(Ok(None), |v| v.map(Some))
+}
+
+fn decode_value(v: IVec) -> Result<Value, KvError> {
+    Ok(Value::decode(v.as_ref())?)
+}
+
+fn decode_pair(prefix_len: usize, k: IVec, v: IVec) -> Result<KvPair, KvError> {
+    let key = str::from_utf8(&k[prefix_len..]).map_err(|e| KvError::Internal(e.to_string()))?;
+    Ok(KvPair::new(key, decode_value(v)?))
+}
+
+impl Storage for SledDb {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        let result = self.0.get(name.as_bytes())?.map(decode_value);
+        flip(result)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let name = SledDb::get_full_key(table, &key);
+        let data = value.encode_to_vec();
+        let result = self.0.insert(name, data)?.map(decode_value);
+        flip(result)
+    }
+
+    fn update(&self, table: &str, key: &str, f: &mut UpdateFn<'_>) -> Result<Updated, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        let mut current = self.0.get(&name)?;
+        loop {
+            let old = flip(current.clone().map(decode_value))?;
+            let new = f(old.as_ref())?;
+            let data = new.encode_to_vec();
+            // 其它写入修改了 value 时用最新的 value 重新计算
+            match self.0.compare_and_swap(&name, current, Some(data))? {
+                Ok(()) => return Ok((old, new)),
+                Err(e) => current = e.current,
+            }
+        }
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        Ok(self.0.contains_key(name)?)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        let result = self.0.remove(name)?.map(decode_value);
+        flip(result)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        let prefix = SledDb::get_table_prefix(table);
+        self.0
+            .scan_prefix(prefix.as_bytes())
+            .map(|item| {
+                let (k, v) = item?;
+                decode_pair(prefix.len(), k, v)
+            })
+            .collect()
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = Vec::new();
+        let mut start = String::new();
+
+        // 找到一个 table 后直接跳到这个 table 的末尾, 不需要遍历所有的 key
+        while let Some(item) = self.0.range(start.as_bytes()..).next() {
+            let (k, _) = item?;
+            let key = str::from_utf8(&k).map_err(|e| KvError::Internal(e.to_string()))?;
+            let Some((table, _)) = key.split_once(':') else {
+                return Err(KvError::Internal(format!("Invalid key: {}", key)));
+            };
+            start = SledDb::get_table_end(table);
+            tables.push(table.to_string());
+        }
+        Ok(tables)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = KvPair>>> {
+        let prefix = SledDb::get_table_prefix(table);
+        let prefix_len = prefix.len();
+        let iter = self
+            .0
+            .scan_prefix(prefix.as_bytes())
+            .filter_map(move |item| {
+                item.ok()
+                    .and_then(|(k, v)| decode_pair(prefix_len, k, v).ok())
+            });
+        Ok(Box::new(iter))
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<KvPair>, KvError> {
+        let prefix = SledDb::get_table_prefix(table);
+        let start = match after {
+            Some(after) => Excluded(SledDb::get_full_key(table, after)),
+            None => Included(prefix.clone()),
+        };
+        let end = Excluded(SledDb::get_table_end(table));
+
+        self.0
+            .range::<String, _>((start, end))
+            .take(limit)
+            .map(|item| {
+                let (k, v) = item?;
+                decode_pair(prefix.len(), k, v)
+            })
+            .collect()
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.0.flush()?;
+        Ok(())
+    }
+
+    fn is_blocking(&self) -> bool {
+        true
+    }
+}
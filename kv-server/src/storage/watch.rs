@@ -0,0 +1,482 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    hash::{BuildHasher, RandomState},
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use course_proto::pb::abi::{EventKind, IndexKind, KvPair, Value, WatchEvent, hfind::Condition};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+use super::eviction::EntryKey;
+use crate::{
+    command::{Storage, UpdateFn, Updated},
+    error::KvError,
+};
+
+/// 每个订阅者最多缓存的事件数, 跟不上的订阅者会丢失最旧的事件
+const EVENT_BUFFER: usize = 1024;
+/// 写入按 key 的哈希分到这么多把锁上, 保证同一个 key 的事件和写入的顺序一致
+const WRITE_LOCKS: usize = 64;
+
+/// 一个 Watch 的订阅, 只接收 table 中以 prefix 开头的 key 的事件
+#[derive(Debug)]
+pub struct Subscription {
+    table: String,
+    prefix: String,
+    rx: broadcast::Receiver<Arc<WatchEvent>>,
+}
+
+impl Subscription {
+    /// 等待下一个事件, 存储被关闭时返回 None.
+    ///
+    /// 订阅者跟不上时返回 KvError::WatchLagged, 这时已经丢失了一部分事件,
+    /// 调用者需要重新读取数据.
+    pub async fn recv(&mut self) -> Result<Option<WatchEvent>, KvError> {
+        loop {
+            match self.rx.recv().await {
+                Ok(event) if event.table == self.table && event.key.starts_with(&self.prefix) => {
+                    return Ok(Some(event.as_ref().clone()));
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(n)) => return Err(KvError::WatchLagged(n)),
+                Err(RecvError::Closed) => return Ok(None),
+            }
+        }
+    }
+}
+
+/// 给任意的 Storage 加上 Watch 的能力, 所有的写入都会产生对应的事件
+///
+/// 事件在 set, del 和 expire 的路径上产生, 和底层存储无关. 过期事件由一个后台线程
+/// 在 key 到期时发出; MemTable 因为内存不足淘汰的 key 不会产生事件.
+#[derive(Debug)]
+pub struct Watched<S> {
+    /// 后台线程通过 Weak 访问存储, 不会推迟存储的释放
+    inner: Arc<S>,
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    tx: broadcast::Sender<Arc<WatchEvent>>,
+    expiring: Mutex<ExpireQueue>,
+    wakeup: Condvar,
+    closed: AtomicBool,
+    write_locks: Vec<Mutex<()>>,
+    hasher: RandomState,
+}
+
+/// 设置了过期时间的 key, 以及设置过期时间时的 value
+#[derive(Debug, Default)]
+struct ExpireQueue {
+    deadlines: BTreeSet<(Instant, EntryKey)>,
+    entries: HashMap<EntryKey, (Instant, Value)>,
+}
+
+impl ExpireQueue {
+    fn cancel(&mut self, key: &EntryKey) {
+        if let Some((deadline, _)) = self.entries.remove(key) {
+            self.deadlines.remove(&(deadline, key.clone()));
+        }
+    }
+
+    fn insert(&mut self, key: EntryKey, deadline: Instant, value: Value) {
+        self.cancel(&key);
+        self.deadlines.insert((deadline, key.clone()));
+        self.entries.insert(key, (deadline, value));
+    }
+
+    /// 最早到期的 key, 还没有 key 到期时返回 None
+    fn first_expired(&self, now: Instant) -> Option<(Instant, EntryKey)> {
+        self.deadlines
+            .first()
+            .filter(|(deadline, _)| *deadline <= now)
+            .cloned()
+    }
+}
+
+impl Shared {
+    fn publish(
+        &self,
+        kind: EventKind,
+        (table, key): EntryKey,
+        old: Option<Value>,
+        new: Option<Value>,
+    ) {
+        // 没有订阅者时 send 会返回错误, 直接忽略
+        let _ = self.tx.send(Arc::new(WatchEvent {
+            kind: kind.into(),
+            table,
+            key,
+            old_value: old,
+            new_value: new,
+        }));
+    }
+
+    /// 对同一个 key 的写入和事件发布需要在同一把锁下进行, 否则订阅者看到的顺序可能和实际不同
+    fn lock_key(&self, table: &str, key: &str) -> MutexGuard<'_, ()> {
+        let i = self.hasher.hash_one((table, key)) as usize % WRITE_LOCKS;
+        self.write_locks[i]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn queue(&self) -> MutexGuard<'_, ExpireQueue> {
+        self.expiring.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// 后台线程: 等到最早的 key 过期时, 确认它确实过期之后发出 Expire 事件
+    fn run_expirer<S: Storage>(&self, inner: Weak<S>) {
+        let mut queue = self.queue();
+        while !self.closed.load(Ordering::Acquire) {
+            let now = Instant::now();
+            if let Some((deadline, key)) = queue.first_expired(now) {
+                // 写入先拿 key 的锁再拿 queue 的锁, 这里也按照同样的顺序加锁
+                drop(queue);
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                self.expire_key(inner.as_ref(), deadline, key);
+                queue = self.queue();
+                continue;
+            }
+
+            queue = match queue.deadlines.first() {
+                Some((deadline, _)) => {
+                    let timeout = deadline.saturating_duration_since(now);
+                    self.wakeup
+                        .wait_timeout(queue, timeout)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .wakeup
+                    .wait(queue)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+    }
+
+    /// 在 key 的锁下确认到期之后没有新的写入, 并且存储中的 key 已经过期删除, 然后才发出 Expire 事件
+    fn expire_key<S: Storage>(&self, inner: &S, deadline: Instant, key: EntryKey) {
+        let _guard = self.lock_key(&key.0, &key.1);
+        let value = {
+            let mut queue = self.queue();
+            queue.deadlines.remove(&(deadline, key.clone()));
+            // 写入和删除会取消过期时间, 重新设置过期时间会改变 deadline
+            match queue.entries.get(&key) {
+                Some((d, _)) if *d == deadline => queue.entries.remove(&key).map(|(_, v)| v),
+                _ => None,
+            }
+        };
+        let Some(value) = value else {
+            return;
+        };
+
+        // 存储读取已经过期的 key 时会把它删除, 读不到才说明 key 确实过期了
+        match inner.contains(&key.0, &key.1) {
+            Ok(false) => self.publish(EventKind::Expire, key, Some(value), None),
+            Ok(true) => {}
+            Err(e) => warn!("Failed to expire {:?}: {}", key, e),
+        }
+    }
+}
+
+impl<S: Storage> Watched<S> {
+    pub fn new(inner: S) -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUFFER);
+        let shared = Arc::new(Shared {
+            tx,
+            expiring: Mutex::default(),
+            wakeup: Condvar::new(),
+            closed: AtomicBool::new(false),
+            write_locks: (0..WRITE_LOCKS).map(|_| Mutex::new(())).collect(),
+            hasher: RandomState::new(),
+        });
+        let inner = Arc::new(inner);
+
+        let expirer = Arc::clone(&shared);
+        let store = Arc::downgrade(&inner);
+        thread::Builder::new()
+            .name("kv-watch-expirer".into())
+            .spawn(move || expirer.run_expirer(store))
+            .expect("Failed to spawn watch expirer thread");
+
+        Self { inner, shared }
+    }
+
+    fn lock_key(&self, table: &str, key: &str) -> MutexGuard<'_, ()> {
+        self.shared.lock_key(table, key)
+    }
+
+    fn cancel_expire(&self, key: &EntryKey) {
+        self.shared.queue().cancel(key);
+    }
+
+    /// 在 key 的锁下修改 value 并发布事件, 用于 update 和 lpush
+    fn modify(
+        &self,
+        table: &str,
+        key: &str,
+        f: impl FnOnce(&S) -> Result<Updated, KvError>,
+    ) -> Result<Updated, KvError> {
+        let _guard = self.lock_key(table, key);
+        let (old, new) = f(&self.inner)?;
+        let key = (table.to_string(), key.to_string());
+        // 修改 value 会保留过期时间, 过期事件里需要带上新的 value
+        {
+            let mut queue = self.shared.queue();
+            if let Some((_, value)) = queue.entries.get_mut(&key) {
+                *value = new.clone();
+            }
+        }
+        self.shared
+            .publish(EventKind::Set, key, old.clone(), Some(new.clone()));
+        Ok((old, new))
+    }
+}
+
+impl<S> Drop for Watched<S> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+        // 持有锁再通知, 避免后台线程在检查 closed 之后, 开始等待之前错过通知
+        let _queue = self.shared.queue();
+        self.shared.wakeup.notify_all();
+    }
+}
+
+impl<S: Storage> Storage for Watched<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let _guard = self.lock_key(table, &key);
+        let old = self.inner.set(table, key.clone(), value.clone())?;
+        let key = (table.to_string(), key);
+        // 写入会清除过期时间
+        self.cancel_expire(&key);
+        self.shared
+            .publish(EventKind::Set, key, old.clone(), Some(value));
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let _guard = self.lock_key(table, key);
+        let old = self.inner.del(table, key)?;
+        let key = (table.to_string(), key.to_string());
+        self.cancel_expire(&key);
+        if old.is_some() {
+            self.shared
+                .publish(EventKind::Delete, key, old.clone(), None);
+        }
+        Ok(old)
+    }
+
+    fn update(&self, table: &str, key: &str, f: &mut UpdateFn<'_>) -> Result<Updated, KvError> {
+        self.modify(table, key, |inner| inner.update(table, key, f))
+    }
+
+    fn lpush(&self, table: &str, key: &str, values: Vec<Value>) -> Result<Updated, KvError> {
+        self.modify(table, key, |inner| inner.lpush(table, key, values))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.inner.tables()
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = KvPair>>> {
+        self.inner.get_iter(table)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_range(table, after, limit)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let _guard = self.lock_key(table, key);
+        let Some(value) = self.inner.get(table, key)? else {
+            return Ok(false);
+        };
+        if !self.inner.expire(table, key, ttl)? {
+            return Ok(false);
+        }
+
+        let deadline = Instant::now() + ttl;
+        let key = (table.to_string(), key.to_string());
+        self.shared.queue().insert(key, deadline, value);
+        self.shared.wakeup.notify_all();
+        Ok(true)
+    }
+
+    fn snapshot(&self) -> Result<u64, KvError> {
+        self.inner.snapshot()
+    }
+
+    fn release_snapshot(&self, id: u64) -> Result<bool, KvError> {
+        self.inner.release_snapshot(id)
+    }
+
+    fn get_at(&self, snapshot: u64, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get_at(snapshot, table, key)
+    }
+
+    fn get_all_at(&self, snapshot: u64, table: &str) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_all_at(snapshot, table)
+    }
+
+    fn create_index(&self, table: &str, kind: IndexKind) -> Result<usize, KvError> {
+        self.inner.create_index(table, kind)
+    }
+
+    fn find(&self, table: &str, cond: &Condition) -> Result<Vec<KvPair>, KvError> {
+        self.inner.find(table, cond)
+    }
+
+    fn watch(&self, table: &str, prefix: &str) -> Result<Subscription, KvError> {
+        Ok(Subscription {
+            table: table.into(),
+            prefix: prefix.into(),
+            rx: self.shared.tx.subscribe(),
+        })
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.inner.flush()
+    }
+
+    fn is_blocking(&self) -> bool {
+        self.inner.is_blocking()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::storage::memory::MemTable;
+
+    fn event(kind: EventKind, key: &str, old: Option<Value>, new: Option<Value>) -> WatchEvent {
+        WatchEvent {
+            kind: kind.into(),
+            table: "t1".into(),
+            key: key.into(),
+            old_value: old,
+            new_value: new,
+        }
+    }
+
+    async fn next(sub: &mut Subscription) -> WatchEvent {
+        timeout(Duration::from_secs(1), sub.recv())
+            .await
+            .expect("no event received")
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn set_and_del_should_publish_events() {
+        let store = Watched::new(MemTable::new());
+        let mut sub = store.watch("t1", "").unwrap();
+
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k1".into(), "v2".into()).unwrap();
+        store.del("t1", "k1").unwrap();
+        // 删除不存在的 key 不会产生事件
+        store.del("t1", "k1").unwrap();
+        store.set("t1", "k2".into(), 42.into()).unwrap();
+
+        let expected = [
+            event(EventKind::Set, "k1", None, Some("v1".into())),
+            event(EventKind::Set, "k1", Some("v1".into()), Some("v2".into())),
+            event(EventKind::Delete, "k1", Some("v2".into()), None),
+            event(EventKind::Set, "k2", None, Some(42.into())),
+        ];
+        for e in expected {
+            assert_eq!(next(&mut sub).await, e);
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_should_filter_by_table_and_prefix() {
+        let store = Watched::new(MemTable::new());
+        let mut sub = store.watch("t1", "user:").unwrap();
+
+        store.set("t2", "user:1".into(), 1.into()).unwrap();
+        store.set("t1", "order:1".into(), 1.into()).unwrap();
+        store.set("t1", "user:2".into(), 2.into()).unwrap();
+
+        let e = next(&mut sub).await;
+        assert_eq!(e, event(EventKind::Set, "user:2", None, Some(2.into())));
+    }
+
+    #[tokio::test]
+    async fn expire_should_publish_event() {
+        let store = Watched::new(MemTable::new());
+        let mut sub = store.watch("t1", "").unwrap();
+
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert!(store.expire("t1", "k1", Duration::from_millis(20)).unwrap());
+        assert!(store.expire("t1", "k2", Duration::from_millis(10)).unwrap());
+        // 重新写入会取消过期
+        store.set("t1", "k2".into(), "v3".into()).unwrap();
+
+        next(&mut sub).await;
+        next(&mut sub).await;
+        let e = next(&mut sub).await;
+        assert_eq!(e.key, "k2");
+        let e = next(&mut sub).await;
+        assert_eq!(e, event(EventKind::Expire, "k1", Some("v1".into()), None));
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn expire_should_not_be_published_for_rewritten_key() {
+        let store = Watched::new(MemTable::new());
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert!(store.expire("t1", "k1", Duration::from_millis(10)).unwrap());
+        let mut sub = store.watch("t1", "").unwrap();
+
+        // 持有 key 的锁, 让后台线程在 key 到期之后等待, 期间绕过 Watched 重新写入 key
+        {
+            let _guard = store.lock_key("t1", "k1");
+            thread::sleep(Duration::from_millis(30));
+            store.inner.set("t1", "k1".into(), "v2".into()).unwrap();
+        }
+        let res = timeout(Duration::from_millis(100), sub.recv()).await;
+        assert!(res.is_err(), "unexpected event {:?}", res);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v2".into()));
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_should_see_lagged_error() {
+        let store = Watched::new(MemTable::new());
+        let mut sub = store.watch("t1", "").unwrap();
+        for i in 0..EVENT_BUFFER + 1 {
+            store.set("t1", format!("k{}", i), 1.into()).unwrap();
+        }
+        assert_eq!(sub.recv().await.unwrap_err(), KvError::WatchLagged(1));
+    }
+}
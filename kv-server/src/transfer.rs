@@ -0,0 +1,361 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Lines, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use bytes::Bytes;
+use course_proto::pb::abi::{KvPair, Value, value};
+use prost::Message;
+use serde_json::json;
+
+use crate::{
+    cli::{decode_hex, encode_hex, format_value, parse_value},
+    error::KvError,
+};
+
+/// protobuf 文件中一个 KvPair 的最大长度, 和网络上一帧的默认上限相同
+const MAX_PAIR_SIZE: u64 = 8 * 1024 * 1024;
+
+/// 导入导出 table 时支持的文件格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// 每行一个 json 对象: `{"key": "k1", "value": {"integer": 42}}`
+    Jsonl,
+    /// 两列 `key,value`, value 使用 kv-cli 的带类型字面量
+    Csv,
+    /// 一组 length delimited 的 KvPair protobuf 消息
+    Protobuf,
+}
+
+impl FromStr for Format {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jsonl" | "ndjson" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            "pb" | "protobuf" => Ok(Format::Protobuf),
+            _ => Err(KvError::InvalidCommand(format!("Unknown format `{}`", s))),
+        }
+    }
+}
+
+impl Format {
+    /// 根据文件的扩展名推断格式
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?;
+        ext.parse().ok()
+    }
+}
+
+/// 按照指定格式写出 KvPair
+pub enum PairWriter<W: Write> {
+    Jsonl(W),
+    Csv(Box<csv::Writer<W>>),
+    Protobuf(W),
+}
+
+impl<W: Write> PairWriter<W> {
+    pub fn new(format: Format, inner: W) -> Result<Self, KvError> {
+        let writer = match format {
+            Format::Jsonl => Self::Jsonl(inner),
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(inner);
+                writer.write_record(["key", "value"]).map_err(csv_error)?;
+                Self::Csv(Box::new(writer))
+            }
+            Format::Protobuf => Self::Protobuf(inner),
+        };
+        Ok(writer)
+    }
+
+    pub fn write(&mut self, pair: &KvPair) -> Result<(), KvError> {
+        let value = pair.value.clone().unwrap_or_default();
+        match self {
+            Self::Jsonl(w) => {
+                let line = json!({ "key": pair.key, "value": value_to_json(&value) });
+                writeln!(w, "{}", line)?;
+            }
+            Self::Csv(w) => w
+                .write_record([pair.key.as_str(), &format_value(&value)])
+                .map_err(csv_error)?,
+            Self::Protobuf(w) => w.write_all(&pair.encode_length_delimited_to_vec())?,
+        }
+        Ok(())
+    }
+
+    /// 刷新缓冲区并返回内部的 writer
+    pub fn finish(self) -> Result<W, KvError> {
+        let mut inner = match self {
+            Self::Jsonl(w) | Self::Protobuf(w) => w,
+            Self::Csv(w) => w
+                .into_inner()
+                .map_err(|e| KvError::ParseError("csv", e.to_string()))?,
+        };
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+/// 按照指定格式读取 KvPair
+pub enum PairReader<R: BufRead> {
+    Jsonl(Lines<R>),
+    Csv(csv::StringRecordsIntoIter<R>),
+    Protobuf(R),
+}
+
+impl<R: BufRead> PairReader<R> {
+    pub fn new(format: Format, inner: R) -> Self {
+        match format {
+            Format::Jsonl => Self::Jsonl(inner.lines()),
+            Format::Csv => Self::Csv(csv::Reader::from_reader(inner).into_records()),
+            Format::Protobuf => Self::Protobuf(inner),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for PairReader<R> {
+    type Item = Result<KvPair, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Jsonl(lines) => {
+                let line = match lines.find(|l| !matches!(l, Ok(l) if l.trim().is_empty()))? {
+                    Ok(line) => line,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                Some(pair_from_json(&line))
+            }
+            Self::Csv(records) => {
+                let record = match records.next()? {
+                    Ok(record) => record,
+                    Err(e) => return Some(Err(csv_error(e))),
+                };
+                match (record.get(0), record.get(1)) {
+                    (Some(key), Some(value)) => {
+                        Some(parse_value(value).map(|value| KvPair::new(key, value)))
+                    }
+                    _ => Some(Err(KvError::ParseError("csv", format!("{:?}", record)))),
+                }
+            }
+            Self::Protobuf(r) => read_length_delimited(r).transpose(),
+        }
+    }
+}
+
+/// 把 Value 转换成 json, 类型信息保存在 key 里, 确保能够无损地转换回来
+pub fn value_to_json(v: &Value) -> serde_json::Value {
+    match &v.value {
+        Some(value::Value::String(s)) => json!({ "string": s }),
+        Some(value::Value::Binary(buf)) => json!({ "binary": encode_hex(buf) }),
+        Some(value::Value::Integer(i)) => json!({ "integer": i }),
+        // json 不能表示 NaN 和无穷大, 用字符串保存
+        Some(value::Value::Float(f)) if !f.is_finite() => json!({ "float": f.to_string() }),
+        Some(value::Value::Float(f)) => json!({ "float": f }),
+        Some(value::Value::Bool(b)) => json!({ "bool": b }),
+        Some(value::Value::Timestamp(t)) => json!({ "timestamp": t }),
+        Some(value::Value::List(list)) => {
+            json!({ "list": list.values.iter().map(value_to_json).collect::<Vec<_>>() })
+        }
+        Some(value::Value::Map(map)) => {
+            let fields: serde_json::Map<_, _> = map
+                .fields
+                .iter()
+                .map(|(k, v)| (k.clone(), value_to_json(v)))
+                .collect();
+            json!({ "map": fields })
+        }
+        None => serde_json::Value::Null,
+    }
+}
+
+/// 把 value_to_json 得到的 json 转换回 Value
+pub fn value_from_json(v: &serde_json::Value) -> Result<Value, KvError> {
+    let invalid = || KvError::ParseError("json", v.to_string());
+    if v.is_null() {
+        return Ok(Value::default());
+    }
+
+    let (kind, inner) = v
+        .as_object()
+        .filter(|o| o.len() == 1)
+        .and_then(|o| o.iter().next())
+        .ok_or_else(invalid)?;
+
+    let value = match (kind.as_str(), inner) {
+        ("string", serde_json::Value::String(s)) => s.as_str().into(),
+        ("binary", serde_json::Value::String(s)) => {
+            Bytes::from(decode_hex(s).ok_or_else(invalid)?).into()
+        }
+        ("integer", v) => v.as_i64().ok_or_else(invalid)?.into(),
+        ("float", serde_json::Value::String(s)) => s.parse::<f64>().map_err(|_| invalid())?.into(),
+        ("float", v) => v.as_f64().ok_or_else(invalid)?.into(),
+        ("bool", serde_json::Value::Bool(b)) => (*b).into(),
+        ("timestamp", v) => Value {
+            value: Some(value::Value::Timestamp(v.as_i64().ok_or_else(invalid)?)),
+        },
+        ("list", serde_json::Value::Array(values)) => values
+            .iter()
+            .map(value_from_json)
+            .collect::<Result<Vec<_>, _>>()?
+            .into(),
+        ("map", serde_json::Value::Object(fields)) => fields
+            .iter()
+            .map(|(k, v)| Ok((k.clone(), value_from_json(v)?)))
+            .collect::<Result<BTreeMap<_, _>, KvError>>()?
+            .into(),
+        _ => return Err(invalid()),
+    };
+    Ok(value)
+}
+
+fn pair_from_json(line: &str) -> Result<KvPair, KvError> {
+    let v: serde_json::Value =
+        serde_json::from_str(line).map_err(|e| KvError::ParseError("json", e.to_string()))?;
+    let key = v["key"]
+        .as_str()
+        .ok_or_else(|| KvError::ParseError("json", line.into()))?;
+    Ok(KvPair::new(key, value_from_json(&v["value"])?))
+}
+
+/// 从流中读取一个 length delimited 的 KvPair, 到达末尾时返回 None
+fn read_length_delimited(r: &mut impl BufRead) -> Result<Option<KvPair>, KvError> {
+    // 先读出 varint 编码的长度, 最多 10 个字节
+    let mut len: u64 = 0;
+    for i in 0..10 {
+        let mut byte = [0u8; 1];
+        if r.read(&mut byte)? == 0 {
+            return match i {
+                0 => Ok(None),
+                _ => Err(KvError::ParseError("protobuf", "truncated length".into())),
+            };
+        }
+        len |= ((byte[0] & 0x7f) as u64) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            // 长度来自文件, 分配之前先检查, 避免损坏的文件导致分配巨大的内存
+            if len > MAX_PAIR_SIZE {
+                return Err(KvError::ParseError(
+                    "protobuf",
+                    format!("length {} exceeds {}", len, MAX_PAIR_SIZE),
+                ));
+            }
+            let mut buf = vec![0u8; len as usize];
+            r.read_exact(&mut buf)?;
+            return Ok(Some(KvPair::decode(&buf[..])?));
+        }
+    }
+    Err(KvError::ParseError("protobuf", "invalid length".into()))
+}
+
+fn csv_error(e: csv::Error) -> KvError {
+    KvError::ParseError("csv", e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::SystemTime};
+
+    use super::*;
+
+    fn all_kinds_of_pairs() -> Vec<KvPair> {
+        vec![
+            KvPair::new("string", "hello, \"world\"\nline 2".into()),
+            KvPair::new("typed string", "i:42".into()),
+            KvPair::new("binary", Bytes::from_static(b"\x00\xde\xad\xbe\xef").into()),
+            KvPair::new("integer", i64::MIN.into()),
+            KvPair::new("float", 0.1.into()),
+            KvPair::new("tiny float", 5e-324.into()),
+            KvPair::new("negative zero", (-0.0).into()),
+            KvPair::new("infinity", f64::INFINITY.into()),
+            KvPair::new("bool", false.into()),
+            KvPair::new("timestamp", SystemTime::UNIX_EPOCH.into()),
+            KvPair::new("list", vec![1.into(), "a".into(), vec![].into()].into()),
+            KvPair::new(
+                "map",
+                BTreeMap::from([
+                    ("name".to_string(), "raku".into()),
+                    ("tags".to_string(), vec!["perl".into()].into()),
+                    ("meta".to_string(), BTreeMap::new().into()),
+                ])
+                .into(),
+            ),
+            KvPair::new("", "".into()),
+        ]
+    }
+
+    fn round_trip(format: Format, pairs: &[KvPair]) -> Vec<KvPair> {
+        let mut writer = PairWriter::new(format, Vec::new()).unwrap();
+        for pair in pairs {
+            writer.write(pair).unwrap();
+        }
+        let buf = writer.finish().unwrap();
+
+        PairReader::new(format, Cursor::new(buf))
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn assert_same(left: &[KvPair], right: &[KvPair]) {
+        // -0.0 == 0.0, 所以用编码后的字节来比较
+        let encode = |pairs: &[KvPair]| pairs.iter().map(|p| p.encode_to_vec()).collect::<Vec<_>>();
+        assert_eq!(encode(left), encode(right));
+    }
+
+    #[test]
+    fn jsonl_should_round_trip() {
+        let pairs = all_kinds_of_pairs();
+        assert_same(&round_trip(Format::Jsonl, &pairs), &pairs);
+    }
+
+    #[test]
+    fn csv_should_round_trip() {
+        let pairs = all_kinds_of_pairs();
+        assert_same(&round_trip(Format::Csv, &pairs), &pairs);
+    }
+
+    #[test]
+    fn protobuf_should_round_trip() {
+        let pairs = all_kinds_of_pairs();
+        assert_same(&round_trip(Format::Protobuf, &pairs), &pairs);
+    }
+
+    #[test]
+    fn nan_should_round_trip() {
+        let pairs = vec![KvPair::new("nan", f64::NAN.into())];
+        for format in [Format::Jsonl, Format::Csv, Format::Protobuf] {
+            let result = round_trip(format, &pairs);
+            match &result[0].value.as_ref().unwrap().value {
+                Some(value::Value::Float(f)) => assert!(f.is_nan()),
+                v => panic!("expect NaN, got {:?}", v),
+            }
+        }
+    }
+
+    #[test]
+    fn format_should_be_inferred_from_path() {
+        assert_eq!(Format::from_path("a/b.jsonl"), Some(Format::Jsonl));
+        assert_eq!(Format::from_path("b.CSV"), Some(Format::Csv));
+        assert_eq!(Format::from_path("b.pb"), Some(Format::Protobuf));
+        assert_eq!(Format::from_path("b.txt"), None);
+    }
+
+    #[test]
+    fn invalid_record_should_fail() {
+        let reader = PairReader::new(Format::Jsonl, Cursor::new("{\"key\": 1}\n"));
+        assert!(reader.collect::<Result<Vec<_>, _>>().is_err());
+
+        let buf = Cursor::new(vec![0x05, 0x01]);
+        let reader = PairReader::new(Format::Protobuf, buf);
+        assert!(reader.collect::<Result<Vec<_>, _>>().is_err());
+
+        // 长度超过上限时不会按照长度分配内存
+        let buf = Cursor::new(vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]);
+        let mut reader = PairReader::new(Format::Protobuf, buf);
+        assert!(matches!(
+            reader.next(),
+            Some(Err(KvError::ParseError("protobuf", _)))
+        ));
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::Result;
+use course_proto::pb::abi::CommandRequest;
+use kv_server::{
+    client::KvClient,
+    command::Storage,
+    error::KvError,
+    server::{Listener, Server},
+    service::Service,
+    storage::{memory::MemTable, sleddb::SledDb},
+};
+use tempfile::tempdir;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+    task::JoinHandle,
+    time,
+};
+
+type ServerHandle = JoinHandle<Result<(), KvError>>;
+
+async fn start_server(store: SledDb) -> Result<(SocketAddr, oneshot::Sender<()>, ServerHandle)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (tx, rx) = oneshot::channel();
+
+    let server = Server::new(Service::new(store), Duration::from_secs(5));
+    let handle = tokio::spawn(server.run(listener, async {
+        rx.await.ok();
+    }));
+    Ok((addr, tx, handle))
+}
+
+/// 不断写入数据, 直到服务器关闭连接, 返回所有收到成功响应的 key
+async fn write_until_closed(addr: SocketAddr, client_id: usize) -> Vec<(String, i64)> {
+    let mut acked = Vec::new();
+    let Ok(mut client) = KvClient::connect(addr).await else {
+        return acked;
+    };
+
+    for i in 0.. {
+        let key = format!("c{}-k{}", client_id, i);
+        match client
+            .execute(CommandRequest::new_hset("t1", key.clone(), i.into()))
+            .await
+        {
+            Ok(res) if res.status == 200 => acked.push((key, i)),
+            _ => break,
+        }
+    }
+    acked
+}
+
+#[tokio::test]
+async fn acknowledged_writes_should_survive_restart() -> Result<()> {
+    let dir = tempdir()?;
+    let (addr, shutdown, server) = start_server(SledDb::new(dir.path())?).await?;
+
+    let writers: Vec<_> = (0..4)
+        .map(|id| tokio::spawn(write_until_closed(addr, id)))
+        .collect();
+
+    // 让客户端写一会儿, 然后模拟收到 SIGTERM
+    time::sleep(Duration::from_millis(200)).await;
+    shutdown.send(()).unwrap();
+    server.await??;
+
+    let mut acked = Vec::new();
+    for writer in writers {
+        acked.extend(writer.await?);
+    }
+    assert!(!acked.is_empty());
+
+    // 服务器退出后所有的连接都已关闭, 此时可以重新打开同一个数据库
+    let store = SledDb::new(dir.path())?;
+    for (key, i) in acked {
+        assert_eq!(store.get("t1", &key)?, Some(i.into()), "lost write {}", key);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_should_stop_accepting_after_shutdown() -> Result<()> {
+    let dir = tempdir()?;
+    let (addr, shutdown, server) = start_server(SledDb::new(dir.path())?).await?;
+
+    let mut client = KvClient::connect(addr).await?;
+    let res = client
+        .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+        .await?;
+    assert_eq!(res.status, 200);
+
+    shutdown.send(()).unwrap();
+    server.await??;
+
+    // 已有连接被关闭, 新连接也无法建立
+    assert!(
+        client
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .await
+            .is_err()
+    );
+    assert!(KvClient::connect(addr).await.is_err());
+    Ok(())
+}
+
+/// 前几次 accept 返回错误的 listener, 模拟文件描述符耗尽
+struct FlakyListener {
+    inner: TcpListener,
+    failures: AtomicUsize,
+}
+
+impl Listener for FlakyListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        if self
+            .failures
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return Err(io::Error::other("Too many open files"));
+        }
+        self.inner.accept().await
+    }
+}
+
+#[tokio::test]
+async fn server_should_keep_accepting_after_accept_error() -> Result<()> {
+    let inner = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = inner.local_addr()?;
+    let listener = FlakyListener {
+        inner,
+        failures: AtomicUsize::new(3),
+    };
+    let (tx, rx) = oneshot::channel();
+    let server = Server::new(Service::new(MemTable::new()), Duration::from_secs(5));
+    let server = tokio::spawn(server.run(listener, async {
+        rx.await.ok();
+    }));
+
+    let mut client = KvClient::connect(addr).await?;
+    let res = client
+        .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+        .await?;
+    assert_eq!(res.status, 200);
+
+    tx.send(()).unwrap();
+    server.await??;
+    Ok(())
+}
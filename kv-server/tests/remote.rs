@@ -0,0 +1,246 @@
+use std::{net::SocketAddr, thread, time::Duration};
+
+use anyhow::Result;
+use course_proto::pb::abi::{CommandRequest, CommandResponse, KvPair, Value};
+use kv_server::{
+    client::KvClient,
+    command::Storage,
+    error::KvError,
+    server::Server,
+    service::Service,
+    storage::{
+        memory::MemTable,
+        remote::{CacheConfig, Remote, RemoteConfig},
+        watch::Watched,
+    },
+};
+use tokio::{net::TcpListener, runtime::Runtime, sync::oneshot};
+
+// Remote 的调用会阻塞当前线程, 所以服务器运行在单独的 runtime 上, 测试本身不在 runtime 中执行
+fn start_server(rt: &Runtime, service: Service<impl Storage>) -> Result<SocketAddr> {
+    let listener = rt.block_on(TcpListener::bind("127.0.0.1:0"))?;
+    let addr = listener.local_addr()?;
+    let server = Server::new(service, Duration::from_secs(1));
+    rt.spawn(server.run(listener, futures::future::pending()));
+    Ok(addr)
+}
+
+fn connect(addr: SocketAddr, cache: Option<CacheConfig>) -> Result<Remote> {
+    let config = RemoteConfig {
+        cache,
+        ..RemoteConfig::new(addr.to_string())
+    };
+    Ok(Remote::connect(config)?)
+}
+
+/// 绕过 Remote 直接在服务器上执行命令
+fn execute(rt: &Runtime, addr: SocketAddr, cmd: CommandRequest) -> Result<CommandResponse> {
+    rt.block_on(async {
+        let mut client = KvClient::connect(addr).await?;
+        Ok(client.execute(cmd).await?)
+    })
+}
+
+#[test]
+fn remote_should_proxy_storage_calls() -> Result<()> {
+    let rt = Runtime::new()?;
+    let addr = start_server(&rt, Service::new(MemTable::new()))?;
+    let remote = connect(addr, None)?;
+    assert!(remote.is_blocking());
+
+    assert_eq!(remote.set("t1", "k1".into(), "v1".into())?, None);
+    assert_eq!(
+        remote.set("t1", "k1".into(), "v2".into())?,
+        Some("v1".into())
+    );
+    assert_eq!(remote.get("t1", "k1")?, Some("v2".into()));
+    assert_eq!(remote.get("t1", "k2")?, None);
+    assert!(remote.contains("t1", "k1")?);
+    assert_eq!(remote.tables()?, ["t1"]);
+
+    for i in 0..1500 {
+        remote.set("t2", format!("k{:04}", i), (i as i64).into())?;
+    }
+    assert_eq!(remote.get_iter("t2")?.count(), 1500);
+    let range = remote.get_range("t2", Some("k0100"), 2)?;
+    assert_eq!(
+        range,
+        [
+            KvPair::new("k0101", 101.into()),
+            KvPair::new("k0102", 102.into())
+        ]
+    );
+
+    let id = remote.snapshot()?;
+    assert_eq!(remote.del("t1", "k1")?, Some("v2".into()));
+    assert_eq!(remote.del("t1", "k1")?, None);
+    assert_eq!(remote.get_at(id, "t1", "k1")?, Some("v2".into()));
+    assert!(remote.release_snapshot(id)?);
+    assert_eq!(
+        remote.get_at(id, "t1", "k1"),
+        Err(KvError::SnapshotNotFound(id))
+    );
+
+    // 和 Redis 一样最后一个 value 在最前面, 整个 Lpush 在远程服务器上执行
+    let (old, new) = remote.lpush("t3", "l1", vec![1.into(), 2.into()])?;
+    assert_eq!(old, None);
+    assert_eq!(new, vec![Value::from(2), 1.into()].into());
+    let (old, new) = remote.lpush("t3", "l1", vec![3.into()])?;
+    assert_eq!(old, Some(vec![Value::from(2), 1.into()].into()));
+    assert_eq!(new, vec![Value::from(3), 2.into(), 1.into()].into());
+    Ok(())
+}
+
+#[test]
+fn snapshot_should_be_read_through_its_connection() -> Result<()> {
+    let rt = Runtime::new()?;
+    let addr = start_server(&rt, Service::new(MemTable::new()))?;
+    let remote = connect(addr, None)?;
+
+    remote.set("t1", "k1".into(), "v1".into())?;
+    let id = remote.snapshot()?;
+    remote.set("t1", "k1".into(), "v2".into())?;
+    // 请求轮流使用连接池中的连接, snapshot 的读取总是发给创建它的连接
+    for _ in 0..8 {
+        assert_eq!(remote.get_at(id, "t1", "k1")?, Some("v1".into()));
+        assert_eq!(remote.get("t1", "k1")?, Some("v2".into()));
+    }
+    assert_eq!(
+        remote.get_all_at(id, "t1")?,
+        [KvPair::new("k1", "v1".into())]
+    );
+    assert!(remote.release_snapshot(id)?);
+    Ok(())
+}
+
+#[test]
+fn reconnect_should_report_lost_snapshots() -> Result<()> {
+    let rt = Runtime::new()?;
+    let listener = rt.block_on(TcpListener::bind("127.0.0.1:0"))?;
+    let addr = listener.local_addr()?;
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = Server::new(Service::new(MemTable::new()), Duration::from_secs(1));
+    let handle = rt.spawn(server.run(listener, async {
+        let _ = stopped.await;
+    }));
+
+    let config = RemoteConfig {
+        pool_size: 1,
+        ..RemoteConfig::new(addr.to_string())
+    };
+    let remote = Remote::connect(config)?;
+    remote.set("t1", "k1".into(), "v1".into())?;
+    let id = remote.snapshot()?;
+
+    // 服务器关闭时断开了连接, snapshot 也随之被释放
+    let _ = stop.send(());
+    rt.block_on(handle)??;
+    assert!(remote.get("t1", "k1").is_err());
+    assert_eq!(
+        remote.get_at(id, "t1", "k1"),
+        Err(KvError::SnapshotLost(id))
+    );
+    assert_eq!(
+        remote.get_at(id, "t1", "k1"),
+        Err(KvError::SnapshotNotFound(id))
+    );
+    Ok(())
+}
+
+#[test]
+fn slow_server_should_time_out() -> Result<()> {
+    let rt = Runtime::new()?;
+    let listener = rt.block_on(TcpListener::bind("127.0.0.1:0"))?;
+    let addr = listener.local_addr()?;
+    // 接受连接但是从不响应
+    rt.spawn(async move {
+        let mut conns = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            conns.push(stream);
+        }
+    });
+
+    let config = RemoteConfig {
+        timeout_ms: 100,
+        pool_size: 1,
+        ..RemoteConfig::new(addr.to_string())
+    };
+    let remote = Remote::connect(config)?;
+    assert_eq!(
+        remote.get("t1", "k1"),
+        Err(KvError::Timeout(Duration::from_millis(100)))
+    );
+    Ok(())
+}
+
+#[test]
+fn server_should_front_another_server() -> Result<()> {
+    let rt = Runtime::new()?;
+    let backend = start_server(&rt, Service::new(MemTable::new()))?;
+    let proxy = start_server(&rt, Service::new(connect(backend, None)?))?;
+
+    execute(
+        &rt,
+        proxy,
+        CommandRequest::new_hset("t1", "k1", "v1".into()),
+    )?;
+    let res = execute(&rt, proxy, CommandRequest::new_hget("t1", "k2"))?;
+    assert_eq!(res.status, 404);
+
+    let res = execute(&rt, backend, CommandRequest::new_hget("t1", "k1"))?;
+    assert_eq!(res.values, [Value::from("v1")]);
+    let res = execute(&rt, proxy, CommandRequest::new_tables())?;
+    assert_eq!(res.values, [Value::from("t1")]);
+    Ok(())
+}
+
+#[test]
+fn cache_should_be_invalidated_by_writes() -> Result<()> {
+    let rt = Runtime::new()?;
+    let addr = start_server(&rt, Service::new(MemTable::new()))?;
+    let remote = connect(addr, Some(CacheConfig::default()))?;
+
+    remote.set("t1", "k1".into(), "v1".into())?;
+    assert_eq!(remote.get("t1", "k1")?, Some("v1".into()));
+
+    // 没有被 Watch 的 table, ttl 之内读不到其它客户端的写入
+    execute(&rt, addr, CommandRequest::new_hset("t1", "k1", "v2".into()))?;
+    assert_eq!(remote.get("t1", "k1")?, Some("v1".into()));
+
+    // 通过 Remote 的写入立即生效
+    remote.set("t1", "k1".into(), "v3".into())?;
+    assert_eq!(remote.get("t1", "k1")?, Some("v3".into()));
+    remote.del("t1", "k1")?;
+    assert!(!remote.contains("t1", "k1")?);
+    Ok(())
+}
+
+#[test]
+fn watched_tables_should_invalidate_cache() -> Result<()> {
+    let rt = Runtime::new()?;
+    let addr = start_server(&rt, Service::new(Watched::new(MemTable::new())))?;
+    let cache = CacheConfig {
+        watch_tables: vec!["t1".into()],
+        ..Default::default()
+    };
+    let remote = connect(addr, Some(cache))?;
+
+    remote.set("t1", "k1".into(), "v1".into())?;
+    assert_eq!(remote.get("t1", "k1")?, Some("v1".into()));
+
+    execute(&rt, addr, CommandRequest::new_hset("t1", "k1", "v2".into()))?;
+    // Watch 的事件是异步到达的
+    for _ in 0..100 {
+        if remote.get("t1", "k1")? == Some("v2".into()) {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("cache was not invalidated by the remote write");
+}
+
+#[test]
+fn connect_should_fail_without_server() {
+    let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    assert!(connect(addr, None).is_err());
+}
@@ -0,0 +1,284 @@
+//! 线性一致性检查.
+//!
+//! 单个 key 上的操作互不影响, 而线性一致性是可以组合的, 所以对每个 key 分别检查.
+//! 检查用的是 Wing & Gong 的回溯搜索: 依次尝试把一个可以最先生效的调用线性化, 结果和模型不一致时回溯,
+//! 并缓存已经搜索过的 (已线性化的调用, 状态), 避免重复搜索.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+};
+
+use course_proto::pb::abi::{CommandRequest, CommandResponse, Value, value};
+use prost::Message;
+
+/// 对一个 key 的操作
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    Get,
+    Set(Value),
+    Del,
+    Lpush(Value),
+}
+
+impl Op {
+    pub fn to_command(&self, table: &str, key: &str) -> CommandRequest {
+        match self {
+            Op::Get => CommandRequest::new_hget(table, key),
+            Op::Set(v) => CommandRequest::new_hset(table, key, v.clone()),
+            Op::Del => CommandRequest::new_hdel(table, key),
+            Op::Lpush(v) => CommandRequest::new_lpush(table, key, vec![v.clone()]),
+        }
+    }
+
+    /// 在 state 上执行操作, 返回新的状态和应该得到的结果
+    fn apply(&self, state: &Option<Value>) -> (Option<Value>, Output) {
+        let ok = |v: Value| Output(200, vec![v]);
+        match self {
+            Op::Get => match state {
+                Some(v) => (state.clone(), ok(v.clone())),
+                None => (None, Output(404, vec![])),
+            },
+            Op::Set(v) => (Some(v.clone()), ok(state.clone().unwrap_or_default())),
+            Op::Del => (None, ok(state.clone().unwrap_or_default())),
+            Op::Lpush(v) => {
+                let mut values = match state.as_ref().map(|s| &s.value) {
+                    None => vec![],
+                    Some(Some(value::Value::List(list))) => list.values.clone(),
+                    Some(_) => return (state.clone(), Output(400, vec![])),
+                };
+                values.insert(0, v.clone());
+                let len = values.len() as i64;
+                (Some(values.into()), ok(len.into()))
+            }
+        }
+    }
+}
+
+/// 调用的结果, 只比较状态码和返回的 values
+#[derive(Clone, Debug, PartialEq)]
+pub struct Output(pub u32, pub Vec<Value>);
+
+impl From<CommandResponse> for Output {
+    fn from(res: CommandResponse) -> Self {
+        Self(res.status, res.values)
+    }
+}
+
+/// 一次调用. 结果未知 (比如超时) 的调用可能在开始之后的任何时候生效, 也可能从未生效
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call {
+    pub client: usize,
+    pub key: String,
+    pub op: Op,
+    pub output: Option<Output>,
+    /// 开始和结束的逻辑时间, 结果未知时结束时间为 u64::MAX
+    pub invoke: u64,
+    pub complete: u64,
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let complete = match self.complete {
+            u64::MAX => "?".to_string(),
+            t => t.to_string(),
+        };
+        write!(
+            f,
+            "[{}, {}] client {} {:?} -> {:?}",
+            self.invoke, complete, self.client, self.op, self.output
+        )
+    }
+}
+
+/// 记录所有客户端的调用. 模拟在单个线程上运行, 用递增的计数器作为逻辑时间就能得到调用之间确切的先后关系
+#[derive(Debug, Default, PartialEq)]
+pub struct History {
+    clock: u64,
+    calls: Vec<Call>,
+}
+
+impl History {
+    /// 记录一次调用的开始, 返回调用的 id
+    pub fn invoke(&mut self, client: usize, key: &str, op: Op) -> usize {
+        self.clock += 1;
+        self.calls.push(Call {
+            client,
+            key: key.into(),
+            op,
+            output: None,
+            invoke: self.clock,
+            complete: u64::MAX,
+        });
+        self.calls.len() - 1
+    }
+
+    /// 记录一次调用的结果
+    pub fn complete(&mut self, id: usize, output: Output) {
+        self.clock += 1;
+        let call = &mut self.calls[id];
+        call.output = Some(output);
+        call.complete = self.clock;
+    }
+
+    pub fn len(&self) -> usize {
+        self.calls.len()
+    }
+
+    /// 检查历史是否是线性一致的, 不是时返回出错的 key 上的所有调用
+    pub fn check(&self) -> Result<(), String> {
+        let mut keys: BTreeMap<&str, Vec<&Call>> = BTreeMap::new();
+        for call in &self.calls {
+            keys.entry(&call.key).or_default().push(call);
+        }
+
+        for (key, calls) in keys {
+            if !Search::new(&calls).run() {
+                let calls: Vec<_> = calls.iter().map(|c| c.to_string()).collect();
+                return Err(format!(
+                    "history of key {} is not linearizable:\n{}",
+                    key,
+                    calls.join("\n")
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Search<'a> {
+    calls: &'a [&'a Call],
+    /// 已经线性化的调用
+    done: Vec<bool>,
+    /// 还没有线性化的, 结果已知的调用个数. 结果未知的调用可以不被线性化
+    pending: usize,
+    /// 已经确认无法继续线性化的 (done, 编码后的状态)
+    failed: HashSet<(Vec<bool>, Vec<u8>)>,
+}
+
+impl<'a> Search<'a> {
+    fn new(calls: &'a [&'a Call]) -> Self {
+        Self {
+            calls,
+            done: vec![false; calls.len()],
+            pending: calls.iter().filter(|c| c.output.is_some()).count(),
+            failed: HashSet::new(),
+        }
+    }
+
+    fn run(&mut self) -> bool {
+        self.search(None)
+    }
+
+    fn search(&mut self, state: Option<Value>) -> bool {
+        if self.pending == 0 {
+            return true;
+        }
+        let key = (
+            self.done.clone(),
+            state
+                .as_ref()
+                .map(Message::encode_to_vec)
+                .unwrap_or_default(),
+        );
+        if self.failed.contains(&key) {
+            return false;
+        }
+
+        // 只有在所有还没线性化的调用结束之前开始的调用, 才可能是下一个生效的
+        let horizon = self
+            .calls
+            .iter()
+            .zip(&self.done)
+            .filter(|(_, done)| !**done)
+            .map(|(c, _)| c.complete)
+            .min()
+            .unwrap_or(u64::MAX);
+
+        for i in 0..self.calls.len() {
+            let call = self.calls[i];
+            if self.done[i] || call.invoke >= horizon {
+                continue;
+            }
+            let (next, output) = call.op.apply(&state);
+            if call.output.as_ref().is_some_and(|o| *o != output) {
+                continue;
+            }
+
+            let known = call.output.is_some() as usize;
+            self.done[i] = true;
+            self.pending -= known;
+            if self.search(next) {
+                return true;
+            }
+            self.done[i] = false;
+            self.pending += known;
+        }
+
+        self.failed.insert(key);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(v: &str) -> Op {
+        Op::Set(v.into())
+    }
+
+    fn ok(v: impl Into<Value>) -> Output {
+        Output(200, vec![v.into()])
+    }
+
+    #[test]
+    fn sequential_history_should_pass() {
+        let mut history = History::default();
+        let id = history.invoke(0, "k1", set("a"));
+        history.complete(id, ok(Value::default()));
+        let id = history.invoke(1, "k1", Op::Get);
+        history.complete(id, ok("a"));
+        let id = history.invoke(0, "k1", Op::Lpush("b".into()));
+        history.complete(id, Output(400, vec![]));
+        assert!(history.check().is_ok());
+    }
+
+    #[test]
+    fn stale_read_should_fail() {
+        let mut history = History::default();
+        let id = history.invoke(0, "k1", set("a"));
+        history.complete(id, ok(Value::default()));
+        let id = history.invoke(1, "k1", Op::Get);
+        history.complete(id, Output(404, vec![]));
+        assert!(history.check().is_err());
+    }
+
+    #[test]
+    fn concurrent_calls_can_take_effect_in_any_order() {
+        let mut history = History::default();
+        let write = history.invoke(0, "k1", set("a"));
+        let read = history.invoke(1, "k1", Op::Get);
+        let push = history.invoke(2, "k2", Op::Lpush("x".into()));
+        history.complete(read, Output(404, vec![]));
+        history.complete(push, ok(1));
+        history.complete(write, ok(Value::default()));
+        assert!(history.check().is_ok());
+    }
+
+    #[test]
+    fn unknown_call_may_take_effect_later() {
+        let mut history = History::default();
+        history.invoke(0, "k1", set("a"));
+        let id = history.invoke(1, "k1", Op::Get);
+        history.complete(id, Output(404, vec![]));
+        let id = history.invoke(1, "k1", Op::Get);
+        history.complete(id, ok("a"));
+        assert!(history.check().is_ok());
+
+        // 已经生效的写入不能再被撤销
+        let id = history.invoke(1, "k1", Op::Get);
+        history.complete(id, Output(404, vec![]));
+        assert!(history.check().is_err());
+    }
+}
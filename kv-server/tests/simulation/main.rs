@@ -0,0 +1,206 @@
+//! 确定性模拟测试.
+//!
+//! 网络, 时间和任务调度都由 turmoil 按照 seed 驱动, 同一个 seed 总是得到同样的执行过程.
+//! 每个 seed 会生成随机的命令序列, 由多个客户端并发执行, 最后检查记录下来的历史是否线性一致.
+//!
+//! 默认运行 0..SEEDS 这几个 seed, 失败时会打印出错的 seed, 用 `SIM_SEED=<seed>` 可以只运行那一个 seed 复现问题,
+//! `SIM_SEEDS=<n>` 可以运行更多的 seed.
+
+mod checker;
+
+use std::{cell::RefCell, env, io, net::SocketAddr, rc::Rc, time::Duration};
+
+use checker::{History, Op, Output};
+use course_proto::pb::abi::CommandRequest;
+use kv_server::{
+    client::KvClient,
+    server::{Listener, Server},
+    service::Service,
+    storage::{index::Indexed, memory::MemTable, watch::Watched},
+};
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+use tokio::time;
+
+const SEEDS: u64 = 8;
+const CLIENTS: usize = 4;
+const OPS_PER_CLIENT: usize = 50;
+const KEYS: &[&str] = &["k1", "k2", "k3"];
+const TABLE: &str = "t1";
+const PORT: u16 = 6379;
+/// 客户端等待响应的时间, 超时后结果未知, 客户端会重新连接
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+type SharedHistory = Rc<RefCell<History>>;
+
+/// turmoil 模拟的网络上的 listener
+struct SimListener(turmoil::net::TcpListener);
+
+impl Listener for SimListener {
+    type Stream = turmoil::net::TcpStream;
+
+    async fn accept(&self) -> io::Result<(Self::Stream, SocketAddr)> {
+        self.0.accept().await
+    }
+}
+
+fn seeds() -> Vec<u64> {
+    if let Ok(seed) = env::var("SIM_SEED") {
+        return vec![seed.parse().expect("SIM_SEED should be a u64")];
+    }
+    let count =
+        env::var("SIM_SEEDS").map_or(SEEDS, |n| n.parse().expect("SIM_SEEDS should be a u64"));
+    (0..count).collect()
+}
+
+fn simulation(seed: u64) -> turmoil::Sim<'static> {
+    turmoil::Builder::new()
+        .rng_seed(seed)
+        .simulation_duration(Duration::from_secs(600))
+        .min_message_latency(Duration::from_millis(1))
+        .max_message_latency(Duration::from_millis(20))
+        .enable_random_order()
+        .build()
+}
+
+/// 客户端 client 的第 i 个随机操作, value 中带上客户端和序号, 这样每次写入的 value 都不一样
+fn random_op(rng: &mut StdRng, client: usize, i: usize) -> (&'static str, Op) {
+    let key = KEYS.choose(rng).unwrap();
+    let value = format!("c{}-{}", client, i).into();
+    let op = match rng.gen_range(0..10) {
+        0..4 => Op::Get,
+        4..7 => Op::Set(value),
+        7 => Op::Del,
+        _ => Op::Lpush(value),
+    };
+    (key, op)
+}
+
+fn client_rng(seed: u64, client: usize) -> StdRng {
+    StdRng::seed_from_u64(seed.wrapping_mul(31).wrapping_add(client as u64))
+}
+
+/// 在模拟中直接调用 Service, 每个调用在开始和结束之间随机的时间点生效
+fn run_service(seed: u64) -> History {
+    let mut sim = simulation(seed);
+    let history = SharedHistory::default();
+    let service: Service = Service::new(MemTable::new());
+
+    for client in 0..CLIENTS {
+        let history = history.clone();
+        let service = service.clone();
+        sim.client(format!("client-{}", client), async move {
+            let mut rng = client_rng(seed, client);
+            for i in 0..OPS_PER_CLIENT {
+                let (key, op) = random_op(&mut rng, client, i);
+                let cmd = op.to_command(TABLE, key);
+                let id = history.borrow_mut().invoke(client, key, op);
+                time::sleep(Duration::from_millis(rng.gen_range(0..10))).await;
+                let res = service.execute(cmd);
+                time::sleep(Duration::from_millis(rng.gen_range(0..10))).await;
+                history.borrow_mut().complete(id, res.into());
+            }
+            Ok(())
+        });
+    }
+
+    sim.run().unwrap_or_else(|e| panic!("seed {}: {}", seed, e));
+    history.take()
+}
+
+/// 客户端通过模拟的网络访问服务器, 期间随机地暂停服务器和某个客户端之间的网络
+fn run_server(seed: u64) -> History {
+    let mut sim = simulation(seed);
+    let history = SharedHistory::default();
+
+    sim.host("server", || async {
+        let listener = turmoil::net::TcpListener::bind(("0.0.0.0", PORT)).await?;
+        let service = Service::new(Watched::new(Indexed::new(MemTable::new())));
+        let server = Server::new(service, Duration::from_secs(1));
+        server
+            .run(SimListener(listener), futures::future::pending())
+            .await?;
+        Ok(())
+    });
+
+    for client in 0..CLIENTS {
+        let history = history.clone();
+        sim.client(format!("client-{}", client), async move {
+            let mut rng = client_rng(seed, client);
+            let mut conn: Option<KvClient<turmoil::net::TcpStream>> = None;
+            for i in 0..OPS_PER_CLIENT {
+                let (key, op) = random_op(&mut rng, client, i);
+                let cmd = op.to_command(TABLE, key);
+                let id = history.borrow_mut().invoke(client, key, op);
+                match time::timeout(TIMEOUT, execute(&mut conn, cmd)).await {
+                    Ok(Ok(res)) => history.borrow_mut().complete(id, res),
+                    // 请求可能已经被执行了, 结果未知. 重新建立连接, 避免读到这个请求迟到的响应
+                    _ => conn = None,
+                }
+                time::sleep(Duration::from_millis(rng.gen_range(0..10))).await;
+            }
+            Ok(())
+        });
+    }
+
+    let mut faults = StdRng::seed_from_u64(seed);
+    let mut held: Option<String> = None;
+    loop {
+        match &held {
+            None if faults.gen_bool(0.001) => {
+                let client = format!("client-{}", faults.gen_range(0..CLIENTS));
+                sim.hold("server", client.as_str());
+                held = Some(client);
+            }
+            Some(client) if faults.gen_bool(0.002) => {
+                sim.release("server", client.as_str());
+                held = None;
+            }
+            _ => {}
+        }
+        match sim.step() {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => panic!("seed {}: {}", seed, e),
+        }
+    }
+    history.take()
+}
+
+async fn execute(
+    conn: &mut Option<KvClient<turmoil::net::TcpStream>>,
+    cmd: CommandRequest,
+) -> turmoil::Result<Output> {
+    let client = match conn {
+        Some(client) => client,
+        None => {
+            let stream = turmoil::net::TcpStream::connect(("server", PORT)).await?;
+            conn.insert(KvClient::new(stream))
+        }
+    };
+    Ok(client.execute(cmd).await?.into())
+}
+
+fn check_seeds(run: fn(u64) -> History) {
+    for seed in seeds() {
+        let history = run(seed);
+        assert_eq!(history.len(), CLIENTS * OPS_PER_CLIENT);
+        if let Err(e) = history.check() {
+            panic!("seed {} failed, rerun with SIM_SEED={}\n{}", seed, seed, e);
+        }
+    }
+}
+
+#[test]
+fn service_workload_should_be_linearizable() {
+    check_seeds(run_service);
+}
+
+#[test]
+fn server_workload_should_be_linearizable() {
+    check_seeds(run_server);
+}
+
+#[test]
+fn same_seed_should_produce_same_history() {
+    assert_eq!(run_service(42), run_service(42));
+}
@@ -0,0 +1,83 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::Result;
+use course_proto::pb::abi::{CommandRequest, value};
+use kv_server::{client::KvClient, server::Server, service::Service, storage::memory::MemTable};
+use tokio::{net::TcpListener, time};
+
+async fn start_server() -> Result<SocketAddr> {
+    start_with(Service::new(MemTable::new())).await
+}
+
+async fn start_with(service: Service) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = Server::new(service, Duration::from_secs(1));
+    tokio::spawn(server.run(listener, futures::future::pending()));
+    Ok(addr)
+}
+
+async fn snapshot(client: &mut KvClient) -> Result<u64> {
+    let res = client.execute(CommandRequest::new_snapshot()).await?;
+    let Some(value::Value::Integer(id)) = res.values[0].value else {
+        panic!("Snapshot should return its id, got {:?}", res);
+    };
+    Ok(id as u64)
+}
+
+#[tokio::test]
+async fn snapshot_should_only_be_used_by_its_connection() -> Result<()> {
+    let addr = start_server().await?;
+    let mut owner = KvClient::connect(addr).await?;
+    let mut other = KvClient::connect(addr).await?;
+
+    owner
+        .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+        .await?;
+    let id = snapshot(&mut owner).await?;
+
+    // 其它连接既不能读取也不能释放这个 snapshot
+    let res = other
+        .execute(CommandRequest::new_hget_at("t1", "k1", id))
+        .await?;
+    assert_eq!(res.status, 404);
+    let res = other
+        .execute(CommandRequest::new_release_snapshot(id))
+        .await?;
+    assert_eq!(res.status, 404);
+
+    let res = owner
+        .execute(CommandRequest::new_hget_at("t1", "k1", id))
+        .await?;
+    assert_eq!(res.values, ["v1".into()]);
+    let res = owner
+        .execute(CommandRequest::new_release_snapshot(id))
+        .await?;
+    assert_eq!(res.values, [true.into()]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn snapshot_should_be_released_when_connection_closes() -> Result<()> {
+    let service = Service::new(MemTable::new());
+    let addr = start_with(service.clone()).await?;
+    let mut client = KvClient::connect(addr).await?;
+    client
+        .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+        .await?;
+    let id = snapshot(&mut client).await?;
+    drop(client);
+
+    // 连接断开之后 snapshot 被异步地释放
+    for _ in 0..50 {
+        let res = service.execute(CommandRequest::new_hget_at("t1", "k1", id));
+        if res.status == 404 {
+            return Ok(());
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!(
+        "Snapshot {} should be released after its connection closed",
+        id
+    );
+}
@@ -0,0 +1,107 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::Result;
+use course_proto::pb::abi::{CommandRequest, EventKind, WatchEvent};
+use futures::StreamExt;
+use kv_server::{
+    client::KvClient,
+    command::Storage,
+    server::Server,
+    service::Service,
+    storage::{memory::MemTable, sleddb::SledDb, watch::Watched},
+};
+use tempfile::tempdir;
+use tokio::{net::TcpListener, time};
+
+async fn start_server(service: Service<impl Storage>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = Server::new(service, Duration::from_secs(1));
+    tokio::spawn(server.run(listener, futures::future::pending()));
+    Ok(addr)
+}
+
+async fn watch_and_write(addr: SocketAddr) -> Result<Vec<WatchEvent>> {
+    let watcher = KvClient::connect(addr).await?;
+    let stream = watcher
+        .watch(CommandRequest::new_watch("t1", "user:"))
+        .await?;
+    tokio::pin!(stream);
+    // 第一个响应表示订阅成功
+    assert_eq!(stream.next().await.unwrap()?.status, 200);
+
+    let mut client = KvClient::connect(addr).await?;
+    for cmd in [
+        CommandRequest::new_hset("t1", "user:1", "alice".into()),
+        CommandRequest::new_hset("t1", "order:1", 100.into()),
+        CommandRequest::new_hset("t1", "user:1", "bob".into()),
+        CommandRequest::new_hdel("t1", "user:1"),
+    ] {
+        client.execute(cmd).await?;
+    }
+
+    let mut events = Vec::new();
+    for _ in 0..3 {
+        let res = time::timeout(Duration::from_secs(1), stream.next())
+            .await?
+            .unwrap()?;
+        events.extend(res.events);
+    }
+    Ok(events)
+}
+
+fn assert_events(events: &[WatchEvent]) {
+    let summary: Vec<_> = events
+        .iter()
+        .map(|e| {
+            (
+                e.kind(),
+                e.key.as_str(),
+                e.old_value.clone(),
+                e.new_value.clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (EventKind::Set, "user:1", None, Some("alice".into())),
+            (
+                EventKind::Set,
+                "user:1",
+                Some("alice".into()),
+                Some("bob".into())
+            ),
+            (EventKind::Delete, "user:1", Some("bob".into()), None),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn watch_should_stream_events_from_memtable() -> Result<()> {
+    let addr = start_server(Service::new(Watched::new(MemTable::new()))).await?;
+    assert_events(&watch_and_write(addr).await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn watch_should_stream_events_from_sleddb() -> Result<()> {
+    let dir = tempdir()?;
+    let addr = start_server(Service::new(Watched::new(SledDb::new(dir.path())?))).await?;
+    assert_events(&watch_and_write(addr).await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn watch_on_plain_storage_should_fail() -> Result<()> {
+    let addr = start_server(Service::new(MemTable::new())).await?;
+    let watcher = KvClient::connect(addr).await?;
+    let stream = watcher.watch(CommandRequest::new_watch("t1", "")).await?;
+    tokio::pin!(stream);
+
+    let res = stream.next().await.unwrap()?;
+    assert_eq!(res.status, 501);
+    // 服务器随后关闭连接
+    assert!(stream.next().await.is_none());
+    Ok(())
+}
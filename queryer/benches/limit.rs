@@ -0,0 +1,116 @@
+//! LIMIT / OFFSET 的 benchmark: `cargo bench -p queryer --bench limit`
+//!
+//! 生成一个几百万行的本地 CSV (行数可以用 QUERYER_BENCH_ROWS 修改), 对比之前先 collect
+//! 一次得到行数再 slice 的做法, 和把 slice 放在 lazy plan 中只执行一次的做法.
+
+use anyhow::Result;
+use polars::prelude::*;
+use std::{
+    env,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+const ITERATIONS: usize = 5;
+const OFFSET: i64 = 1000;
+const LIMIT: IdxSize = 10;
+// 每个 case 的名字和对应的 SQL 子句
+const CASES: [(&str, &str); 3] = [
+    ("scan", ""),
+    ("filter", "WHERE value > 500000"),
+    ("sort", "ORDER BY value"),
+];
+
+fn generate(path: &Path, rows: usize) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "id,category,value")?;
+    for i in 0..rows {
+        // 简单的伪随机数, 让排序和过滤有意义
+        let value = (i as u64).wrapping_mul(2654435761) % 1_000_000;
+        writeln!(writer, "{},c{},{}", i, i % 100, value)?;
+    }
+    Ok(())
+}
+
+// 和 CASES 中的 SQL 子句相同的 lazy plan
+fn plan(source: &str, case: &str) -> PolarsResult<LazyFrame> {
+    let lf = LazyCsvReader::new(source).finish()?;
+    Ok(match case {
+        "filter" => lf.filter(col("value").gt(lit(500_000))),
+        "sort" => lf.sort(
+            ["value"],
+            SortMultipleOptions::default().with_maintain_order(true),
+        ),
+        _ => lf,
+    })
+}
+
+// 多次执行取中位数
+fn measure(mut f: impl FnMut() -> Result<usize>) -> Result<(Duration, usize)> {
+    let mut times = Vec::with_capacity(ITERATIONS);
+    let mut height = 0;
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        height = f()?;
+        times.push(start.elapsed());
+    }
+    times.sort();
+    Ok((times[ITERATIONS / 2], height))
+}
+
+// 之前的做法: 先执行一次整个查询得到行数, 再 slice 和 limit
+fn two_pass(lf: LazyFrame) -> Result<usize> {
+    let height = lf.clone().collect()?.height();
+    let df = lf.slice(OFFSET, height as IdxSize).limit(LIMIT).collect()?;
+    Ok(df.height())
+}
+
+fn one_pass(lf: LazyFrame) -> Result<usize> {
+    Ok(lf.slice(OFFSET, LIMIT).collect()?.height())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let rows = env::var("QUERYER_BENCH_ROWS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3_000_000);
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("bench.csv");
+    println!("generating {} rows in {}", rows, path.display());
+    generate(&path, rows)?;
+    let source = path.to_str().unwrap();
+
+    println!(
+        "{:<8} {:>12} {:>12} {:>12}",
+        "case", "two pass", "one pass", "query"
+    );
+    for (name, clause) in CASES {
+        let (before, expected) = measure(|| two_pass(plan(source, name)?))?;
+        let (after, height) = measure(|| one_pass(plan(source, name)?))?;
+        assert_eq!(height, expected);
+
+        let sql = format!(
+            "SELECT * FROM read_csv('{}') {} LIMIT {} OFFSET {}",
+            source, clause, LIMIT, OFFSET
+        );
+        let mut times = Vec::with_capacity(ITERATIONS);
+        for _ in 0..ITERATIONS {
+            let start = Instant::now();
+            assert_eq!(queryer::query(&sql).await?.height(), expected);
+            times.push(start.elapsed());
+        }
+        times.sort();
+
+        println!(
+            "{:<8} {:>12?} {:>12?} {:>12?}",
+            name,
+            before,
+            after,
+            times[ITERATIONS / 2]
+        );
+    }
+    Ok(())
+}
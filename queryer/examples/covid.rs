@@ -1,7 +1,6 @@
 use anyhow::Result;
 use polars::prelude::*;
 use std::io::Cursor;
-use reqwest;
 
 #[tokio::main]
 async fn main() -> Result<()> {
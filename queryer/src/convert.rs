@@ -1,28 +1,99 @@
-use anyhow::{anyhow, Result};
+use anyhow::{Result, anyhow};
+
+use crate::source::Format;
 use polars::prelude::*;
 use sqlparser::ast::{
-    BinaryOperator as SqlBinaryOperator, Expr as SqlExpr, Offset as SqlOffset, OrderByExpr,
-    SelectItem, SetExpr, Statement, TableFactor, TableWithJoins, Value as SqlValue,
+    BinaryOperator as SqlBinaryOperator, CastKind, DataType as SqlDataType, DuplicateTreatment,
+    Expr as SqlExpr, Function as SqlFunction, FunctionArg, FunctionArgExpr, FunctionArguments,
+    GroupByExpr, Ident, JoinConstraint, JoinOperator, Offset as SqlOffset, OrderByExpr, Query,
+    Select, SelectItem, SetExpr, SetOperator, SetQuantifier, Statement, TableFactor,
+    TableWithJoins, TrimWhereField, UnaryOperator, Value as SqlValue, WindowType,
 };
 
+// 不在结果中的 ORDER BY 表达式先作为临时列加到 select 中, 排序之后再去掉
+const SORT_COLUMN: &str = "__sort";
+
 // SQL 抽象语法树结构
 pub struct Sql<'a> {
+    // WITH 中按顺序定义的 CTE
+    pub(crate) ctes: Vec<(&'a str, Sql<'a>)>,
+    // WHERE 中的子查询
+    pub(crate) subqueries: Vec<Subquery<'a>>,
     pub(crate) selection: Vec<Expr>,
     pub(crate) condition: Option<Expr>,
-    pub(crate) source: &'a str,
-    pub(crate) order_by: Vec<(String, bool)>,
+    pub(crate) group_by: Option<GroupBy>,
+    pub(crate) source: Table<'a>,
+    pub(crate) joins: Vec<Join<'a>>,
+    pub(crate) order_by: Option<OrderBy>,
     pub(crate) offset: Option<i64>,
     pub(crate) limit: Option<usize>,
 }
 
+// FROM 中的一个数据源, 没有别名时用 source 本身来限定列名.
+// 用 read_parquet('path') 这样的表函数时 format 指定了格式, 子查询时 query 是嵌套的查询
+pub struct Table<'a> {
+    pub(crate) source: &'a str,
+    pub(crate) alias: Option<&'a str>,
+    pub(crate) format: Option<Format>,
+    pub(crate) query: Option<Nested<'a>>,
+}
+
+// FROM 中嵌套的查询: 子查询, 或者 UNION / INTERSECT / EXCEPT 的结果
+pub enum Nested<'a> {
+    Select(Box<Sql<'a>>),
+    Set(SetOperation<'a>),
+}
+
+// 集合运算, all 为 false 时结果去重
+pub struct SetOperation<'a> {
+    pub(crate) op: SetOperator,
+    pub(crate) all: bool,
+    pub(crate) left: Box<Nested<'a>>,
+    pub(crate) right: Box<Nested<'a>>,
+}
+
+// WHERE 中的子查询. 执行时它的结果以 name 为名加到数据源上: 标量子查询是它唯一的值,
+// IN 子查询是 expr 是否在结果中 (按 SQL 的语义可能是 NULL). 标量子查询的 expr 为 None
+pub struct Subquery<'a> {
+    pub(crate) name: String,
+    pub(crate) sql: Sql<'a>,
+    pub(crate) expr: Option<Expr>,
+}
+
+// 和之前所有数据源 join 的结果再做 join, on 是若干对相等的列, CROSS JOIN 时为空
+pub struct Join<'a> {
+    pub(crate) table: Table<'a>,
+    pub(crate) how: JoinType,
+    pub(crate) on: Vec<(Expr, Expr)>,
+}
+
+// ORDER BY 在 select 之后一次按所有 keys 排序, 相等的行保持原来的顺序.
+// hidden 是只为排序而加到 select 中的临时列
+pub struct OrderBy {
+    pub(crate) keys: Vec<Expr>,
+    pub(crate) options: SortMultipleOptions,
+    pub(crate) hidden: Vec<String>,
+}
+
+// GROUP BY 分组聚合. keys 为空时对整个表聚合
+pub struct GroupBy {
+    pub(crate) keys: Vec<Expr>,
+    pub(crate) aggs: Vec<Expr>,
+    pub(crate) having: Option<Expr>,
+}
+
 // 包装结构体
 pub struct Expression(pub(crate) Box<SqlExpr>);
+pub struct Function(pub(crate) SqlFunction);
 pub struct Operation(pub(crate) SqlBinaryOperator);
 pub struct Projection<'a>(pub(crate) &'a SelectItem);
 pub struct Source<'a>(pub(crate) &'a [TableWithJoins]);
+pub struct Relation<'a>(pub(crate) &'a TableFactor);
+pub struct Constraint<'a>(pub(crate) &'a JoinConstraint);
 pub struct Order<'a>(pub(crate) &'a OrderByExpr);
 pub struct Offset<'a>(pub(crate) &'a SqlOffset);
 pub struct Value(pub(crate) SqlValue);
+pub struct SqlType(pub(crate) SqlDataType);
 
 // Convert SqlValue to LiteralValue
 impl TryFrom<Value> for LiteralValue {
@@ -47,6 +118,42 @@ impl TryFrom<Value> for LiteralValue {
     }
 }
 
+// Convert SqlType to DataType
+impl TryFrom<SqlType> for DataType {
+    type Error = anyhow::Error;
+
+    fn try_from(t: SqlType) -> Result<Self, Self::Error> {
+        match t.0 {
+            SqlDataType::TinyInt(_) => Ok(DataType::Int8),
+            SqlDataType::SmallInt(_) | SqlDataType::Int2(_) => Ok(DataType::Int16),
+            SqlDataType::Int(_)
+            | SqlDataType::Integer(_)
+            | SqlDataType::Int4(_)
+            | SqlDataType::Int32 => Ok(DataType::Int32),
+            SqlDataType::BigInt(_) | SqlDataType::Int8(_) | SqlDataType::Int64 => {
+                Ok(DataType::Int64)
+            }
+            SqlDataType::Real | SqlDataType::Float4 | SqlDataType::Float32 => Ok(DataType::Float32),
+            SqlDataType::Float(_)
+            | SqlDataType::Float8
+            | SqlDataType::Float64
+            | SqlDataType::Double
+            | SqlDataType::DoublePrecision
+            | SqlDataType::Numeric(_)
+            | SqlDataType::Decimal(_) => Ok(DataType::Float64),
+            SqlDataType::Bool | SqlDataType::Boolean => Ok(DataType::Boolean),
+            SqlDataType::Char(_)
+            | SqlDataType::Character(_)
+            | SqlDataType::Varchar(_)
+            | SqlDataType::Text
+            | SqlDataType::String(_) => Ok(DataType::String),
+            SqlDataType::Date => Ok(DataType::Date),
+            SqlDataType::Timestamp(..) => Ok(DataType::Datetime(TimeUnit::Microseconds, None)),
+            t => Err(anyhow!("Cast to {} not supported", t)),
+        }
+    }
+}
+
 // Convert Operation to Operator
 impl TryFrom<Operation> for Operator {
     type Error = anyhow::Error;
@@ -84,38 +191,162 @@ impl<'a> From<Offset<'a>> for i64 {
     }
 }
 
-// Convert Source
-impl<'a> TryFrom<Source<'a>> for &'a str {
+// Convert Source, 逗号分隔的多个数据源相当于 CROSS JOIN
+impl<'a> TryFrom<Source<'a>> for (Table<'a>, Vec<Join<'a>>) {
     type Error = anyhow::Error;
 
     fn try_from(source: Source<'a>) -> Result<Self, Self::Error> {
-        if source.0.len() != 1 {
-            return Err(anyhow!("Only support single table"));
-        }
+        let first = source
+            .0
+            .first()
+            .ok_or_else(|| anyhow!("Query without FROM not supported"))?;
 
-        let table = &source.0[0];
-        if !table.joins.is_empty() {
-            return Err(anyhow!("Only support single table without joins"));
+        let table = Relation(&first.relation).try_into()?;
+        let mut joins = Vec::new();
+        for (i, item) in source.0.iter().enumerate() {
+            if i > 0 {
+                joins.push(Join {
+                    table: Relation(&item.relation).try_into()?,
+                    how: JoinType::Cross,
+                    on: Vec::new(),
+                });
+            }
+            for join in &item.joins {
+                let (how, constraint) = match &join.join_operator {
+                    JoinOperator::Inner(c) => (JoinType::Inner, Some(c)),
+                    JoinOperator::LeftOuter(c) => (JoinType::Left, Some(c)),
+                    JoinOperator::RightOuter(c) => (JoinType::Right, Some(c)),
+                    JoinOperator::FullOuter(c) => (JoinType::Full, Some(c)),
+                    JoinOperator::CrossJoin => (JoinType::Cross, None),
+                    _ => return Err(anyhow!("Join {} not supported", join)),
+                };
+                let on = match constraint {
+                    Some(c) => Constraint(c).try_into()?,
+                    None => Vec::new(),
+                };
+                joins.push(Join {
+                    table: Relation(&join.relation).try_into()?,
+                    how,
+                    on,
+                });
+            }
         }
 
-        match &table.relation {
-            TableFactor::Table { name, .. } => Ok(&name.0.first().unwrap().value),
+        Ok((table, joins))
+    }
+}
+
+// Convert Relation
+impl<'a> TryFrom<Relation<'a>> for Table<'a> {
+    type Error = anyhow::Error;
+
+    fn try_from(relation: Relation<'a>) -> Result<Self, Self::Error> {
+        match relation.0 {
+            TableFactor::Table {
+                name, alias, args, ..
+            } => {
+                let name = &name.0.first().unwrap().value;
+                let alias = alias.as_ref().map(|a| a.name.value.as_str());
+                let Some(args) = args else {
+                    return Ok(Table {
+                        source: name,
+                        alias,
+                        format: None,
+                        query: None,
+                    });
+                };
+
+                let format = Format::from_function(name)?;
+                let source = match args.args.as_slice() {
+                    [
+                        FunctionArg::Unnamed(FunctionArgExpr::Expr(SqlExpr::Value(
+                            SqlValue::SingleQuotedString(path),
+                        ))),
+                    ] => path,
+                    _ => return Err(anyhow!("{} expects a single path argument", name)),
+                };
+                Ok(Table {
+                    source,
+                    alias,
+                    format: Some(format),
+                    query: None,
+                })
+            }
+            // 没有别名的子查询用 subquery 来限定列名
+            TableFactor::Derived {
+                lateral: false,
+                subquery,
+                alias,
+            } => Ok(Table {
+                source: alias.as_ref().map_or("subquery", |a| a.name.value.as_str()),
+                alias: None,
+                format: None,
+                query: Some(Nested::Select(Box::new(subquery.as_ref().try_into()?))),
+            }),
             _ => Err(anyhow!("Only support table")),
         }
     }
 }
 
-// Convert Order
-impl<'a> TryFrom<Order<'a>> for (String, bool) {
+// Convert Constraint, ON 只支持用 AND 连接的等值条件
+impl<'a> TryFrom<Constraint<'a>> for Vec<(Expr, Expr)> {
+    type Error = anyhow::Error;
+
+    fn try_from(constraint: Constraint<'a>) -> Result<Self, Self::Error> {
+        fn equalities(expr: &SqlExpr, on: &mut Vec<(Expr, Expr)>) -> Result<()> {
+            match expr {
+                SqlExpr::BinaryOp {
+                    left,
+                    op: SqlBinaryOperator::And,
+                    right,
+                } => {
+                    equalities(left, on)?;
+                    equalities(right, on)
+                }
+                SqlExpr::BinaryOp {
+                    left,
+                    op: SqlBinaryOperator::Eq,
+                    right,
+                } => {
+                    let l = Expression(left.clone()).try_into()?;
+                    let r = Expression(right.clone()).try_into()?;
+                    on.push((l, r));
+                    Ok(())
+                }
+                SqlExpr::Nested(expr) => equalities(expr, on),
+                expr => Err(anyhow!("Only support equality in JOIN ON, got {}", expr)),
+            }
+        }
+
+        let mut on = Vec::new();
+        match constraint.0 {
+            JoinConstraint::On(expr) => equalities(expr, &mut on)?,
+            JoinConstraint::Using(columns) => {
+                for c in columns {
+                    on.push((col(&c.value), col(&c.value)));
+                }
+            }
+            _ => return Err(anyhow!("Join requires ON or USING")),
+        }
+        Ok(on)
+    }
+}
+
+// Convert Order to (descending, nulls_last). 和标准 SQL 以及 PostgreSQL 一样, NULL 默认被当作
+// 最大的值: 升序时在最后, 降序时在最前
+impl<'a> TryFrom<Order<'a>> for (bool, bool) {
     type Error = anyhow::Error;
 
     fn try_from(order: Order<'a>) -> Result<Self, Self::Error> {
-        let expr = &order.0.expr;
-        let asc = !order.0.asc.unwrap_or(true);
-        match expr {
-            SqlExpr::Identifier(id) => Ok((id.value.clone(), asc)),
-            _ => Err(anyhow!("Order by only support identifier")),
+        if order.0.with_fill.is_some() {
+            return Err(anyhow!("ORDER BY ... WITH FILL not supported"));
         }
+        let descending = order.0.asc == Some(false);
+        let nulls_last = match order.0.nulls_first {
+            Some(first) => !first,
+            None => !descending,
+        };
+        Ok((descending, nulls_last))
     }
 }
 
@@ -125,6 +356,21 @@ impl<'a> TryFrom<Projection<'a>> for Expr {
 
     fn try_from(proj: Projection<'a>) -> Result<Self, Self::Error> {
         match proj.0 {
+            // 带表名的列 a.location 在结果中叫 location
+            SelectItem::UnnamedExpr(SqlExpr::Identifier(id)) => {
+                Ok(col(&id.value).alias(unqualified(&id.value)))
+            }
+            // 函数调用在结果中用 SQL 原文作为列名, 避免和参数列重名
+            SelectItem::UnnamedExpr(
+                expr @ (SqlExpr::Function(_)
+                | SqlExpr::Cast { .. }
+                | SqlExpr::Trim { .. }
+                | SqlExpr::Substring { .. }),
+            ) => {
+                let name = output_name(expr);
+                let expr: Expr = Expression(Box::new(expr.clone())).try_into()?;
+                Ok(expr.alias(&name))
+            }
             SelectItem::UnnamedExpr(expr) => Expression(Box::new(expr.clone())).try_into(),
             SelectItem::ExprWithAlias { expr, alias } => {
                 let expr: Expr = Expression(Box::new(expr.clone())).try_into()?;
@@ -163,6 +409,135 @@ impl TryFrom<Expression> for Expr {
                     _ => return Err(anyhow!("Operator not supported")),
                 })
             }
+            SqlExpr::UnaryOp { op, expr } => {
+                let expr: Expr = Expression(expr).try_into()?;
+                match op {
+                    UnaryOperator::Not => Ok(expr.not()),
+                    UnaryOperator::Minus => Ok(-expr),
+                    UnaryOperator::Plus => Ok(expr),
+                    op => Err(anyhow!("Unary operator {} not supported", op)),
+                }
+            }
+            SqlExpr::IsNull(expr) => Ok(Expr::try_from(Expression(expr))?.is_null()),
+            SqlExpr::IsNotNull(expr) => Ok(Expr::try_from(Expression(expr))?.is_not_null()),
+            SqlExpr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                let expr: Expr = Expression(expr).try_into()?;
+                let list = list
+                    .into_iter()
+                    .map(|item| Expression(Box::new(item)).try_into())
+                    .collect::<Result<Vec<Expr>>>()?;
+                Ok(negate(in_list(expr, list)?, negated))
+            }
+            SqlExpr::Between {
+                expr,
+                negated,
+                low,
+                high,
+            } => {
+                let expr: Expr = Expression(expr).try_into()?;
+                let low: Expr = Expression(low).try_into()?;
+                let high: Expr = Expression(high).try_into()?;
+                let between = expr.clone().gt_eq(low).and(expr.lt_eq(high));
+                Ok(negate(between, negated))
+            }
+            SqlExpr::Like {
+                negated,
+                any: false,
+                expr,
+                pattern,
+                escape_char,
+            } => like(expr, pattern, escape_char, negated, false),
+            SqlExpr::ILike {
+                negated,
+                any: false,
+                expr,
+                pattern,
+                escape_char,
+            } => like(expr, pattern, escape_char, negated, true),
+            // CASE WHEN 从最后一个分支开始, 嵌套成 when/then/otherwise
+            SqlExpr::Case {
+                operand,
+                conditions,
+                results,
+                else_result,
+            } => {
+                let operand: Option<Expr> =
+                    operand.map(|e| Expression(e).try_into()).transpose()?;
+                let mut expr = match else_result {
+                    Some(e) => Expression(e).try_into()?,
+                    None => lit(NULL),
+                };
+                for (condition, result) in conditions.into_iter().zip(results).rev() {
+                    let condition: Expr = Expression(Box::new(condition)).try_into()?;
+                    let condition = match &operand {
+                        Some(operand) => operand.clone().eq(condition),
+                        None => condition,
+                    };
+                    let result: Expr = Expression(Box::new(result)).try_into()?;
+                    expr = when(condition).then(result).otherwise(expr);
+                }
+                Ok(expr)
+            }
+            // 子查询的结果在执行时作为一列加进来, 见 Sql 的 subqueries
+            SqlExpr::Subquery(q) => Ok(col(subquery_column(&q, None))),
+            SqlExpr::InSubquery {
+                expr,
+                subquery,
+                negated,
+            } => Ok(negate(
+                col(subquery_column(&subquery, Some(&expr))),
+                negated,
+            )),
+            SqlExpr::Nested(expr) => Expression(expr).try_into(),
+            SqlExpr::Function(f) => Function(f).try_into(),
+            // CAST 转换失败时报错, TRY_CAST / SAFE_CAST 转换失败时为 NULL
+            SqlExpr::Cast {
+                kind,
+                expr,
+                data_type,
+                format: None,
+            } => {
+                let expr: Expr = Expression(expr).try_into()?;
+                let dtype = SqlType(data_type).try_into()?;
+                Ok(match kind {
+                    CastKind::Cast | CastKind::DoubleColon => expr.strict_cast(dtype),
+                    CastKind::TryCast | CastKind::SafeCast => expr.cast(dtype),
+                })
+            }
+            SqlExpr::Trim {
+                expr,
+                trim_where,
+                trim_what,
+                trim_characters: None,
+            } => {
+                let expr: Expr = Expression(expr).try_into()?;
+                let what = match trim_what {
+                    Some(what) => Expression(what).try_into()?,
+                    None => lit(NULL),
+                };
+                Ok(match trim_where {
+                    Some(TrimWhereField::Leading) => expr.str().strip_chars_start(what),
+                    Some(TrimWhereField::Trailing) => expr.str().strip_chars_end(what),
+                    _ => expr.str().strip_chars(what),
+                })
+            }
+            SqlExpr::Substring {
+                expr,
+                substring_from: Some(from),
+                substring_for,
+                ..
+            } => {
+                let expr = Expression(expr).try_into()?;
+                let from = Expression(from).try_into()?;
+                let length = substring_for
+                    .map(|e| Expression(e).try_into())
+                    .transpose()?;
+                Ok(substr(expr, from, length))
+            }
             SqlExpr::Identifier(id) => Ok(col(&id.value)),
             SqlExpr::Value(v) => {
                 let value: LiteralValue = Value(v).try_into()?;
@@ -173,58 +548,1067 @@ impl TryFrom<Expression> for Expr {
     }
 }
 
+// 列表中都是常量时用 is_in 查找, 否则展开成多个相等条件的 OR.
+// 和 SQL 一样, expr 为 NULL, 或者没有找到但是列表中有 NULL 时, 结果是 NULL
+fn in_list(expr: Expr, list: Vec<Expr>) -> Result<Expr> {
+    let mut values = Vec::with_capacity(list.len());
+    let mut has_null = false;
+    for item in &list {
+        match item {
+            Expr::Literal(LiteralValue::Null) => has_null = true,
+            Expr::Literal(v) => match v.to_any_value() {
+                Some(v) => values.push(v.into_static()),
+                None => break,
+            },
+            _ => break,
+        }
+    }
+    if values.len() + has_null as usize != list.len() {
+        let mut matched = lit(false);
+        for item in list {
+            matched = matched.or(expr.clone().eq(item));
+        }
+        return Ok(matched);
+    }
+
+    let values = Series::from_any_values("".into(), &values, false)?;
+    let missing = match has_null {
+        true => lit(NULL),
+        false => lit(false),
+    };
+    Ok(when(expr.clone().is_null())
+        .then(lit(NULL))
+        .when(expr.is_in(lit(values)))
+        .then(lit(true))
+        .otherwise(missing))
+}
+
+fn negate(expr: Expr, negated: bool) -> Expr {
+    match negated {
+        true => expr.not(),
+        false => expr,
+    }
+}
+
+// LIKE / ILIKE 转换成正则表达式匹配, 模式只支持字符串常量
+fn like(
+    expr: Box<SqlExpr>,
+    pattern: Box<SqlExpr>,
+    escape_char: Option<String>,
+    negated: bool,
+    case_insensitive: bool,
+) -> Result<Expr> {
+    let pattern = match *pattern {
+        SqlExpr::Value(SqlValue::SingleQuotedString(pattern)) => pattern,
+        pattern => return Err(anyhow!("LIKE pattern {} must be a string", pattern)),
+    };
+    let escape = match escape_char
+        .as_deref()
+        .map(|e| e.chars().collect::<Vec<_>>())
+    {
+        None => None,
+        Some(chars) if chars.len() == 1 => Some(chars[0]),
+        Some(_) => return Err(anyhow!("ESCAPE must be a single character")),
+    };
+
+    let expr: Expr = Expression(expr).try_into()?;
+    let regex = like_to_regex(&pattern, escape, case_insensitive);
+    Ok(negate(expr.str().contains(lit(regex), true), negated))
+}
+
+// % 匹配任意个字符, _ 匹配一个字符, 其它字符按原样匹配
+fn like_to_regex(pattern: &str, escape: Option<char>, case_insensitive: bool) -> String {
+    let mut regex = String::from(match case_insensitive {
+        true => "(?is)^",
+        false => "(?s)^",
+    });
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            c if Some(c) == escape => match chars.next() {
+                Some(c) => c,
+                None => break,
+            },
+            '%' => {
+                regex.push_str(".*");
+                continue;
+            }
+            '_' => {
+                regex.push('.');
+                continue;
+            }
+            c => c,
+        };
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            regex.push('\\');
+        }
+        regex.push(c);
+    }
+    regex.push('$');
+    regex
+}
+
+// Convert Function to Expr, 支持 COUNT/SUM/AVG/MIN/MAX 聚合函数和 scalar() 中的标量函数
+impl TryFrom<Function> for Expr {
+    type Error = anyhow::Error;
+
+    fn try_from(func: Function) -> Result<Self, Self::Error> {
+        let f = func.0;
+        let name = f.name.to_string().to_lowercase();
+        if f.filter.is_some() || !f.within_group.is_empty() {
+            return Err(anyhow!(
+                "Function {} with FILTER/WITHIN GROUP not supported",
+                name
+            ));
+        }
+
+        let (distinct, args) = match f.args {
+            FunctionArguments::None => (false, Vec::new()),
+            FunctionArguments::List(list) => (
+                list.duplicate_treatment == Some(DuplicateTreatment::Distinct),
+                list.args,
+            ),
+            FunctionArguments::Subquery(_) => {
+                return Err(anyhow!("Subquery as argument of {} not supported", name));
+            }
+        };
+        // COUNT(*) 的参数为 None
+        let args = args
+            .into_iter()
+            .map(|arg| match arg {
+                FunctionArg::Unnamed(FunctionArgExpr::Expr(e)) => {
+                    Expression(Box::new(e)).try_into().map(Some)
+                }
+                FunctionArg::Unnamed(FunctionArgExpr::Wildcard) => Ok(None),
+                arg => Err(anyhow!("Argument {} of {} not supported", arg, name)),
+            })
+            .collect::<Result<Vec<Option<Expr>>>>()?;
+
+        if let Some(over) = f.over {
+            if distinct {
+                return Err(anyhow!(
+                    "DISTINCT is not allowed in window function {}",
+                    name
+                ));
+            }
+            return window(&name, args, over);
+        }
+
+        if !is_aggregate_name(&name) {
+            if distinct {
+                return Err(anyhow!("DISTINCT is not allowed in function {}", name));
+            }
+            let args = args
+                .into_iter()
+                .map(|arg| arg.ok_or_else(|| anyhow!("Function {} does not accept *", name)))
+                .collect::<Result<Vec<_>>>()?;
+            return scalar(&name, args);
+        }
+        aggregation(&name, &args, distinct)
+    }
+}
+
+// 聚合函数, COUNT(*) 的参数为 None
+fn aggregation(name: &str, args: &[Option<Expr>], distinct: bool) -> Result<Expr> {
+    let expr = match args {
+        [None] if name == "count" && !distinct => return Ok(len()),
+        [Some(expr)] => expr.clone(),
+        _ => return Err(anyhow!("Function {} expects exactly one argument", name)),
+    };
+    // 和 SQL 一样, 聚合时忽略 NULL
+    let expr = match distinct {
+        true => expr.drop_nulls().unique(),
+        false => expr,
+    };
+    Ok(match name {
+        "count" => expr.count(),
+        "sum" => expr.sum(),
+        "avg" => expr.mean(),
+        "min" => expr.min(),
+        _ => expr.max(),
+    })
+}
+
+// 窗口函数, 在每个 PARTITION BY 的分组中按 ORDER BY 的顺序计算, 结果对应回原来的行.
+// 有 ORDER BY 时 SUM / AVG / COUNT / MIN / MAX 是从分组的第一行到当前行的累计值, 和 SQL 默认的
+// RANGE 窗口一样包括排序值相同的所有行; 没有 ORDER BY 时是整个分组的聚合值
+fn window(name: &str, args: Vec<Option<Expr>>, over: WindowType) -> Result<Expr> {
+    let spec = match over {
+        WindowType::WindowSpec(spec) => spec,
+        WindowType::NamedWindow(w) => return Err(anyhow!("Named window {} not supported", w)),
+    };
+    if spec.window_frame.is_some() {
+        return Err(anyhow!("Window frame in OVER not supported"));
+    }
+
+    let partition_by = spec
+        .partition_by
+        .into_iter()
+        .map(|e| Expression(Box::new(e)).try_into())
+        .collect::<Result<Vec<Expr>>>()?;
+    // polars 的窗口只能按同一个方向排序
+    let mut order_by = Vec::new();
+    let mut direction = None;
+    for order in &spec.order_by {
+        let (descending, nulls_last) = Order(order).try_into()?;
+        if direction.is_some_and(|d| d != (descending, nulls_last)) {
+            return Err(anyhow!(
+                "ORDER BY in OVER with different directions not supported"
+            ));
+        }
+        direction = Some((descending, nulls_last));
+        order_by.push(Expr::try_from(Expression(Box::new(order.expr.clone())))?);
+    }
+    let (descending, nulls_last) = direction.unwrap_or_default();
+
+    let expr = match (name, args.as_slice()) {
+        // RANK 自己按 ORDER BY 的值排序, 只需要按 PARTITION BY 分组. polars 的 rank 中 NULL 的
+        // 排名也是 NULL, 这里在每个值前面加上它是否为 NULL, 按它们组成的 struct 排序. 这样 NULL
+        // 之间排名相同, 并且按照 NULLS FIRST / LAST 排在最前或最后
+        ("rank" | "dense_rank", []) => {
+            if order_by.is_empty() {
+                return Err(anyhow!("Function {} requires ORDER BY", name));
+            }
+            let mut fields = Vec::new();
+            for (i, key) in order_by.iter().enumerate() {
+                let null = match nulls_last != descending {
+                    true => key.clone().is_null(),
+                    false => key.clone().is_not_null(),
+                };
+                fields.push(null.alias(format!("{}.null", i)));
+                fields.push(key.clone().alias(i.to_string()));
+            }
+            let method = match name {
+                "rank" => RankMethod::Min,
+                _ => RankMethod::Dense,
+            };
+            let expr = as_struct(fields).rank(RankOptions { method, descending }, None);
+            return Ok(partitioned(expr, partition_by));
+        }
+        ("row_number", []) => row_number(),
+        // 和 SQL 默认的 RANGE 窗口一样, ORDER BY 的值相同的行结果相同, 见 peers
+        ("count", [None]) if !order_by.is_empty() => peers(row_number(), &order_by),
+        ("lag" | "lead", [Some(expr), rest @ ..]) if rest.len() <= 2 => {
+            let offset = match rest.first() {
+                Some(Some(offset)) => offset.clone(),
+                None => lit(1),
+                Some(None) => return Err(anyhow!("Function {} does not accept *", name)),
+            };
+            let offset = match name {
+                "lag" => offset,
+                _ => lit(0) - offset,
+            };
+            match rest.get(1) {
+                Some(Some(default)) => expr.clone().shift_and_fill(offset, default.clone()),
+                Some(None) => return Err(anyhow!("Function {} does not accept *", name)),
+                None => expr.clone().shift(offset),
+            }
+        }
+        // 累计的结果在值为 NULL 的行上也是 NULL, 向前填充之后才是到这一行为止的结果
+        (_, [Some(expr)]) if is_aggregate_name(name) && !order_by.is_empty() => {
+            let expr = expr.clone();
+            let expr = match name {
+                "count" => expr.cum_count(false),
+                "sum" => expr.cum_sum(false).forward_fill(None),
+                "avg" => {
+                    expr.clone()
+                        .cast(DataType::Float64)
+                        .cum_sum(false)
+                        .forward_fill(None)
+                        / expr.cum_count(false).cast(DataType::Float64)
+                }
+                "min" => expr.cum_min(false).forward_fill(None),
+                _ => expr.cum_max(false).forward_fill(None),
+            };
+            peers(expr, &order_by)
+        }
+        _ if is_aggregate_name(name) => {
+            return Ok(partitioned(aggregation(name, &args, false)?, partition_by));
+        }
+        _ => return Err(anyhow!("Window function {} not supported", name)),
+    };
+
+    if order_by.is_empty() {
+        return Ok(partitioned(expr, partition_by));
+    }
+    // 稳定排序, ORDER BY 的值相同的行保持原来的顺序
+    let options = SortOptions::default()
+        .with_order_descending(descending)
+        .with_nulls_last(nulls_last)
+        .with_maintain_order(true);
+    // 没有 PARTITION BY 时整个表是一个分组
+    let partition_by = match partition_by.is_empty() {
+        true => vec![lit(true)],
+        false => partition_by,
+    };
+    Ok(expr.over_with_options(
+        partition_by,
+        Some((order_by, options)),
+        WindowMapping::GroupsToRows,
+    ))
+}
+
+// 分组中的行号, 从 1 开始
+fn row_number() -> Expr {
+    int_range(lit(1), len().cast(DataType::Int64) + lit(1), 1, IDX_DTYPE)
+}
+
+// 在排好序的分组中, 每一行取和它 ORDER BY 的值相同的最后一行 (peer) 的结果
+fn peers(expr: Expr, order_by: &[Expr]) -> Expr {
+    let index = int_range(lit(0), len().cast(DataType::Int64), 1, DataType::Int64);
+    let end = index.clone().eq(len().cast(DataType::Int64) - lit(1));
+    let last = order_by.iter().fold(end, |last, key| {
+        last.or(key.clone().neq_missing(key.clone().shift(lit(-1))))
+    });
+    let last = when(last).then(index).otherwise(lit(NULL));
+    expr.gather(last.backward_fill(None))
+}
+
+// 没有 PARTITION BY 时对整个表计算
+fn partitioned(expr: Expr, partition_by: Vec<Expr>) -> Expr {
+    match partition_by.is_empty() {
+        true => expr,
+        false => expr.over(partition_by),
+    }
+}
+
+// 标量函数. 先检查函数是否存在和参数的个数, 再转换成 polars 的表达式
+fn scalar(name: &str, args: Vec<Expr>) -> Result<Expr> {
+    let (min, max) = match name {
+        "lower" | "upper" | "length" | "trim" | "abs" | "sqrt" => (1, 1),
+        "round" => (1, 2),
+        "substr" => (2, 3),
+        "nullif" => (2, 2),
+        "concat" | "coalesce" => (1, usize::MAX),
+        _ => return Err(anyhow!("Function {} not supported", name)),
+    };
+    if args.len() < min || args.len() > max {
+        let expected = match (min, max) {
+            (min, max) if min == max => format!("{}", min),
+            (min, usize::MAX) => format!("at least {}", min),
+            (min, max) => format!("{} to {}", min, max),
+        };
+        return Err(anyhow!(
+            "Function {} expects {} argument(s), got {}",
+            name,
+            expected,
+            args.len()
+        ));
+    }
+
+    Ok(match (name, args.as_slice()) {
+        ("lower", [s]) => s.clone().str().to_lowercase(),
+        ("upper", [s]) => s.clone().str().to_uppercase(),
+        ("length", [s]) => s.clone().str().len_chars(),
+        ("trim", [s]) => s.clone().str().strip_chars(lit(NULL)),
+        ("substr", [s, from, length @ ..]) => {
+            substr(s.clone(), from.clone(), length.first().cloned())
+        }
+        ("concat", args) => concat_str(args, "", true),
+        ("abs", [x]) => x.clone().abs(),
+        ("sqrt", [x]) => x.clone().sqrt(),
+        ("round", [x]) => x.clone().round(0),
+        ("round", [x, Expr::Literal(LiteralValue::Int64(decimals))]) if *decimals >= 0 => {
+            x.clone().round(*decimals as u32)
+        }
+        ("round", _) => {
+            return Err(anyhow!(
+                "Second argument of round must be a non-negative integer"
+            ));
+        }
+        ("coalesce", args) => coalesce(args),
+        ("nullif", [a, b]) => when(a.clone().eq(b.clone()))
+            .then(lit(NULL))
+            .otherwise(a.clone()),
+        _ => unreachable!(),
+    })
+}
+
+// SQL 中字符串的下标从 1 开始, 没有长度时截取到结尾
+fn substr(s: Expr, from: Expr, length: Option<Expr>) -> Expr {
+    s.str()
+        .slice(from - lit(1), length.unwrap_or_else(|| lit(NULL)))
+}
+
+fn is_aggregate_name(name: &str) -> bool {
+    matches!(name, "count" | "sum" | "avg" | "min" | "max")
+}
+
+// 带 OVER 的是窗口函数, 不是聚合
+fn is_aggregate(f: &SqlFunction) -> bool {
+    f.over.is_none() && is_aggregate_name(&f.name.to_string().to_lowercase())
+}
+
+// 表达式中是否包含聚合函数
+fn has_aggregate(expr: &SqlExpr) -> bool {
+    match expr {
+        SqlExpr::Function(f) => {
+            is_aggregate(f)
+                || match &f.args {
+                    FunctionArguments::List(list) => list.args.iter().any(|arg| {
+                        matches!(arg, FunctionArg::Unnamed(FunctionArgExpr::Expr(e)) if has_aggregate(e))
+                    }),
+                    _ => false,
+                }
+        }
+        SqlExpr::BinaryOp { left, right, .. } => has_aggregate(left) || has_aggregate(right),
+        SqlExpr::Nested(expr)
+        | SqlExpr::Cast { expr, .. }
+        | SqlExpr::Trim { expr, .. }
+        | SqlExpr::UnaryOp { expr, .. }
+        | SqlExpr::IsNull(expr)
+        | SqlExpr::IsNotNull(expr) => has_aggregate(expr),
+        SqlExpr::Between {
+            expr, low, high, ..
+        } => has_aggregate(expr) || has_aggregate(low) || has_aggregate(high),
+        SqlExpr::InList { expr, list, .. } => has_aggregate(expr) || list.iter().any(has_aggregate),
+        SqlExpr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            operand.as_deref().is_some_and(has_aggregate)
+                || conditions.iter().chain(results).any(has_aggregate)
+                || else_result.as_deref().is_some_and(has_aggregate)
+        }
+        _ => false,
+    }
+}
+
+// 没有别名时结果列的名字, 标识符直接使用它的名字, 其它表达式使用 SQL 原文
+fn output_name(expr: &SqlExpr) -> String {
+    match expr {
+        SqlExpr::Identifier(id) => id.value.clone(),
+        expr => expr.to_string(),
+    }
+}
+
+// 去掉列名前面的表名或别名
+pub(crate) fn unqualified(name: &str) -> &str {
+    name.rsplit('.').next().unwrap_or(name)
+}
+
+// 子查询的结果在外层查询中的列名, 相同的子查询只执行一次. expr 是 IN 左边的表达式
+fn subquery_column(q: &Query, expr: Option<&SqlExpr>) -> String {
+    match expr {
+        None => format!("__subquery({})", q),
+        Some(expr) => format!("__in_subquery({} IN ({}))", expr, q),
+    }
+}
+
+// 按顺序找出表达式中的子查询, 不会进入子查询的内部. 第二项是 IN 左边的表达式,
+// 标量子查询为 None
+type Found<'a> = Vec<(&'a Query, Option<&'a SqlExpr>)>;
+
+fn subqueries<'a>(expr: &'a SqlExpr, found: &mut Found<'a>) {
+    match expr {
+        SqlExpr::Subquery(q) => found.push((q, None)),
+        SqlExpr::InSubquery { expr, subquery, .. } => {
+            subqueries(expr, found);
+            found.push((subquery, Some(expr)));
+        }
+        SqlExpr::BinaryOp { left, right, .. } => {
+            subqueries(left, found);
+            subqueries(right, found);
+        }
+        SqlExpr::Nested(expr)
+        | SqlExpr::UnaryOp { expr, .. }
+        | SqlExpr::IsNull(expr)
+        | SqlExpr::IsNotNull(expr)
+        | SqlExpr::Cast { expr, .. }
+        | SqlExpr::Like { expr, .. }
+        | SqlExpr::ILike { expr, .. } => subqueries(expr, found),
+        SqlExpr::Between {
+            expr, low, high, ..
+        } => {
+            for e in [expr, low, high] {
+                subqueries(e, found);
+            }
+        }
+        SqlExpr::InList { expr, list, .. } => {
+            subqueries(expr, found);
+            list.iter().for_each(|e| subqueries(e, found));
+        }
+        SqlExpr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            operand.iter().for_each(|e| subqueries(e, found));
+            conditions
+                .iter()
+                .chain(results)
+                .for_each(|e| subqueries(e, found));
+            else_result.iter().for_each(|e| subqueries(e, found));
+        }
+        SqlExpr::Function(f) => {
+            if let FunctionArguments::List(list) = &f.args {
+                for arg in &list.args {
+                    if let FunctionArg::Unnamed(FunctionArgExpr::Expr(e)) = arg {
+                        subqueries(e, found);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// ORDER BY 可以使用结果中的列名, 别名, 位置 (从 1 开始) 或者任意表达式.
+// 不在结果中的表达式作为临时列加到 projection 的最后, 和其它列一样在 select 或聚合中计算
+fn sort_keys(
+    projection: &[SelectItem],
+    orders: &[OrderByExpr],
+) -> Result<(Vec<SelectItem>, Option<OrderBy>)> {
+    if orders.is_empty() {
+        return Ok((projection.to_vec(), None));
+    }
+
+    // 结果中的列名, 和 Projection 的转换一致
+    let names: Vec<Option<&str>> = projection
+        .iter()
+        .map(|p| match p {
+            SelectItem::ExprWithAlias { alias, .. } => Some(alias.value.as_str()),
+            SelectItem::UnnamedExpr(SqlExpr::Identifier(id)) => Some(unqualified(&id.value)),
+            _ => None,
+        })
+        .collect();
+
+    let mut items = projection.to_vec();
+    let mut keys = Vec::new();
+    let mut hidden = Vec::new();
+    let mut descending = Vec::new();
+    let mut nulls_last = Vec::new();
+    for order in orders {
+        let (desc, last) = Order(order).try_into()?;
+        descending.push(desc);
+        nulls_last.push(last);
+
+        let (name, expr) = match (&order.expr, projection) {
+            // 只有 * 时 (比如 UNION 的结果) 结果中的列要到执行时才知道, 直接按位置引用
+            (SqlExpr::Value(SqlValue::Number(n, _)), [SelectItem::Wildcard(_)]) => {
+                let i = n
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|i| *i >= 1)
+                    .ok_or_else(|| anyhow!("ORDER BY position {} is not in select list", n))?;
+                keys.push(nth(i - 1));
+                continue;
+            }
+            (SqlExpr::Value(SqlValue::Number(n, _)), _) => {
+                let i = n
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|i| (1..=projection.len()).contains(i))
+                    .ok_or_else(|| anyhow!("ORDER BY position {} is not in select list", n))?;
+                match &projection[i - 1] {
+                    SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                        (names[i - 1], expr)
+                    }
+                    _ => return Err(anyhow!("ORDER BY position {} refers to *", n)),
+                }
+            }
+            (SqlExpr::Identifier(id), _) => {
+                let name = names.iter().flatten().find(|name| **name == id.value);
+                (name.copied(), &order.expr)
+            }
+            (expr, _) => (None, expr),
+        };
+
+        keys.push(match name {
+            Some(name) => col(name),
+            None => {
+                let name = format!("{}{}", SORT_COLUMN, hidden.len());
+                items.push(SelectItem::ExprWithAlias {
+                    expr: expr.clone(),
+                    alias: Ident::new(&name),
+                });
+                let key = col(&name);
+                hidden.push(name);
+                key
+            }
+        });
+    }
+
+    let order_by = OrderBy {
+        keys,
+        options: SortMultipleOptions {
+            descending,
+            nulls_last,
+            maintain_order: true,
+            ..Default::default()
+        },
+        hidden,
+    };
+    Ok((items, Some(order_by)))
+}
+
+// 把带 GROUP BY 或聚合函数的查询拆成分组聚合和之后的 select.
+// select 的每一项要么是 GROUP BY 的 key, 要么包含聚合函数
+fn aggregate(
+    projection: &[SelectItem],
+    group_by: &[SqlExpr],
+    having: Option<&SqlExpr>,
+) -> Result<(GroupBy, Vec<Expr>)> {
+    let items = projection
+        .iter()
+        .map(|p| match p {
+            SelectItem::UnnamedExpr(expr) => Ok((expr, None)),
+            SelectItem::ExprWithAlias { expr, alias } => Ok((expr, Some(alias))),
+            _ => Err(anyhow!("Wildcard not supported in aggregate query")),
+        })
+        .collect::<Result<Vec<(&SqlExpr, Option<&Ident>)>>>()?;
+
+    // GROUP BY 可以使用 select 中的位置 (从 1 开始) 或者别名
+    let mut keys = Vec::new();
+    for expr in group_by {
+        let expr = match expr {
+            SqlExpr::Value(SqlValue::Number(n, _)) => n
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| items.get(i.wrapping_sub(1)))
+                .map(|(expr, _)| *expr)
+                .ok_or_else(|| anyhow!("GROUP BY position {} is not in select list", n))?,
+            SqlExpr::Identifier(id) => items
+                .iter()
+                .find(|(_, alias)| alias.is_some_and(|a| a.value == id.value))
+                .map_or(expr, |(expr, _)| *expr),
+            expr => expr,
+        };
+        if has_aggregate(expr) {
+            return Err(anyhow!(
+                "Aggregate function not allowed in GROUP BY: {}",
+                expr
+            ));
+        }
+        keys.push(expr);
+    }
+
+    let mut aggs: Vec<Expr> = Vec::new();
+    let mut names = Vec::new();
+    let mut selection = Vec::new();
+    for &(expr, alias) in &items {
+        let name = output_name(expr);
+        if !keys.contains(&expr) {
+            if !has_aggregate(expr) {
+                return Err(anyhow!(
+                    "Column {} must appear in GROUP BY or be used in an aggregate function",
+                    expr
+                ));
+            }
+            // 相同的聚合只计算一次
+            if !names.contains(&name) {
+                let agg: Expr = Expression(Box::new(expr.clone())).try_into()?;
+                aggs.push(agg.alias(&name));
+                names.push(name.clone());
+            }
+        }
+        selection.push(match (alias, expr) {
+            (Some(alias), _) => col(&name).alias(&alias.value),
+            (None, SqlExpr::Identifier(id)) => col(&name).alias(unqualified(&id.value)),
+            (None, _) => col(&name),
+        });
+    }
+
+    let having = match having {
+        Some(expr) => {
+            let expr = having_expr(expr, &items, &keys, &mut aggs, &mut names)?;
+            Some(Expression(Box::new(expr)).try_into()?)
+        }
+        None => None,
+    };
+
+    let keys = keys
+        .into_iter()
+        .map(|expr| {
+            let key: Expr = Expression(Box::new(expr.clone())).try_into()?;
+            Ok(key.alias(output_name(expr)))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((GroupBy { keys, aggs, having }, selection))
+}
+
+// HAVING 在聚合的结果上过滤, 把其中的 GROUP BY key, select 的别名和聚合函数都换成聚合结果中的列.
+// 不在 select 中的聚合函数作为临时列加到 aggs 中, 最后的 select 会把它去掉
+fn having_expr(
+    expr: &SqlExpr,
+    items: &[(&SqlExpr, Option<&Ident>)],
+    keys: &[&SqlExpr],
+    aggs: &mut Vec<Expr>,
+    names: &mut Vec<String>,
+) -> Result<SqlExpr> {
+    let column = |name: String| SqlExpr::Identifier(Ident::new(name));
+    if keys.contains(&expr) {
+        return Ok(column(output_name(expr)));
+    }
+    match expr {
+        // select 中的每一项都已经是聚合结果中的列
+        SqlExpr::Identifier(id) => {
+            return items
+                .iter()
+                .find(|(_, alias)| alias.is_some_and(|a| a.value == id.value))
+                .map(|(expr, _)| column(output_name(expr)))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Column {} in HAVING must appear in GROUP BY or be used in an aggregate function",
+                        id
+                    )
+                });
+        }
+        SqlExpr::CompoundIdentifier(_) => {
+            return Err(anyhow!(
+                "Column {} in HAVING must appear in GROUP BY or be used in an aggregate function",
+                expr
+            ));
+        }
+        SqlExpr::Function(f) if is_aggregate(f) => {
+            let name = output_name(expr);
+            if !names.contains(&name) {
+                let agg: Expr = Expression(Box::new(expr.clone())).try_into()?;
+                aggs.push(agg.alias(&name));
+                names.push(name.clone());
+            }
+            return Ok(column(name));
+        }
+        _ => {}
+    }
+
+    let mut expr = expr.clone();
+    for child in children_mut(&mut expr) {
+        *child = having_expr(child, items, keys, aggs, names)?;
+    }
+    Ok(expr)
+}
+
+// 表达式的直接子表达式
+fn children_mut(expr: &mut SqlExpr) -> Vec<&mut SqlExpr> {
+    match expr {
+        SqlExpr::BinaryOp { left, right, .. } => vec![left, right],
+        SqlExpr::Nested(expr)
+        | SqlExpr::UnaryOp { expr, .. }
+        | SqlExpr::IsNull(expr)
+        | SqlExpr::IsNotNull(expr)
+        | SqlExpr::Cast { expr, .. } => vec![expr],
+        SqlExpr::Like { expr, pattern, .. } | SqlExpr::ILike { expr, pattern, .. } => {
+            vec![expr, pattern]
+        }
+        SqlExpr::Between {
+            expr, low, high, ..
+        } => vec![expr, low, high],
+        SqlExpr::InList { expr, list, .. } => {
+            let mut children = vec![expr.as_mut()];
+            children.extend(list.iter_mut());
+            children
+        }
+        SqlExpr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => operand
+            .iter_mut()
+            .map(|e| e.as_mut())
+            .chain(conditions.iter_mut())
+            .chain(results.iter_mut())
+            .chain(else_result.iter_mut().map(|e| e.as_mut()))
+            .collect(),
+        SqlExpr::Function(f) => match &mut f.args {
+            FunctionArguments::List(list) => list
+                .args
+                .iter_mut()
+                .filter_map(|arg| match arg {
+                    FunctionArg::Unnamed(FunctionArgExpr::Expr(e)) => Some(e),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
 // Convert Statement to Sql
 impl<'a> TryFrom<&'a Statement> for Sql<'a> {
     type Error = anyhow::Error;
 
     fn try_from(sql: &'a Statement) -> Result<Self, Self::Error> {
         match sql {
-            Statement::Query(q) => {
-                let offset = q.offset.as_ref().map(|v| Offset(v).into());
-                let limit = q.limit.as_ref().and_then(|v| match v {
-                    SqlExpr::Value(SqlValue::Number(n, _)) => n.parse().ok(),
-                    _ => None,
-                });
+            Statement::Query(q) => q.as_ref().try_into(),
+            _ => Err(anyhow!("Only support Query")),
+        }
+    }
+}
 
-                let mut order_by = Vec::new();
-                if let Some(orders) = &q.order_by {
-                    for expr in orders.exprs.iter() {
-                        order_by.push((
-                            match &expr.expr {
-                                SqlExpr::Identifier(id) => id.value.clone(),
-                                _ => return Err(anyhow!("Order by only support identifier")),
-                            },
-                            !expr.asc.unwrap_or(true),
-                        ));
-                    }
-                }
+// 一个 SELECT, ORDER BY 的 keys 要和 select 的列一起处理
+fn select<'a>(s: &'a Select, orders: &[OrderByExpr]) -> Result<Sql<'a>> {
+    let (projection, order_by) = sort_keys(&s.projection, orders)?;
+    let keys = match &s.group_by {
+        GroupByExpr::Expressions(keys, modifiers) if modifiers.is_empty() => keys,
+        _ => return Err(anyhow!("Only support GROUP BY expressions")),
+    };
+
+    // 子查询只支持出现在 WHERE 中
+    let mut found = Vec::new();
+    for p in &projection {
+        if let SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } = p {
+            subqueries(expr, &mut found);
+        }
+    }
+    s.having
+        .iter()
+        .chain(keys)
+        .for_each(|e| subqueries(e, &mut found));
+    if !found.is_empty() {
+        return Err(anyhow!("Subquery is only supported in WHERE"));
+    }
 
-                let mut selection = Vec::new();
-                let condition;
+    let mut selection = Vec::new();
+    let group_by = if !keys.is_empty()
+        || s.having.is_some()
+        || projection.iter().any(|p| match p {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                has_aggregate(expr)
+            }
+            _ => false,
+        }) {
+        let (group_by, items) = aggregate(&projection, keys, s.having.as_ref())?;
+        selection = items;
+        Some(group_by)
+    } else {
+        for p in &projection {
+            selection.push(Projection(p).try_into()?);
+        }
+        None
+    };
 
-                if let SetExpr::Select(s) = &*q.body {
-                    for p in &s.projection {
-                        selection.push(Projection(p).try_into()?);
-                    }
+    let condition = s
+        .selection
+        .as_ref()
+        .map(|v| Expression(Box::new(v.clone())).try_into())
+        .transpose()?;
 
-                    condition = s.selection
-                        .as_ref()
-                        .map(|v| Expression(Box::new(v.clone())).try_into())
-                        .transpose()?;
-
-                    Ok(Sql {
-                        selection,
-                        condition,
-                        source: Source(&s.from).try_into()?,
-                        order_by,
-                        offset,
-                        limit,
-                    })
-                } else {
-                    Err(anyhow!("Only support Select query"))
+    let mut found = Vec::new();
+    s.selection.iter().for_each(|e| subqueries(e, &mut found));
+    let mut subqueries: Vec<Subquery> = Vec::new();
+    for (q, expr) in found {
+        let name = subquery_column(q, expr);
+        if !subqueries.iter().any(|s| s.name == name) {
+            let sql = q.try_into()?;
+            let expr = expr
+                .map(|e| Expression(Box::new(e.clone())).try_into())
+                .transpose()?;
+            subqueries.push(Subquery { name, sql, expr });
+        }
+    }
+
+    let (source, joins) = Source(&s.from).try_into()?;
+
+    Ok(Sql {
+        ctes: Vec::new(),
+        subqueries,
+        selection,
+        condition,
+        group_by,
+        source,
+        joins,
+        order_by,
+        offset: None,
+        limit: None,
+    })
+}
+
+// Convert Query to Sql, CTE 和子查询也是 Query
+impl<'a> TryFrom<&'a Query> for Sql<'a> {
+    type Error = anyhow::Error;
+
+    fn try_from(q: &'a Query) -> Result<Self, Self::Error> {
+        let offset = q.offset.as_ref().map(|v| Offset(v).into());
+        let limit = q.limit.as_ref().and_then(|v| match v {
+            SqlExpr::Value(SqlValue::Number(n, _)) => n.parse().ok(),
+            _ => None,
+        });
+
+        let mut ctes = Vec::new();
+        if let Some(with) = &q.with {
+            if with.recursive {
+                return Err(anyhow!("WITH RECURSIVE not supported"));
+            }
+            for cte in &with.cte_tables {
+                if !cte.alias.columns.is_empty() {
+                    return Err(anyhow!("Column list of CTE {} not supported", cte.alias));
                 }
+                ctes.push((
+                    cte.alias.name.value.as_str(),
+                    cte.query.as_ref().try_into()?,
+                ));
             }
-            _ => Err(anyhow!("Only support Query")),
         }
+
+        let orders = q.order_by.as_ref().map_or(&[][..], |o| &o.exprs);
+        let mut sql = match &*q.body {
+            SetExpr::Select(s) => select(s, orders)?,
+            // UNION 等集合运算的结果作为数据源, ORDER BY 作用在整个结果上
+            body => {
+                let nested = Nested::try_from(body)?;
+                let wildcard = SelectItem::Wildcard(Default::default());
+                let (projection, order_by) = sort_keys(&[wildcard], orders)?;
+                let selection = projection
+                    .iter()
+                    .map(|p| Projection(p).try_into())
+                    .collect::<Result<_>>()?;
+                let source = match &nested {
+                    Nested::Set(set) => match set.op {
+                        SetOperator::Union => "union",
+                        SetOperator::Intersect => "intersect",
+                        SetOperator::Except => "except",
+                    },
+                    Nested::Select(_) => "subquery",
+                };
+                Sql {
+                    ctes: Vec::new(),
+                    subqueries: Vec::new(),
+                    selection,
+                    condition: None,
+                    group_by: None,
+                    source: Table {
+                        source,
+                        alias: None,
+                        format: None,
+                        query: Some(nested),
+                    },
+                    joins: Vec::new(),
+                    order_by,
+                    offset: None,
+                    limit: None,
+                }
+            }
+        };
+        sql.ctes = ctes;
+        sql.offset = offset;
+        sql.limit = limit;
+        Ok(sql)
+    }
+}
+
+// 集合运算的两边可以是 SELECT, 括号中的查询或者另一个集合运算
+impl<'a> TryFrom<&'a SetExpr> for Nested<'a> {
+    type Error = anyhow::Error;
+
+    fn try_from(body: &'a SetExpr) -> Result<Self, Self::Error> {
+        match body {
+            SetExpr::Select(s) => Ok(Nested::Select(Box::new(select(s, &[])?))),
+            SetExpr::Query(q) => Ok(Nested::Select(Box::new(q.as_ref().try_into()?))),
+            SetExpr::SetOperation {
+                op,
+                set_quantifier,
+                left,
+                right,
+            } => {
+                let all = match set_quantifier {
+                    SetQuantifier::All => true,
+                    SetQuantifier::Distinct | SetQuantifier::None => false,
+                    quantifier => return Err(anyhow!("{} {} not supported", op, quantifier)),
+                };
+                Ok(Nested::Set(SetOperation {
+                    op: *op,
+                    all,
+                    left: Box::new(left.as_ref().try_into()?),
+                    right: Box::new(right.as_ref().try_into()?),
+                }))
+            }
+            _ => Err(anyhow!("Only support Select query")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dialect::TyrDialect;
+    use sqlparser::parser::Parser;
+
+    fn int(n: i64) -> Expr {
+        lit(LiteralValue::Int64(n))
+    }
+
+    fn expr(sql: &str) -> Result<Expr> {
+        let expr = Parser::new(&TyrDialect).try_with_sql(sql)?.parse_expr()?;
+        Expression(Box::new(expr)).try_into()
+    }
+
+    #[test]
+    fn in_list_and_between_should_be_converted() -> Result<()> {
+        let a = || col("a");
+        let found = |values: Series, missing: Expr| {
+            when(a().is_null())
+                .then(lit(NULL))
+                .when(a().is_in(lit(values)))
+                .then(lit(true))
+                .otherwise(missing)
+        };
+        let ints = Series::new("".into(), [1i64, 2]);
+        assert_eq!(expr("a IN (1, 2)")?, found(ints.clone(), lit(false)));
+        assert_eq!(expr("a IN (1, NULL, 2)")?, found(ints, lit(NULL)));
+        let strs = Series::new("".into(), ["x"]);
+        assert_eq!(expr("a NOT IN ('x')")?, found(strs, lit(false)).not());
+        // 列表中不是常量时展开成 OR
+        assert_eq!(
+            expr("a IN (b, 1)")?,
+            lit(false).or(a().eq(col("b"))).or(a().eq(int(1)))
+        );
+        assert_eq!(
+            expr("a BETWEEN 1 AND 2")?,
+            a().gt_eq(int(1)).and(a().lt_eq(int(2)))
+        );
+        assert_eq!(
+            expr("a NOT BETWEEN 1 AND 2")?,
+            a().gt_eq(int(1)).and(a().lt_eq(int(2))).not()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unary_and_null_checks_should_be_converted() -> Result<()> {
+        assert_eq!(expr("NOT a")?, col("a").not());
+        assert_eq!(expr("-a")?, -col("a"));
+        assert_eq!(expr("a IS NULL")?, col("a").is_null());
+        assert_eq!(expr("a IS NOT NULL")?, col("a").is_not_null());
+        assert!(expr("~a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn case_should_be_converted() -> Result<()> {
+        assert_eq!(
+            expr("CASE WHEN a > 1 THEN 'big' WHEN a > 0 THEN 'small' ELSE 'none' END")?,
+            when(col("a").gt(int(1))).then(lit("big")).otherwise(
+                when(col("a").gt(int(0)))
+                    .then(lit("small"))
+                    .otherwise(lit("none"))
+            )
+        );
+        assert_eq!(
+            expr("CASE a WHEN 1 THEN 'one' END")?,
+            when(col("a").eq(int(1)))
+                .then(lit("one"))
+                .otherwise(lit(NULL))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn like_should_be_converted_to_regex() -> Result<()> {
+        assert_eq!(like_to_regex("a%b_c", None, false), "(?s)^a.*b.c$");
+        assert_eq!(like_to_regex("1.5*%", None, true), "(?is)^1\\.5\\*.*$");
+        assert_eq!(like_to_regex("100!%", Some('!'), false), "(?s)^100%$");
+        assert_eq!(
+            expr("a NOT ILIKE 'x%'")?,
+            col("a").str().contains(lit("(?is)^x.*$"), true).not()
+        );
+        assert!(expr("a LIKE b").is_err());
+        Ok(())
     }
 }
@@ -1,4 +1,3 @@
-use crate::example_sql;
 use sqlparser::dialect::Dialect;
 
 #[derive(Debug, Default)]
@@ -6,24 +5,22 @@ pub struct TyrDialect;
 
 impl Dialect for TyrDialect {
     fn is_identifier_start(&self, ch: char) -> bool {
-        ('a'..='z').contains(&ch) || ('A'..='Z').contains(&ch) || ch == '_'
+        ch.is_ascii_alphabetic() || ch == '_'
     }
 
     fn is_identifier_part(&self, ch: char) -> bool {
-        ('a'..='z').contains(&ch)
-            || ('A'..='Z').contains(&ch)
-            || ('0'..='9').contains(&ch)
-            || [':', '/', '?', '&', '=', '-', '_', '.'].contains(&ch)
+        ch.is_ascii_alphanumeric() || [':', '/', '?', '&', '=', '-', '_', '.'].contains(&ch)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::example_sql;
     use sqlparser::parser::Parser;
 
     #[test]
     fn it_works() {
-        assert!(Parser::parse_sql(&TyrDialect::default(), &example_sql()).is_ok());
+        assert!(Parser::parse_sql(&TyrDialect, &example_sql()).is_ok());
     }
 }
@@ -0,0 +1,143 @@
+use anyhow::{Result, anyhow};
+use polars::prelude::*;
+use std::ops::Range;
+
+use crate::convert::{Join, Table};
+
+// 数据源中的一列, actual 是它在 join 之后的 DataFrame 中的名字
+struct Column {
+    table: usize,
+    qualified: String,
+    name: String,
+    actual: String,
+}
+
+// 把 SQL 中的列名 (可以用表的别名限定) 解析成 DataFrame 中真正的列名.
+// 只有一个数据源时不改列名; 有多个数据源时每一列都改名成 "别名.列名", join 之后不会重名
+pub struct Columns {
+    qualify: bool,
+    tables: Vec<String>,
+    columns: Vec<Column>,
+}
+
+impl Columns {
+    pub fn new(qualify: bool) -> Self {
+        Self {
+            qualify,
+            tables: Vec::new(),
+            columns: Vec::new(),
+        }
+    }
+
+    // 登记数据源的所有列, 返回改名之后的 LazyFrame
+    pub fn add(&mut self, table: &Table, mut df: LazyFrame) -> Result<LazyFrame> {
+        let qualifier = table.alias.unwrap_or(table.source);
+        if self.tables.iter().any(|t| t == qualifier) {
+            return Err(anyhow!("Table {} specified more than once", qualifier));
+        }
+        let index = self.tables.len();
+        self.tables.push(qualifier.to_string());
+
+        let schema = df.collect_schema()?;
+        let names: Vec<String> = schema.iter_names().map(|n| n.to_string()).collect();
+        let mut actuals = Vec::with_capacity(names.len());
+        for name in &names {
+            let qualified = format!("{}.{}", qualifier, name);
+            let actual = match self.qualify {
+                true => qualified.clone(),
+                false => name.clone(),
+            };
+            actuals.push(actual.clone());
+            self.columns.push(Column {
+                table: index,
+                qualified,
+                name: name.clone(),
+                actual,
+            });
+        }
+
+        Ok(match self.qualify {
+            true => df.rename(names, actuals, true),
+            false => df,
+        })
+    }
+
+    // 把表达式中引用的列换成真正的列名. 找不到的列保持原样, 执行时由 polars 报错
+    pub fn resolve(&self, expr: Expr) -> Result<Expr> {
+        self.resolve_in(expr, 0..self.tables.len(), false)
+    }
+
+    // 把 left 和最后登记的数据源 right 按照 join 的条件连接起来
+    pub fn join(&self, left: LazyFrame, right: LazyFrame, join: Join) -> Result<LazyFrame> {
+        if join.how == JoinType::Cross {
+            return Ok(left.cross_join(right, None));
+        }
+
+        // ON 中等式的两边可以按任意顺序书写
+        let index = self.tables.len() - 1;
+        let (lefts, rights) = (0..index, index..index + 1);
+        let mut left_on = Vec::new();
+        let mut right_on = Vec::new();
+        for (l, r) in join.on {
+            let resolved = self
+                .resolve_in(l.clone(), lefts.clone(), true)
+                .and_then(|l| Ok((l, self.resolve_in(r.clone(), rights.clone(), true)?)));
+            let (l, r) = match resolved {
+                Ok(pair) => pair,
+                Err(_) => (
+                    self.resolve_in(r, lefts.clone(), true)?,
+                    self.resolve_in(l, rights.clone(), true)?,
+                ),
+            };
+            left_on.push(l);
+            right_on.push(r);
+        }
+
+        Ok(left
+            .join_builder()
+            .with(right)
+            .how(join.how)
+            .left_on(left_on)
+            .right_on(right_on)
+            .coalesce(JoinCoalesce::KeepColumns)
+            .finish())
+    }
+
+    // 只在 tables 范围内的数据源中解析, strict 时找不到列也是错误
+    fn resolve_in(&self, expr: Expr, tables: Range<usize>, strict: bool) -> Result<Expr> {
+        let mut error = None;
+        let expr = expr.map_expr(|e| match e {
+            Expr::Column(name) => match self.lookup(&name, tables.clone()) {
+                Ok(Some(column)) => col(&column.actual),
+                Ok(None) if strict => {
+                    let tables = self.tables[tables.clone()].join(", ");
+                    error.get_or_insert(anyhow!("Column {} not found in {}", name, tables));
+                    Expr::Column(name)
+                }
+                Ok(None) => Expr::Column(name),
+                Err(e) => {
+                    error.get_or_insert(e);
+                    Expr::Column(name)
+                }
+            },
+            e => e,
+        });
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(expr),
+        }
+    }
+
+    fn lookup(&self, name: &str, tables: Range<usize>) -> Result<Option<&Column>> {
+        let mut found = self
+            .columns
+            .iter()
+            .filter(|c| tables.contains(&c.table) && (c.name == name || c.qualified == name));
+        match (found.next(), found.next()) {
+            (Some(column), None) => Ok(Some(column)),
+            (None, _) => Ok(None),
+            _ => Err(anyhow!("Column {} is ambiguous", name)),
+        }
+    }
+}
@@ -1,16 +1,22 @@
-use anyhow::{anyhow, Result};
+use anyhow::{Result, anyhow};
 use polars::prelude::*;
 use sqlparser::parser::Parser;
 use std::{
+    collections::HashMap,
     convert::TryInto,
     ops::{Deref, DerefMut},
 };
-use tracing::info;
 
 mod convert;
 mod dialect;
-use convert::Sql;
+mod join;
+mod set;
+mod source;
+use convert::{GroupBy, Nested, OrderBy, SetOperation, Sql, Subquery, Table};
 use dialect::TyrDialect;
+use join::Columns;
+use set::combine;
+use source::load;
 
 #[derive(Debug)]
 pub struct DataFrame(polars::frame::DataFrame);
@@ -37,61 +43,213 @@ impl From<polars::frame::DataFrame> for DataFrame {
 
 /// 从 SQL 查询中得到 DataFrame
 pub async fn query<T: AsRef<str>>(sql: T) -> Result<DataFrame> {
-    let ast = Parser::parse_sql(&TyrDialect::default(), sql.as_ref())?;
+    let ast = Parser::parse_sql(&TyrDialect, sql.as_ref())?;
 
     if ast.len() != 1 {
         return Err(anyhow!("Only support single sql at the moment"));
     }
 
-    let sql = &ast[0];
-
     // 整个 SQL AST 转换成我们定义的 Sql 结构的细节都在 convert/mod.rs 中
+    let sql = (&ast[0]).try_into()?;
+    Ok(plan(sql, &HashMap::new()).await?.collect()?.into())
+}
+
+// 标量子查询先执行, 多于一行时报错, 没有结果时为 NULL
+fn scalar_subquery(df: LazyFrame, dtype: &DataType) -> Result<Expr> {
+    let df = df.limit(2).collect()?;
+    let value = match df.height() {
+        0 => Series::full_null("".into(), 1, dtype),
+        1 => df.get_columns()[0].as_materialized_series().clone(),
+        _ => return Err(anyhow!("Scalar subquery must return at most one row")),
+    };
+    Ok(lit(value).first())
+}
+
+// IN 子查询: 和去重后的结果做 left join, 匹配上的行 name 列为 true. 再按 SQL 的语义处理
+// NULL: 左边为 NULL, 或者没有匹配上但子查询的结果中有 NULL 时都是 NULL, 这样 NOT IN 也正确
+fn in_subquery(
+    df: LazyFrame,
+    columns: &Columns,
+    subquery: LazyFrame,
+    (value, dtype): (Expr, DataType),
+    expr: Expr,
+    name: &str,
+) -> Result<LazyFrame> {
+    let key = format!("{}.key", name);
+    let nulls = format!("{}.nulls", name);
+    let mut df = df.with_column(columns.resolve(expr)?.alias(&key));
+    let left = df
+        .collect_schema()?
+        .get(&key)
+        .cloned()
+        .unwrap_or(DataType::Null);
+    let dtype = set::supertype(&left, &dtype)
+        .ok_or_else(|| anyhow!("Cannot compare {} with {} in IN subquery", left, dtype))?;
+
+    let values = subquery
+        .clone()
+        .select([value.clone().cast(dtype.clone()).alias(&key)])
+        .drop_nulls(None)
+        .unique(None, UniqueKeepStrategy::Any)
+        .with_column(lit(true).alias(name));
+    let has_null = subquery.select([value.is_null().any(false).alias(&nulls)]);
+    let found = when(col(&key).is_null())
+        .then(lit(NULL))
+        .when(col(name).is_not_null())
+        .then(lit(true))
+        .when(col(&nulls))
+        .then(lit(NULL))
+        .otherwise(lit(false));
+    Ok(df
+        .with_column(col(&key).cast(dtype))
+        .join(
+            values,
+            [col(&key)],
+            [col(&key)],
+            JoinArgs::new(JoinType::Left),
+        )
+        .cross_join(has_null, None)
+        .with_column(found.alias(name)))
+}
+
+// 把 Sql 转换成 LazyFrame. CTE, FROM 中的子查询和 WHERE 中的子查询都递归地生成各自的
+// LazyFrame, 嵌套在外层的 plan 中一起执行. ctes 是外层已经定义的 CTE
+async fn plan(sql: Sql<'_>, ctes: &HashMap<String, LazyFrame>) -> Result<LazyFrame> {
     let Sql {
-        source,
+        ctes: defined,
+        subqueries,
+        mut source,
+        joins,
         condition,
+        group_by,
         selection,
         order_by,
         offset,
         limit,
-    } = sql.try_into()?;
+    } = sql;
 
-    info!("retrieving data from source: {}", source);
-    let df = match source {
-        source if source.starts_with("http") => {
-            let data = reqwest::get(source).await?.text().await?;
-            CsvReader::new(std::io::Cursor::new(data)).finish()?
-        }
-        _ => CsvReader::new(std::fs::File::open(source)?).finish()?,
-    };
+    // 后面的 CTE 可以引用前面的 CTE, 同名时内层的覆盖外层的
+    let mut ctes = ctes.clone();
+    for (name, sql) in defined {
+        let df = Box::pin(plan(sql, &ctes)).await?;
+        ctes.insert(name.to_string(), df);
+    }
+
+    // 依次和每个数据源 join, 列名的解析在 join.rs 中
+    let mut columns = Columns::new(!joins.is_empty());
+    let df = frame(&mut source, &ctes).await?;
+    let mut filtered = columns.add(&source, df)?;
+    for mut join in joins {
+        let df = frame(&mut join.table, &ctes).await?;
+        let right = columns.add(&join.table, df)?;
+        filtered = columns.join(filtered, right, join)?;
+    }
 
-    let mut filtered = match condition {
-        Some(expr) => df.lazy().filter(expr),
-        None => df.lazy(),
+    // WHERE 中的子查询只能有一列, 结果以 name 为名加进来, 条件中用 name 引用它.
+    // 这些临时的列在过滤之后去掉, 不会出现在 SELECT * 中
+    let mut temporary = Vec::new();
+    for Subquery { name, sql, expr } in subqueries {
+        let mut df = Box::pin(plan(sql, &ctes)).await?;
+        let schema = df.collect_schema()?;
+        let (value, dtype) = match schema.len() {
+            1 => {
+                let (name, dtype) = schema.get_at_index(0).unwrap();
+                (col(name.clone()), dtype.clone())
+            }
+            _ => return Err(anyhow!("Subquery must return exactly one column")),
+        };
+        filtered = match expr {
+            Some(expr) => {
+                temporary.extend([col(format!("{}.key", name)), col(format!("{}.nulls", name))]);
+                in_subquery(filtered, &columns, df, (value, dtype), expr, &name)?
+            }
+            None => filtered.with_column(scalar_subquery(df, &dtype)?.alias(&name)),
+        };
+        temporary.push(col(&name));
+    }
+
+    if let Some(expr) = condition {
+        filtered = filtered.filter(columns.resolve(expr)?);
+    }
+    if !temporary.is_empty() {
+        filtered = filtered.drop(temporary);
+    }
+
+    // 处理 group by 和聚合, having 条件在聚合之后过滤.
+    // 聚合之后的 select 引用的是聚合结果中的列, 不需要再解析
+    let selection = match group_by {
+        Some(GroupBy { keys, aggs, having }) => {
+            let keys = resolve_all(&columns, keys)?;
+            let aggs = resolve_all(&columns, aggs)?;
+            filtered = match keys.is_empty() {
+                true => filtered.select(&aggs),
+                false => filtered.group_by(keys).agg(aggs),
+            };
+            if let Some(having) = having {
+                filtered = filtered.filter(having);
+            }
+            selection
+        }
+        None => resolve_all(&columns, selection)?,
     };
 
     filtered = filtered.select(&selection);
 
-    // 处理 order by
-    for (col, ascending) in order_by {
-        filtered = filtered.sort(
-            vec![col],
-            SortMultipleOptions {
-                descending: vec![!ascending],
-                ..Default::default()
-            },
-        );
+    // 处理 order by, 之后去掉只用来排序的列
+    if let Some(OrderBy {
+        keys,
+        options,
+        hidden,
+    }) = order_by
+    {
+        filtered = filtered.sort_by_exprs(keys, options);
+        if !hidden.is_empty() {
+            filtered = filtered.drop(hidden);
+        }
     }
 
-    if let Some(offset) = offset {
-        let height = filtered.clone().collect()?.height();
-        filtered = filtered.slice(offset as i64, height as u32);
+    // offset 和 limit 合成一个 slice 放在 lazy plan 中, polars 会把它下推到排序和 scan 中,
+    // 数据源只需要读取一次
+    if offset.is_some() || limit.is_some() {
+        let length = limit.map_or(IdxSize::MAX, |limit| {
+            IdxSize::try_from(limit).unwrap_or(IdxSize::MAX)
+        });
+        filtered = filtered.slice(offset.unwrap_or(0), length);
     }
 
-    if let Some(limit) = limit {
-        filtered = filtered.limit(limit as u32);
+    Ok(filtered)
+}
+
+// 数据源可以是子查询, 集合运算, CTE 或者文件 / URL. 没有指定格式时, 和 CTE 同名的数据源就是 CTE
+async fn frame(table: &mut Table<'_>, ctes: &HashMap<String, LazyFrame>) -> Result<LazyFrame> {
+    if let Some(nested) = table.query.take() {
+        return nested_plan(nested, ctes).await;
+    }
+    match ctes.get(table.source) {
+        Some(df) if table.format.is_none() => Ok(df.clone()),
+        _ => load(table).await,
     }
+}
 
-    Ok(filtered.collect()?.into())
+// 集合运算的两边分别生成 LazyFrame, 合并的细节在 set.rs 中
+async fn nested_plan(nested: Nested<'_>, ctes: &HashMap<String, LazyFrame>) -> Result<LazyFrame> {
+    match nested {
+        Nested::Select(sql) => Box::pin(plan(*sql, ctes)).await,
+        Nested::Set(SetOperation {
+            op,
+            all,
+            left,
+            right,
+        }) => {
+            let left = Box::pin(nested_plan(*left, ctes)).await?;
+            let right = Box::pin(nested_plan(*right, ctes)).await?;
+            combine(left, right, op, all)
+        }
+    }
+}
+
+fn resolve_all(columns: &Columns, exprs: Vec<Expr>) -> Result<Vec<Expr>> {
+    exprs.into_iter().map(|e| columns.resolve(e)).collect()
 }
 
 pub fn example_sql() -> String {
@@ -107,7 +265,7 @@ pub fn example_sql() -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tracing_subscriber::{fmt, EnvFilter};
+    use tracing_subscriber::{EnvFilter, fmt};
 
     #[tokio::test]
     async fn query_covid() -> Result<()> {
@@ -130,4 +288,872 @@ mod tests {
         println!("{:?}", df);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn query_group_by() -> Result<()> {
+        let sql = "SELECT continent, SUM(new_cases) cases, MAX(total_deaths) AS deaths, COUNT(*) \
+            FROM examples/covid.csv WHERE continent = 'Europe' OR continent = 'Asia' \
+            GROUP BY continent ORDER BY deaths DESC";
+        let df = query(sql).await?;
+
+        assert_eq!(
+            df.get_column_names(),
+            ["continent", "cases", "deaths", "COUNT(*)"]
+        );
+        assert_eq!(df.height(), 2);
+        let continents: Vec<_> = df.column("continent")?.str()?.into_no_null_iter().collect();
+        assert_eq!(continents, ["Asia", "Europe"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_having_and_count_distinct() -> Result<()> {
+        let sql = "SELECT continent, COUNT(DISTINCT location) countries FROM examples/covid.csv \
+            GROUP BY 1 HAVING COUNT(location) > 50 ORDER BY continent";
+        let df = query(sql).await?;
+
+        assert_eq!(df.get_column_names(), ["continent", "countries"]);
+        let counts: Vec<_> = df.column("countries")?.u32()?.into_no_null_iter().collect();
+        assert!(!counts.is_empty());
+        assert!(counts.iter().all(|&n| n > 50));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_aggregate_without_group_by() -> Result<()> {
+        let sql = "SELECT COUNT(*) total, AVG(new_cases) FROM examples/covid.csv";
+        let df = query(sql).await?;
+        assert_eq!(df.height(), 1);
+        assert_eq!(df.column("total")?.u32()?.get(0), Some(246));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn non_aggregated_column_should_be_rejected() {
+        let sql = "SELECT location, SUM(new_cases) FROM examples/covid.csv GROUP BY continent";
+        let err = query(sql).await.unwrap_err();
+        assert!(err.to_string().contains("must appear in GROUP BY"));
+    }
+
+    #[tokio::test]
+    async fn query_order_by_multiple_columns() -> Result<()> {
+        let sql = "SELECT continent, location FROM examples/covid.csv \
+            WHERE continent IN ('Oceania', 'South America') ORDER BY continent DESC, location";
+        let df = query(sql).await?;
+
+        let continents = df.column("continent")?.str()?.into_no_null_iter();
+        let locations = df.column("location")?.str()?.into_no_null_iter();
+        let rows: Vec<_> = continents.zip(locations).collect();
+        assert_eq!(rows.len(), 38);
+        assert_eq!(rows[0], ("South America", "Argentina"));
+        let mut expected = rows.clone();
+        expected.sort_by(|a, b| b.0.cmp(a.0).then(a.1.cmp(b.1)));
+        assert_eq!(rows, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_order_by_alias_position_and_expression() -> Result<()> {
+        let europe = "FROM examples/covid.csv WHERE continent = 'Europe'";
+        let first = |df: &DataFrame| -> Result<String> {
+            Ok(df.column("name")?.str()?.get(0).unwrap().to_string())
+        };
+
+        let sql = format!(
+            "SELECT location name, total_deaths deaths {} ORDER BY deaths DESC NULLS LAST",
+            europe
+        );
+        assert_eq!(first(&query(sql).await?)?, "Russia");
+        let sql = format!(
+            "SELECT location name, total_deaths {} ORDER BY 2 DESC NULLS LAST",
+            europe
+        );
+        assert_eq!(first(&query(sql).await?)?, "Russia");
+        let sql = format!("SELECT location name {} ORDER BY name DESC", europe);
+        assert_eq!(first(&query(sql).await?)?, "Vatican");
+
+        // 不在结果中的表达式只用来排序, 不会出现在结果中
+        let sql = format!(
+            "SELECT location name {} AND population > 1000000 \
+            ORDER BY total_deaths / population DESC NULLS LAST, 1",
+            europe
+        );
+        let df = query(sql).await?;
+        assert_eq!(df.get_column_names(), ["name"]);
+        assert_eq!(first(&df)?, "Bulgaria");
+
+        let sql = format!("SELECT location name {} ORDER BY 2", europe);
+        let err = query(sql).await.unwrap_err();
+        assert!(err.to_string().contains("not in select list"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_order_by_nulls() -> Result<()> {
+        // 排序之后第一行和最后一行是否为 NULL
+        let ends = |order: &str| {
+            let sql = format!(
+                "SELECT icu_patients FROM examples/covid.csv ORDER BY icu_patients {}",
+                order
+            );
+            async move {
+                let nulls = query(sql).await?.column("icu_patients")?.is_null();
+                Ok::<_, anyhow::Error>((nulls.get(0).unwrap(), nulls.get(nulls.len() - 1).unwrap()))
+            }
+        };
+
+        // 没有 NULLS FIRST / LAST 时 NULL 被当作最大的值, 降序时在最前
+        assert_eq!(ends("").await?, (false, true));
+        assert_eq!(ends("DESC").await?, (true, false));
+        assert_eq!(ends("NULLS FIRST").await?, (true, false));
+        assert_eq!(ends("DESC NULLS FIRST").await?, (true, false));
+        assert_eq!(ends("DESC NULLS LAST").await?, (false, true));
+        assert_eq!(ends("ASC NULLS LAST").await?, (false, true));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_order_by_should_be_stable() -> Result<()> {
+        // 相等的行保持文件中的顺序
+        let sql = "SELECT continent, location FROM examples/covid.csv WHERE continent IS NOT NULL";
+        let df = query(sql).await?;
+        let continents = df.column("continent")?.str()?.into_no_null_iter();
+        let locations = df.column("location")?.str()?.into_no_null_iter();
+        let mut expected: Vec<_> = continents.zip(locations).collect();
+        expected.sort_by(|a, b| a.0.cmp(b.0));
+
+        let df = query(format!("{} ORDER BY continent", sql)).await?;
+        let continents = df.column("continent")?.str()?.into_no_null_iter();
+        let locations = df.column("location")?.str()?.into_no_null_iter();
+        let rows: Vec<_> = continents.zip(locations).collect();
+        assert_eq!(rows, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_order_by_aggregate() -> Result<()> {
+        let sql = "SELECT continent FROM examples/covid.csv WHERE continent IS NOT NULL \
+            GROUP BY continent ORDER BY COUNT(*) DESC";
+        let df = query(sql).await?;
+        assert_eq!(df.get_column_names(), ["continent"]);
+        let continents: Vec<_> = df.column("continent")?.str()?.into_no_null_iter().collect();
+        assert_eq!(
+            continents,
+            [
+                "Africa",
+                "Europe",
+                "Asia",
+                "North America",
+                "Oceania",
+                "South America"
+            ]
+        );
+
+        let sql = "SELECT continent FROM examples/covid.csv GROUP BY continent ORDER BY location";
+        let err = query(sql).await.unwrap_err();
+        assert!(err.to_string().contains("must appear in GROUP BY"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_limit_and_offset() -> Result<()> {
+        let locations = |df: DataFrame| -> Result<Vec<String>> {
+            let locations = df.column("location")?.str()?.into_no_null_iter();
+            Ok(locations.map(|l| l.to_string()).collect())
+        };
+        let sql = "SELECT location FROM examples/covid.csv ORDER BY location";
+        let all = locations(query(sql).await?)?;
+
+        let df = query(format!("{} LIMIT 3 OFFSET 2", sql)).await?;
+        assert_eq!(locations(df)?, all[2..5]);
+        let df = query(format!("{} OFFSET 240", sql)).await?;
+        assert_eq!(locations(df)?, all[240..]);
+        let df = query(format!("{} LIMIT 0", sql)).await?;
+        assert_eq!(df.height(), 0);
+        let df = query(format!("{} LIMIT 5 OFFSET 1000", sql)).await?;
+        assert_eq!(df.height(), 0);
+
+        // 没有排序时按文件中的顺序
+        let sql =
+            "SELECT location FROM examples/covid.csv WHERE continent = 'Europe' LIMIT 2 OFFSET 1";
+        assert_eq!(locations(query(sql).await?)?, ["Andorra", "Austria"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_join() -> Result<()> {
+        let sql = "SELECT c.location, c.continent, t.code FROM examples/covid.csv c \
+            JOIN examples/continents.csv t ON c.continent = t.continent \
+            WHERE location = 'France'";
+        let df = query(sql).await?;
+        assert_eq!(df.get_column_names(), ["location", "continent", "code"]);
+        assert_eq!(df.height(), 1);
+        assert_eq!(df.column("code")?.str()?.get(0), Some("EU"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_join_types() -> Result<()> {
+        let count = |how: &str| {
+            format!(
+                "SELECT code FROM examples/covid.csv a {} examples/continents.csv b \
+                ON b.continent = a.continent",
+                how
+            )
+        };
+        assert_eq!(query(count("INNER JOIN")).await?.height(), 234);
+        assert_eq!(query(count("LEFT JOIN")).await?.height(), 246);
+        assert_eq!(query(count("RIGHT JOIN")).await?.height(), 235);
+        assert_eq!(query(count("FULL OUTER JOIN")).await?.height(), 247);
+
+        let sql = "SELECT a.location, b.code FROM examples/covid.csv a \
+            CROSS JOIN examples/continents.csv b";
+        assert_eq!(query(sql).await?.height(), 246 * 7);
+        let sql = "SELECT a.location, b.code FROM examples/covid.csv a, examples/continents.csv b";
+        assert_eq!(query(sql).await?.height(), 246 * 7);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_join_with_group_by() -> Result<()> {
+        let sql = "SELECT t.code, COUNT(*) countries FROM examples/covid.csv c \
+            JOIN examples/continents.csv t ON c.continent = t.continent \
+            GROUP BY t.code HAVING COUNT(*) > 50";
+        let df = query(sql).await?;
+        assert_eq!(df.get_column_names(), ["code", "countries"]);
+        let codes = df.column("code")?.str()?.into_no_null_iter();
+        let counts = df.column("countries")?.u32()?.into_no_null_iter();
+        let mut rows: Vec<_> = codes.zip(counts).collect();
+        rows.sort();
+        assert_eq!(rows, [("AF", 57), ("EU", 51)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ambiguous_column_should_be_rejected() {
+        let sql = "SELECT continent FROM examples/covid.csv a \
+            JOIN examples/continents.csv b ON a.continent = b.continent";
+        let err = query(sql).await.unwrap_err();
+        assert!(err.to_string().contains("ambiguous"));
+
+        let sql = "SELECT a.location FROM examples/covid.csv a \
+            JOIN examples/continents.csv a ON a.continent = a.continent";
+        let err = query(sql).await.unwrap_err();
+        assert!(err.to_string().contains("more than once"));
+    }
+
+    #[tokio::test]
+    async fn query_string_functions() -> Result<()> {
+        let sql = "SELECT lower(location), upper(continent) c, length(location) len, \
+            substr(location, 2, 3) sub, substr(location, 3) rest, trim('  x ') t, \
+            concat(iso_code, '-', continent) tag \
+            FROM examples/covid.csv WHERE location = 'France'";
+        let df = query(sql).await?;
+
+        assert_eq!(
+            df.get_column_names(),
+            ["lower(location)", "c", "len", "sub", "rest", "t", "tag"]
+        );
+        assert_eq!(df.column("lower(location)")?.str()?.get(0), Some("france"));
+        assert_eq!(df.column("c")?.str()?.get(0), Some("EUROPE"));
+        assert_eq!(df.column("len")?.u32()?.get(0), Some(6));
+        assert_eq!(df.column("sub")?.str()?.get(0), Some("ran"));
+        assert_eq!(df.column("rest")?.str()?.get(0), Some("ance"));
+        assert_eq!(df.column("t")?.str()?.get(0), Some("x"));
+        assert_eq!(df.column("tag")?.str()?.get(0), Some("FRA-Europe"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_math_functions() -> Result<()> {
+        let sql = "SELECT abs(0 - total_deaths) d, round(total_cases_per_million, 1) r1, \
+            round(total_cases_per_million) r0, sqrt(total_deaths) s \
+            FROM examples/covid.csv WHERE location = 'France'";
+        let df = query(sql).await?;
+
+        assert_eq!(df.column("d")?.f64()?.get(0), Some(167985.0));
+        assert_eq!(df.column("r1")?.f64()?.get(0), Some(603427.6));
+        assert_eq!(df.column("r0")?.f64()?.get(0), Some(603428.0));
+        assert_eq!(df.column("s")?.f64()?.get(0), Some(167985f64.sqrt()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_coalesce_nullif_and_cast() -> Result<()> {
+        let sql = "SELECT location, coalesce(continent, 'World') c, nullif(continent, 'Europe') n, \
+            CAST(total_deaths AS BIGINT) deaths, CAST(last_updated_date AS DATE) day, \
+            TRY_CAST(location AS INT) bad \
+            FROM examples/covid.csv WHERE location = 'France' OR location = 'Europe'";
+        let df = query(sql).await?;
+
+        let c: Vec<_> = df.column("c")?.str()?.into_iter().collect();
+        assert_eq!(c, [Some("World"), Some("Europe")]);
+        assert_eq!(df.column("n")?.null_count(), 2);
+        assert_eq!(df.column("deaths")?.i64()?.get(1), Some(167985));
+        assert_eq!(df.column("day")?.dtype(), &DataType::Date);
+        assert_eq!(df.column("bad")?.null_count(), 2);
+
+        let sql = "SELECT CAST(location AS INT) FROM examples/covid.csv";
+        assert!(query(sql).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scalar_function_of_aggregate() -> Result<()> {
+        let sql = "SELECT lower(continent) c, round(avg(total_deaths), 0) deaths \
+            FROM examples/covid.csv WHERE continent = 'Oceania' GROUP BY c";
+        let df = query(sql).await?;
+
+        assert_eq!(df.column("c")?.str()?.get(0), Some("oceania"));
+        let deaths = df.column("deaths")?.f64()?.get(0).unwrap();
+        assert_eq!(deaths, deaths.round());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bad_function_calls_should_be_rejected() {
+        for (sql, message) in [
+            (
+                "SELECT foo(location) FROM examples/covid.csv",
+                "not supported",
+            ),
+            (
+                "SELECT lower(location, 1) FROM examples/covid.csv",
+                "expects 1 argument(s), got 2",
+            ),
+            (
+                "SELECT substr(location) FROM examples/covid.csv",
+                "expects 2 to 3 argument(s), got 1",
+            ),
+            ("SELECT coalesce() FROM examples/covid.csv", "at least 1"),
+            (
+                "SELECT round(total_cases, new_cases) FROM examples/covid.csv",
+                "non-negative integer",
+            ),
+            (
+                "SELECT CAST(location AS BLOB) FROM examples/covid.csv",
+                "not supported",
+            ),
+        ] {
+            let err = query(sql).await.unwrap_err();
+            assert!(err.to_string().contains(message), "{}: {}", sql, err);
+        }
+    }
+
+    #[tokio::test]
+    async fn query_in_between_and_null_checks() -> Result<()> {
+        let count = |condition: &str| {
+            let sql = format!(
+                "SELECT COUNT(*) n FROM examples/covid.csv WHERE {}",
+                condition
+            );
+            async move {
+                let df = query(sql).await?;
+                Ok::<_, anyhow::Error>(df.column("n")?.u32()?.get(0).unwrap())
+            }
+        };
+
+        assert_eq!(count("continent IN ('Europe', 'Asia')").await?, 98);
+        assert_eq!(count("continent NOT IN ('Europe', 'Asia')").await?, 136);
+        // 和 SQL 一样, NULL 不在任何列表中, 列表中有 NULL 时 NOT IN 不会成立
+        assert_eq!(count("NOT (continent IN ('Europe', 'Asia'))").await?, 136);
+        assert_eq!(count("continent NOT IN ('Europe', NULL)").await?, 0);
+        assert_eq!(count("continent IN ('Europe', NULL)").await?, 51);
+        assert_eq!(count("continent IS NULL").await?, 12);
+        assert_eq!(count("continent IS NOT NULL").await?, 234);
+        assert_eq!(count("NOT (continent = 'Europe')").await?, 183);
+        assert_eq!(
+            count("total_deaths BETWEEN 100000 AND 200000").await?,
+            count("total_deaths >= 100000 AND total_deaths <= 200000").await?
+        );
+        assert_eq!(
+            count("total_deaths NOT BETWEEN 100000 AND 200000").await?,
+            count("total_deaths < 100000 OR total_deaths > 200000").await?
+        );
+        assert_eq!(
+            count("-total_deaths < -1000000").await?,
+            count("total_deaths > 1000000").await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_like_and_case() -> Result<()> {
+        let sql = "SELECT location FROM examples/covid.csv WHERE location LIKE 'United %'";
+        let df = query(sql).await?;
+        let locations: Vec<_> = df.column("location")?.str()?.into_no_null_iter().collect();
+        assert_eq!(
+            locations,
+            [
+                "United Arab Emirates",
+                "United Kingdom",
+                "United States",
+                "United States Virgin Islands"
+            ]
+        );
+
+        let sql = "SELECT location FROM examples/covid.csv WHERE location ILIKE 'fr_nce'";
+        assert_eq!(query(sql).await?.height(), 1);
+        let sql = "SELECT location FROM examples/covid.csv WHERE location NOT LIKE '%a%'";
+        let df = query(sql).await?;
+        assert!(
+            df.column("location")?
+                .str()?
+                .into_no_null_iter()
+                .all(|l| !l.contains('a'))
+        );
+
+        let sql = "SELECT location, \
+            CASE WHEN total_deaths > 1000000 THEN 'high' WHEN total_deaths > 100000 THEN 'medium' \
+            ELSE 'low' END level, \
+            CASE continent WHEN 'Europe' THEN 'EU' END eu \
+            FROM examples/covid.csv WHERE location IN ('France', 'Japan', 'United States')";
+        let df = query(sql).await?;
+        let levels: Vec<_> = df.column("level")?.str()?.into_no_null_iter().collect();
+        assert_eq!(levels, ["medium", "low", "high"]);
+        let eu: Vec<_> = df.column("eu")?.str()?.into_iter().collect();
+        assert_eq!(eu, [Some("EU"), None, None]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_cte() -> Result<()> {
+        // 后面的 CTE 可以引用前面的 CTE
+        let sql = "WITH europe AS (SELECT location, total_deaths FROM examples/covid.csv \
+            WHERE continent = 'Europe'), \
+            deadliest AS (SELECT location FROM europe ORDER BY total_deaths DESC NULLS LAST LIMIT 1) \
+            SELECT * FROM deadliest";
+        let df = query(sql).await?;
+        assert_eq!(df.column("location")?.str()?.get(0), Some("Russia"));
+
+        let sql = "WITH europe AS (SELECT * FROM examples/covid.csv WHERE continent = 'Europe') \
+            SELECT COUNT(*) AS n FROM europe";
+        let df = query(sql).await?;
+        assert_eq!(df.column("n")?.u32()?.get(0), Some(51));
+
+        let sql = "WITH c AS (SELECT continent, code FROM examples/continents.csv) \
+            SELECT d.location, c.code FROM examples/covid.csv d \
+            JOIN c ON d.continent = c.continent WHERE d.location = 'France'";
+        let df = query(sql).await?;
+        assert_eq!(df.column("code")?.str()?.get(0), Some("EU"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_from_subquery() -> Result<()> {
+        let sql = "SELECT continent, n FROM (SELECT continent, COUNT(*) AS n \
+            FROM examples/covid.csv WHERE continent IS NOT NULL GROUP BY continent) AS t \
+            WHERE n > 40 ORDER BY n DESC";
+        let df = query(sql).await?;
+        let continents: Vec<_> = df.column("continent")?.str()?.into_no_null_iter().collect();
+        assert_eq!(continents, ["Africa", "Europe", "Asia", "North America"]);
+
+        let sql = "SELECT t.location, c.code FROM (SELECT location, continent \
+            FROM examples/covid.csv WHERE location = 'Japan') t \
+            JOIN examples/continents.csv c ON t.continent = c.continent";
+        let df = query(sql).await?;
+        assert_eq!(df.column("code")?.str()?.get(0), Some("AS"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_where_subquery() -> Result<()> {
+        let sql = "SELECT location FROM examples/covid.csv WHERE continent = 'Europe' \
+            AND total_deaths >= (SELECT MAX(total_deaths) FROM examples/covid.csv \
+            WHERE continent = 'Europe')";
+        let df = query(sql).await?;
+        assert_eq!(df.height(), 1);
+        assert_eq!(df.column("location")?.str()?.get(0), Some("Russia"));
+
+        let subquery = "SELECT continent FROM examples/continents.csv WHERE code IN ('EU', 'AS')";
+        let sql = format!(
+            "SELECT location FROM examples/covid.csv WHERE continent IN ({})",
+            subquery
+        );
+        assert_eq!(query(sql).await?.height(), 98);
+        // continent 为 NULL 的行在 NOT IN 中也不满足条件
+        let sql = format!(
+            "SELECT location FROM examples/covid.csv WHERE continent NOT IN ({})",
+            subquery
+        );
+        assert_eq!(query(sql).await?.height(), 136);
+        // 在 OR 中也按每一行求值
+        let sql = format!(
+            "SELECT location FROM examples/covid.csv WHERE continent IN ({}) \
+            OR location = 'Russia'",
+            subquery
+        );
+        assert_eq!(query(sql).await?.height(), 98);
+        // 子查询的结果中有 NULL 时, NOT IN 没有满足条件的行
+        let sql = "SELECT location FROM examples/covid.csv WHERE continent NOT IN \
+            (SELECT continent FROM examples/covid.csv WHERE continent IS NULL OR continent = 'Europe')";
+        assert_eq!(query(sql).await?.height(), 0);
+
+        // 子查询的临时列不会出现在 SELECT * 中
+        let all = query("SELECT * FROM examples/covid.csv").await?;
+        let sql = format!(
+            "SELECT * FROM examples/covid.csv WHERE continent IN ({}) AND total_deaths >= \
+            (SELECT MAX(total_deaths) FROM examples/covid.csv WHERE continent = 'Europe')",
+            subquery
+        );
+        let df = query(sql).await?;
+        assert_eq!(df.get_column_names(), all.get_column_names());
+
+        let sql = "SELECT location FROM examples/covid.csv \
+            WHERE total_deaths = (SELECT total_deaths FROM examples/covid.csv)";
+        let err = query(sql).await.unwrap_err();
+        assert!(err.to_string().contains("at most one row"));
+
+        let sql = "SELECT location FROM examples/covid.csv \
+            WHERE continent IN (SELECT continent, code FROM examples/continents.csv)";
+        let err = query(sql).await.unwrap_err();
+        assert!(err.to_string().contains("exactly one column"));
+
+        let sql =
+            "SELECT (SELECT MAX(total_deaths) FROM examples/covid.csv) FROM examples/covid.csv";
+        let err = query(sql).await.unwrap_err();
+        assert!(err.to_string().contains("only supported in WHERE"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_union() -> Result<()> {
+        let sql = "SELECT continent FROM examples/covid.csv WHERE location = 'France' \
+            UNION ALL SELECT continent FROM examples/covid.csv WHERE location = 'Germany'";
+        assert_eq!(query(sql).await?.height(), 2);
+        let sql = sql.replace("UNION ALL", "UNION");
+        assert_eq!(query(sql).await?.height(), 1);
+
+        // 不同的数据源, 结果使用左边的列名, ORDER BY 作用在整个结果上
+        let sql = "SELECT continent AS name FROM examples/continents.csv \
+            UNION SELECT location FROM examples/covid.csv WHERE location = 'France' \
+            ORDER BY name LIMIT 5";
+        let df = query(sql).await?;
+        assert_eq!(df.get_column_names(), ["name"]);
+        let names: Vec<_> = df.column("name")?.str()?.into_no_null_iter().collect();
+        assert_eq!(names, ["Africa", "Antarctica", "Asia", "Europe", "France"]);
+
+        // 整数和浮点数合并成 Float64
+        let sql = "SELECT total_deaths FROM examples/covid.csv WHERE location = 'France' \
+            UNION ALL SELECT LENGTH(code) FROM examples/continents.csv WHERE code = 'EU'";
+        let df = query(sql).await?;
+        let deaths: Vec<_> = df
+            .column("total_deaths")?
+            .f64()?
+            .into_no_null_iter()
+            .collect();
+        assert_eq!(deaths, [167985.0, 2.0]);
+
+        let sql = "SELECT COUNT(*) AS n FROM (\
+            SELECT location FROM examples/covid.csv WHERE continent = 'Europe' \
+            UNION ALL SELECT location FROM examples/covid.csv WHERE continent = 'Asia') t";
+        let df = query(sql).await?;
+        assert_eq!(df.column("n")?.u32()?.get(0), Some(98));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_intersect_and_except() -> Result<()> {
+        let continents = |df: DataFrame| -> Result<Vec<Option<String>>> {
+            let continents = df.column("continent")?.str()?.into_iter();
+            Ok(continents.map(|c| c.map(|c| c.to_string())).collect())
+        };
+
+        let sql = "SELECT continent FROM examples/continents.csv \
+            INTERSECT SELECT continent FROM examples/covid.csv ORDER BY 1";
+        let df = query(sql).await?;
+        assert_eq!(df.height(), 6);
+        assert_eq!(continents(df)?[0].as_deref(), Some("Africa"));
+
+        let sql = "SELECT continent FROM examples/continents.csv \
+            EXCEPT SELECT continent FROM examples/covid.csv";
+        assert_eq!(continents(query(sql).await?)?, [Some("Antarctica".into())]);
+
+        // NULL 和 NULL 看作相同的值, 结果去重
+        let sql = "SELECT continent FROM examples/covid.csv \
+            EXCEPT SELECT continent FROM examples/continents.csv";
+        assert_eq!(continents(query(sql).await?)?, [None]);
+
+        // ALL 时按照重复的次数计算
+        let sql = "SELECT continent FROM examples/covid.csv WHERE continent = 'Oceania' \
+            EXCEPT ALL SELECT continent FROM examples/continents.csv";
+        assert_eq!(query(sql).await?.height(), 23);
+        let sql = "SELECT continent FROM examples/covid.csv WHERE continent = 'Oceania' \
+            INTERSECT ALL (SELECT continent FROM examples/covid.csv \
+            WHERE continent = 'Oceania' LIMIT 3)";
+        assert_eq!(query(sql).await?.height(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incompatible_set_operations_should_be_rejected() {
+        let sql = "SELECT location, continent FROM examples/covid.csv \
+            UNION SELECT continent FROM examples/continents.csv";
+        let err = query(sql).await.unwrap_err();
+        assert!(err.to_string().contains("same number of columns"));
+
+        let sql = "SELECT location FROM examples/covid.csv \
+            EXCEPT SELECT total_deaths FROM examples/covid.csv";
+        let err = query(sql).await.unwrap_err();
+        assert!(err.to_string().contains("incompatible types"));
+    }
+
+    #[tokio::test]
+    async fn query_row_number() -> Result<()> {
+        // 每个大洲死亡人数最多的国家
+        let sql = "SELECT location FROM (SELECT location, ROW_NUMBER() OVER \
+            (PARTITION BY continent ORDER BY total_deaths DESC NULLS LAST) AS n \
+            FROM examples/covid.csv WHERE continent IN ('Asia', 'Europe')) t \
+            WHERE n = 1 ORDER BY location";
+        let df = query(sql).await?;
+        let locations: Vec<_> = df.column("location")?.str()?.into_no_null_iter().collect();
+        assert_eq!(locations, ["India", "Russia"]);
+
+        // 没有 PARTITION BY 时整个表是一个分组, ORDER BY 可以有多个 key
+        let sql = "SELECT date, city, ROW_NUMBER() OVER (ORDER BY date, city) AS n, \
+            COUNT(*) OVER (PARTITION BY city) AS days, SUM(cases) OVER () AS total \
+            FROM examples/daily.csv ORDER BY n";
+        let df = query(sql).await?;
+        let cities: Vec<_> = df.column("city")?.str()?.into_no_null_iter().collect();
+        assert_eq!(cities[..3], ["Berlin", "Paris", "Berlin"]);
+        let n: Vec<_> = df.column("n")?.u32()?.into_no_null_iter().collect();
+        assert_eq!(n, [1, 2, 3, 4, 5, 6, 7]);
+        let days: Vec<_> = df.column("days")?.u32()?.into_no_null_iter().collect();
+        assert_eq!(days, [3, 4, 3, 4, 3, 4, 4]);
+        assert!(
+            df.column("total")?
+                .i64()?
+                .into_no_null_iter()
+                .all(|t| t == 89)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_rank_lag_and_running_total() -> Result<()> {
+        let sql = "SELECT city, date, \
+            cases - LAG(cases) OVER (PARTITION BY city ORDER BY date) AS change, \
+            LEAD(cases, 1, 0) OVER (PARTITION BY city ORDER BY date) AS next, \
+            SUM(cases) OVER (PARTITION BY city ORDER BY date) AS total, \
+            AVG(cases) OVER (PARTITION BY city ORDER BY date) AS average, \
+            RANK() OVER (PARTITION BY city ORDER BY cases DESC) AS rank, \
+            DENSE_RANK() OVER (PARTITION BY city ORDER BY cases DESC) AS dense \
+            FROM examples/daily.csv ORDER BY city, date";
+        let df = query(sql).await?;
+        let ints = |name: &str| -> Result<Vec<Option<i64>>> {
+            let column = df.column(name)?.cast(&DataType::Int64)?;
+            Ok(column.i64()?.into_iter().collect())
+        };
+        assert_eq!(
+            ints("change")?,
+            [None, Some(3), Some(-2), None, Some(5), Some(0), Some(15)]
+        );
+        let next: Vec<_> = ints("next")?.into_iter().flatten().collect();
+        assert_eq!(next, [8, 6, 0, 15, 15, 30, 0]);
+        let total: Vec<_> = ints("total")?.into_iter().flatten().collect();
+        assert_eq!(total, [5, 13, 19, 10, 25, 40, 70]);
+        let average: Vec<_> = df.column("average")?.f64()?.into_no_null_iter().collect();
+        assert_eq!(average[..2], [5.0, 6.5]);
+        assert_eq!(average[6], 17.5);
+        let rank: Vec<_> = ints("rank")?.into_iter().flatten().collect();
+        assert_eq!(rank, [3, 1, 2, 4, 2, 2, 1]);
+        let dense: Vec<_> = ints("dense")?.into_iter().flatten().collect();
+        assert_eq!(dense, [3, 1, 2, 3, 2, 2, 1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn window_order_by_should_include_peers() -> Result<()> {
+        // ORDER BY 的值相同的行同时计入累计的结果, RANK 可以按多个值排序
+        let sql = "SELECT city, date, \
+            SUM(cases) OVER (ORDER BY date) AS total, \
+            COUNT(*) OVER (ORDER BY date) AS n, \
+            RANK() OVER (ORDER BY city, cases) AS rank, \
+            DENSE_RANK() OVER (ORDER BY city, cases) AS dense \
+            FROM examples/daily.csv ORDER BY city, date";
+        let df = query(sql).await?;
+        let ints = |name: &str| -> Result<Vec<i64>> {
+            let column = df.column(name)?.cast(&DataType::Int64)?;
+            Ok(column.i64()?.into_no_null_iter().collect())
+        };
+        assert_eq!(ints("total")?, [15, 38, 59, 15, 38, 59, 89]);
+        assert_eq!(ints("n")?, [2, 4, 6, 2, 4, 6, 7]);
+        assert_eq!(ints("rank")?, [1, 3, 2, 4, 5, 5, 7]);
+        assert_eq!(ints("dense")?, [1, 3, 2, 4, 5, 5, 6]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rank_should_treat_nulls_as_peers() -> Result<()> {
+        // NULL 之间排名相同, 默认被当作最大的值, 也可以用 NULLS FIRST / LAST 指定位置
+        let sql = "SELECT icu_patients, \
+            RANK() OVER (ORDER BY icu_patients) AS asc_rank, \
+            RANK() OVER (ORDER BY icu_patients DESC) AS desc_rank, \
+            DENSE_RANK() OVER (ORDER BY icu_patients NULLS FIRST) AS dense \
+            FROM examples/covid.csv";
+        let df = query(sql).await?;
+        let icu = df.column("icu_patients")?;
+        let nulls = icu.null_count();
+        let values = icu.len() - nulls;
+        assert!(nulls > 0 && values > 0);
+
+        let ranks = |name: &str| -> Result<Vec<(bool, u32)>> {
+            let ranks = df.column(name)?.u32()?.clone();
+            Ok(icu
+                .is_null()
+                .into_no_null_iter()
+                .zip(ranks.into_no_null_iter())
+                .collect())
+        };
+        let null_ranks = |name: &str| -> Result<Vec<u32>> {
+            let ranks = ranks(name)?.into_iter();
+            Ok(ranks.filter(|(null, _)| *null).map(|(_, r)| r).collect())
+        };
+        assert!(
+            null_ranks("asc_rank")?
+                .iter()
+                .all(|&r| r as usize == values + 1)
+        );
+        assert!(null_ranks("desc_rank")?.iter().all(|&r| r == 1));
+        let first_value = ranks("desc_rank")?
+            .into_iter()
+            .filter(|(null, _)| !null)
+            .map(|(_, r)| r)
+            .min();
+        assert_eq!(first_value, Some(nulls as u32 + 1));
+        assert!(null_ranks("dense")?.iter().all(|&r| r == 1));
+        let distinct = icu.as_materialized_series().drop_nulls().n_unique()?;
+        let max = ranks("dense")?.into_iter().map(|(_, r)| r).max();
+        assert_eq!(max, Some(distinct as u32 + 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn having_should_use_aliases_and_group_keys() -> Result<()> {
+        // HAVING 中可以使用 select 的别名, GROUP BY 的 key 和不在 select 中的聚合函数
+        let sql = "SELECT city, SUM(cases) AS total FROM examples/daily.csv \
+            GROUP BY city HAVING total > 10 AND city <> 'Berlin' AND MAX(cases) >= 30";
+        let df = query(sql).await?;
+        assert_eq!(df.get_column_names(), ["city", "total"]);
+        let cities: Vec<_> = df.column("city")?.str()?.into_no_null_iter().collect();
+        assert_eq!(cities, ["Paris"]);
+        let total = df.column("total")?.cast(&DataType::Int64)?;
+        assert_eq!(total.i64()?.get(0), Some(70));
+
+        let sql = "SELECT COUNT(*) AS days FROM examples/daily.csv \
+            GROUP BY city HAVING city = 'Berlin'";
+        let df = query(sql).await?;
+        let days = df.column("days")?.cast(&DataType::Int64)?;
+        assert_eq!(days.i64()?.into_no_null_iter().collect::<Vec<_>>(), [3]);
+
+        let sql = "SELECT city FROM examples/daily.csv GROUP BY city HAVING cases > 10";
+        let err = query(sql).await.unwrap_err();
+        assert!(
+            err.to_string().contains("must appear in GROUP BY"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bad_window_functions_should_be_rejected() {
+        for (sql, error) in [
+            (
+                "SELECT RANK() OVER (PARTITION BY city) FROM examples/daily.csv",
+                "requires ORDER BY",
+            ),
+            (
+                "SELECT ROW_NUMBER() OVER (ORDER BY date, cases DESC) FROM examples/daily.csv",
+                "different directions",
+            ),
+            (
+                "SELECT SUM(cases) OVER (ORDER BY date ROWS BETWEEN 1 PRECEDING AND CURRENT ROW) \
+                FROM examples/daily.csv",
+                "Window frame",
+            ),
+            (
+                "SELECT NTILE(2) OVER (ORDER BY date) FROM examples/daily.csv",
+                "not supported",
+            ),
+        ] {
+            let err = query(sql).await.unwrap_err();
+            assert!(err.to_string().contains(error), "{}: {}", sql, err);
+        }
+    }
+
+    // 把 covid.csv 转成各种格式, 放在临时目录中
+    fn write_formats() -> Result<tempfile::TempDir> {
+        use flate2::{Compression, write::GzEncoder};
+        use std::fs::File;
+
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name);
+        let mut df = CsvReader::new(File::open("examples/covid.csv")?).finish()?;
+
+        ParquetWriter::new(File::create(path("covid.parquet"))?).finish(&mut df)?;
+        IpcWriter::new(File::create(path("covid.arrow"))?).finish(&mut df)?;
+        JsonWriter::new(File::create(path("covid.json"))?)
+            .with_json_format(JsonFormat::Json)
+            .finish(&mut df)?;
+        JsonWriter::new(File::create(path("covid.ndjson"))?)
+            .with_json_format(JsonFormat::JsonLines)
+            .finish(&mut df)?;
+        let mut gz = GzEncoder::new(File::create(path("covid.csv.gz"))?, Compression::default());
+        CsvWriter::new(&mut gz).finish(&mut df)?;
+        gz.finish()?;
+        std::fs::copy(path("covid.parquet"), path("covid.data"))?;
+        Ok(dir)
+    }
+
+    #[tokio::test]
+    async fn query_file_formats() -> Result<()> {
+        let tmp = write_formats()?;
+        let dir = tmp.path().display();
+
+        // 绝对路径不是合法的标识符, 用双引号括起来
+        for file in [
+            "covid.csv.gz",
+            "covid.parquet",
+            "covid.arrow",
+            "covid.json",
+            "covid.ndjson",
+        ] {
+            let sql = format!(
+                "SELECT location, total_cases FROM \"{}/{}\" WHERE continent = 'Europe'",
+                dir, file
+            );
+            let df = query(sql).await?;
+            assert_eq!(df.get_column_names(), ["location", "total_cases"]);
+            assert_eq!(df.height(), 51, "{}", file);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_table_functions() -> Result<()> {
+        let tmp = write_formats()?;
+        let dir = tmp.path().display();
+
+        // 没有扩展名时默认是 CSV, 用表函数指定格式
+        assert!(
+            query(format!("SELECT * FROM \"{}/covid.data\"", dir))
+                .await
+                .is_err()
+        );
+        let sql = format!(
+            "SELECT c.location, t.code FROM read_parquet('{}/covid.data') c \
+            JOIN read_csv('examples/continents.csv') t ON c.continent = t.continent \
+            WHERE c.location = 'Japan'",
+            dir
+        );
+        let df = query(sql).await?;
+        assert_eq!(df.column("code")?.str()?.get(0), Some("AS"));
+
+        let err = query("SELECT * FROM read_xml('examples/covid.csv')")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not supported"));
+        Ok(())
+    }
 }
@@ -0,0 +1,105 @@
+use anyhow::{Result, anyhow};
+use polars::prelude::*;
+use sqlparser::ast::SetOperator;
+
+// INTERSECT ALL / EXCEPT ALL 时, 相同的行按出现的顺序编号, 编号也相同才算同一行
+const ROW_COLUMN: &str = "__row";
+
+// UNION / INTERSECT / EXCEPT 两个查询的结果. 两边的列按位置对应, 结果使用左边的列名.
+// all 为 false 时结果去重, 和 SQL 一样 NULL 和 NULL 看作相同的值
+pub fn combine(left: LazyFrame, right: LazyFrame, op: SetOperator, all: bool) -> Result<LazyFrame> {
+    let (left, right, names) = reconcile(left, right, op)?;
+
+    let how = match op {
+        SetOperator::Union => {
+            let df = concat([left, right], UnionArgs::default())?;
+            return Ok(match all {
+                true => df,
+                false => df.unique_stable(None, UniqueKeepStrategy::First),
+            });
+        }
+        SetOperator::Intersect => JoinType::Semi,
+        SetOperator::Except => JoinType::Anti,
+    };
+
+    // 左边的行在右边中有 (INTERSECT) 或者没有 (EXCEPT) 相同的行时保留
+    let filter = |left: LazyFrame, right: LazyFrame, keys: Vec<Expr>| {
+        left.join_builder()
+            .with(right)
+            .how(how)
+            .left_on(keys.clone())
+            .right_on(keys)
+            .join_nulls(true)
+            .finish()
+    };
+    let mut keys: Vec<Expr> = names.iter().map(|name| col(name.clone())).collect();
+    Ok(match all {
+        true => {
+            let row = int_range(lit(0), len(), 1, IDX_DTYPE).over(keys.clone());
+            let left = left.with_column(row.clone().alias(ROW_COLUMN));
+            let right = right.with_column(row.alias(ROW_COLUMN));
+            keys.push(col(ROW_COLUMN));
+            filter(left, right, keys).drop([ROW_COLUMN])
+        }
+        false => filter(
+            left.unique_stable(None, UniqueKeepStrategy::First),
+            right,
+            keys,
+        ),
+    })
+}
+
+// 检查两边的列数和类型, 把两边的列转换成相同的类型, 右边的列改用左边的列名
+fn reconcile(
+    mut left: LazyFrame,
+    mut right: LazyFrame,
+    op: SetOperator,
+) -> Result<(LazyFrame, LazyFrame, Vec<PlSmallStr>)> {
+    let lefts = left.collect_schema()?;
+    let rights = right.collect_schema()?;
+    if lefts.len() != rights.len() {
+        return Err(anyhow!(
+            "Each {} query must have the same number of columns, got {} and {}",
+            op,
+            lefts.len(),
+            rights.len()
+        ));
+    }
+
+    let mut names = Vec::with_capacity(lefts.len());
+    let mut left_columns = Vec::with_capacity(lefts.len());
+    let mut right_columns = Vec::with_capacity(rights.len());
+    for ((name, l), (other, r)) in lefts.iter().zip(rights.iter()) {
+        let dtype = supertype(l, r).ok_or_else(|| {
+            anyhow!(
+                "{} column {} has incompatible types {} and {}",
+                op,
+                name,
+                l,
+                r
+            )
+        })?;
+        left_columns.push(col(name.clone()).cast(dtype.clone()));
+        right_columns.push(col(other.clone()).cast(dtype).alias(name.clone()));
+        names.push(name.clone());
+    }
+
+    Ok((
+        left.select(left_columns),
+        right.select(right_columns),
+        names,
+    ))
+}
+
+// 两列可以合并时的共同类型. 数值之间可以合并, 有浮点数时是 Float64, 否则是 Int64
+pub(crate) fn supertype(l: &DataType, r: &DataType) -> Option<DataType> {
+    match (l, r) {
+        _ if l == r => Some(l.clone()),
+        (DataType::Null, t) | (t, DataType::Null) => Some(t.clone()),
+        _ if l.is_numeric() && r.is_numeric() => match l.is_float() || r.is_float() {
+            true => Some(DataType::Float64),
+            false => Some(DataType::Int64),
+        },
+        _ => None,
+    }
+}
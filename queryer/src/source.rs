@@ -0,0 +1,196 @@
+use anyhow::{Result, anyhow};
+use polars::prelude::*;
+use polars_plan::plans::ScanSources;
+use reqwest::header::CONTENT_TYPE;
+use std::{fs::File, io::Cursor, path::PathBuf};
+use tracing::info;
+
+use crate::convert::Table;
+
+// 数据源的格式. 压缩过的 CSV / NDJSON 读取时会自动解压
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Parquet,
+    Json,
+    NdJson,
+    Ipc,
+}
+
+impl Format {
+    // read_parquet('path') 这样的表函数
+    pub fn from_function(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "read_csv" => Ok(Self::Csv),
+            "read_parquet" => Ok(Self::Parquet),
+            "read_json" => Ok(Self::Json),
+            "read_ndjson" => Ok(Self::NdJson),
+            "read_ipc" | "read_arrow" => Ok(Self::Ipc),
+            _ => Err(anyhow!("Table function {} not supported", name)),
+        }
+    }
+
+    // 从扩展名判断格式, URL 中 ? 之后的部分和 .gz / .zst 这样的压缩后缀会被忽略
+    pub fn from_extension(source: &str) -> Option<Self> {
+        let path = source.split('?').next().unwrap_or(source).to_lowercase();
+        let path = ["gz", "zst"]
+            .iter()
+            .find_map(|ext| path.strip_suffix(&format!(".{}", ext)))
+            .unwrap_or(&path);
+        match path.rsplit_once('.')?.1 {
+            "csv" | "tsv" => Some(Self::Csv),
+            "parquet" => Some(Self::Parquet),
+            "json" => Some(Self::Json),
+            "ndjson" | "jsonl" => Some(Self::NdJson),
+            "arrow" | "ipc" | "feather" => Some(Self::Ipc),
+            _ => None,
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next()?.trim().to_lowercase();
+        match mime.as_str() {
+            "text/csv" => Some(Self::Csv),
+            "application/vnd.apache.parquet" | "application/x-parquet" => Some(Self::Parquet),
+            "application/json" => Some(Self::Json),
+            "application/x-ndjson" | "application/jsonl" => Some(Self::NdJson),
+            "application/vnd.apache.arrow.file" => Some(Self::Ipc),
+            _ => None,
+        }
+    }
+}
+
+// 加载数据源. 格式依次由表函数, 扩展名, http 的 Content-Type 决定, 都没有时当作 CSV.
+//
+// http 的数据要先整个下载到内存中, 再和本地文件一样 lazy 地 scan. JSON 数组没有 lazy 的 scan,
+// 无论来自哪里都会整个读成 DataFrame, 过滤条件和用到的列不能下推到读取中
+pub async fn load(table: &Table<'_>) -> Result<LazyFrame> {
+    let source = table.source;
+    info!("retrieving data from source: {}", source);
+
+    let format = table.format.or_else(|| Format::from_extension(source));
+    match source {
+        source if source.starts_with("http") => {
+            let res = reqwest::get(source).await?.error_for_status()?;
+            let format = format.or_else(|| {
+                let content_type = res.headers().get(CONTENT_TYPE)?.to_str().ok()?;
+                Format::from_content_type(content_type)
+            });
+            let data = res.bytes().await?;
+            scan(
+                ScanSources::Buffers([data].into()),
+                format.unwrap_or(Format::Csv),
+            )
+        }
+        _ => {
+            let sources = ScanSources::Paths([PathBuf::from(source)].into());
+            scan(sources, format.unwrap_or(Format::Csv))
+        }
+    }
+}
+
+// 本地文件和下载的数据都用 lazy 的 scan, polars 会把过滤条件和用到的列下推到读取中
+fn scan(sources: ScanSources, format: Format) -> Result<LazyFrame> {
+    let df = match format {
+        Format::Csv => LazyCsvReader::new_with_sources(sources).finish()?,
+        Format::Parquet => LazyFrame::scan_parquet_sources(sources, Default::default())?,
+        Format::NdJson => LazyJsonLineReader::new_with_sources(sources).finish()?,
+        Format::Ipc => LazyFrame::scan_ipc_sources(sources, Default::default())?,
+        Format::Json => read_json(sources)?.lazy(),
+    };
+    Ok(df)
+}
+
+// JSON 数组只能整个读进来
+fn read_json(sources: ScanSources) -> Result<DataFrame> {
+    let df = match sources {
+        ScanSources::Paths(paths) => match paths.as_ref() {
+            [path] => JsonReader::new(File::open(path)?).finish()?,
+            _ => return Err(anyhow!("JSON source must be a single file")),
+        },
+        ScanSources::Buffers(buffers) => match buffers.as_ref() {
+            [data] => JsonReader::new(Cursor::new(data.clone())).finish()?,
+            _ => return Err(anyhow!("JSON source must be a single buffer")),
+        },
+        ScanSources::Files(_) => return Err(anyhow!("JSON source must be a path or a buffer")),
+    };
+    Ok(df)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn format_should_be_detected() {
+        assert_eq!(Format::from_extension("a/b.csv"), Some(Format::Csv));
+        assert_eq!(Format::from_extension("a/b.CSV.gz"), Some(Format::Csv));
+        assert_eq!(
+            Format::from_extension("https://x.io/b.parquet?v=1"),
+            Some(Format::Parquet)
+        );
+        assert_eq!(Format::from_extension("b.jsonl"), Some(Format::NdJson));
+        assert_eq!(Format::from_extension("b.arrow"), Some(Format::Ipc));
+        assert_eq!(Format::from_extension("b.data"), None);
+        assert_eq!(Format::from_extension("data"), None);
+
+        assert_eq!(
+            Format::from_content_type("text/csv; charset=utf-8"),
+            Some(Format::Csv)
+        );
+        assert_eq!(
+            Format::from_content_type("application/json"),
+            Some(Format::Json)
+        );
+        assert_eq!(Format::from_content_type("text/plain"), None);
+
+        assert_eq!(
+            Format::from_function("READ_PARQUET").unwrap(),
+            Format::Parquet
+        );
+        assert!(Format::from_function("read_xml").is_err());
+    }
+
+    fn path(path: &str) -> ScanSources {
+        ScanSources::Paths([PathBuf::from(path)].into())
+    }
+
+    #[test]
+    fn scan_should_push_down_predicate_and_projection() -> Result<()> {
+        let plan = scan(path("examples/covid.csv"), Format::Csv)?
+            .filter(col("continent").eq(lit("Europe")))
+            .select([col("location")])
+            .explain(true)?;
+        // 过滤和投影都在 scan 中完成, 而不是读完之后再做
+        assert!(plan.contains("SELECTION"), "{}", plan);
+        assert!(plan.contains("PROJECT 2/"), "{}", plan);
+        assert!(!plan.contains("FILTER"), "{}", plan);
+        Ok(())
+    }
+
+    #[test]
+    fn downloaded_data_should_be_scanned_lazily() -> Result<()> {
+        use flate2::{Compression, write::GzEncoder};
+        use std::io::Write;
+
+        // 压缩过的 CSV 也可以直接 scan
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(&std::fs::read("examples/covid.csv")?)?;
+        let data = Bytes::from(gz.finish()?);
+        let mut df = scan(ScanSources::Buffers([data].into()), Format::Csv)?.collect()?;
+        let mut ndjson = Vec::new();
+        JsonWriter::new(&mut ndjson)
+            .with_json_format(JsonFormat::JsonLines)
+            .finish(&mut df)?;
+
+        let buffers = ScanSources::Buffers([Bytes::from(ndjson)].into());
+        let lf = scan(buffers, Format::NdJson)?
+            .filter(col("continent").eq(lit("Europe")))
+            .select([col("location")]);
+        let plan = lf.clone().explain(true)?;
+        assert!(!plan.contains("FILTER"), "{}", plan);
+        assert_eq!(lf.collect()?.height(), 51);
+        Ok(())
+    }
+}